@@ -112,7 +112,7 @@ where
     fn get_node_from_id(
         &self,
         scope_id: SCOPEID,
-    ) -> Result<ESymbolNodeRef<SCOPEID, SYMID>, SymbolError> {
+    ) -> Result<ESymbolNodeRef<'_, SCOPEID, SYMID>, SymbolError> {
         let node_id = self.get_node_id_from_scope_id(scope_id)?;
         self.tree.get(node_id).ok_or(SymbolError::InvalidScope)
     }
@@ -387,19 +387,19 @@ where
         scope.get_scope_fqn_name().to_owned()
     }
 
-    pub fn get_writer(&mut self, scope_id: SCOPEID) -> SymbolTreeWriter<SCOPEID, SYMID, V> {
+    pub fn get_writer(&mut self, scope_id: SCOPEID) -> SymbolTreeWriter<'_, SCOPEID, SYMID, V> {
         SymbolTreeWriter::new(self, scope_id)
     }
 
-    pub fn get_root_writer(&mut self) -> SymbolTreeWriter<SCOPEID, SYMID, V> {
+    pub fn get_root_writer(&mut self) -> SymbolTreeWriter<'_, SCOPEID, SYMID, V> {
         SymbolTreeWriter::new(self, self.get_root_scope_id())
     }
 
-    pub fn get_reader(&self, scope_id: SCOPEID) -> SymbolTreeReader<SCOPEID, SYMID, V> {
+    pub fn get_reader(&self, scope_id: SCOPEID) -> SymbolTreeReader<'_, SCOPEID, SYMID, V> {
         SymbolTreeReader::new(self, scope_id)
     }
 
-    pub fn get_root_reader(&self) -> SymbolTreeReader<SCOPEID, SYMID, V> {
+    pub fn get_root_reader(&self) -> SymbolTreeReader<'_, SCOPEID, SYMID, V> {
         self.get_reader(self.get_root_scope_id())
     }
 
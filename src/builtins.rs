@@ -0,0 +1,84 @@
+/// Functions the compiler knows about before any source is read
+/// They live in the root scope of the symbol tree with a Value::BuiltIn value
+use crate::symbols::{SymbolError, SymbolTree};
+use crate::value::Value;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BuiltIn {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Eq,
+    Not,
+    Nth,
+    NthRest,
+}
+
+impl BuiltIn {
+    pub const ALL: [BuiltIn; 8] = [
+        BuiltIn::Add,
+        BuiltIn::Sub,
+        BuiltIn::Mul,
+        BuiltIn::Div,
+        BuiltIn::Eq,
+        BuiltIn::Not,
+        BuiltIn::Nth,
+        BuiltIn::NthRest,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        use BuiltIn::*;
+        match self {
+            Add => "+",
+            Sub => "-",
+            Mul => "*",
+            Div => "div",
+            Eq => "eq",
+            Not => "not",
+            Nth => "nth",
+            NthRest => "nthrest",
+        }
+    }
+
+    /// Min and max number of args this builtin takes
+    pub fn arity(&self) -> (usize, Option<usize>) {
+        use BuiltIn::*;
+        match self {
+            Add | Sub | Mul | Div => (2, None),
+            Eq | Nth | NthRest => (2, Some(2)),
+            Not => (1, Some(1)),
+        }
+    }
+
+    pub fn accepts(&self, n: usize) -> bool {
+        let (min, max) = self.arity();
+        n >= min && max.map(|max| n <= max).unwrap_or(true)
+    }
+}
+
+impl std::fmt::Display for BuiltIn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Add all of the builtins to the root scope
+pub fn add_builtins(syms: &mut SymbolTree) -> Result<(), SymbolError> {
+    let root = syms.get_root_scope_id();
+
+    for b in BuiltIn::ALL {
+        let id = syms.create_symbol_in_scope(root, b.name())?;
+        syms.set_value_for_id(id, Value::BuiltIn(b))?;
+    }
+
+    Ok(())
+}
+
+/// Get the builtin a symbol is bound to, if any
+pub fn get_builtin(syms: &SymbolTree, id: crate::symbols::SymbolScopeId) -> Option<BuiltIn> {
+    match syms.get_symbol_info_from_id(id).ok()?.value {
+        Some(Value::BuiltIn(b)) => Some(b),
+        _ => None,
+    }
+}
@@ -4,6 +4,13 @@ pub enum CliErrorKind {
     UnrecognisedCommand(String),
     #[error("Need a build action")]
     NoAction,
+    #[error("{}:{line}:{col}: {msg}", file.to_string_lossy())]
+    ProjectFile {
+        file: std::path::PathBuf,
+        line: usize,
+        col: usize,
+        msg: String,
+    },
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
@@ -1,4 +1,4 @@
-use crate::opts::{Action, Opts};
+use crate::opts::{Action, MessageFormat, Opts, Pass};
 use clap::{Arg, ArgAction, Command};
 
 use std::{
@@ -15,7 +15,9 @@ lazy_static::lazy_static! {
     static ref COMMANDS : Vec<CommandInfo> = vec![
         CommandInfo::new("build",Action::Build, "Build the project"),
         CommandInfo::new("check",Action::Check, "Check for errors"),
+        CommandInfo::new("run",Action::Run, "Build and run the project"),
         CommandInfo::new("lsp",Action::Lsp, "Launch LSP server"),
+        CommandInfo::new("fmt",Action::Fmt, "Format the project's source files"),
     ];
 
     static ref COM_TO_COM_INFO : HashMap<&'static str,CommandInfo> = {
@@ -68,10 +70,52 @@ impl Parser {
             .get_one::<String>("config-file")
             .unwrap_or(&self.default_project_file)
             .into();
-        let opts = load_project_file(path)?;
+        let mut opts = load_project_file(path)?;
+
+        let passes = |id: &str| {
+            m.get_many::<String>(id)
+                .into_iter()
+                .flatten()
+                .filter_map(|name| Pass::from_name(name))
+                .collect::<Vec<_>>()
+        };
+
+        opts.codegen.disable.extend(passes("disable"));
+        opts.codegen.dump.extend(passes("dump"));
+
+        // Only some commands take a message format
+        let format = m.try_get_one::<String>("message-format").ok().flatten();
+        if let Some(format) = format.and_then(|f| MessageFormat::from_name(f)) {
+            opts.message_format = format;
+        }
+
+        opts.check_format = m.try_get_one::<bool>("check").ok().flatten() == Some(&true);
+
         Ok(Opts { action, ..opts })
     }
 
+    fn make_pass_arg(&self, id: &'static str, help: &'static str) -> clap::Arg {
+        let names: Vec<_> = Pass::ALL.iter().map(|p| p.name()).collect();
+
+        Arg::new(id)
+            .long(id)
+            .help(help)
+            .value_name("PASS")
+            .value_parser(clap::builder::PossibleValuesParser::new(names))
+            .action(ArgAction::Append)
+            .global(true)
+    }
+
+    fn make_message_format_arg(&self) -> clap::Arg {
+        let names: Vec<_> = MessageFormat::ALL.iter().map(|f| f.name()).collect();
+
+        Arg::new("message-format")
+            .long("message-format")
+            .help("How errors are written")
+            .value_name("FORMAT")
+            .value_parser(clap::builder::PossibleValuesParser::new(names))
+    }
+
     fn make_config_file_command(
         &self,
         command: &'static str,
@@ -94,10 +138,27 @@ impl Parser {
                     .short('v')
                     .action(ArgAction::SetTrue)
                     .global(true),
-            );
+            )
+            .arg(self.make_pass_arg("disable", "Don't run an optimisation pass"))
+            .arg(self.make_pass_arg("dump", "Print the code before and after an optimisation pass"));
 
         for com_info in COM_TO_COM_INFO.values() {
-            com = com.subcommand(self.make_config_file_command(com_info.id, com_info.help_text))
+            let mut sub = self.make_config_file_command(com_info.id, com_info.help_text);
+
+            if matches!(com_info.action, Action::Build | Action::Check) {
+                sub = sub.arg(self.make_message_format_arg());
+            }
+
+            if matches!(com_info.action, Action::Fmt) {
+                sub = sub.arg(
+                    Arg::new("check")
+                        .long("check")
+                        .help("Don't change files, fail if any aren't formatted")
+                        .action(ArgAction::SetTrue),
+                );
+            }
+
+            com = com.subcommand(sub)
         }
 
         com.get_matches()
@@ -120,8 +181,8 @@ impl Parser {
     }
 }
 
-/// Find the ploy project file from a supplied path
-/// If the path is a dir it will append the default_project_file
+// Find the ploy project file from a supplied path
+// If the path is a dir it will append the default_project_file
 // fn find_project_file<P: AsRef<Path>>(
 //     path: P,
 //     default_project_file: &str,
@@ -1,5 +1,6 @@
-use std::path::Path;
-use crate::opts::Opts;
+use std::path::{Path, PathBuf};
+use crate::opts::{CodeGenOpts, LspOpts, Opts, Pass, Project, DEFAULT_PROJECT_FILE};
+use crate::sources::Lines;
 use serde::Deserialize;
 use super::error::CliErrorKind;
 
@@ -7,14 +8,172 @@ use super::error::CliErrorKind;
 #[serde(default)]
 #[serde(rename_all = "kebab-case")]
 pub struct ProjectFile {
-    pub opts: Option<Opts>,
+    pub project: Project,
+    pub opts: Opts,
+    pub lsp: LspOpts,
+    pub codegen: CodeGenOpts,
+}
+
+impl ProjectFile {
+    /// Merge all of the tables into one set of options
+    /// An entry point is compiled if [opts] doesn't name a file
+    pub fn into_opts(self) -> Opts {
+        let mut opts = self.opts;
+        let project = self.project;
+
+        if opts.project_file == Path::new(DEFAULT_PROJECT_FILE) {
+            if let Some(entry) = project.entry_points.first() {
+                opts.project_file = entry.clone()
+            }
+        }
+
+        Opts {
+            project,
+            lsp: self.lsp,
+            codegen: self.codegen,
+            ..opts
+        }
+    }
+}
+
+/// Parse the text of a project file
+/// Returns the project file and a warning for every key we don't understand
+pub fn parse_project_file<P: AsRef<Path>>(
+    text: &str,
+    path: P,
+) -> Result<(ProjectFile, Vec<String>), CliErrorKind> {
+    let path = path.as_ref();
+
+    let to_error = |e: toml::de::Error| {
+        let loc = e
+            .span()
+            .and_then(|r| Lines::new(text).get_location_from_offset(r.start))
+            .unwrap_or_default();
+
+        CliErrorKind::ProjectFile {
+            file: path.to_path_buf(),
+            line: loc.line + 1,
+            col: loc.col + 1,
+            msg: e.message().trim().to_owned(),
+        }
+    };
+
+    let mut warnings = vec![];
+    let de = toml::Deserializer::parse(text).map_err(to_error)?;
+
+    let config: ProjectFile = serde_ignored::deserialize(de, |key| {
+        warnings.push(format!("{}: unknown key `{key}`", path.to_string_lossy()))
+    })
+    .map_err(to_error)?;
+
+    // Only one file is compiled, so say which entry points are left out
+    let entry_points = &config.project.entry_points;
+    if config.opts.project_file == Path::new(DEFAULT_PROJECT_FILE) && entry_points.len() > 1 {
+        let ignored: Vec<_> = entry_points[1..].iter().map(|p| p.to_string_lossy()).collect();
+        warnings.push(format!(
+            "{}: only the first entry point `{}` is built, ignoring {}",
+            path.to_string_lossy(),
+            entry_points[0].to_string_lossy(),
+            ignored.join(", ")
+        ))
+    }
+
+    Ok((config, warnings))
 }
 
 pub fn load_project_file<P: AsRef<Path>>(path : P) -> Result<Opts,CliErrorKind> {
     use anyhow::Context;
     let path = path.as_ref();
     let f = std::fs::read_to_string(path).with_context(|| format!("Can't load configuration file: {}", path.to_string_lossy()))?;
-    let config : ProjectFile = toml::from_str(&f).context("Yaml deserializing error")?;
-    let opts = config.opts.unwrap_or_default();
-    Ok(opts)
+    let (config, warnings) = parse_project_file(&f, path)?;
+
+    for w in warnings {
+        eprintln!("Warning: {w}")
+    }
+
+    Ok(config.into_opts())
+}
+
+#[allow(unused_imports)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_project_file() {
+        let text = r#"
+[project]
+name = "Test"
+version = "0.1.0"
+source-roots = ["src"]
+entry-points = ["main.ploy"]
+search-paths = ["lib"]
+colour = "blue"
+
+[opts]
+verbosity = "Info"
+speed = 11
+
+[lsp]
+log-file = "lsp.log"
+
+[codegen]
+registers = 8
+disable = ["dce"]
+dump = ["fold", "copy-prop"]
+"#;
+        let (config, warnings) = parse_project_file(text, "Ploy.toml").unwrap();
+
+        assert_eq!(
+            warnings,
+            vec![
+                "Ploy.toml: unknown key `opts.speed`",
+                "Ploy.toml: unknown key `project.colour`"
+            ]
+        );
+
+        let opts = config.into_opts();
+        assert_eq!(opts.project.name, "Test");
+        assert_eq!(opts.project.version.as_deref(), Some("0.1.0"));
+        assert_eq!(opts.project_file, PathBuf::from("main.ploy"));
+        assert_eq!(opts.lsp.log_file, Some(PathBuf::from("lsp.log")));
+        assert_eq!(opts.codegen.registers, 8);
+        assert!(!opts.codegen.enabled(Pass::Dce));
+        assert!(opts.codegen.dumps(Pass::CopyProp));
+
+        let paths: Vec<_> = opts.search_paths().cloned().collect();
+        assert_eq!(paths, vec![PathBuf::from("src"), PathBuf::from("lib")]);
+    }
+
+    #[test]
+    fn test_project_file_entry_points() {
+        let text = "[project]\nname = \"Test\"\nentry-points = [\"a.ploy\", \"b.ploy\", \"c.ploy\"]\n";
+        let (config, warnings) = parse_project_file(text, "Ploy.toml").unwrap();
+        assert_eq!(
+            warnings,
+            vec!["Ploy.toml: only the first entry point `a.ploy` is built, ignoring b.ploy, c.ploy"]
+        );
+        assert_eq!(config.into_opts().project_file, PathBuf::from("a.ploy"));
+
+        // A file named in [opts] is built instead of any entry point
+        let text = format!("{text}[opts]\nproject-file = \"main.ploy\"\n");
+        let (config, warnings) = parse_project_file(&text, "Ploy.toml").unwrap();
+        assert!(warnings.is_empty(), "{warnings:?}");
+        assert_eq!(config.into_opts().project_file, PathBuf::from("main.ploy"));
+    }
+
+    #[test]
+    fn test_project_file_errors() {
+        let err = |text| parse_project_file(text, "Ploy.toml").unwrap_err().to_string();
+
+        let text = "[project]\nname = \"Test\"\nversion = 1\n";
+        assert!(err(text).starts_with("Ploy.toml:3:11: "), "{}", err(text));
+
+        let text = "[project]\nversion = \"1\"\n";
+        assert!(err(text).starts_with("Ploy.toml:1:1: "), "{}", err(text));
+        assert!(err(text).contains("name"));
+
+        let text = "[lsp]\nlog-file = \n";
+        assert!(err(text).starts_with("Ploy.toml:2:"), "{}", err(text));
+    }
 }
@@ -1,2 +1,107 @@
+/// Back end driver
+/// Generates code for a module and runs it on the VM
+use crate::frontend::Module;
+use crate::ir::{
+    cfg::Cfg,
+    codegen::CodeGen,
+    error::IrError,
+    instructions::Instruction,
+    regalloc::RegAlloc,
+    vm::{constant_index, HeapObject, Machine, Registers, FALSE, NULL, TRUE},
+};
+use crate::opts::{CodeGenOpts, Pass};
+use crate::value::Value;
 
+/// A pass over a Cfg in SSA form, returns how many things it changed
+type SsaPass = fn(&mut Cfg) -> usize;
 
+pub struct Program {
+    pub code: Vec<Instruction>,
+    pub constants: Vec<Value>,
+}
+
+impl Program {
+    pub fn new(module: &Module, opts: &CodeGenOpts) -> Result<Self, IrError> {
+        let mut cg = CodeGen::with_opts(module, opts);
+        cg.code_gen_module()?;
+
+        if opts.dumps(Pass::Fold) {
+            for (id, val) in cg.folded() {
+                eprintln!("fold: {} => {val}", module.ast.get_source_text(*id));
+            }
+        }
+
+        let code = Self::optimise(cg.code(), opts)?;
+        let code = RegAlloc::new(opts.registers).alloc(&code)?;
+
+        Ok(Self {
+            code,
+            constants: cg.constants().to_vec(),
+        })
+    }
+
+    /// Run the passes that work on SSA form, if any are enabled
+    fn optimise(code: &[Instruction], opts: &CodeGenOpts) -> Result<Vec<Instruction>, IrError> {
+        let passes: [(Pass, SsaPass); 2] = [(Pass::CopyProp, Cfg::propagate_copies), (Pass::Dce, Cfg::eliminate_dead_code)];
+
+        if !passes.iter().any(|(p, _)| opts.enabled(*p)) {
+            return Ok(code.to_vec());
+        }
+
+        let mut cfg = Cfg::new(code)?;
+        cfg.to_ssa();
+
+        for (pass, run) in passes.into_iter().filter(|(p, _)| opts.enabled(*p)) {
+            if opts.dumps(pass) {
+                eprintln!("{} before:\n{cfg}", pass.name());
+            }
+
+            let changed = run(&mut cfg);
+
+            if opts.dumps(pass) {
+                eprintln!("{} after, {changed} changed:\n{cfg}", pass.name());
+            }
+        }
+
+        cfg.from_ssa();
+        Ok(cfg.to_code())
+    }
+
+    pub fn run(&self) -> Result<Value, IrError> {
+        let mut regs = Registers::for_code(&self.code);
+        let mut machine = Machine::new(&self.code, &mut regs);
+        let ret = machine.run()?;
+        Ok(self.to_value(&machine, ret))
+    }
+
+    /// Convert a raw register value into a Value
+    /// Everything but a number is tagged, so the value alone says what it is
+    fn to_value(&self, machine: &Machine, v: usize) -> Value {
+        match v {
+            NULL => return Value::Null,
+            TRUE => return Value::Bool(true),
+            FALSE => return Value::Bool(false),
+            _ => (),
+        }
+
+        if let Some(c) = constant_index(v).and_then(|i| self.constants.get(i)) {
+            return c.clone();
+        }
+
+        let to_value = |v: usize| self.to_value(machine, v);
+
+        match machine.get_heap_object(v) {
+            Some(HeapObject::Map(pairs)) => {
+                let pairs = pairs.iter().map(|(k, v)| (to_value(*k), to_value(*v)));
+                return Value::Map(pairs.collect::<Vec<_>>().into());
+            }
+            Some(HeapObject::Array(vals)) => {
+                return Value::Array(vals.iter().map(|v| to_value(*v)).collect::<Vec<_>>().into());
+            }
+            Some(HeapObject::Closure { entry, .. }) => return Value::Code(*entry),
+            None => (),
+        }
+
+        Value::Signed(v as i64)
+    }
+}
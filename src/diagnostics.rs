@@ -0,0 +1,399 @@
+/// Diagnostics, errors rendered with the source they're about
+/// A diagnostic has a message, an optional code, labelled spans of a source file and
+/// notes and help to go after them. The primary span is where the problem is, the
+/// secondary ones are there to explain it
+///
+/// ```text
+/// error[E0003]: Type error: if branches disagree, int and string
+///  --> main.ploy:2:17
+///   |
+/// 2 | (if (eq 1 2) 10 "no")
+///   |                 ^^^^
+///   |              -- this is int
+/// ```
+use crate::sources::SourceFile;
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use std::io::IsTerminal;
+use std::ops::Range;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Level {
+    Error,
+    Warning,
+}
+
+impl Level {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warning => "warning",
+        }
+    }
+
+    fn style(&self) -> Style {
+        match self {
+            Level::Error => Style::Red,
+            Level::Warning => Style::Yellow,
+        }
+    }
+
+    /// The DiagnosticSeverity the language server protocol uses
+    pub fn lsp_severity(&self) -> usize {
+        match self {
+            Level::Error => 1,
+            Level::Warning => 2,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Style {
+    Red,
+    Yellow,
+    Blue,
+    Bold,
+}
+
+impl Style {
+    fn paint(self, text: &str, colour: bool) -> String {
+        let code = match self {
+            Style::Red => "1;31",
+            Style::Yellow => "1;33",
+            Style::Blue => "1;34",
+            Style::Bold => "1",
+        };
+
+        if colour && !text.is_empty() {
+            format!("\x1b[{code}m{text}\x1b[0m")
+        } else {
+            text.to_owned()
+        }
+    }
+}
+
+/// A span of the source, offsets into its text
+#[derive(Clone, Debug, PartialEq)]
+pub struct Label {
+    pub range: Range<usize>,
+    pub message: String,
+    pub primary: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub level: Level,
+    pub code: Option<&'static str>,
+    pub message: String,
+    /// The file the labels are in, without it only the message and notes are shown
+    pub source: Option<SourceFile>,
+    pub labels: Vec<Label>,
+    pub notes: Vec<String>,
+    pub help: Vec<String>,
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.render(false))
+    }
+}
+
+/// Whether to colour diagnostics written to stderr
+pub fn use_colour() -> bool {
+    std::io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none()
+}
+
+/// Render diagnostics one after another
+pub fn render_all(diagnostics: &[Diagnostic], colour: bool) -> String {
+    let rendered: Vec<_> = diagnostics.iter().map(|d| d.render(colour)).collect();
+    rendered.join("\n")
+}
+
+/// Where a label is in its source, lines and columns from 0
+/// The end is exclusive, a label ending with a newline ends at the end of that line
+struct Place {
+    start: (usize, usize),
+    end: (usize, usize),
+}
+
+/// Lines of a multi line label past this many have their middle left out
+const MAX_LABEL_LINES: usize = 4;
+
+impl Diagnostic {
+    pub fn new<S: Into<String>>(level: Level, message: S) -> Self {
+        Self {
+            level,
+            code: None,
+            message: message.into(),
+            source: None,
+            labels: vec![],
+            notes: vec![],
+            help: vec![],
+        }
+    }
+
+    pub fn error<S: Into<String>>(message: S) -> Self {
+        Self::new(Level::Error, message)
+    }
+
+    pub fn with_code(mut self, code: &'static str) -> Self {
+        self.code = Some(code);
+        self
+    }
+
+    pub fn with_source(mut self, source: &SourceFile) -> Self {
+        self.source = Some(source.clone());
+        self
+    }
+
+    pub fn primary<S: Into<String>>(mut self, range: Range<usize>, message: S) -> Self {
+        self.labels.push(Label {
+            range,
+            message: message.into(),
+            primary: true,
+        });
+        self
+    }
+
+    pub fn secondary<S: Into<String>>(mut self, range: Range<usize>, message: S) -> Self {
+        self.labels.push(Label {
+            range,
+            message: message.into(),
+            primary: false,
+        });
+        self
+    }
+
+    pub fn note<S: Into<String>>(mut self, note: S) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    pub fn help<S: Into<String>>(mut self, help: S) -> Self {
+        self.help.push(help.into());
+        self
+    }
+
+    /// The label the location in the header comes from
+    fn main_label(&self) -> Option<&Label> {
+        self.labels.iter().find(|l| l.primary).or(self.labels.first())
+    }
+
+    pub fn render(&self, colour: bool) -> String {
+        let mut out = String::new();
+        let level = self.level.style();
+        let blue = |t: &str| Style::Blue.paint(t, colour);
+
+        let header = match self.code {
+            Some(code) => format!("{}[{code}]", self.level.name()),
+            None => self.level.name().to_owned(),
+        };
+        let _ = writeln!(out, "{}: {}", level.paint(&header, colour), Style::Bold.paint(&self.message, colour));
+
+        let snippet = self.source.as_ref().zip(self.main_label());
+        let mut width = 1;
+
+        if let Some((source, main)) = snippet {
+            let lines = line_ranges(source.text());
+            let place = |l: &Label| place(source.text(), &lines, &l.range);
+
+            let places: Vec<_> = self.labels.iter().map(place).collect();
+            let shown = shown_lines(&places);
+            width = (shown.last().unwrap() + 1).to_string().len();
+            let gutter = blue(&format!("{} |", " ".repeat(width)));
+
+            let (line, col) = place(main).start;
+            let _ = writeln!(out, "{}{} {}:{}:{}", " ".repeat(width), blue("-->"), source.origin, line + 1, col + 1);
+            let _ = writeln!(out, "{gutter}");
+
+            let mut prev = None;
+
+            for &n in &shown {
+                if prev.is_some_and(|p| p + 1 < n) {
+                    let _ = writeln!(out, "{}", blue("..."));
+                }
+                prev = Some(n);
+
+                let text = line_text(source.text(), &lines, n);
+                let _ = writeln!(out, "{} {text}", blue(&format!("{:>width$} |", n + 1)));
+
+                // One row of underlining for each label on this line
+                let mut marks: Vec<_> = self
+                    .labels
+                    .iter()
+                    .zip(&places)
+                    .filter(|(_, p)| p.start.0 <= n && n <= p.end.0)
+                    .collect();
+                marks.sort_by_key(|(l, p)| (!l.primary, p.start));
+
+                for (label, p) in marks {
+                    let indent = text.chars().take_while(|c| c.is_whitespace()).count();
+                    let from = if n == p.start.0 { p.start.1 } else { indent };
+                    let to = if n == p.end.0 { p.end.1 } else { text.chars().count() };
+                    let (mark, style) = if label.primary { ('^', level) } else { ('-', Style::Blue) };
+                    let marks = mark.to_string().repeat(to.saturating_sub(from).max(1));
+
+                    let mut row = format!("{gutter} {}{}", " ".repeat(from), style.paint(&marks, colour));
+                    if n == p.end.0 && !label.message.is_empty() {
+                        row = format!("{row} {}", style.paint(&label.message, colour));
+                    }
+                    let _ = writeln!(out, "{row}");
+                }
+            }
+
+            if !self.notes.is_empty() || !self.help.is_empty() {
+                let _ = writeln!(out, "{gutter}");
+            }
+        }
+
+        let notes = self.notes.iter().map(|n| ("note", n));
+        for (kind, text) in notes.chain(self.help.iter().map(|h| ("help", h))) {
+            let _ = writeln!(out, "{} {} {text}", blue(&format!("{:>width$} =", "")), Style::Bold.paint(&format!("{kind}:"), colour));
+        }
+
+        out
+    }
+}
+
+/// The range of the text of every line, without its newline
+fn line_ranges(text: &str) -> Vec<Range<usize>> {
+    let mut start = 0;
+    let mut ret = vec![];
+
+    for line in text.split('\n') {
+        ret.push(start..start + line.len());
+        start += line.len() + 1;
+    }
+
+    ret
+}
+
+fn line_text<'a>(text: &'a str, lines: &[Range<usize>], n: usize) -> &'a str {
+    text[lines[n].clone()].trim_end_matches('\r')
+}
+
+/// Line and column, in chars, of an offset
+fn line_col(text: &str, lines: &[Range<usize>], offset: usize) -> (usize, usize) {
+    let offset = offset.min(text.len());
+    let line = lines.iter().rposition(|r| r.start <= offset).unwrap_or(0);
+    let r = &lines[line];
+    let col = text.get(r.start..offset.min(r.end)).map(|t| t.chars().count()).unwrap_or(0);
+    (line, col)
+}
+
+fn place(text: &str, lines: &[Range<usize>], range: &Range<usize>) -> Place {
+    let mut end = line_col(text, lines, range.end);
+
+    // Ending just after a newline is ending at the end of the line before
+    if range.end > range.start && end.1 == 0 && end.0 > 0 {
+        end = line_col(text, lines, lines[end.0 - 1].end);
+    }
+
+    let start = line_col(text, lines, range.start);
+    let end = if end < start { start } else { end };
+    Place { start, end }
+}
+
+/// Every line a label is on, leaving out the middle of long ones
+fn shown_lines(places: &[Place]) -> Vec<usize> {
+    let mut shown = BTreeSet::new();
+
+    for p in places {
+        let (first, last) = (p.start.0, p.end.0);
+        if last - first < MAX_LABEL_LINES {
+            shown.extend(first..=last);
+        } else {
+            shown.extend([first, first + 1, last - 1, last]);
+        }
+    }
+
+    shown.into_iter().collect()
+}
+
+#[allow(unused_imports)]
+mod test {
+    use super::*;
+    use crate::sources::SourceOrigin;
+    use pretty_assertions::assert_eq;
+
+    fn source(text: &str) -> SourceFile {
+        SourceFile::new(text.to_owned(), SourceOrigin::Text)
+    }
+
+    #[test]
+    fn test_render() {
+        let sf = source("(def x 1)\n(if (eq 1 2) 10 \"no\")\n");
+
+        let d = Diagnostic::error("if branches disagree, int and string")
+            .with_code("E0003")
+            .with_source(&sf)
+            .primary(26..30, "this is a string")
+            .secondary(23..25, "this is an int")
+            .help("make both branches the same type");
+
+        let expected = [
+            "error[E0003]: if branches disagree, int and string",
+            " --> <text>:2:17",
+            "  |",
+            "2 | (if (eq 1 2) 10 \"no\")",
+            "  |                 ^^^^ this is a string",
+            "  |              -- this is an int",
+            "  |",
+            "  = help: make both branches the same type",
+            "",
+        ];
+        assert_eq!(d.render(false), expected.join("\n"));
+
+        // No source, no snippet
+        let d = Diagnostic::error("Division by zero").note("at pc 0x0010");
+        assert_eq!(d.render(false), "error: Division by zero\n  = note: at pc 0x0010\n");
+
+        let coloured = Diagnostic::error("oops").render(true);
+        assert_eq!(coloured, "\x1b[1;31merror\x1b[0m: \x1b[1moops\x1b[0m\n");
+    }
+
+    #[test]
+    fn test_render_lines() {
+        let text = (1..=12).map(|n| format!("  line{n}")).collect::<Vec<_>>().join("\n");
+        let sf = source(&text);
+        let offset = |line: usize, col: usize| sf.get_offset(line, col).unwrap();
+
+        // A label over three lines underlines each, the message goes on the last
+        let d = Diagnostic::error("spans").with_source(&sf).primary(offset(0, 4)..offset(2, 5), "here");
+        let expected = [
+            "error: spans",
+            " --> <text>:1:5",
+            "  |",
+            "1 |   line1",
+            "  |     ^^^",
+            "2 |   line2",
+            "  |   ^^^^^",
+            "3 |   line3",
+            "  |   ^^^ here",
+            "",
+        ];
+        assert_eq!(d.render(false), expected.join("\n"));
+
+        // The middle of a long label is left out, as are the lines between labels
+        let d = Diagnostic::error("long")
+            .with_source(&sf)
+            .primary(offset(0, 2)..offset(6, 7), "")
+            .secondary(offset(10, 2)..offset(10, 7), "and this");
+        let rendered = d.render(false);
+        let numbered: Vec<_> = rendered.lines().filter_map(|l| l.split(" |").next()?.trim().parse::<usize>().ok()).collect();
+        assert_eq!(numbered, vec![1, 2, 6, 7, 11]);
+        assert_eq!(rendered.matches("...").count(), 2);
+        assert!(rendered.contains("11 |   line11\n   |   ----- and this\n"), "{rendered}");
+
+        // Columns are counted in chars
+        let sf = source("(let [é 1]\n  (+ é x))");
+        let d = Diagnostic::error("Undefined symbol x").with_source(&sf).primary(20..21, "");
+        assert!(d.render(false).contains(" --> <text>:2:8\n"));
+        assert!(d.render(false).contains("2 |   (+ é x))\n  |        ^\n"));
+
+        // Empty spans still get a mark
+        let sf = source(&text);
+        let d = Diagnostic::error("end").with_source(&sf).primary(text.len()..text.len(), "eof");
+        assert!(d.render(false).ends_with("12 |   line12\n   |         ^ eof\n"));
+    }
+}
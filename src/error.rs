@@ -1,7 +1,10 @@
 use unraveler::Severity;
 
 use crate::cli::CliErrorKind;
+use crate::diagnostics::{render_all, Diagnostic};
 use crate::frontend::FrontEndError;
+use crate::ir::error::IrError;
+use crate::lsp::LspError;
 use crate::sources::{SourcesError, FileSpan, SourceFile};
 
 #[derive(thiserror::Error)]
@@ -15,8 +18,80 @@ pub enum PloyErrorKind {
     #[error(transparent)] 
     SourceError(#[from] SourcesError),
 
+    #[error("Runtime error: {0}")]
+    Ir(#[from] IrError),
+
+    #[error("Language server: {0}")]
+    Lsp(#[from] LspError),
+
     #[error(transparent)]
     Other(#[from] anyhow::Error),
+
+    /// Every error found compiling a source file
+    #[error("{}", render_all(&to_diagnostics(.errors, .source_file), false))]
+    InSource {
+        errors: Vec<FrontEndError>,
+        source_file: Box<SourceFile>,
+    },
+}
+
+fn to_diagnostics(errors: &[FrontEndError], source: &SourceFile) -> Vec<Diagnostic> {
+    errors.iter().map(|e| e.to_diagnostic(source)).collect()
+}
+
+impl PloyErrorKind {
+    /// The error as diagnostics to show the user
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        use PloyErrorKind::*;
+        let error = |code, e: &dyn std::fmt::Display| Diagnostic::error(e.to_string()).with_code(code);
+
+        match self {
+            InSource { errors, source_file } => to_diagnostics(errors, source_file),
+            FrontEnd(e) => {
+                let d = Diagnostic::error(e.kind.to_string()).with_code(e.kind.code());
+                let d = e.notes.iter().fold(d, |d, n| d.note(n));
+                vec![e.help.iter().fold(d, |d, h| d.help(h))]
+            }
+            Ir(e) => vec![error("E0100", &e.kind).note(format!("at pc {:#06x}", e.pc))],
+            Cli(e) => vec![error("E0200", e)],
+            SourceError(e) => vec![error("E0300", e)],
+            Lsp(e) => vec![error("E0400", e)],
+            Other(e) => vec![Diagnostic::error(format!("{e:#}"))],
+        }
+    }
+
+    /// The error as JSON objects, one for each error
+    /// Ones that aren't in a source file have null for where they are
+    pub fn to_json(&self) -> Vec<serde_json::Value> {
+        if let PloyErrorKind::InSource { errors, source_file } = self {
+            return errors.iter().map(|e| e.to_json(source_file)).collect();
+        }
+
+        let kind = match self {
+            PloyErrorKind::Cli(_) => "cli",
+            PloyErrorKind::FrontEnd(e) => e.kind.name(),
+            PloyErrorKind::SourceError(_) => "source",
+            PloyErrorKind::Ir(_) => "runtime",
+            PloyErrorKind::Lsp(_) => "lsp",
+            _ => "other",
+        };
+
+        self.diagnostics()
+            .into_iter()
+            .map(|d| {
+                serde_json::json!({
+                    "file": null,
+                    "range": null,
+                    "line": null,
+                    "col": null,
+                    "severity": d.level.name(),
+                    "kind": kind,
+                    "code": d.code,
+                    "message": d.message,
+                })
+            })
+            .collect()
+    }
 }
 
 impl std::fmt::Debug for PloyErrorKind {
@@ -29,15 +104,17 @@ impl std::fmt::Debug for PloyErrorKind {
 }
 
 
+/// The error, shown in the source file it's from
 pub fn to_full_error(e: FrontEndError, source_file : &SourceFile) -> PloyErrorKind {
-    let loc = source_file.get_file_span_from_offset(e.pos.start).unwrap();
-    let line = loc.span.location.line;
-    let col = loc.span.location.col;
-    let line_text = source_file.get_line(line).unwrap();
-    let spaces = " ".repeat(col);
-    let text = format!("{e}\nFile: {:?}\nLine: {} Col: {}\n\n{line_text}\n{}^", loc.origin, line +1, col+1, spaces);
-    let err = anyhow::anyhow!(text);
-    PloyErrorKind::Other(err)
+    to_full_errors(vec![e], source_file)
+}
+
+/// Every error, one after another, each shown in the file it's in
+pub fn to_full_errors(errors: Vec<FrontEndError>, source_file: &SourceFile) -> PloyErrorKind {
+    PloyErrorKind::InSource {
+        errors,
+        source_file: Box::new(source_file.clone()),
+    }
 }
 
 #[derive(Clone)]
@@ -50,14 +127,14 @@ struct ErrorStruct<K : Clone> {
 impl<K: Clone> ErrorStruct<K> {
     pub fn set_kind(self, kind: K) -> Self {
         Self {
-            kind: kind.into(),
+            kind,
             ..self
         }
     }
 
     pub fn new( e: K, pos: &std::ops::Range<usize> ) -> Self {
         Self {
-            kind: e.into(),
+            kind: e,
             severity: Severity::Error,
             pos : pos.clone(),
         }
@@ -0,0 +1,361 @@
+/// Source formatter
+/// Re-indents code using the usual Lisp rules, where the lines break is left to the author
+/// * Forms on the same line are one space apart, with no space just inside brackets
+/// * Close brackets go on the line of what they close, unless that's a comment
+/// * Blank lines are kept, but never more than one in a row
+/// * Special forms indent their body by 2, and the args before it by 4
+/// * Calls line their args up with the first arg, data lines up with the first element
+/// * Maps with a pair on each line have their values lined up
+use crate::frontend::{parse_ast, parse_cst, tokenize, tokenize_with_trivia};
+use crate::frontend::{CstKind, CstNode, FrontEndError, TokenKind};
+use crate::sources::SourceFile;
+use std::path::{Path, PathBuf};
+
+/// The number of args a special form takes before its body
+/// and and or aren't here, they line up like calls
+fn special_args(name: &str) -> Option<usize> {
+    match name {
+        "do" | "cond" => Some(0),
+        "def" | "define" | "fn" | "let" | "if" => Some(1),
+        "macro" | "defmacro" => Some(2),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Layout {
+    /// Special form, args before the body are indented by 4 and the body by 2
+    Block(usize),
+    /// Line up with the first arg, if it's on the same line as what's called
+    Call,
+    /// Line up with the first element
+    Data,
+}
+
+/// A form or comment, with the number of line breaks before it
+struct Item<'a> {
+    node: &'a CstNode,
+    newlines: usize,
+}
+
+impl<'a> Item<'a> {
+    fn is_comment(&self) -> bool {
+        self.node.token_kind() == Some(TokenKind::Comment)
+    }
+}
+
+fn items<'a>(children: &'a [CstNode], source: &str) -> Vec<Item<'a>> {
+    let mut newlines = 0;
+    let mut ret = vec![];
+
+    for node in children {
+        if node.token_kind() == Some(TokenKind::Whitespace) {
+            newlines += node.text(source).matches('\n').count();
+        } else {
+            ret.push(Item { node, newlines });
+            newlines = 0;
+        }
+    }
+
+    ret
+}
+
+struct Formatter<'a> {
+    source: &'a str,
+    out: String,
+    /// Column the next char goes in, in chars
+    col: usize,
+}
+
+impl<'a> Formatter<'a> {
+    fn new(source: &'a str) -> Self {
+        Self {
+            source,
+            out: String::new(),
+            col: 0,
+        }
+    }
+
+    fn write(&mut self, text: &str) {
+        self.out.push_str(text);
+
+        match text.rfind('\n') {
+            Some(i) => self.col = text[i + 1..].chars().count(),
+            None => self.col += text.chars().count(),
+        }
+    }
+
+    /// Start a new line, keeping at most one blank line
+    fn newline(&mut self, newlines: usize, indent: usize) {
+        for _ in 0..newlines.clamp(1, 2) {
+            self.out.push('\n');
+        }
+        self.out.push_str(&" ".repeat(indent));
+        self.col = indent;
+    }
+
+    /// The width of a node formatted on its own, None if it takes more than one line
+    fn width(&self, node: &CstNode) -> Option<usize> {
+        let mut f = Formatter::new(self.source);
+        f.node(node);
+        (!f.out.contains('\n')).then(|| f.out.chars().count())
+    }
+
+    fn node(&mut self, node: &CstNode) {
+        match node.kind {
+            CstKind::Token(TokenKind::Comment) => self.write(node.text(self.source).trim_end()),
+            CstKind::Token(_) => self.write(node.text(self.source)),
+            CstKind::File => self.file(node),
+            CstKind::Prefixed => self.prefixed(node),
+            CstKind::List | CstKind::Array | CstKind::Map => self.group(node),
+        }
+    }
+
+    fn file(&mut self, node: &CstNode) {
+        let mut after_comment = false;
+
+        for (i, item) in items(&node.children, self.source).iter().enumerate() {
+            if i > 0 {
+                if item.newlines > 0 || after_comment {
+                    self.newline(item.newlines, 0);
+                } else {
+                    self.write(" ");
+                }
+            }
+
+            self.node(item.node);
+            after_comment = item.is_comment();
+        }
+
+        if !self.out.is_empty() {
+            self.out.push('\n');
+        }
+    }
+
+    /// The prefix sticks to what it applies to
+    fn prefixed(&mut self, node: &CstNode) {
+        let indent = self.col;
+        let (prefix, rest) = node.children.split_first().expect("Empty prefixed node");
+        self.node(prefix);
+
+        let mut after_comment = false;
+
+        for item in items(rest, self.source) {
+            if item.newlines > 0 || after_comment {
+                self.newline(item.newlines, indent);
+            }
+
+            self.node(item.node);
+            after_comment = item.is_comment();
+        }
+    }
+
+    fn layout(&self, node: &CstNode, items: &[Item]) -> Layout {
+        use TokenKind::*;
+
+        if node.kind != CstKind::List {
+            return Layout::Data;
+        }
+
+        match items.first().and_then(|i| i.node.token_kind()) {
+            Some(Identifier) => {
+                let name = items[0].node.text(self.source);
+                special_args(name).map(Layout::Block).unwrap_or(Layout::Call)
+            }
+            Some(FqnIdentifier | KeyWord) => Layout::Call,
+            _ => Layout::Data,
+        }
+    }
+
+    /// Width to pad map keys to, if all of its pairs are a line each
+    fn key_width(&self, node: &CstNode, items: &[Item]) -> Option<usize> {
+        if node.kind != CstKind::Map || items.len() < 4 || !items.len().is_multiple_of(2) {
+            return None;
+        }
+
+        let mut width = 0;
+
+        for (i, pair) in items.chunks(2).enumerate() {
+            let [key, value] = pair else { unreachable!() };
+
+            if key.is_comment() || value.is_comment() || value.newlines > 0 || (i > 0 && key.newlines == 0) {
+                return None;
+            }
+
+            width = width.max(self.width(key.node)?);
+        }
+
+        Some(width)
+    }
+
+    fn group(&mut self, node: &CstNode) {
+        let open_col = self.col;
+        let (open, rest) = node.children.split_first().expect("Group without an open bracket");
+
+        let (inner, close) = if node.is_closed() {
+            let (close, inner) = rest.split_last().unwrap();
+            (inner, Some(close))
+        } else {
+            (rest, None)
+        };
+
+        let items = items(inner, self.source);
+        let layout = self.layout(node, &items);
+        let key_width = self.key_width(node, &items);
+
+        self.node(open);
+
+        let mut forms = 0;
+        let mut first_arg_col = None;
+        let mut key_col = open_col + 1;
+        let mut on_first_line = true;
+        let mut after_comment = false;
+
+        for (i, item) in items.iter().enumerate() {
+            if i > 0 && (item.newlines > 0 || after_comment) {
+                on_first_line = false;
+
+                let indent = match layout {
+                    Layout::Block(args) if forms <= args => open_col + 4,
+                    Layout::Block(_) => open_col + 2,
+                    Layout::Call => first_arg_col.unwrap_or(open_col + 1),
+                    Layout::Data => open_col + 1,
+                };
+
+                self.newline(item.newlines, indent);
+            } else if i > 0 {
+                let pad = match key_width {
+                    Some(w) if forms % 2 == 1 => (key_col + w + 1).saturating_sub(self.col).max(1),
+                    _ => 1,
+                };
+                self.write(&" ".repeat(pad));
+            }
+
+            if !item.is_comment() {
+                if layout == Layout::Call && forms == 1 && on_first_line {
+                    first_arg_col = Some(self.col);
+                }
+
+                if forms % 2 == 0 {
+                    key_col = self.col;
+                }
+
+                forms += 1;
+            }
+
+            self.node(item.node);
+            after_comment = item.is_comment();
+        }
+
+        if let Some(close) = close {
+            if after_comment {
+                self.newline(1, open_col);
+            }
+            self.node(close);
+        }
+    }
+}
+
+/// Format a concrete syntax tree of the source given
+pub fn format_cst(cst: &CstNode, source: &str) -> String {
+    let mut f = Formatter::new(source);
+    f.node(cst);
+    f.out
+}
+
+/// Format the text of a source file
+/// Code that doesn't parse isn't formatted, the errors are returned instead
+pub fn format_source(source_file: &SourceFile) -> Result<String, Vec<FrontEndError>> {
+    let tokes = tokenize(source_file);
+    let (_, errors) = parse_ast(&tokes, source_file.clone());
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let cst = parse_cst(&tokenize_with_trivia(source_file));
+    Ok(format_cst(&cst, source_file.text()))
+}
+
+/// Is this a .ploy source file
+pub fn is_source<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref().extension().is_some_and(|e| e == "ploy")
+}
+
+/// Every .ploy file in a directory and the directories in it, sorted
+pub fn find_sources<P: AsRef<Path>>(dir: P) -> std::io::Result<Vec<PathBuf>> {
+    let mut ret = vec![];
+
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        if path.is_dir() {
+            ret.extend(find_sources(&path)?);
+        } else if is_source(&path) {
+            ret.push(path);
+        }
+    }
+
+    ret.sort();
+    Ok(ret)
+}
+
+#[allow(unused_imports)]
+mod test {
+    use super::*;
+    use crate::sources::SourceOrigin;
+    use pretty_assertions::assert_eq;
+
+    fn format(text: &str) -> String {
+        let source_file = SourceFile::new(text.to_owned(), SourceOrigin::Text);
+        format_source(&source_file).unwrap_or_else(|e| panic!("{text}: {e:?}"))
+    }
+
+    #[test]
+    fn test_format() {
+        let tests = [
+            ("(def   x    1)", "(def x 1)\n"),
+            ("( + 1 2 )\n\n\n\n(- 3\n4\n)", "(+ 1 2)\n\n(- 3\n   4)\n"),
+            (
+                "(def add (fn [a b]\n(let [x (z a 10)\ny 1]\n(+ x y))))",
+                "(def add (fn [a b]\n           (let [x (z a 10)\n                 y 1]\n             (+ x y))))\n",
+            ),
+            ("(if (eq a 1)\n\"yes\"\n\"no\")", "(if (eq a 1)\n  \"yes\"\n  \"no\")\n"),
+            ("(if\n(eq a 1)\n1 2)", "(if\n    (eq a 1)\n  1 2)\n"),
+            ("(and a\nb\n     c)", "(and a\n     b\n     c)\n"),
+            ("(or\na b)", "(or\n a b)\n"),
+            (
+                "(macro m [xs]\n` (+ 1 ,@ xs))\n(f ' x\n'(1 2))",
+                "(macro m [xs]\n  `(+ 1 ,@xs))\n(f 'x\n   '(1 2))\n",
+            ),
+            (
+                "(def m {:a 1\n:long-key 2\n  :bb {:c 3}})",
+                "(def m {:a        1\n        :long-key 2\n        :bb       {:c 3}})\n",
+            ),
+            ("{:a 1 :b\n2}", "{:a 1 :b\n 2}\n"),
+        ];
+
+        for (text, expected) in tests {
+            assert_eq!(format(text), expected, "{text}");
+            assert_eq!(format(expected), expected, "{expected}");
+        }
+    }
+
+    #[test]
+    fn test_format_comments() {
+        let text = "; Squares\n\n(def sq   (fn [x] ; the arg\n(* x x)   ; times\n))\n; end   ";
+        let expected = "; Squares\n\n(def sq (fn [x] ; the arg\n          (* x x) ; times\n        ))\n; end\n";
+        assert_eq!(format(text), expected);
+        assert_eq!(format(expected), expected);
+
+        let source_file = SourceFile::new("(def x".to_owned(), SourceOrigin::Text);
+        assert!(format_source(&source_file).is_err());
+    }
+
+    #[test]
+    fn test_is_source() {
+        assert!(is_source("src/main.ploy"));
+        assert!(!is_source("Ploy.toml"));
+        assert!(!is_source("ploy"));
+    }
+}
@@ -12,31 +12,47 @@ use super::{ploytokens::SlimToken, prelude::*, syntax::{SyntaxErrorKind, AstLowe
 use crate::{
     sources::{FileSpan, SourceFile},
     symbols::{ScopeId, SymbolId, SymbolScopeId},
+    value::Arities,
 };
 
 #[derive(Clone, PartialEq, Debug)]
 pub struct LetData {
-    id: AstNodeId,
-    let_scope: ScopeId,
-    bindings: ThinVec<SymbolId>,
+    pub id: AstNodeId,
+    pub let_scope: ScopeId,
+    pub bindings: ThinVec<SymbolScopeId>,
 }
 
 impl LetData {
-    pub fn new(_ast: &AstLowerer, id: AstNodeId) -> Self {
+    pub fn new(ast: &AstLowerer, id: AstNodeId) -> Self {
+        let args = ast.ast.get_nth_kid_id(id, 0).expect("Missing let args!");
+
+        let bindings = ast
+            .ast
+            .get_kids_ids(args)
+            .into_iter()
+            .filter_map(|arg| {
+                let sym = ast.ast.get_nth_kid_id(arg, 0)?;
+                match ast.ast.tree.get(sym)?.value().kind {
+                    AstNodeKind::Symbol(sym_id) => Some(sym_id),
+                    _ => None,
+                }
+            })
+            .collect();
+
         Self {
             id,
-            let_scope: *_ast.id_to_scope.get(&id).unwrap(),
-            bindings: Default::default(),
+            let_scope: *ast.id_to_scope.get(&id).unwrap(),
+            bindings,
         }
     }
 }
 
 #[derive(Clone, PartialEq, Debug)]
 pub struct IfData {
-    id: AstNodeId,
-    predicate: AstNodeId,
-    if_true: AstNodeId,
-    if_false: Option<AstNodeId>,
+    pub id: AstNodeId,
+    pub predicate: AstNodeId,
+    pub if_true: AstNodeId,
+    pub if_false: Option<AstNodeId>,
 }
 
 impl IfData {
@@ -58,16 +74,16 @@ impl IfData {
 
 #[derive(Clone, PartialEq, Debug)]
 pub struct ApplicationData {
-    id: AstNodeId,
-    func: AstNodeId,
-    args: ThinVec<AstNodeId>,
+    pub id: AstNodeId,
+    pub func: AstNodeId,
+    pub args: ThinVec<AstNodeId>,
 }
 
 impl ApplicationData {
     pub fn new(ast: &AstLowerer, id: AstNodeId) -> Self {
-        let mut kids = ast.ast.tree.get(id).expect("Can't find node").children();
-        let func = kids.next().unwrap().id();
-        let args = kids.map(|n| n.id()).collect();
+        let mut kids = ast.ast.get_kids_ids(id).into_iter();
+        let func = kids.next().unwrap();
+        let args = kids.collect();
 
         Self { id, func, args }
     }
@@ -77,17 +93,269 @@ impl ApplicationData {
     }
 }
 
+/// (cond test value test value ... :else value)
+#[derive(Clone, PartialEq, Debug)]
+pub struct CondData {
+    pub id: AstNodeId,
+    pub clauses: ThinVec<(AstNodeId, AstNodeId)>,
+    pub default: Option<AstNodeId>,
+}
+
+impl CondData {
+    pub fn new(ast: &AstLowerer, id: AstNodeId) -> Result<Self, SyntaxErrorKind> {
+        let kids = ast.ast.get_kids_ids(id);
+
+        if !kids.len().is_multiple_of(2) {
+            return Err(SyntaxErrorKind::OddCond);
+        }
+
+        let mut clauses = ThinVec::new();
+        let mut default = None;
+
+        for pair in kids.chunks(2) {
+            let (test, value) = (pair[0], pair[1]);
+
+            if default.is_some() {
+                return Err(SyntaxErrorKind::ClauseAfterElse);
+            }
+
+            if ast.ast.tree.get(test).unwrap().value().kind == AstNodeKind::KeyWord
+                && ast.ast.get_source_text(test) == ":else"
+            {
+                default = Some(value)
+            } else {
+                clauses.push((test, value))
+            }
+        }
+
+        Ok(Self {
+            id,
+            clauses,
+            default,
+        })
+    }
+}
+
+/// (do form form ...)
+#[derive(Clone, PartialEq, Debug)]
+pub struct DoData {
+    pub id: AstNodeId,
+    pub forms: ThinVec<AstNodeId>,
+}
+
+impl DoData {
+    pub fn new(ast: &AstLowerer, id: AstNodeId) -> Self {
+        Self {
+            id,
+            forms: ast.ast.get_kids_ids(id),
+        }
+    }
+}
+
+/// (macro name [params] body ...) or a defmacro
+/// Calls are expanded by the lowerer, this is what's left of the definition
+#[derive(Clone, PartialEq, Debug)]
+pub struct MacroData {
+    pub id: AstNodeId,
+    pub name: SymbolScopeId,
+    pub params: ThinVec<SymbolScopeId>,
+    /// The lambda body holding the params and the forms to expand to
+    pub body: AstNodeId,
+    /// The scope of the params, the lambda body's
+    pub scope: ScopeId,
+}
+
+impl MacroData {
+    pub fn new(ast: &AstLowerer, id: AstNodeId) -> Result<Self, SyntaxErrorKind> {
+        let ast = &ast.ast;
+        let kind = |id| ast.tree.get(id).unwrap().value().kind.clone();
+
+        let name = match ast.get_nth_kid_id(id, 0).map(kind) {
+            Some(AstNodeKind::Symbol(sym_id)) => sym_id,
+            _ => return Err(SyntaxErrorKind::Expected("macro name".to_owned())),
+        };
+
+        // The params and body are parsed as a lambda so they get their own scope
+        let lambda = ast.get_nth_kid_id(id, 1).expect("Macro with no body");
+        let body = ast.get_nth_kid_id(lambda, 0).expect("Macro with no body");
+        let args = ast.get_nth_kid_id(body, 0).expect("Macro body with no args");
+
+        let scope = match ast.tree.get(body).unwrap().prev_sibling().map(|n| kind(n.id())) {
+            Some(AstNodeKind::SetScope(scope)) => scope,
+            _ => panic!("Macro body hasn't been scoped"),
+        };
+
+        let params = ast
+            .get_kids_ids(args)
+            .into_iter()
+            .filter_map(|id| match kind(id) {
+                AstNodeKind::Symbol(sym_id) => Some(Ok(sym_id)),
+                AstNodeKind::RestArg => Some(Err(SyntaxErrorKind::RestOutsideFn)),
+                _ => None,
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            id,
+            name,
+            params,
+            body,
+            scope,
+        })
+    }
+}
+
+/// One arity of a lambda
 #[derive(Clone, PartialEq, Debug)]
 pub struct LambdaBodyData {
-    scope: ScopeId,
-    params: ThinVec<SymbolScopeId>,
-    returns: (),
+    pub id: AstNodeId,
+    /// Each body has its own scope so they can use the same param names
+    pub scope: ScopeId,
+    pub params: ThinVec<SymbolScopeId>,
+    /// Bound to a list of any args after the params
+    pub rest: Option<SymbolScopeId>,
+}
+
+impl LambdaBodyData {
+    /// Number of params, the fewest args the body takes if it has a rest param
+    pub fn arity(&self) -> usize {
+        self.params.len()
+    }
+
+    pub fn accepts(&self, n: usize) -> bool {
+        match self.rest {
+            Some(_) => n >= self.arity(),
+            None => n == self.arity(),
+        }
+    }
 }
 
+/// (fn [params] body ...) or (fn ([params] body ...) ...)
 #[derive(Clone, PartialEq, Debug)]
 pub struct LambdaData {
-    id: AstNodeId,
-    bodies: ThinVec<LambdaBodyData>,
+    pub id: AstNodeId,
+    /// Scope holding every body
+    pub scope: ScopeId,
+    pub bodies: ThinVec<LambdaBodyData>,
+    /// Locals of enclosing functions and lets the lambda refers to
+    /// Their values are copied into the closure when the lambda is evaluated
+    pub captures: ThinVec<SymbolScopeId>,
+}
+
+impl LambdaData {
+    pub fn new(ast: &AstLowerer, id: AstNodeId) -> Result<Self, SyntaxErrorKind> {
+        let scope = *ast.id_to_scope.get(&id).unwrap();
+        let sym_of = |id| match ast.ast.tree.get(id).unwrap().value().kind {
+            AstNodeKind::Symbol(sym_id) => Some(sym_id),
+            _ => None,
+        };
+
+        let mut bodies: ThinVec<LambdaBodyData> = ThinVec::new();
+
+        for body in ast.ast.get_kids_ids(id) {
+            let args = ast.ast.get_nth_kid_id(body, 0).expect("Lambda body with no args");
+            let mut params = ThinVec::new();
+            let mut rest = None;
+
+            for arg in ast.ast.get_kids_ids(args) {
+                if rest.is_some() {
+                    return Err(SyntaxErrorKind::ArgAfterRest);
+                }
+
+                match ast.ast.tree.get(arg).unwrap().value().kind {
+                    AstNodeKind::RestArg => {
+                        let arg = ast.ast.get_nth_kid_id(arg, 0).expect("Rest arg with no name");
+                        rest = sym_of(arg)
+                    }
+                    _ => params.extend(sym_of(arg)),
+                }
+            }
+
+            let scope = *ast.id_to_scope.get(&body).unwrap();
+            let body = LambdaBodyData { id: body, scope, params, rest };
+
+            // Calls are dispatched on the number of args so only one body can take each count
+            if body.rest.is_some() && bodies.iter().any(|b| b.rest.is_some()) {
+                return Err(SyntaxErrorKind::DuplicateRest);
+            }
+
+            let overlaps = |b: &&LambdaBodyData| b.accepts(body.arity()) || body.accepts(b.arity());
+
+            if let Some(other) = bodies.iter().find(overlaps) {
+                let arity = body.arity().max(other.arity());
+                return Err(SyntaxErrorKind::DuplicateArity(arity));
+            }
+
+            bodies.push(body)
+        }
+
+        // Escape analysis, anything referred to in here that's defined in a
+        // local scope outside of the lambda has to be captured
+        let mut captures = ThinVec::new();
+
+        for sym_id in ast.ast.get_rec_ids(id).into_iter().filter_map(sym_of) {
+            let outside = !ast.is_inside_scope(sym_id.scope_id, scope);
+
+            if outside && !ast.is_global_scope(sym_id.scope_id) && !captures.contains(&sym_id) {
+                captures.push(sym_id)
+            }
+        }
+
+        Ok(Self {
+            id,
+            scope,
+            bodies,
+            captures,
+        })
+    }
+
+    /// Number of args the bodies take
+    pub fn arities(&self) -> Arities {
+        let (rest, fixed): (Vec<_>, Vec<_>) = self.bodies.iter().partition(|b| b.rest.is_some());
+
+        Arities {
+            fixed: fixed.iter().map(|b| b.arity()).collect(),
+            rest: rest.first().map(|b| b.arity()),
+        }
+    }
+
+    /// The body a call with n args runs
+    pub fn body_for(&self, n: usize) -> Option<&LambdaBodyData> {
+        self.bodies.iter().find(|b| b.accepts(n))
+    }
+}
+
+/// (recur args ...), jumps back to the start of the enclosing fn body with new args
+/// Only allowed in tail position so it never needs a new frame
+#[derive(Clone, PartialEq, Debug)]
+pub struct RecurData {
+    pub id: AstNodeId,
+    pub args: ThinVec<AstNodeId>,
+    /// The lambda body it jumps to
+    pub body: AstNodeId,
+    /// The params the args are bound to, with the rest param last if there is one
+    pub params: ThinVec<SymbolScopeId>,
+}
+
+impl RecurData {
+    pub fn new(ast: &AstLowerer, id: AstNodeId, body: &LambdaBodyData) -> Result<Self, SyntaxErrorKind> {
+        let args = ast.ast.get_kids_ids(id);
+        let params: ThinVec<_> = body.params.iter().cloned().chain(body.rest).collect();
+
+        if args.len() != params.len() {
+            return Err(SyntaxErrorKind::RecurArgs {
+                expected: params.len(),
+                found: args.len(),
+            });
+        }
+
+        Ok(Self {
+            id,
+            args,
+            body: body.id,
+            params,
+        })
+    }
 }
 
 #[derive(Clone, PartialEq, Debug)]
@@ -99,6 +367,10 @@ pub enum ToProcessKind {
     Let,
     Lambda,
     Symbol,
+    Cond,
+    Do,
+    Macro,
+    Recur,
 }
 
 #[derive(Clone, PartialEq, Debug)]
@@ -115,9 +387,9 @@ pub enum Literal {
     Symbol(SymbolScopeId),
 }
 
-impl Into<AstNodeKind> for ToProcessKind {
-    fn into(self) -> AstNodeKind {
-        AstNodeKind::ToProcess(self)
+impl From<ToProcessKind> for AstNodeKind {
+    fn from(value: ToProcessKind) -> Self {
+        AstNodeKind::ToProcess(value)
     }
 }
 
@@ -127,9 +399,14 @@ pub enum AstNodeKind {
 
     If(Box<IfData>),
     Application(Box<ApplicationData>),
+    Lambda(Box<LambdaData>),
     Symbol(SymbolScopeId),
     AssignSymbol(SymbolScopeId),
     Let(Box<LetData>),
+    Cond(Box<CondData>),
+    Do(Box<DoData>),
+    Macro(Box<MacroData>),
+    Recur(Box<RecurData>),
 
     Quoted,
     Program,
@@ -146,20 +423,37 @@ pub enum AstNodeKind {
     Scope,
     KeyWord,
     Define,
-    Lambda,
     LambdaBody,
-    Cond,
     And,
     Or,
-    Do,
-    Macro,
     Arg,
     Args,
     LetArg,
     LetArgs,
     SetScope(ScopeId),
+    /// (import foo::bar)
+    Import,
+    ModuleName,
+    /// The forms of an imported file, evaluated in the module's scope
+    Module(ScopeId),
     MetaData,
     Block,
+    /// (defmacro name [params] `template), a macro whose body is a template
+    DefMacro,
+    /// `form, a template filled in by a macro call
+    QuasiQuote,
+    /// ,form inside a template
+    Unquote,
+    /// ,@form inside a template, spliced into the enclosing form
+    UnquoteSplicing,
+    /// `& name` at the end of a fn's args or a vector pattern
+    RestArg,
+    /// `[a b & more]` in a let or fn's args, lowered into plain bindings
+    VecPattern,
+    /// `{:keys [x y]}` in a let or fn's args, lowered into plain bindings
+    MapPattern,
+    /// A form that didn't parse, in place of whatever it was meant to be
+    Error,
     #[default]
     Nothing,
     True,
@@ -167,8 +461,12 @@ pub enum AstNodeKind {
 }
 
 impl AstNodeKind {
+    pub fn is_set_scope(&self) -> bool {
+        matches!(self, AstNodeKind::SetScope(..))
+    }
+
     pub fn creates_new_scope(&self) -> bool {
-        matches!(self, AstNodeKind::Lambda | AstNodeKind::Let(..) | AstNodeKind::ToProcess(ToProcessKind::Let) | AstNodeKind::ToProcess(ToProcessKind::Lambda))
+        matches!(self, AstNodeKind::Lambda(..) | AstNodeKind::LambdaBody | AstNodeKind::Let(..) | AstNodeKind::ToProcess(ToProcessKind::Let) | AstNodeKind::ToProcess(ToProcessKind::Lambda))
     }
 }
 
@@ -178,6 +476,8 @@ pub struct AstNode {
     pub token_range: std::ops::Range<usize>,
     pub text_range: std::ops::Range<usize>,
     pub text_span: FileSpan,
+    /// The :type given in the source, ToInfer if there wasn't one
+    pub type_annotation: Type,
 }
 
 pub fn get_text_range(tokes_range: &[SlimToken]) -> std::ops::Range<usize> {
@@ -206,6 +506,7 @@ impl AstNode {
             token_range: node.range.clone(),
             text_range,
             text_span,
+            type_annotation: node.type_annotation.clone(),
         }
     }
 }
@@ -227,6 +528,12 @@ pub struct Ast {
     pub meta_data: HashMap<AstNodeId, MetaData>,
     pub source_file: crate::sources::SourceFile,
     pub tokens: Vec<SlimToken>,
+    /// Files grafted in by imports
+    pub imported: Vec<SourceFile>,
+    /// Text of nodes made by macro expansion, which sit at the call site instead of their own text
+    pub expanded_text: HashMap<AstNodeId, String>,
+    /// Symbols moved by macro expansion, resolved in the scope they were written in
+    pub resolve_in: HashMap<AstNodeId, ScopeId>,
 }
 
 impl Ast {
@@ -234,19 +541,94 @@ impl Ast {
         &self.source_file
     }
 
+    /// Get the file a node's text comes from
+    pub fn get_source_file_for_node(&self, id: AstNodeId) -> &SourceFile {
+        let origin = &self.tree.get(id).unwrap().value().text_span.origin;
+
+        self.imported
+            .iter()
+            .find(|sf| &sf.origin == origin)
+            .unwrap_or(&self.source_file)
+    }
+
+    /// Copy another file's program into this tree, wrapped in a module node
+    /// The module is placed before any existing top level forms
+    pub fn graft_module(&mut self, other: Ast, scope: ScopeId) -> AstNodeId {
+        let root = other.tree.root();
+
+        let node = root.value().change_kind(AstNodeKind::Module(scope));
+        let id = match self.tree.root_mut().first_child() {
+            Some(mut first) => first.insert_before(node).id(),
+            None => self.tree.root_mut().append(node).id(),
+        };
+
+        for kid in root.children() {
+            Self::copy_node(&mut self.tree, id, kid);
+        }
+
+        self.imported.push(other.source_file);
+        id
+    }
+
+    fn copy_node(tree: &mut AstTree, parent: AstNodeId, node: AstNodeRef) {
+        let id = tree.get_mut(parent).unwrap().append(node.value().clone()).id();
+
+        for kid in node.children() {
+            Self::copy_node(tree, id, kid);
+        }
+    }
+
     pub fn get_root_id(&self) -> AstNodeId {
         self.tree.root().id()
     }
 
+    /// Get the ids of this node's children, skipping scope markers
+    pub fn get_kids_ids(&self, id: AstNodeId) -> ThinVec<AstNodeId> {
+        self.tree
+            .get(id)
+            .unwrap()
+            .children()
+            .filter(|node| !node.value().kind.is_set_scope())
+            .map(|node| node.id())
+            .collect()
+    }
+
     pub fn get_nth_kid_id(&self, id: AstNodeId, n: usize) -> Option<AstNodeId> {
         self.tree
             .get(id)
             .unwrap()
             .children()
+            .filter(|node| !node.value().kind.is_set_scope())
             .nth(n)
             .map(|node| node.id())
     }
 
+    pub fn get_source_text(&self, id: AstNodeId) -> &str {
+        if let Some(text) = self.expanded_text.get(&id) {
+            return text;
+        }
+
+        let r = &self.tree.get(id).unwrap().value().text_range;
+        &self.get_source_file_for_node(id).text()[r.clone()]
+    }
+
+    /// If this node was made by expanding a macro template
+    pub fn is_expanded(&self, id: AstNodeId) -> bool {
+        self.expanded_text.contains_key(&id)
+    }
+
+    /// An error at this node, in whichever file the node came from
+    pub fn node_error<E: Into<FrontEndErrorKind>>(&self, id: AstNodeId, kind: E) -> FrontEndError {
+        let err = FrontEndError::new(kind, &self.tree.get(id).unwrap().value().text_range);
+        let file = self.get_source_file_for_node(id);
+
+        if file.origin == self.get_source_file().origin {
+            err
+        } else {
+            err.set_file(file)
+        }
+    }
+
     /// Get all of the ids of this node Recursively, depth first
     fn get_rec_ids_inner(&self, id: AstNodeId, nodes: &mut Vec<AstNodeId>) {
         nodes.push(id);
@@ -300,6 +682,9 @@ impl Ast {
             meta_data: Default::default(),
             source_file,
             tokens,
+            imported: vec![],
+            expanded_text: Default::default(),
+            resolve_in: Default::default(),
         };
 
         ret.add_node(None, parse_node);
@@ -308,19 +693,18 @@ impl Ast {
     }
 }
 
-pub fn to_ast(tokes: &[Token], source_file: SourceFile) -> Result<Ast, FrontEndError> {
+/// Parse tokens into an Ast, with every syntax error found
+/// The Ast is only any use for compiling if there aren't any
+pub fn parse_ast(tokes: &[Token], source_file: SourceFile) -> (Ast, Vec<FrontEndError>) {
     let tokens = Span::from_slice(tokes);
+    let (matched, errors) = super::parsers::parse_program(tokens);
+    (Ast::new(matched, tokes, source_file), errors)
+}
 
-    let (rest, matched) = super::parsers::parse_program(tokens)?;
-
-    if !rest.is_empty() {
-        let err = rest.as_slice()[0].location;
-        let syntax = SyntaxErrorKind::Unexpected;
-        let err = FrontEndError::new(syntax, &err.as_range());
-        Err(err)
-    } else {
-        let ast = Ast::new(matched, tokes, source_file);
-        Ok(ast)
+pub fn to_ast(tokes: &[Token], source_file: SourceFile) -> Result<Ast, Vec<FrontEndError>> {
+    match parse_ast(tokes, source_file) {
+        (ast, errors) if errors.is_empty() => Ok(ast),
+        (_, errors) => Err(errors),
     }
 }
 
@@ -0,0 +1,168 @@
+/// Concrete syntax tree
+/// Made from a token stream that keeps trivia, every token is a leaf so the
+/// source can be rebuilt from the tree byte for byte, comments and all
+/// Brackets only have to balance, anything else that won't parse is kept as tokens
+use super::parsers::is_prefix;
+use super::prelude::*;
+use std::iter::Peekable;
+use std::ops::Range;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CstKind {
+    /// A whole file
+    File,
+    /// ( .. )
+    List,
+    /// [ .. ]
+    Array,
+    /// { .. }
+    Map,
+    /// A prefix like ' or ^ and the form it applies to
+    Prefixed,
+    /// A single token, including whitespace and comments
+    Token(TokenKind),
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct CstNode {
+    pub kind: CstKind,
+    /// Bytes in the source this covers
+    pub range: Range<usize>,
+    pub children: Vec<CstNode>,
+}
+
+impl CstNode {
+    fn token(toke: &Token) -> Self {
+        Self {
+            kind: CstKind::Token(toke.kind),
+            range: toke.extra.as_range(),
+            children: vec![],
+        }
+    }
+
+    fn new(kind: CstKind, children: Vec<CstNode>) -> Self {
+        let range = match (children.first(), children.last()) {
+            (Some(first), Some(last)) => first.range.start..last.range.end,
+            _ => 0..0,
+        };
+
+        Self { kind, range, children }
+    }
+
+    pub fn token_kind(&self) -> Option<TokenKind> {
+        match self.kind {
+            CstKind::Token(k) => Some(k),
+            _ => None,
+        }
+    }
+
+    pub fn is_trivia(&self) -> bool {
+        self.token_kind().is_some_and(|k| k.is_trivia())
+    }
+
+    /// Lists, arrays and maps
+    pub fn is_group(&self) -> bool {
+        matches!(self.kind, CstKind::List | CstKind::Array | CstKind::Map)
+    }
+
+    /// A group that has its close bracket, the file ended before it otherwise
+    pub fn is_closed(&self) -> bool {
+        use TokenKind::*;
+        matches!(
+            (self.kind, self.children.last().and_then(|c| c.token_kind())),
+            (CstKind::List, Some(CloseBracket))
+                | (CstKind::Array, Some(CloseSquareBracket))
+                | (CstKind::Map, Some(CloseBrace))
+        )
+    }
+
+    /// Children that aren't trivia
+    pub fn forms(&self) -> impl Iterator<Item = &CstNode> {
+        self.children.iter().filter(|c| !c.is_trivia())
+    }
+
+    /// Every token under this node, in source order
+    pub fn tokens(&self) -> Box<dyn Iterator<Item = &CstNode> + '_> {
+        if self.children.is_empty() {
+            Box::new(std::iter::once(self).filter(|n| n.token_kind().is_some()))
+        } else {
+            Box::new(self.children.iter().flat_map(|c| c.tokens()))
+        }
+    }
+
+    /// The source this node covers
+    pub fn text<'a>(&self, source: &'a str) -> &'a str {
+        &source[self.range.clone()]
+    }
+
+    /// Rebuild the source from the tokens in the tree
+    pub fn to_text(&self, source: &str) -> String {
+        self.tokens().map(|t| t.text(source)).collect()
+    }
+}
+
+type Tokens<'a, 'b> = Peekable<std::slice::Iter<'b, Token<'a>>>;
+
+fn close_for(open: TokenKind) -> Option<(CstKind, TokenKind)> {
+    use TokenKind::*;
+    match open {
+        OpenBracket => Some((CstKind::List, CloseBracket)),
+        OpenSquareBracket => Some((CstKind::Array, CloseSquareBracket)),
+        OpenBrace => Some((CstKind::Map, CloseBrace)),
+        _ => None,
+    }
+}
+
+fn is_close(kind: TokenKind) -> bool {
+    use TokenKind::*;
+    matches!(kind, CloseBracket | CloseSquareBracket | CloseBrace)
+}
+
+/// The next element, tokens must not be empty
+/// A close bracket that doesn't close anything is a token on its own
+fn parse_element(tokens: &mut Tokens) -> CstNode {
+    let toke = tokens.next().expect("Parsing past the end of the tokens");
+    let mut children = vec![CstNode::token(toke)];
+
+    if let Some((kind, close)) = close_for(toke.kind) {
+        while let Some(next) = tokens.peek() {
+            if next.kind == close {
+                children.push(CstNode::token(next));
+                tokens.next();
+                break;
+            }
+            children.push(parse_element(tokens));
+        }
+
+        CstNode::new(kind, children)
+    } else if is_prefix(toke.kind) {
+        while let Some(next) = tokens.peek() {
+            if next.kind.is_trivia() {
+                children.push(CstNode::token(next));
+                tokens.next();
+            } else {
+                if !is_close(next.kind) {
+                    children.push(parse_element(tokens));
+                }
+                break;
+            }
+        }
+
+        CstNode::new(CstKind::Prefixed, children)
+    } else {
+        children.pop().unwrap()
+    }
+}
+
+/// Build a concrete syntax tree from the tokens of a file
+/// The tokens should come from tokenize_with_trivia, the tree only keeps what it's given
+pub fn parse_cst(tokes: &[Token]) -> CstNode {
+    let mut tokens = tokes.iter().peekable();
+    let mut children = vec![];
+
+    while tokens.peek().is_some() {
+        children.push(parse_element(&mut tokens));
+    }
+
+    CstNode::new(CstKind::File, children)
+}
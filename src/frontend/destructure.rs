@@ -0,0 +1,268 @@
+/// Lowers destructuring patterns in lets and fn args into plain bindings
+/// (let [[a b & more] v] ...) becomes
+/// (let [#0 v a (nth #0 0) b (nth #0 1) more (nthrest #0 2)] ...)
+/// and {:keys [x y]} binds x to (:x #0) and y to (:y #0)
+///
+/// A pattern in a fn's args is replaced by a temp param
+/// and the body is wrapped in a let that destructures it
+/// Temps are named with a # so they can't clash with anything in the source
+use super::prelude::*;
+use super::syntax::SyntaxErrorKind;
+use crate::symbols::ScopeId;
+use std::collections::HashSet;
+
+pub struct Destructurer<'a> {
+    ast: &'a mut Ast,
+    /// Where nth and nthrest are looked up, so they can't be shadowed
+    root_scope: ScopeId,
+    /// Number of temps made so far
+    temps: usize,
+}
+
+fn is_pattern(kind: &AstNodeKind) -> bool {
+    matches!(kind, AstNodeKind::VecPattern | AstNodeKind::MapPattern)
+}
+
+impl<'a> Destructurer<'a> {
+    pub fn new(ast: &'a mut Ast, root_scope: ScopeId) -> Self {
+        Self {
+            ast,
+            root_scope,
+            temps: 0,
+        }
+    }
+
+    fn kind(&self, id: AstNodeId) -> AstNodeKind {
+        self.ast.tree.get(id).unwrap().value().kind.clone()
+    }
+
+    pub fn lower(mut self) -> Result<(), FrontEndError> {
+        for id in self.ast.get_rec_ids(self.ast.get_root_id()) {
+            match self.kind(id) {
+                AstNodeKind::LetArg => self.lower_let_arg(id)?,
+                AstNodeKind::LambdaBody => self.lower_params(id)?,
+                _ => (),
+            }
+        }
+
+        self.check_leftovers()
+    }
+
+    /// Replace a let binding to a pattern with the bindings it's made of
+    fn lower_let_arg(&mut self, id: AstNodeId) -> Result<(), FrontEndError> {
+        let kids = self.ast.get_kids_ids(id);
+
+        if !is_pattern(&self.kind(kids[0])) {
+            return Ok(());
+        }
+
+        let mut bindings = vec![];
+        self.bind(kids[0], kids[1], &mut bindings)?;
+        self.check_duplicates(&bindings)?;
+
+        for (target, value) in bindings {
+            let let_arg = self.new_node(id, AstNodeKind::LetArg, None);
+            self.ast.tree.get_mut(let_arg).unwrap().append_id(target);
+            self.ast.tree.get_mut(let_arg).unwrap().append_id(value);
+            self.ast.tree.get_mut(id).unwrap().insert_id_before(let_arg);
+        }
+
+        self.ast.tree.get_mut(id).unwrap().detach();
+        Ok(())
+    }
+
+    /// Swap patterns in a fn's args for temps, destructured by a let around the body
+    fn lower_params(&mut self, id: AstNodeId) -> Result<(), FrontEndError> {
+        // Macros are parsed as lambdas but their params are substituted, not bound
+        // so patterns in them are left for check_leftovers
+        let node = self.ast.tree.get(id).unwrap();
+        let lambda = node.parent().filter(|p| p.value().kind == ToProcessKind::Lambda.into());
+        let in_macro = lambda.and_then(|l| l.parent()).is_some_and(|p| {
+            let kind = &p.value().kind;
+            *kind == ToProcessKind::Macro.into() || *kind == AstNodeKind::DefMacro
+        });
+
+        if lambda.is_none() || in_macro {
+            return Ok(());
+        }
+
+        let kids = self.ast.get_kids_ids(id);
+        let (args, forms) = kids.split_first().expect("Lambda body with no args");
+
+        let mut bindings = vec![];
+
+        for arg in self.ast.get_kids_ids(*args) {
+            let arg = match self.kind(arg) {
+                AstNodeKind::RestArg => self.ast.get_nth_kid_id(arg, 0).unwrap(),
+                _ => arg,
+            };
+
+            if is_pattern(&self.kind(arg)) {
+                let (temp, name) = self.new_temp(arg);
+                self.ast.tree.get_mut(arg).unwrap().insert_id_before(temp);
+                self.ast.tree.get_mut(arg).unwrap().detach();
+                let start = bindings.len();
+                self.destructure(arg, &name, &mut bindings)?;
+                self.check_duplicates(&bindings[start..])?;
+            }
+        }
+
+        if bindings.is_empty() {
+            return Ok(());
+        }
+
+        let wrapper = self.new_node(id, ToProcessKind::Let.into(), None);
+        let let_args = self.new_node(id, AstNodeKind::LetArgs, None);
+        self.ast.tree.get_mut(wrapper).unwrap().append_id(let_args);
+
+        for (target, value) in bindings {
+            let let_arg = self.new_node(id, AstNodeKind::LetArg, None);
+            self.ast.tree.get_mut(let_arg).unwrap().append_id(target);
+            self.ast.tree.get_mut(let_arg).unwrap().append_id(value);
+            self.ast.tree.get_mut(let_args).unwrap().append_id(let_arg);
+        }
+
+        for form in forms {
+            self.ast.tree.get_mut(wrapper).unwrap().append_id(*form);
+        }
+
+        self.ast.tree.get_mut(id).unwrap().append_id(wrapper);
+        Ok(())
+    }
+
+    /// The bindings needed to bind value to target, a name or a pattern
+    fn bind(
+        &mut self,
+        target: AstNodeId,
+        value: AstNodeId,
+        bindings: &mut Vec<(AstNodeId, AstNodeId)>,
+    ) -> Result<(), FrontEndError> {
+        if !is_pattern(&self.kind(target)) {
+            bindings.push((target, value));
+            return Ok(());
+        }
+
+        // Evaluate the value once, then pick it apart
+        let (temp, name) = self.new_temp(target);
+        bindings.push((temp, value));
+        self.destructure(target, &name, bindings)
+    }
+
+    /// A pattern can only bind each name once
+    fn check_duplicates(&self, bindings: &[(AstNodeId, AstNodeId)]) -> Result<(), FrontEndError> {
+        let mut seen = HashSet::new();
+
+        for (target, _) in bindings {
+            let name = self.ast.get_source_text(*target);
+
+            if !seen.insert(name) {
+                let err = SyntaxErrorKind::DuplicateBinding(name.to_owned());
+                return Err(self.ast.node_error(*target, err));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Bind the names in a pattern to parts of the value held in the temp called name
+    fn destructure(
+        &mut self,
+        pattern: AstNodeId,
+        name: &str,
+        bindings: &mut Vec<(AstNodeId, AstNodeId)>,
+    ) -> Result<(), FrontEndError> {
+        let kids = self.ast.get_kids_ids(pattern);
+
+        match self.kind(pattern) {
+            AstNodeKind::VecPattern => {
+                for (i, kid) in kids.iter().enumerate() {
+                    if self.kind(*kid) != AstNodeKind::RestArg {
+                        let value = self.builtin_call(pattern, "nth", name, i);
+                        self.bind(*kid, value, bindings)?;
+                        continue;
+                    }
+
+                    if i + 1 != kids.len() {
+                        return Err(self.ast.node_error(kids[i + 1], SyntaxErrorKind::ArgAfterRest));
+                    }
+
+                    let target = self.ast.get_nth_kid_id(*kid, 0).unwrap();
+                    let value = self.builtin_call(pattern, "nthrest", name, i);
+                    self.bind(target, value, bindings)?;
+                }
+            }
+
+            AstNodeKind::MapPattern => {
+                for key in kids {
+                    // (:key temp)
+                    let text = format!(":{}", self.ast.get_source_text(key));
+                    let value = self.new_node(pattern, ToProcessKind::Application.into(), None);
+                    let keyword = self.new_node(pattern, AstNodeKind::KeyWord, Some(text));
+                    let map = self.new_symbol(pattern, name);
+                    self.ast.tree.get_mut(value).unwrap().append_id(keyword);
+                    self.ast.tree.get_mut(value).unwrap().append_id(map);
+                    bindings.push((key, value));
+                }
+            }
+
+            _ => panic!("Destructuring something that isn't a pattern"),
+        }
+
+        Ok(())
+    }
+
+    /// (func name n), with func looked up in the root scope
+    fn builtin_call(&mut self, at: AstNodeId, func: &str, name: &str, n: usize) -> AstNodeId {
+        let call = self.new_node(at, ToProcessKind::Application.into(), None);
+        let func = self.new_symbol(at, func);
+        self.ast.resolve_in.insert(func, self.root_scope);
+        let arg = self.new_symbol(at, name);
+        let n = self.new_node(at, AstNodeKind::Number, Some(n.to_string()));
+
+        for kid in [func, arg, n] {
+            self.ast.tree.get_mut(call).unwrap().append_id(kid);
+        }
+
+        call
+    }
+
+    /// A param or let binding for a new temp, and its name
+    fn new_temp(&mut self, at: AstNodeId) -> (AstNodeId, String) {
+        let name = format!("#{}", self.temps);
+        self.temps += 1;
+        (self.new_node(at, AstNodeKind::Arg, Some(name.clone())), name)
+    }
+
+    fn new_symbol(&mut self, at: AstNodeId, name: &str) -> AstNodeId {
+        self.new_node(at, ToProcessKind::Symbol.into(), Some(name.to_owned()))
+    }
+
+    /// A new node with the span of the node at, so errors point at the pattern
+    fn new_node(&mut self, at: AstNodeId, kind: AstNodeKind, text: Option<String>) -> AstNodeId {
+        let node = self.ast.tree.get(at).unwrap().value();
+        let node = AstNode {
+            kind,
+            type_annotation: Type::ToInfer,
+            ..node.clone()
+        };
+
+        let id = self.ast.tree.orphan(node).id();
+
+        if let Some(text) = text {
+            self.ast.expanded_text.insert(id, text);
+        }
+
+        id
+    }
+
+    /// Patterns anywhere other than a let or a fn's args
+    fn check_leftovers(&self) -> Result<(), FrontEndError> {
+        for id in self.ast.get_rec_ids(self.ast.get_root_id()) {
+            if is_pattern(&self.kind(id)) {
+                return Err(self.ast.node_error(id, SyntaxErrorKind::PatternOutsideBinding));
+            }
+        }
+
+        Ok(())
+    }
+}
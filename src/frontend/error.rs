@@ -1,8 +1,10 @@
 use super::{prelude::*, semantics::SemanticErrorKind};
 use super::span::get_text_range;
 use super::syntax::SyntaxErrorKind;
-use crate::sources::{FileSpan, SearchPathsError};
+use crate::diagnostics::{Diagnostic, Level};
+use crate::sources::{FileSpan, SearchPathsError, SourceFile};
 use itertools::Itertools;
+use thin_vec::ThinVec;
 use thiserror::Error;
 use unraveler::{ParseError, ParseErrorKind, Severity};
 
@@ -15,14 +17,55 @@ pub enum FrontEndErrorKind {
     #[error("Parsing: {0}")]
     ParseError(#[from] ParseErrorKind),
 
-    #[error(transparent)]
-    SemanticError(SemanticErrorKind),
+    /// Boxed as the types in it would make every error bigger
+    #[error("Type error: {0}")]
+    SemanticError(Box<SemanticErrorKind>),
     #[error(transparent)]
     SearchsPathError(SearchPathsError),
+    #[error("Can't find module {0}")]
+    ModuleNotFound(String),
+    #[error("Import cycle: {0}")]
+    ImportCycle(String),
     #[error("Misc: {0}")]
     Other(String),
 }
 
+impl FrontEndErrorKind {
+    /// What sort of error this is, for tools reading JSON errors
+    pub fn name(&self) -> &'static str {
+        use FrontEndErrorKind::*;
+        match self {
+            SyntaxError(_) => "syntax",
+            ParseError(_) => "parse",
+            SemanticError(_) => "type",
+            SearchsPathError(_) => "search-path",
+            ModuleNotFound(_) => "module-not-found",
+            ImportCycle(_) => "import-cycle",
+            Other(_) => "other",
+        }
+    }
+
+    /// Code shown with the error so it can be looked up
+    pub fn code(&self) -> &'static str {
+        use FrontEndErrorKind::*;
+        match self {
+            SyntaxError(_) => "E0001",
+            ParseError(_) => "E0002",
+            SemanticError(_) => "E0003",
+            SearchsPathError(_) => "E0004",
+            ModuleNotFound(_) => "E0005",
+            ImportCycle(_) => "E0006",
+            Other(_) => "E0007",
+        }
+    }
+}
+
+impl From<SemanticErrorKind> for FrontEndErrorKind {
+    fn from(value: SemanticErrorKind) -> Self {
+        FrontEndErrorKind::SemanticError(Box::new(value))
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum ErrorPos {
     TokenRange(std::ops::Range<usize>),
@@ -34,6 +77,12 @@ pub struct FrontEndError {
     pub kind: FrontEndErrorKind,
     pub severity: Severity,
     pub pos: std::ops::Range<usize>,
+    /// The file pos is in, if it isn't the file being compiled
+    pub file: Option<Box<SourceFile>>,
+    /// Other spans of the file that explain the error, with what they are
+    pub labels: ThinVec<(std::ops::Range<usize>, String)>,
+    pub notes: ThinVec<String>,
+    pub help: ThinVec<String>,
 }
 
 impl std::fmt::Display for FrontEndError {
@@ -49,12 +98,87 @@ impl std::error::Error for FrontEndError {
 }
 
 impl FrontEndError {
+    pub fn set_file(self, file: &SourceFile) -> Self {
+        Self {
+            file: Some(Box::new(file.clone())),
+            ..self
+        }
+    }
+
     pub fn set_kind<K: Into<FrontEndErrorKind>>(self, kind: K) -> Self {
         Self {
             kind: kind.into(),
             ..self
         }
     }
+
+    pub fn with_label<S: Into<String>>(mut self, range: std::ops::Range<usize>, message: S) -> Self {
+        self.labels.push((range, message.into()));
+        self
+    }
+
+    pub fn with_note<S: Into<String>>(mut self, note: S) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    pub fn with_help<S: Into<String>>(mut self, help: S) -> Self {
+        self.help.push(help.into());
+        self
+    }
+
+    pub fn level(&self) -> Level {
+        // A fatal error only stops the parser backtracking, to the user it's the same
+        match self.severity {
+            Severity::Error | Severity::Fatal => Level::Error,
+        }
+    }
+
+    /// A diagnostic showing the error in the source it's from
+    /// source is the file being compiled, used if the error isn't in another file
+    pub fn to_diagnostic(&self, source: &SourceFile) -> Diagnostic {
+        let mut d = Diagnostic::new(self.level(), self.kind.to_string())
+            .with_code(self.kind.code())
+            .with_source(self.file.as_deref().unwrap_or(source))
+            .primary(self.pos.clone(), "");
+
+        for (range, message) in &self.labels {
+            d = d.secondary(range.clone(), message);
+        }
+        for note in &self.notes {
+            d = d.note(note);
+        }
+        for help in &self.help {
+            d = d.help(help);
+        }
+
+        d
+    }
+
+    /// The error as a JSON object, lines and columns count from 1
+    /// source is the file being compiled, used if the error isn't in another file
+    pub fn to_json(&self, source: &SourceFile) -> serde_json::Value {
+        let source = self.file.as_deref().unwrap_or(source);
+        let span = source.get_file_span_from_range(self.pos.clone());
+
+        serde_json::json!({
+            "file": source.origin.to_string(),
+            "range": { "start": self.pos.start, "end": self.pos.end },
+            "line": span.as_ref().map(|s| s.span.location.line + 1),
+            "col": span.as_ref().map(|s| s.span.location.col + 1),
+            "severity": self.level().name(),
+            "kind": self.kind.name(),
+            "code": self.kind.code(),
+            "message": self.kind.to_string(),
+        })
+    }
+}
+
+/// Lets a single error be returned with ? where every error found is reported
+impl From<FrontEndError> for Vec<FrontEndError> {
+    fn from(e: FrontEndError) -> Self {
+        vec![e]
+    }
 }
 
 impl FrontEndError {
@@ -63,18 +187,27 @@ impl FrontEndError {
             kind: e.into(),
             severity: Severity::Error,
             pos : pos.clone(),
+            file: None,
+            labels: ThinVec::new(),
+            notes: ThinVec::new(),
+            help: ThinVec::new(),
         }
     }
 }
 
 impl<'a> ParseError<Span<'a>> for FrontEndError {
     fn from_error_kind(input: Span<'a>, kind: ParseErrorKind, severity: Severity) -> Self {
-        let pos = get_text_range(input);
+        // Just the token the error is at, not everything after it
+        let pos = get_text_range(input.take(1).unwrap_or(input));
 
         Self {
             kind: kind.into(),
             severity,
             pos,
+            file: None,
+            labels: ThinVec::new(),
+            notes: ThinVec::new(),
+            help: ThinVec::new(),
         }
     }
 
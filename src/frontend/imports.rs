@@ -0,0 +1,143 @@
+/// Loads the files a program imports
+/// (import foo::bar) loads foo/bar.ploy through the search paths
+/// Imports are followed depth first, so every module comes after the modules it imports
+use super::prelude::*;
+use crate::opts::Opts;
+use crate::sources::{SourceFile, SourceLoader, SourceOrigin};
+use crate::symbols::{ScopeId, SymbolTree};
+
+use itertools::Itertools;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+pub struct ImportedModule {
+    pub name: String,
+    pub ast: Ast,
+}
+
+pub struct ModuleGraph {
+    loader: SourceLoader,
+    entry: SourceOrigin,
+    /// The chain of modules currently being loaded, used to find cycles
+    loading: Vec<(String, PathBuf)>,
+    loaded: HashSet<PathBuf>,
+    /// Every imported module, dependencies first
+    pub modules: Vec<ImportedModule>,
+}
+
+/// Get the file a module lives in, foo::bar is foo/bar.ploy
+pub fn module_file(name: &str) -> PathBuf {
+    let mut ret: PathBuf = name.split("::").collect();
+    ret.set_extension("ploy");
+    ret
+}
+
+/// Get or create the scope for a module, foo::bar is scope bar inside scope foo
+pub fn get_module_scope(syms: &mut SymbolTree, name: &str) -> ScopeId {
+    name.split("::").fold(syms.get_root_scope_id(), |scope, part| {
+        syms.create_or_get_scope_for_parent(part, scope)
+    })
+}
+
+fn canonical(p: &Path) -> PathBuf {
+    std::fs::canonicalize(p).unwrap_or_else(|_| p.to_path_buf())
+}
+
+/// Get the module name of every import in this ast
+fn get_imports(ast: &Ast) -> Vec<(AstNodeId, String)> {
+    ast.get_rec_ids(ast.get_root_id())
+        .into_iter()
+        .filter(|id| ast.tree.get(*id).unwrap().value().kind == AstNodeKind::Import)
+        .filter_map(|id| {
+            let name = ast.get_nth_kid_id(id, 0)?;
+            Some((id, ast.get_source_text(name).to_owned()))
+        })
+        .collect()
+}
+
+impl ModuleGraph {
+    pub fn new(opts: &Opts, entry: &SourceFile) -> Self {
+        let mut loader = SourceLoader::new();
+        let mut loading = vec![];
+
+        if let SourceOrigin::File(_, path) = &entry.origin {
+            if let Some(dir) = path.parent() {
+                let _ = loader.add_search_path(dir);
+            }
+            loading.push((path.to_string_lossy().into_owned(), canonical(path)));
+        }
+
+        for p in opts.search_paths() {
+            let _ = loader.add_search_path(p);
+        }
+
+        Self {
+            loader,
+            entry: entry.origin.clone(),
+            loading,
+            loaded: Default::default(),
+            modules: vec![],
+        }
+    }
+
+    /// Load every module this ast imports, and every module they import
+    pub fn load_imports(&mut self, ast: &Ast) -> Result<(), Vec<FrontEndError>> {
+        use FrontEndErrorKind::*;
+
+        for (id, name) in get_imports(ast) {
+            let err = |kind: FrontEndErrorKind| {
+                let node = ast.tree.get(id).unwrap().value();
+                let err = FrontEndError::new(kind, &node.text_range);
+
+                if ast.get_source_file().origin == self.entry {
+                    err
+                } else {
+                    err.set_file(ast.get_source_file())
+                }
+            };
+
+            let path = self
+                .loader
+                .resolve_file_path(module_file(&name))
+                .map_err(|_| err(ModuleNotFound(name.clone())))?;
+
+            let key = canonical(&path);
+
+            if let Some(pos) = self.loading.iter().position(|(_, p)| *p == key) {
+                let cycle = self.loading[pos..]
+                    .iter()
+                    .map(|(n, _)| n.as_str())
+                    .chain([name.as_str()])
+                    .join(" -> ");
+                let help = "move what the modules share into one they can all import";
+                return Err(err(ImportCycle(cycle)).with_help(help).into());
+            }
+
+            if self.loaded.contains(&key) {
+                continue;
+            }
+
+            let source = self
+                .loader
+                .load_file(&path)
+                .and_then(|id| self.loader.get_source_file(id).cloned())
+                .map_err(|_| err(ModuleNotFound(name.clone())))?;
+
+            let tokes = tokenize(&source);
+            let imported = to_ast(&tokes, source.clone())
+                .map_err(|errs| errs.into_iter().map(|e| e.set_file(&source)).collect::<Vec<_>>())?;
+
+            self.loading.push((name.clone(), key.clone()));
+            self.load_imports(&imported)?;
+            self.loading.pop();
+
+            self.loaded.insert(key);
+            self.modules.push(ImportedModule {
+                name,
+                ast: imported,
+            });
+        }
+
+        Ok(())
+    }
+}
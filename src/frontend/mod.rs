@@ -1,6 +1,7 @@
 /// Compiler front end
 /// tokenising and parsing into an AST
 mod ast;
+mod cst;
 mod error;
 mod parsenode;
 pub mod parsers;
@@ -11,22 +12,26 @@ mod tokens;
 mod types;
 mod semantics;
 mod module;
+mod imports;
+mod destructure;
 
 mod prelude {
     pub use super::{
-        ast::{to_ast, Ast, AstNode, AstNodeKind, ToProcessKind},
-        ast::{AstNodeId, AstNodeMut, AstNodeRef},
+        ast::{parse_ast, to_ast, Ast, AstNode, AstNodeKind, ToProcessKind},
+        ast::{AstNodeId, AstNodeMut, AstNodeRef, LambdaBodyData},
         error::{FrontEndError, FrontEndErrorKind, PResult},
-        ploytokens::tokenize,
+        ploytokens::{tokenize, tokenize_with_trivia},
     };
 
     pub use super::ast::AstTree;
+    pub use super::cst::{parse_cst, CstKind, CstNode};
     pub use super::parsenode::ParseNode;
     pub use super::ploytokens::Token;
     pub use super::span::{ Span,get_text_range };
-    pub use super::tokens::{ParseText, TokenKind};
+    pub use super::tokens::{parse_number, parse_quoted_string, ParseText, TokenKind};
     pub use super::types::*;
     pub use super::module::{ Module, ModuleJob };
+    pub use super::imports::module_file;
 }
 
 pub use prelude::*;
@@ -1,4 +1,4 @@
-use crate::error::to_full_error;
+use crate::error::to_full_errors;
 use crate::error::PloyErrorKind;
 use crate::opts::Opts;
 use std::collections::HashMap;
@@ -7,8 +7,11 @@ use super::prelude::*;
 use crate::sources::SourceOrigin;
 use crate::sources::{SourceFile, SourceLoader};
 use crate::symbols::ScopeId;
-use crate::symbols::SymbolTree;
+use crate::symbols::{SymbolScopeId, SymbolTree};
 use super::syntax::AstLowerer;
+use crate::builtins::add_builtins;
+use super::imports::{get_module_scope, ModuleGraph};
+use super::semantics::SemanticAnalyzer;
 
 #[derive(Clone, Debug)]
 pub struct ModuleJob {
@@ -30,43 +33,112 @@ pub struct Module {
     pub ast: Ast,
     pub from: ModuleJob,
     pub id_to_scope: HashMap<AstNodeId, ScopeId>,
+    pub definitions: HashMap<SymbolScopeId, AstNodeId>,
+    /// The scope of every imported module, with the module's name
+    pub module_scopes: HashMap<ScopeId, String>,
+    /// The type of every checked node
+    pub types: HashMap<AstNodeId, Type>,
 }
 
 impl Module {
+    /// Compile a module, keeping the front end errors so callers can report their positions
+    /// Every syntax error is found, then if there are none every undefined name and bad special form
+    /// Then the first type error in each top level form
+    pub fn new(module_job: ModuleJob) -> Result<Self, Vec<FrontEndError>> {
+        let mut syms = SymbolTree::new();
+        add_builtins(&mut syms).expect("Can't add builtins to an empty symbol tree");
 
-    pub fn get_scope_for_node(&self, id: AstNodeId) -> Option<ScopeId> {
-        self.id_to_scope.get(&id).cloned()
-    }
+        let tokes = tokenize(&module_job.source);
+        let mut ast = to_ast(&tokes, module_job.source.clone())?;
 
-}
+        let mut graph = ModuleGraph::new(&module_job.opts, &module_job.source);
+        graph.load_imports(&ast)?;
 
-impl TryFrom<ModuleJob> for Module {
-    type Error = PloyErrorKind;
+        let mut module_scopes = HashMap::new();
 
-    fn try_from(module_job: ModuleJob) -> Result<Self, Self::Error> {
-        let mut syms = SymbolTree::new();
-
-        let tokes = tokenize(&module_job.source);
-        let mut ast =
-            to_ast(&tokes, module_job.source.clone()).map_err(|e| to_full_error(e, &module_job.source))?;
+        // Grafting puts each module first, so go backwards to keep dependencies first
+        for imported in graph.modules.into_iter().rev() {
+            let scope = get_module_scope(&mut syms, &imported.name);
+            module_scopes.insert(scope, imported.name);
+            ast.graft_module(imported.ast, scope);
+        }
 
         let mut ast_lowerer = AstLowerer {
             syms: &mut syms,
             ast: &mut ast,
             id_to_scope: HashMap::new(),
+            definitions: HashMap::new(),
         };
 
-        ast_lowerer
-            .lower()
-            .map_err(|e| to_full_error(e, &module_job.source))?;
+        ast_lowerer.expand()?;
+        ast_lowerer.lower()?;
 
-        let ret = Self {
+        let mut ret = Self {
             id_to_scope: ast_lowerer.id_to_scope,
+            definitions: ast_lowerer.definitions,
+            module_scopes,
+            types: HashMap::new(),
             syms,
             ast,
-            from: module_job.clone(),
+            from: module_job,
         };
 
+        SemanticAnalyzer::new(&mut ret).analyze()?;
+
         Ok(ret)
     }
+
+    pub fn get_source_file(&self) -> &SourceFile {
+        &self.from.source
+    }
+
+    /// Top level definitions, of this file or an imported module, live for the whole program
+    pub fn is_global(&self, id: SymbolScopeId) -> bool {
+        id.scope_id == self.syms.get_root_scope_id() || self.module_scopes.contains_key(&id.scope_id)
+    }
+
+    pub fn get_scope_for_node(&self, id: AstNodeId) -> Option<ScopeId> {
+        self.id_to_scope.get(&id).cloned()
+    }
+
+    /// The type the checker gave this node, ToInfer if it couldn't tell
+    pub fn get_type(&self, id: AstNodeId) -> Type {
+        self.types.get(&id).cloned().unwrap_or_default()
+    }
+
+    /// The node that introduced this symbol, None for builtins
+    pub fn get_definition(&self, id: SymbolScopeId) -> Option<AstNodeId> {
+        self.definitions.get(&id).cloned()
+    }
+
+    /// Find the symbol at this offset in the source text of the module's own file
+    /// Includes the names in defines, which aren't part of the lowered tree
+    pub fn get_symbol_at(&self, offset: usize) -> Option<(AstNodeId, SymbolScopeId)> {
+        let origin = &self.get_source_file().origin;
+        let contains = |v: &AstNode| {
+            let r = &v.text_range;
+            &v.text_span.origin == origin && r.start <= offset && offset <= r.end
+        };
+
+        let in_tree = self.ast.get_rec_ids(self.ast.get_root_id()).into_iter();
+        let defs = self.definitions.values().cloned();
+
+        // Nodes from a macro template all sit on the whole call
+        in_tree.chain(defs).filter(|id| !self.ast.is_expanded(*id)).find_map(|id| {
+            let v = self.ast.tree.get(id)?.value();
+            match v.kind {
+                AstNodeKind::Symbol(sym_id) if contains(v) => Some((id, sym_id)),
+                _ => None,
+            }
+        })
+    }
+}
+
+impl TryFrom<ModuleJob> for Module {
+    type Error = PloyErrorKind;
+
+    fn try_from(module_job: ModuleJob) -> Result<Self, Self::Error> {
+        let source = module_job.source.clone();
+        Module::new(module_job).map_err(|errs| to_full_errors(errs, &source))
+    }
 }
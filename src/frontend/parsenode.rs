@@ -12,6 +12,7 @@ pub struct ParseNode {
     pub range: std::ops::Range<usize>,
     pub children: ThinVec<ParseNode>,
     pub meta_data: Option<Box<ParseNode>>,
+    pub type_annotation: Type,
 }
 
 impl ParseNode {
@@ -29,6 +30,11 @@ impl ParseNode {
         self
     }
 
+    pub fn change_type(mut self, type_annotation: Option<Type>) -> Self {
+        self.type_annotation = type_annotation.unwrap_or_default();
+        self
+    }
+
     pub fn is_kind(&self, k: AstNodeKind) -> bool {
         self.kind == k
     }
@@ -103,6 +109,7 @@ impl NodeBuilder {
             range: self.range,
             children: self.children,
             meta_data: self.meta_data.map(Box::new),
+            type_annotation: Type::ToInfer,
         }
     }
 }
@@ -8,6 +8,7 @@ use unraveler::{
 
 use super::prelude::*;
 use crate::frontend::syntax::SyntaxErrorKind;
+use super::parsenode::NodeBuilder;
 
 ////////////////////////////////////////////////////////////////////////////////
 // Helpers
@@ -53,6 +54,29 @@ fn parse_quoted(input: Span) -> PResult<ParseNode> {
     Ok((rest, node.into()))
 }
 
+fn parse_quasiquote(input: Span) -> PResult<ParseNode> {
+    let (rest, atom) = preceded(tag(TokenKind::BackTick), parse_atom)(input)?;
+    let node = ParseNode::builder(AstNodeKind::QuasiQuote, input, rest).child(atom);
+    Ok((rest, node.build()))
+}
+
+fn parse_unquote(input: Span) -> PResult<ParseNode> {
+    let (rest, atom) = preceded(tag(TokenKind::Comma), parse_atom)(input)?;
+    let node = ParseNode::builder(AstNodeKind::Unquote, input, rest).child(atom);
+    Ok((rest, node.build()))
+}
+
+fn parse_unquote_splicing(input: Span) -> PResult<ParseNode> {
+    let (rest, atom) = preceded(tag(TokenKind::CommaAt), parse_atom)(input)?;
+    let node = ParseNode::builder(AstNodeKind::UnquoteSplicing, input, rest).child(atom);
+    Ok((rest, node.build()))
+}
+
+/// `form ,form and ,@form, only allowed in a defmacro
+fn parse_template(input: Span) -> PResult<ParseNode> {
+    alt((parse_quasiquote, parse_unquote, parse_unquote_splicing))(input)
+}
+
 fn parse_null(input: Span) -> PResult<ParseNode> {
     use {AstNodeKind::*, TokenKind::*};
     let (rest, _) = tag([OpenBracket, CloseBracket])(input)?;
@@ -96,10 +120,7 @@ fn parse_lambda_type(_input: Span) -> PResult<Type> {
     panic!()
 }
 
-fn parse_simple_type(input: Span) -> PResult<Type> {
-    let (rest, matched) = tag(TokenKind::Identifier)(input)?;
-    let text = matched.get(0).unwrap().extra.get_text();
-
+fn simple_type(text: &str) -> Option<Type> {
     let ta = match text {
         "bool" => Type::Bool,
         "f32" => Type::F32,
@@ -108,46 +129,47 @@ fn parse_simple_type(input: Span) -> PResult<Type> {
         "string" => Type::String,
         "char" => Type::Char,
         "struct" => Type::Struct,
-        _ => {
-            return Err(FrontEndError::from_error_kind(
-                input,
-                ParseErrorKind::NoMatch,
-                Severity::Error,
-            ))
-        }
+        _ => return None,
     };
+    Some(ta)
+}
+
+fn no_match(input: Span) -> FrontEndError {
+    FrontEndError::from_error_kind(input, ParseErrorKind::NoMatch, Severity::Error)
+}
+
+fn parse_simple_type(input: Span) -> PResult<Type> {
+    let (rest, matched) = tag(TokenKind::Identifier)(input)?;
+    let text = matched.as_slice()[0].extra.get_text();
+    let ta = simple_type(text).ok_or_else(|| no_match(input))?;
+    Ok((rest, ta))
+}
+
+/// :int lexes as a keyword, only take it as a type if it names a builtin type
+fn parse_keyword_type(input: Span) -> PResult<Type> {
+    let (rest, matched) = tag(TokenKind::KeyWord)(input)?;
+    let text = matched.as_slice()[0].extra.get_text();
+    let ta = simple_type(&text[1..]).ok_or_else(|| no_match(input))?;
     Ok((rest, ta))
 }
 
 fn parse_unknown_type(input: Span) -> PResult<Type> {
     let (rest, matched) = tag(TokenKind::Identifier)(input)?;
-    let text = matched.get(0).unwrap().extra.get_text();
+    let text = matched.as_slice()[0].extra.get_text();
     Ok((rest, Type::User(text.to_owned())))
 }
 
 fn parse_type_annotation(input: Span) -> PResult<Type> {
     use TokenKind::*;
-    let (rest, _) = tag(Colon)(input)?;
-    let (rest, matched) = alt((parse_simple_type, parse_unknown_type))(rest)?;
-    Ok((rest, matched))
-}
-
-fn parse_application_head(input: Span) -> PResult<ParseNode> {
-    let (rest, matched) = alt((
-        parse_keyword,
-        parse_symbol,
-        parse_application,
-    ))(input)
-    .map_err(|mut e| {
-        e.kind = SyntaxErrorKind::IllegalApplication.into();
-        e
-    })?;
-
+    let colon_type = preceded(tag(Colon), alt((parse_simple_type, parse_unknown_type)));
+    let (rest, matched) = alt((colon_type, parse_keyword_type))(input)?;
     Ok((rest, matched))
 }
 
+/// Any form can be at the head, so a list can be passed to a macro as data
+/// Heads that can't be called are rejected once macros have been expanded
 fn parse_application(input: Span) -> PResult<ParseNode> {
-    let body = pair(parse_application_head, many0(parse_atom));
+    let body = pair(parse_atom, many0(parse_atom));
 
     let parsed = parse_bracketed(cut(body))(input);
 
@@ -161,8 +183,11 @@ fn parse_application(input: Span) -> PResult<ParseNode> {
 
 ////////////////////////////////////////////////////////////////////////////////
 fn get_text<'a>(input: Span<'a>, txt: &'a str) -> PResult<'a, Span<'a>> {
-    use TokenKind::*;
-    let (rest, matched) = tag(Identifier)(input)?;
+    get_text_of_kind(input, TokenKind::Identifier, txt)
+}
+
+fn get_text_of_kind<'a>(input: Span<'a>, kind: TokenKind, txt: &'a str) -> PResult<'a, Span<'a>> {
+    let (rest, matched) = tag(kind)(input)?;
 
     if match_text(&matched.as_slice()[0], txt) {
         Ok((rest, matched))
@@ -183,9 +208,12 @@ fn parse_specials(input: Span) -> PResult<ParseNode> {
         parse_let,
         parse_and,
         parse_or,
-        // parse_cond,
-        // parse_do,
-        // parse_macro,
+        parse_import,
+        parse_cond,
+        parse_do,
+        parse_macro,
+        parse_defmacro,
+        parse_recur,
     ))(input)?;
 
     Ok((rest, matched))
@@ -199,15 +227,73 @@ fn parse_and(input: Span) -> PResult<ParseNode> {
     parse_simple(input, "and", AstNodeKind::And)
 }
 
-fn parse_do(input: Span) -> PResult<ParseNode> {
-    parse_simple(input, "do", AstNodeKind::Do)
+pub fn parse_do(input: Span) -> PResult<ParseNode> {
+    parse_simple(input, "do", ToProcessKind::Do.into())
 }
-fn parse_cond(input: Span) -> PResult<ParseNode> {
-    parse_simple(input, "cond", AstNodeKind::Cond)
+
+pub fn parse_recur(input: Span) -> PResult<ParseNode> {
+    parse_simple(input, "recur", ToProcessKind::Recur.into())
+}
+
+pub fn parse_cond(input: Span) -> PResult<ParseNode> {
+    parse_simple(input, "cond", ToProcessKind::Cond.into())
 }
 
-fn parse_macro(input: Span) -> PResult<ParseNode> {
-    parse_simple(input, "macro", AstNodeKind::Macro)
+/// (defmacro name [params] `template)
+/// Shaped like a macro, the expander checks the body is a single template
+pub fn parse_defmacro(input: Span) -> PResult<ParseNode> {
+    parse_macro_form(input, "defmacro", AstNodeKind::DefMacro)
+}
+
+/// (macro name [params] body ...)
+pub fn parse_macro(input: Span) -> PResult<ParseNode> {
+    parse_macro_form(input, "macro", ToProcessKind::Macro.into())
+}
+
+/// The params and body become a lambda so they're scoped like one
+fn parse_macro_form<'a>(
+    input: Span<'a>,
+    txt: &'a str,
+    kind: AstNodeKind,
+) -> PResult<'a, ParseNode> {
+    let body = preceded(
+        txt_tag(txt),
+        cut(tuple((parse_arg, parse_args, parse_forms))),
+    );
+
+    let (rest, (name, args, forms)) = parse_bracketed(body)(input)?;
+
+    // Everything from the params up to the closing bracket
+    let start = args.range.start;
+    let len = rest.get_range().start - 1 - start;
+
+    let body = NodeBuilder::new(AstNodeKind::LambdaBody, start, len)
+        .child(args)
+        .children(forms)
+        .build();
+
+    let lambda = NodeBuilder::new(ToProcessKind::Lambda.into(), start, len)
+        .child(body)
+        .build();
+
+    let node = ParseNode::builder(kind, input, rest).children([name, lambda]);
+    Ok((rest, node.build()))
+}
+
+/// (import foo::bar) or (require foo::bar)
+pub fn parse_import(input: Span) -> PResult<ParseNode> {
+    use {SyntaxErrorKind::*, TokenKind::*};
+
+    let name = wrap_err(Expected("module name".to_owned()), |i| {
+        parse_kind(i, [Identifier, FqnIdentifier], AstNodeKind::ModuleName)
+    });
+
+    let body = preceded(alt((txt_tag("import"), txt_tag("require"))), cut(name));
+
+    let (rest, name) = parse_bracketed(body)(input)?;
+
+    let node = ParseNode::builder(AstNodeKind::Import, input, rest).child(name);
+    Ok((rest, node.build()))
 }
 
 pub fn parse_define(input: Span) -> PResult<ParseNode> {
@@ -250,7 +336,7 @@ pub fn parse_if(input: Span) -> PResult<ParseNode> {
 
 fn parse_let_arg(input: Span) -> PResult<ParseNode> {
     use {AstNodeKind::LetArg, TokenKind::*};
-    let (rest, (arg, val)) = pair(parse_arg, parse_atom)(input)?;
+    let (rest, (arg, val)) = pair(parse_binding, parse_atom)(input)?;
     let node = ParseNode::builder(LetArg, input, rest)
         .children([arg, val])
         .build();
@@ -382,15 +468,53 @@ pub fn parse_arg(input: Span) -> PResult<ParseNode> {
         parse_kind(i, [Identifier, FqnIdentifier], Arg)
     })(rest)?;
 
-    let (rest, _opt) = opt(parse_type_annotation)(rest)?;
-    Ok((rest, matched.change_meta(meta)))
+    let (rest, type_annotation) = opt(parse_type_annotation)(rest)?;
+    Ok((rest, matched.change_meta(meta).change_type(type_annotation)))
+}
+
+/// `& name`, collects any args left over into a list
+pub fn parse_rest_arg(input: Span) -> PResult<ParseNode> {
+    let (rest, arg) = preceded(tag(TokenKind::Ampersand), cut(parse_binding))(input)?;
+    let node = ParseNode::builder(AstNodeKind::RestArg, input, rest).child(arg);
+    Ok((rest, node.build()))
+}
+
+/// `[a b & more]`, the names and patterns in square brackets
+fn parse_bindings(input: Span) -> PResult<Vec<ParseNode>> {
+    use TokenKind::*;
+    parse_wrapped_many(
+        OpenSquareBracket,
+        CloseSquareBracket,
+        "]",
+        alt((parse_rest_arg, cut(parse_binding))),
+    )(input)
+}
+
+/// `[a [b c] & more]`, binds the elements of a list
+pub fn parse_vec_pattern(input: Span) -> PResult<ParseNode> {
+    let (rest, matched) = parse_bindings(input)?;
+    let node = ParseNode::builder(AstNodeKind::VecPattern, input, rest).children(matched);
+    Ok((rest, node.build()))
+}
+
+/// `{:keys [x y]}`, binds x and y to the values of :x and :y in a map
+pub fn parse_map_pattern(input: Span) -> PResult<ParseNode> {
+    use TokenKind::*;
+    let keys = parse_wrapped_many(OpenSquareBracket, CloseSquareBracket, "]", cut(parse_arg));
+    let keys_tag = |i| get_text_of_kind(i, KeyWord, ":keys");
+    let (rest, matched) = parse_braced(preceded(keys_tag, cut(keys)))(input)?;
+    let node = ParseNode::builder(AstNodeKind::MapPattern, input, rest).children(matched);
+    Ok((rest, node.build()))
+}
+
+/// Anything a fn arg or let can bind to, a name or a pattern to destructure
+pub fn parse_binding(input: Span) -> PResult<ParseNode> {
+    alt((parse_vec_pattern, parse_map_pattern, parse_arg))(input)
 }
 
 pub fn parse_args(input: Span) -> PResult<ParseNode> {
-    use {AstNodeKind::Args, SyntaxErrorKind::*, TokenKind::*};
-    let (rest, matched) =
-        parse_wrapped_many(OpenSquareBracket, CloseSquareBracket, "]", cut(parse_arg))(input)?;
-    let node = ParseNode::builder(Args, input, rest).children(matched);
+    let (rest, matched) = parse_bindings(input)?;
+    let node = ParseNode::builder(AstNodeKind::Args, input, rest).children(matched);
     Ok((rest, node.build()))
 }
 
@@ -438,11 +562,12 @@ pub fn parse_lambda(input: Span) -> PResult<ParseNode> {
         succeeded(cut(body), cut(tag(CloseBracket))),
     )(input)?;
 
-    let (rest, _ret_type) = opt(parse_type_annotation)(rest)?;
+    let (rest, ret_type) = opt(parse_type_annotation)(rest)?;
 
-    let node = ParseNode::builder(AstNodeKind::Lambda, input, rest)
+    let node = ParseNode::builder(ToProcessKind::Lambda, input, rest)
         .children(lambdas)
-        .build();
+        .build()
+        .change_type(ret_type);
     Ok((rest, node))
 }
 
@@ -541,11 +666,88 @@ fn parse_atom(input: Span) -> PResult<ParseNode> {
         parse_list,
         parse_quoted,
         parse_map,
+        parse_template,
     ))(input)
 }
 
-pub fn parse_program(input: Span) -> PResult<ParseNode> {
-    let (rest, matched) = many0(parse_atom)(input)?;
-    let x = ParseNode::builder(AstNodeKind::Program, input, rest).children(matched);
-    Ok((rest, x.into()))
+/// Tokens that start a form but aren't one on their own
+pub(super) fn is_prefix(kind: TokenKind) -> bool {
+    use TokenKind::*;
+    matches!(kind, Quote | BackTick | Comma | CommaAt | Caret | Hash)
+}
+
+/// Skip the form at the start of input, after it failed to parse
+/// Goes past the bracket matching the one it opens with, unless an open bracket at
+/// the start of a line comes first, that's taken as the next top level form
+fn skip_form(input: Span) -> Span {
+    use TokenKind::*;
+
+    let starts_line = |t: &Token| t.extra.start == 0 || t.extra.base[..t.extra.start].ends_with('\n');
+    let mut depth = 0usize;
+    let mut skipped = 0;
+
+    for t in input.iter() {
+        if depth > 0 && t.kind == OpenBracket && starts_line(t) {
+            break;
+        }
+
+        skipped += 1;
+
+        match t.kind {
+            OpenBracket | OpenSquareBracket | OpenBrace => depth += 1,
+            CloseBracket | CloseSquareBracket | CloseBrace => depth = depth.saturating_sub(1),
+            k if is_prefix(k) => continue,
+            _ => (),
+        }
+
+        if depth == 0 {
+            break;
+        }
+    }
+
+    input.drop(skipped).expect("Skipped past the end of the input")
+}
+
+/// Parse every top level form, carrying on past any that are broken
+/// Each broken form is an Error node in the program and an error in the list returned
+pub fn parse_program(input: Span) -> (ParseNode, Vec<FrontEndError>) {
+    let mut rest = input;
+    let mut forms = vec![];
+    let mut errors = vec![];
+
+    while !rest.is_empty() {
+        match parse_atom(rest) {
+            Ok((next, form)) => {
+                forms.push(form);
+                rest = next;
+            }
+
+            Err(e) => {
+                // Nothing matched at all, rather than a form going wrong part way through
+                let e = match e.severity() {
+                    Severity::Fatal => e,
+                    Severity::Error => {
+                        use TokenKind::*;
+                        let toke = &rest.as_slice()[0];
+                        let err = FrontEndError::new(SyntaxErrorKind::Unexpected, &toke.location.as_range());
+
+                        match toke.kind {
+                            CloseBracket | CloseSquareBracket | CloseBrace => {
+                                err.with_help("there's nothing open for this to close")
+                            }
+                            _ => err,
+                        }
+                    }
+                };
+
+                let next = skip_form(rest);
+                forms.push(ParseNode::builder(AstNodeKind::Error, rest, next).build());
+                errors.push(e);
+                rest = next;
+            }
+        }
+    }
+
+    let node = ParseNode::builder(AstNodeKind::Program, input, rest).children(forms);
+    (node.build(), errors)
 }
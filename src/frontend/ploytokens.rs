@@ -67,7 +67,7 @@ fn to_tokens_kinds(source_file: &SourceFile) -> Vec<(TokenKind, std::ops::Range<
 }
 
 // Tokenize a source file, remove comments
-fn to_tokens(source_file: &SourceFile) -> Vec<Token> {
+fn to_tokens(source_file: &SourceFile) -> Vec<Token<'_>> {
     to_tokens_kinds(source_file)
         .into_iter()
         .filter(|x| !x.0.is_comment())
@@ -81,7 +81,34 @@ fn to_tokens(source_file: &SourceFile) -> Vec<Token> {
         .collect()
 }
 
-pub fn tokenize(source_file: &SourceFile) -> Vec<Token> {
+pub fn tokenize(source_file: &SourceFile) -> Vec<Token<'_>> {
     let tokes = to_tokens(source_file);
     tokes
 }
+
+/// Tokenize a source file, keeping comments and whitespace
+/// Every byte of the file is in exactly one token, in order
+pub fn tokenize_with_trivia(source_file: &SourceFile) -> Vec<Token<'_>> {
+    let text = source_file.text();
+    let mut pos = 0;
+    let mut ret = vec![];
+
+    let mut push = |kind, r: std::ops::Range<usize>| {
+        ret.push(Token::new(kind, TextSpan::new(r.start, r.len()), ParseText::new(text, r)))
+    };
+
+    for (kind, r) in to_tokens_kinds(source_file) {
+        // The lexer skips whitespace, so the gaps are whitespace
+        if r.start > pos {
+            push(TokenKind::Whitespace, pos..r.start);
+        }
+        pos = r.end;
+        push(kind, r);
+    }
+
+    if pos < text.len() {
+        push(TokenKind::Whitespace, pos..text.len());
+    }
+
+    ret
+}
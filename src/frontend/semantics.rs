@@ -2,32 +2,669 @@
 // So...
 // function arrity
 // types
+//
+// Types are inferred by unification, Hindley-Milner style
+// Symbols bound by def and let are generalised so they can be used at more than one type
 
 use super::prelude::*;
+use crate::builtins::{get_builtin, BuiltIn};
+use crate::symbols::SymbolScopeId;
+use std::collections::{HashMap, HashSet};
 use thiserror::Error;
 
-pub struct SemanticAnalyzer<'a> {
-    module : &'a mut Module,
-}
-
 #[derive(Debug, Error, Clone)]
 pub enum SemanticErrorKind {
+    #[error("expected {expected}, found {found}")]
+    Mismatch { expected: Type, found: Type },
+    #[error("if branches disagree, {0} and {1}")]
+    IfBranches(Type, Type),
+    #[error("returns {found} but is annotated as {expected}")]
+    ReturnType { expected: Type, found: Type },
+    #[error("a value of type {0} can't be called")]
+    NotCallable(Type),
+    #[error("expected {expected} args, found {found}")]
+    WrongNumberOfArgs { expected: usize, found: usize },
+    #[error("expected at least {expected} args, found {found}")]
+    TooFewArgs { expected: usize, found: usize },
+    #[error("unknown type {0}")]
+    UnknownType(String),
+    #[error("{0} would have to contain itself")]
+    InfiniteType(Type),
+    #[error("cond clauses disagree, {0} and {1}")]
+    CondBranches(Type, Type),
+}
+
+pub struct SemanticAnalyzer<'a> {
+    module: &'a mut Module,
+    subst: Substitution,
+    /// Type scheme of every symbol bound so far
+    env: HashMap<SymbolScopeId, Scheme>,
+    /// Symbols whose binding is still being checked, lambda params and recursive defines
+    /// Their type variables can't be generalised
+    mono: Vec<SymbolScopeId>,
 }
 
+type SResult<T> = Result<T, FrontEndError>;
+
 impl<'a> SemanticAnalyzer<'a> {
     pub fn new(module: &'a mut Module) -> Self {
         Self {
-            module
+            module,
+            subst: Default::default(),
+            env: Default::default(),
+            mono: vec![],
         }
     }
 
-    pub fn analyze(&mut self) -> Result<(),FrontEndError> {
+    pub fn analyze(&mut self) -> Result<(), Vec<FrontEndError>> {
         self.check_types()?;
         Ok(())
     }
 
-    pub fn check_types(&mut self) -> Result<(),FrontEndError>{
-        panic!()
+    /// Infer the type of every node, checking them against the annotations
+    /// Each top level form is checked even if an earlier one failed, the first error in each is reported
+    pub fn check_types(&mut self) -> Result<(), Vec<FrontEndError>> {
+        let root = self.module.ast.get_root_id();
+        let mut errors = vec![];
+        let mut ret = Type::Void;
+
+        for id in self.kids(root) {
+            match self.check(id) {
+                Ok(t) => ret = t,
+                Err(e) => {
+                    errors.push(e);
+                    // A failed form can leave its locals behind
+                    self.mono.clear();
+                }
+            }
+        }
+
+        self.module.types.insert(root, ret);
+
+        // Now everything has been unified, replace the variables with what we found
+        for t in self.module.types.values_mut() {
+            *t = self.subst.resolve(t)
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn kind(&self, id: AstNodeId) -> AstNodeKind {
+        self.module.ast.tree.get(id).unwrap().value().kind.clone()
+    }
+
+    fn kids(&self, id: AstNodeId) -> thin_vec::ThinVec<AstNodeId> {
+        self.module.ast.get_kids_ids(id)
+    }
+
+    /// An error at this node, in whichever file the node came from
+    fn error(&self, id: AstNodeId, kind: SemanticErrorKind) -> FrontEndError {
+        let ast = &self.module.ast;
+        let err = FrontEndError::new(kind, &ast.tree.get(id).unwrap().value().text_range);
+        let file = ast.get_source_file_for_node(id);
+
+        if file.origin == ast.get_source_file().origin {
+            err
+        } else {
+            err.set_file(file)
+        }
+    }
+
+    /// The annotated type of a node, a fresh variable if there isn't one
+    fn annotation(&mut self, id: AstNodeId) -> SResult<Type> {
+        match &self.module.ast.tree.get(id).unwrap().value().type_annotation {
+            Type::User(name) => Err(self.error(id, SemanticErrorKind::UnknownType(name.clone()))),
+            Type::ToInfer => Ok(self.subst.fresh()),
+            t => Ok(t.clone()),
+        }
+    }
+
+    /// Point at another node to explain an error, if it's in the same file as the error
+    fn label(&self, err: FrontEndError, id: AstNodeId, message: String) -> FrontEndError {
+        let ast = &self.module.ast;
+        let in_file = err.file.as_deref().unwrap_or(ast.get_source_file()).origin.clone();
+
+        if ast.get_source_file_for_node(id).origin == in_file {
+            err.with_label(ast.tree.get(id).unwrap().value().text_range.clone(), message)
+        } else {
+            err
+        }
+    }
+
+    /// Unify the type found at this node with the type it should have
+    fn expect(&mut self, id: AstNodeId, expected: &Type, found: &Type) -> SResult<()> {
+        self.subst.unify(expected, found).map_err(|e| {
+            let kind = match e {
+                UnifyError::InfiniteType => {
+                    SemanticErrorKind::InfiniteType(self.subst.resolve(found))
+                }
+                UnifyError::Mismatch => SemanticErrorKind::Mismatch {
+                    expected: self.subst.resolve(expected),
+                    found: self.subst.resolve(found),
+                },
+            };
+            self.error(id, kind)
+        })
+    }
+
+    /// Generalise a type over the variables that aren't pinned by a binding still being checked
+    fn generalize(&self, t: &Type) -> Scheme {
+        let env_vars: HashSet<usize> = self
+            .mono
+            .iter()
+            .filter_map(|sym_id| self.env.get(sym_id))
+            .flat_map(|s| self.subst.resolve(&s.ty).free_vars())
+            .collect();
+
+        self.subst.generalize(t, &env_vars)
+    }
+
+    fn check(&mut self, id: AstNodeId) -> SResult<Type> {
+        let t = self.check_node(id)?;
+        self.module.types.insert(id, t.clone());
+        Ok(t)
+    }
+
+    /// Check a sequence of forms, the type is the type of the last one
+    fn check_forms(&mut self, ids: &[AstNodeId]) -> SResult<Type> {
+        let mut ret = Type::Void;
+
+        for id in ids {
+            ret = self.check(*id)?;
+        }
+
+        Ok(ret)
+    }
+
+    fn check_node(&mut self, id: AstNodeId) -> SResult<Type> {
+        use AstNodeKind::*;
+
+        let t = match self.kind(id) {
+            Program | Module(_) => self.check_forms(&self.kids(id))?,
+
+            Import => Type::Void,
+            Number => Type::Integer,
+            QuotedString => Type::String,
+            KeyWord => Type::KeyWord,
+            True | False => Type::Bool,
+            Null => Type::Void,
+
+            Map => {
+                self.check_forms(&self.kids(id))?;
+                Type::Map
+            }
+
+            Array | List => {
+                self.check_forms(&self.kids(id))?;
+                Type::Array
+            }
+
+            Quoted => {
+                let kid = self.module.ast.get_nth_kid_id(id, 0).unwrap();
+                match self.kind(kid) {
+                    Symbol(..) => Type::KeyWord,
+                    _ => self.check(kid)?,
+                }
+            }
+
+            Symbol(sym_id) => {
+                if let Some(b) = get_builtin(&self.module.syms, sym_id) {
+                    self.builtin_type(b)
+                } else {
+                    match self.env.get(&sym_id).cloned() {
+                        Some(scheme) => self.subst.instantiate(&scheme),
+                        None => self.subst.fresh(),
+                    }
+                }
+            }
+
+            AssignSymbol(sym_id) => {
+                let val = self.module.ast.get_nth_kid_id(id, 0).unwrap();
+                self.bind(sym_id, val)?
+            }
+
+            LetArg => {
+                let sym = self.module.ast.get_nth_kid_id(id, 0).unwrap();
+                let val = self.module.ast.get_nth_kid_id(id, 1).unwrap();
+
+                match self.kind(sym) {
+                    Symbol(sym_id) => {
+                        let t = self.bind(sym_id, val)?;
+                        self.module.types.insert(sym, t.clone());
+                        t
+                    }
+                    _ => self.subst.fresh(),
+                }
+            }
+
+            Let(_) => {
+                let kids = self.kids(id);
+                let (args, forms) = kids.split_first().expect("Let with no args");
+                self.check(*args)?;
+                self.check_forms(forms)?
+            }
+
+            If(if_data) => {
+                self.check(if_data.predicate)?;
+                let if_true = self.check(if_data.if_true)?;
+
+                match if_data.if_false {
+                    Some(id) => {
+                        let if_false = self.check(id)?;
+
+                        if self.subst.unify(&if_true, &if_false).is_err() {
+                            let (if_true, if_false) = (self.subst.resolve(&if_true), self.subst.resolve(&if_false));
+                            let message = format!("this is {if_true}");
+                            let err = self.error(id, SemanticErrorKind::IfBranches(if_true, if_false));
+                            return Err(self.label(err, if_data.if_true, message));
+                        }
+
+                        if_true
+                    }
+
+                    // It's null when the predicate is false, so only known if the true branch is null too
+                    None => match self.subst.unify(&if_true, &Type::Void) {
+                        Ok(()) => Type::Void,
+                        Err(_) => self.subst.fresh(),
+                    },
+                }
+            }
+
+            Do(do_data) => self.check_forms(&do_data.forms)?,
+
+            // Never returns, so it fits wherever it's used
+            Recur(recur_data) => {
+                for (param, arg) in recur_data.params.iter().zip(&recur_data.args) {
+                    let found = self.check(*arg)?;
+
+                    if let Some(scheme) = self.env.get(param).cloned() {
+                        let expected = self.subst.instantiate(&scheme);
+                        self.expect(*arg, &expected, &found)?;
+                    }
+                }
+
+                self.subst.fresh()
+            }
+
+            Cond(cond_data) => {
+                let mut values = vec![];
+
+                for (test, value) in cond_data.clauses.iter() {
+                    self.check(*test)?;
+                    values.push((*value, self.check(*value)?));
+                }
+
+                if let Some(default) = cond_data.default {
+                    values.push((default, self.check(default)?));
+                }
+
+                match values.split_first() {
+                    None => Type::Void,
+                    Some(((first_id, first), rest)) => {
+                        for (value, t) in rest {
+                            if self.subst.unify(first, t).is_err() {
+                                let (first, t) = (self.subst.resolve(first), self.subst.resolve(t));
+                                let message = format!("the first clause is {first}");
+                                let err = self.error(*value, SemanticErrorKind::CondBranches(first, t));
+                                return Err(self.label(err, *first_id, message));
+                            }
+                        }
+                        first.clone()
+                    }
+                }
+            }
+
+            // Only the expansions are checked
+            Macro(_) => Type::Void,
+
+            // Could be any of the values, only known if they all agree
+            And | Or => {
+                let kids = self.kids(id);
+                let mut types = vec![];
+
+                for id in kids {
+                    let t = self.check(id)?;
+                    types.push(self.subst.resolve(&t));
+                }
+
+                match types.split_first() {
+                    None => Type::Bool,
+                    Some((first, rest)) if rest.iter().all(|t| t == first) => first.clone(),
+                    _ => self.subst.fresh(),
+                }
+            }
+
+            Lambda(_) => self.check_lambda(id)?,
+
+            Application(app_data) => {
+                self.check_application(id, app_data.func, &app_data.args)?
+            }
+
+            _ => {
+                self.check_forms(&self.kids(id))?;
+                self.subst.fresh()
+            }
+        };
+
+        Ok(t)
+    }
+
+    /// Bind a symbol to a value and generalise its type
+    /// The annotation on the symbol's definition has to agree with the value
+    fn bind(&mut self, sym_id: SymbolScopeId, val: AstNodeId) -> SResult<Type> {
+        let def = self.module.get_definition(sym_id);
+
+        let t = match def {
+            Some(def) => self.annotation(def)?,
+            None => self.subst.fresh(),
+        };
+
+        // A multi arity fn has no single signature, so calls to itself can't pin one down
+        if let AstNodeKind::Lambda(lambda) = self.kind(val) {
+            if lambda.bodies.len() > 1 {
+                self.expect(val, &t, &Type::Lambda)?;
+            }
+        }
+
+        // Bound but not generalised while the value is checked, so it can refer to itself
+        self.env.insert(sym_id, Scheme::mono(t.clone()));
+        self.mono.push(sym_id);
+        let found = self.check(val);
+        self.mono.pop();
+
+        let found = found?;
+        self.expect(val, &t, &found)?;
+
+        let scheme = self.generalize(&t);
+        self.env.insert(sym_id, scheme);
+
+        if let Some(def) = def {
+            self.module.types.insert(def, t.clone());
+        }
+
+        Ok(t)
+    }
+
+    fn check_lambda(&mut self, id: AstNodeId) -> SResult<Type> {
+        let returns = self.annotation(id)?;
+        let bodies = self.kids(id);
+        let mut ret = Type::Lambda;
+
+        for body in bodies.iter() {
+            let kids = self.kids(*body);
+            let (args, forms) = kids.split_first().expect("Lambda body with no args");
+            let depth = self.mono.len();
+            let mut params = vec![];
+            let mut variadic = false;
+
+            for param in self.kids(*args) {
+                // The rest param holds a list of whatever's left over
+                if self.kind(param) == AstNodeKind::RestArg {
+                    let rest = self.kids(param)[0];
+
+                    if let AstNodeKind::Symbol(sym_id) = self.kind(rest) {
+                        self.env.insert(sym_id, Scheme::mono(Type::Array));
+                        self.mono.push(sym_id);
+                    }
+
+                    self.module.types.insert(rest, Type::Array);
+                    variadic = true;
+                    continue;
+                }
+
+                let t = self.annotation(param)?;
+
+                if let AstNodeKind::Symbol(sym_id) = self.kind(param) {
+                    self.env.insert(sym_id, Scheme::mono(t.clone()));
+                    self.mono.push(sym_id);
+                }
+
+                self.module.types.insert(param, t.clone());
+                params.push(t);
+            }
+
+            let found = self.check_forms(forms);
+            self.mono.truncate(depth);
+            let found = found?;
+
+            if self.subst.unify(&returns, &found).is_err() {
+                let kind = SemanticErrorKind::ReturnType {
+                    expected: self.subst.resolve(&returns),
+                    found: self.subst.resolve(&found),
+                };
+                return Err(self.error(*forms.last().unwrap_or(body), kind));
+            }
+
+            // Multi arity lambdas don't have a single signature
+            if bodies.len() == 1 {
+                ret = if variadic {
+                    Type::Variadic(params, Box::new(returns.clone()))
+                } else {
+                    Type::Fn(params, Box::new(returns.clone()))
+                }
+            }
+        }
+
+        Ok(ret)
+    }
+
+    fn check_application(
+        &mut self,
+        id: AstNodeId,
+        func: AstNodeId,
+        args: &[AstNodeId],
+    ) -> SResult<Type> {
+        let func_type = self.check(func)?;
+        let mut arg_types = vec![];
+
+        for arg in args {
+            arg_types.push(self.check(*arg)?);
+        }
+
+        match self.kind(func) {
+            // (:key map), anything that isn't a map just gives null
+            AstNodeKind::KeyWord => return Ok(self.subst.fresh()),
+
+            AstNodeKind::Symbol(sym_id) => {
+                if let Some(b) = get_builtin(&self.module.syms, sym_id) {
+                    return self.check_builtin(id, b, args, &arg_types);
+                }
+            }
+
+            _ => (),
+        }
+
+        match self.subst.resolve(&func_type) {
+            Type::Fn(params, ret) => {
+                if params.len() != args.len() {
+                    let kind = SemanticErrorKind::WrongNumberOfArgs {
+                        expected: params.len(),
+                        found: args.len(),
+                    };
+                    return Err(self.error(id, kind));
+                }
+
+                for ((param, arg), arg_type) in params.iter().zip(args).zip(&arg_types) {
+                    self.expect(*arg, param, arg_type)?;
+                }
+
+                Ok(*ret)
+            }
+
+            Type::Variadic(params, ret) => {
+                if args.len() < params.len() {
+                    let kind = SemanticErrorKind::TooFewArgs {
+                        expected: params.len(),
+                        found: args.len(),
+                    };
+                    return Err(self.error(id, kind));
+                }
+
+                for ((param, arg), arg_type) in params.iter().zip(args).zip(&arg_types) {
+                    self.expect(*arg, param, arg_type)?;
+                }
+
+                Ok(*ret)
+            }
+
+            // Not known yet, it must be a function taking these args
+            Type::Var(_) => {
+                let ret = self.subst.fresh();
+                let expected = Type::Fn(arg_types, Box::new(ret.clone()));
+                self.expect(func, &expected, &func_type)?;
+                Ok(ret)
+            }
+
+            Type::Lambda => Ok(self.subst.fresh()),
+
+            t => Err(self.error(func, SemanticErrorKind::NotCallable(t))),
+        }
+    }
+
+    /// Builtins can take a range of args so calls to them are checked here
+    fn check_builtin(
+        &mut self,
+        id: AstNodeId,
+        b: BuiltIn,
+        args: &[AstNodeId],
+        arg_types: &[Type],
+    ) -> SResult<Type> {
+        use BuiltIn::*;
+
+        if !b.accepts(args.len()) {
+            let kind = match b.arity() {
+                (min, None) => SemanticErrorKind::TooFewArgs {
+                    expected: min,
+                    found: args.len(),
+                },
+                (min, Some(_)) => SemanticErrorKind::WrongNumberOfArgs {
+                    expected: min,
+                    found: args.len(),
+                },
+            };
+            return Err(self.error(id, kind));
+        }
+
+        match b {
+            Add | Sub | Mul | Div => {
+                for (arg, t) in args.iter().zip(arg_types) {
+                    self.expect(*arg, &Type::Integer, t)?;
+                }
+                Ok(Type::Integer)
+            }
+
+            // Both sides of an eq are the same type
+            Eq => {
+                self.expect(args[1], &arg_types[0], &arg_types[1])?;
+                Ok(Type::Bool)
+            }
+
+            Not => Ok(Type::Bool),
+
+            Nth | NthRest => {
+                let expected = [Type::Array, Type::Integer];
+
+                for ((arg, t), e) in args.iter().zip(arg_types).zip(&expected) {
+                    self.expect(*arg, e, t)?;
+                }
+
+                match b {
+                    Nth => Ok(self.subst.fresh()),
+                    _ => Ok(Type::Array),
+                }
+            }
+        }
+    }
+
+    /// The type of a builtin used as a value rather than called
+    fn builtin_type(&mut self, b: BuiltIn) -> Type {
+        use BuiltIn::*;
+
+        match b {
+            Add | Sub | Mul | Div => {
+                Type::Fn(vec![Type::Integer, Type::Integer], Box::new(Type::Integer))
+            }
+            Eq => {
+                let t = self.subst.fresh();
+                Type::Fn(vec![t.clone(), t], Box::new(Type::Bool))
+            }
+            Not => Type::Fn(vec![self.subst.fresh()], Box::new(Type::Bool)),
+            Nth => Type::Fn(vec![Type::Array, Type::Integer], Box::new(self.subst.fresh())),
+            NthRest => Type::Fn(vec![Type::Array, Type::Integer], Box::new(Type::Array)),
+        }
     }
 }
 
+#[allow(unused_imports)]
+mod test {
+    use super::*;
+    use crate::opts::Opts;
+    use crate::sources::{SourceFile, SourceOrigin};
+    use pretty_assertions::assert_eq;
+
+    fn check(text: &str) -> Result<Module, FrontEndError> {
+        let source = SourceFile::new(text.to_owned(), SourceOrigin::Text);
+        Module::new(ModuleJob::new(&Opts::default(), &source)).map_err(|errs| errs[0].clone())
+    }
+
+    /// The type of the last top level form
+    fn type_of_last(text: &str) -> Type {
+        let module = check(text).unwrap_or_else(|e| panic!("{text}: {e}"));
+        let last = module.ast.get_kids_ids(module.ast.get_root_id()).last().copied().unwrap();
+        module.get_type(last)
+    }
+
+    #[test]
+    fn test_check_forms() {
+        use Type::*;
+
+        let tests = [
+            ("(do 1 \"a\")", String),
+            ("(cond (eq 1 2) :a :else :b)", KeyWord),
+            ("(and 1 2)", Integer),
+            ("[1 2]", Array),
+            ("(nth [1 2] 0)", Var(0)),
+            ("(def f (fn [g] (if true (g 1)))) (f (fn [x] ()))", Void),
+            ("(def f (fn [& xs] xs)) (f 1 :a)", Array),
+            ("(:a {:a 1})", Var(0)),
+            ("(:a 1)", Var(0)),
+        ];
+
+        for (text, expected) in tests {
+            match (type_of_last(text), expected) {
+                (Var(_), Var(_)) => (),
+                (t, expected) => assert_eq!(t, expected, "{text}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_recursion_is_monomorphic() {
+        // Inside its own definition f has one type, it's only generalised after
+        let err = check("(def f (fn [x] (do (f 1) (f \"a\") x)))").err().unwrap();
+        assert_eq!(err.to_string(), "Type error: expected int, found string");
+
+        assert_eq!(type_of_last("(def f (fn [x] (do (f 1) x))) (f 2)"), Type::Integer);
+    }
+
+    #[test]
+    fn test_error_labels() {
+        let err = check("(if true\n  1\n  \"no\")").err().unwrap();
+        assert_eq!(err.pos, 15..19);
+        assert_eq!(err.labels.to_vec(), vec![(11..12, "this is int".to_owned())]);
+
+        let err = check("(cond true 1 :else \"a\")").err().unwrap();
+        assert_eq!(err.to_string(), "Type error: cond clauses disagree, int and string");
+        assert_eq!(err.labels.len(), 1);
+
+        let err = check("(def f (fn [a b] a)) (f 1)").err().unwrap();
+        assert_eq!(err.to_string(), "Type error: expected 2 args, found 1");
+
+        let err = check("(def f (fn [a & b] a)) (f)").err().unwrap();
+        assert_eq!(err.to_string(), "Type error: expected at least 1 args, found 0");
+    }
+}
@@ -1,20 +1,24 @@
-use super::ast::{ApplicationData, IfData};
+use super::ast::{
+    ApplicationData, CondData, DoData, IfData, LambdaBodyData, LambdaData, MacroData, RecurData,
+};
+use super::destructure::Destructurer;
 /// Checks AST for Syntax errors
 /// Does some AST lowering
 /// and other processing
 use super::prelude::*;
 
+use crate::builtins::get_builtin;
 use crate::error::to_full_error;
 use crate::frontend::ast::LetData;
 use crate::sources::SourceFile;
-use crate::symbols::{ScopeId, SymbolTree};
+use crate::symbols::{ScopeId, SymbolScopeId, SymbolTree};
 use crate::value;
 
 use anyhow::Context;
 use serde::Deserialize;
 use std::sync::atomic::AtomicIsize;
 use std::vec;
-use std::{collections::HashMap, io::Cursor};
+use std::{collections::{HashMap, HashSet}, io::Cursor};
 use thin_vec::ThinVec;
 use thiserror::Error;
 use unraveler::Item;
@@ -38,13 +42,61 @@ pub enum SyntaxErrorKind {
     Expected(String),
     #[error("Undefined symbol {0}")]
     UndefinedSymbol(String),
+    #[error("{0} is already defined")]
+    AlreadyDefined(String),
+    #[error("Number {0} is too big")]
+    InvalidNumber(String),
     #[error("Unexpected input")]
     Unexpected,
 
     #[error("This isn't something you can call")]
     IllegalApplication,
+
+    #[error("Cond needs a value for every test")]
+    OddCond,
+    #[error("Nothing can come after an :else clause")]
+    ClauseAfterElse,
+    #[error("Macro {name} takes {expected} args, got {found}")]
+    MacroArgs {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    #[error("Macro {0} keeps expanding into itself")]
+    MacroRecursion(String),
+    #[error("More than one body of this fn takes {0} args")]
+    DuplicateArity(usize),
+    #[error("Only one body of a fn can have a & rest param")]
+    DuplicateRest,
+    #[error("Nothing can come after a & rest param")]
+    ArgAfterRest,
+    #[error("& rest params can only be used in a fn")]
+    RestOutsideFn,
+    #[error("{0} is bound more than once in this pattern")]
+    DuplicateBinding(String),
+    #[error("Destructuring can only be used in a let or a fn's args")]
+    PatternOutsideBinding,
+    #[error("recur can only be used in a fn")]
+    RecurOutsideFn,
+    #[error("recur can only be used in tail position")]
+    RecurNotInTail,
+    #[error("recur takes {expected} args here, got {found}")]
+    RecurArgs { expected: usize, found: usize },
+    #[error("defmacro can only be used at the top level")]
+    NestedDefMacro,
+    #[error("The body of macro {0} must be a single quasiquoted template")]
+    MacroTemplate(String),
+    #[error("Only the macro's parameters can be unquoted")]
+    BadUnquote,
+    #[error("Can't splice {0}, it isn't a list")]
+    BadSplice(String),
+    #[error("Quasiquote and unquote can only be used in a defmacro")]
+    TemplateOutsideMacro,
 }
 
+/// Most macro expansions we'll do before giving up on a program
+const MAX_MACRO_EXPANSIONS: usize = 1000;
+
 fn get_str<'a>(x: Token<'a>, txt: &'a str) -> &'a str {
     &txt[x.location.as_range()]
 }
@@ -59,6 +111,25 @@ pub struct AstLowerer<'a> {
     pub syms: &'a mut SymbolTree,
     pub ast: &'a mut super::ast::Ast,
     pub id_to_scope: HashMap<AstNodeId, ScopeId>,
+    /// The node that introduced each symbol
+    pub definitions: HashMap<SymbolScopeId, AstNodeId>,
+}
+
+/// A copy of a macro's body being made for one call
+struct Expansion {
+    /// The call, every node copied from the body sits on it
+    call: AstNode,
+    call_scope: ScopeId,
+    /// Each param's arg, with the scope markers around it
+    subs: HashMap<SymbolScopeId, Vec<AstNodeId>>,
+    /// The scope every node was in before expanding, args still resolve there
+    node_scopes: HashMap<AstNodeId, ScopeId>,
+    /// The scope of the macro's params
+    scope: ScopeId,
+    /// Scopes in the body and their copies, the params' scope is copied to the call's
+    scopes: HashMap<ScopeId, ScopeId>,
+    /// Symbols bound in the body and their copies
+    symbols: HashMap<SymbolScopeId, SymbolScopeId>,
 }
 
 fn num_of_children(n: AstNodeRef) -> usize {
@@ -80,6 +151,24 @@ fn check_args(args: &[AstNodeRef], min: usize, max: usize) -> Result<(), SyntaxE
     }
 }
 
+/// False for the head of an application if it's a value that's never a function
+fn can_be_called(kind: &AstNodeKind) -> bool {
+    use AstNodeKind::*;
+    !matches!(
+        kind,
+        Number | QuotedString | True | False | Null | Array | Map | List | Quoted
+    )
+}
+
+/// Ok if nothing went wrong
+fn all_ok(errors: Vec<FrontEndError>) -> Result<(), Vec<FrontEndError>> {
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
 fn min_args(args: &[AstNodeRef], min: usize) -> Result<(), SyntaxErrorKind> {
     if args.len() < min {
         Err(SyntaxErrorKind::NotEngoughArgs)
@@ -89,14 +178,27 @@ fn min_args(args: &[AstNodeRef], min: usize) -> Result<(), SyntaxErrorKind> {
 }
 
 impl<'a> AstLowerer<'a> {
-    pub fn lower(&mut self) -> Result<(), FrontEndError> {
+    /// Desugars destructuring and expands macros, so lowering only sees plain forms
+    /// Macro args are unevaluated forms, so they're spliced before anything checks them
+    pub fn expand(&mut self) -> Result<(), Vec<FrontEndError>> {
+        Destructurer::new(self.ast, self.syms.get_root_scope_id()).lower()?;
         self.add_scopes()?;
         self.intern_symbol_assignments()?;
+        self.expand_macros()?;
+
+        Ok(())
+    }
+
+    /// Passes that check names and special forms report every error they find
+    /// Later passes need a clean tree, so they aren't run if there are any
+    pub fn lower(&mut self) -> Result<(), Vec<FrontEndError>> {
         self.intern_refs()?;
+        self.lower_defines()?;
         self.create_values()?;
         self.make_node_to_scope_table();
 
         self.process_special_forms()?;
+        self.process_recurs()?;
 
         Ok(())
     }
@@ -122,7 +224,13 @@ impl<'a> AstLowerer<'a> {
         let mut n = self.ast.tree.get_mut(id).unwrap();
         let v = n.value();
 
-        if v.kind.creates_new_scope() {
+        if let AstNodeKind::Module(module_scope) = v.kind {
+            let before = v.change_kind(AstNodeKind::SetScope(module_scope));
+            let after = v.change_kind(AstNodeKind::SetScope(current_scope));
+            n.insert_before(before);
+            n.insert_after(after);
+            module_scope
+        } else if v.kind.creates_new_scope() {
             let new_scope_name = format!("scope_{}", self.syms.get_next_scope_id());
             let new_scope = self
                 .syms
@@ -156,25 +264,148 @@ impl<'a> AstLowerer<'a> {
         Ok(())
     }
 
-    fn intern_refs(&mut self) -> Result<(), FrontEndError> {
+    fn intern_refs(&mut self) -> Result<(), Vec<FrontEndError>> {
         let nodes = self
             .get_node_values_with_scope(self.ast.tree.root().id(), self.syms.get_root_scope_id());
+        let mut errors = vec![];
 
         for (id, v, current_scope) in nodes.into_iter() {
-            use {SymbolResolutionBarrier::Global, SyntaxErrorKind::*};
             if v.kind == AstNodeKind::ToProcess(ToProcessKind::Symbol) {
-                let r = &v.text_range;
-                let name = self.get_source_text(r);
-                let sym_id = self
-                    .syms
-                    .resolve_label(name, current_scope, Global)
-                    .map_err(|_e| FrontEndError::new(UndefinedSymbol(name.to_owned()), r))?;
-
-                self.change_node_kind(id, AstNodeKind::Symbol(sym_id))
+                match self.resolve_ref(id, current_scope) {
+                    Some(sym_id) => self.change_node_kind(id, AstNodeKind::Symbol(sym_id)),
+                    None => {
+                        let name = self.ast.get_source_text(id).to_owned();
+                        errors.push(self.node_error(id, SyntaxErrorKind::UndefinedSymbol(name)))
+                    }
+                }
             }
         }
 
-        Ok(())
+        all_ok(errors)
+    }
+
+    /// Resolve a symbol node seen from this scope, or wherever macro expansion says it resolves
+    fn resolve_ref(&self, id: AstNodeId, scope: ScopeId) -> Option<SymbolScopeId> {
+        if self.ast.tree.get(id)?.value().kind != ToProcessKind::Symbol.into() {
+            return None;
+        }
+
+        let name = self.ast.get_source_text(id);
+        let scope = self.ast.resolve_in.get(&id).cloned().unwrap_or(scope);
+
+        if name.contains("::") {
+            return self.resolve_fqn(name);
+        }
+
+        let mut sym_id = self.resolve_label(name, scope)?;
+
+        // A let binding can only be seen once it's bound,
+        // before that the name means whatever it did outside the let
+        while self.bound_later(id, sym_id) {
+            let outside = self.syms.get_parent_scope_id(sym_id.scope_id)?;
+            sym_id = self.resolve_label(name, outside)?;
+        }
+
+        Some(sym_id)
+    }
+
+    /// Is this a let binding whose value is still being worked out where id is
+    /// That's true if id is in the binding's own value or the value of an earlier binding
+    fn bound_later(&self, id: AstNodeId, sym_id: SymbolScopeId) -> bool {
+        let Some(def) = self.definitions.get(&sym_id) else {
+            return false;
+        };
+
+        let Some(let_arg) = self.ast.tree.get(*def).and_then(|n| n.parent()) else {
+            return false;
+        };
+
+        if let_arg.value().kind != AstNodeKind::LetArg {
+            return false;
+        }
+
+        // The LetArg holding id, if id is in this let's bindings
+        let let_args = let_arg.parent().map(|p| p.id());
+        let node = self.ast.tree.get(id).unwrap();
+
+        let Some(arg) = node.ancestors().find(|a| a.parent().map(|p| p.id()) == let_args) else {
+            return false;
+        };
+
+        arg.id() == let_arg.id() || let_arg.prev_siblings().any(|s| s.id() == arg.id())
+    }
+
+    /// Resolve a name as seen from this scope
+    /// An imported module is its own root, code in it only sees its own definitions and the builtins
+    fn resolve_label(&self, name: &str, scope: ScopeId) -> Option<SymbolScopeId> {
+        let sym_id = self
+            .syms
+            .resolve_label(name, scope, SymbolResolutionBarrier::Global)
+            .ok()?;
+
+        match self.get_module_scope(scope) {
+            Some(module) if !self.is_inside_scope(sym_id.scope_id, module) => {
+                get_builtin(self.syms, sym_id).map(|_| sym_id)
+            }
+            _ => Some(sym_id),
+        }
+    }
+
+    /// The imported module this scope is in, None if it's in the file being compiled
+    fn get_module_scope(&self, scope: ScopeId) -> Option<ScopeId> {
+        let root = self.syms.get_root_scope_id();
+        let mut scope = Some(scope);
+
+        while let Some(s) = scope {
+            if s != root && self.is_global_scope(s) {
+                return Some(s);
+            }
+            scope = self.syms.get_parent_scope_id(s);
+        }
+
+        None
+    }
+
+    /// Resolve foo::bar::baz to baz in the scope of module foo::bar
+    fn resolve_fqn(&self, name: &str) -> Option<SymbolScopeId> {
+        let (path, sym) = name.rsplit_once("::")?;
+        let path: Vec<_> = path.split("::").collect();
+        let scope = self
+            .syms
+            .find_sub_scope_id(&path, self.syms.get_root_scope_id())
+            .ok()?;
+
+        let info = self.syms.get_symbol_info(sym, scope).ok()?;
+        Some(info.symbol_id)
+    }
+
+    /// Scopes that live for the whole program, the root and every imported module's
+    pub fn is_global_scope(&self, scope: ScopeId) -> bool {
+        let root = self.ast.get_root_id();
+
+        scope == self.syms.get_root_scope_id()
+            || self.ast.get_kids_ids(root).into_iter().any(|id| {
+                self.ast.tree.get(id).unwrap().value().kind == AstNodeKind::Module(scope)
+            })
+    }
+
+    /// Is scope the same as outer, or nested somewhere inside it
+    pub fn is_inside_scope(&self, scope: ScopeId, outer: ScopeId) -> bool {
+        let mut scope = Some(scope);
+
+        while let Some(s) = scope {
+            if s == outer {
+                return true;
+            }
+            scope = self.syms.get_parent_scope_id(s);
+        }
+
+        false
+    }
+
+    /// An error at this node, in whichever file the node came from
+    fn node_error(&self, id: AstNodeId, kind: SyntaxErrorKind) -> FrontEndError {
+        self.ast.node_error(id, kind)
     }
 
     fn get_node_values_with_scope(
@@ -195,13 +426,9 @@ impl<'a> AstLowerer<'a> {
         ret
     }
     /// Lower defines to include the symbol id
-    fn lower_defines(
-        &mut self,
-        syms: &mut SymbolTree,
-        _: &SourceFile,
-    ) -> Result<(), FrontEndError> {
-        let nodes =
-            self.get_node_values_with_scope(self.ast.tree.root().id(), syms.get_root_scope_id());
+    fn lower_defines(&mut self) -> Result<(), FrontEndError> {
+        let nodes = self
+            .get_node_values_with_scope(self.ast.tree.root().id(), self.syms.get_root_scope_id());
 
         for (id, value, _) in nodes.into_iter() {
             if value.kind == AstNodeKind::Define {
@@ -224,28 +451,409 @@ impl<'a> AstLowerer<'a> {
     }
 
     /// Change all symbol defs, lambdas and defines, to symbol ids
-    fn intern_symbol_assignments(&mut self) -> Result<(), FrontEndError> {
+    fn intern_symbol_assignments(&mut self) -> Result<(), Vec<FrontEndError>> {
         let nodes = self
             .get_node_values_with_scope(self.ast.tree.root().id(), self.syms.get_root_scope_id());
+        let mut errors = vec![];
 
         for (id, value, current_scope) in nodes.into_iter() {
             if value.kind == AstNodeKind::Arg {
-                let name = self.get_source_text(&value.text_range).to_owned();
-                let sym_id = self
-                    .syms
-                    .create_symbol_in_scope(current_scope, &name)
-                    .expect("Symbol exists TODO: error properly");
-                self.change_node_kind(id, AstNodeKind::Symbol(sym_id))
+                let name = self.ast.get_source_text(id).to_owned();
+
+                match self.syms.create_symbol_in_scope(current_scope, &name) {
+                    Ok(sym_id) => {
+                        self.definitions.insert(sym_id, id);
+                        self.change_node_kind(id, AstNodeKind::Symbol(sym_id))
+                    }
+                    Err(_) => errors.push(self.node_error(id, SyntaxErrorKind::AlreadyDefined(name))),
+                }
+            }
+        }
+
+        all_ok(errors)
+    }
+
+    fn macro_name(&self, mac: &MacroData) -> String {
+        self.syms
+            .get_symbol_info_from_id(mac.name)
+            .map(|si| si.name().to_owned())
+            .unwrap_or_default()
+    }
+
+    /// Replace every call to a macro with a copy of the macro's body
+    /// This runs before refs are resolved, so the args are the forms as they were written
+    /// A call is one whose head resolves to a macro's name from where the call is,
+    /// so a local with the same name shadows a macro and a module's macros need module::name
+    fn expand_macros(&mut self) -> Result<(), FrontEndError> {
+        let root = self.ast.get_root_id();
+        let mut macros = HashMap::new();
+        let mut in_macros = HashSet::new();
+        let mut in_templates = HashSet::new();
+
+        for id in self.ast.get_rec_ids(root) {
+            let kind = self.ast.tree.get(id).unwrap().value().kind.clone();
+            let is_defmacro = kind == AstNodeKind::DefMacro;
+
+            if is_defmacro || kind == ToProcessKind::Macro.into() {
+                let data = MacroData::new(self, id).map_err(|e| self.node_error(id, e))?;
+
+                if is_defmacro {
+                    self.check_defmacro(id, &data)?;
+                    in_templates.extend(self.ast.get_rec_ids(id));
+                }
+
+                macros.insert(data.name, data);
+                in_macros.extend(self.ast.get_rec_ids(id));
+            }
+        }
+
+        self.check_templates(&in_templates)?;
+
+        let mut expansions = 0;
+
+        while let Some((id, sym_id)) = self.find_macro_call(&macros, &in_macros) {
+            let mac = macros[&sym_id].clone();
+
+            if expansions == MAX_MACRO_EXPANSIONS {
+                let err = SyntaxErrorKind::MacroRecursion(self.macro_name(&mac));
+                return Err(self.node_error(id, err));
+            }
+
+            self.expand_macro(id, &mac)?;
+            expansions += 1;
+        }
+
+        Ok(())
+    }
+
+    /// A defmacro is only allowed at the top level of a file
+    /// Its body is a single template, which can only unquote the params
+    fn check_defmacro(&self, id: AstNodeId, mac: &MacroData) -> Result<(), FrontEndError> {
+        use SyntaxErrorKind::*;
+
+        let kind = |id| self.ast.tree.get(id).unwrap().value().kind.clone();
+        let parent = self.ast.tree.get(id).unwrap().parent().map(|p| kind(p.id()));
+
+        if !matches!(parent, Some(AstNodeKind::Program | AstNodeKind::Module(_))) {
+            return Err(self.node_error(id, NestedDefMacro));
+        }
+
+        let template = match &self.ast.get_kids_ids(mac.body)[1..] {
+            [quasi] if kind(*quasi) == AstNodeKind::QuasiQuote => *quasi,
+            _ => return Err(self.node_error(id, MacroTemplate(self.macro_name(mac)))),
+        };
+
+        for id in self.ast.get_rec_ids(template) {
+            if matches!(kind(id), AstNodeKind::Unquote | AstNodeKind::UnquoteSplicing) {
+                let param = self.unquoted_param(id, mac.scope);
+
+                if !param.is_some_and(|p| mac.params.contains(&p)) {
+                    return Err(self.node_error(id, BadUnquote));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The param an unquote names, looked up with the params so the template can't shadow them
+    fn unquoted_param(&self, id: AstNodeId, scope: ScopeId) -> Option<SymbolScopeId> {
+        self.resolve_ref(self.ast.get_nth_kid_id(id, 0)?, scope)
+    }
+
+    /// Quasiquote and unquote anywhere but in the body of a defmacro
+    fn check_templates(&self, in_templates: &HashSet<AstNodeId>) -> Result<(), FrontEndError> {
+        for id in self.ast.get_rec_ids(self.ast.get_root_id()) {
+            let kind = &self.ast.tree.get(id).unwrap().value().kind;
+            let is_template = matches!(
+                kind,
+                AstNodeKind::QuasiQuote | AstNodeKind::Unquote | AstNodeKind::UnquoteSplicing
+            );
+
+            if is_template && !in_templates.contains(&id) {
+                return Err(self.node_error(id, SyntaxErrorKind::TemplateOutsideMacro));
             }
         }
 
         Ok(())
     }
 
+    /// Find the first call to a macro
+    /// Calls inside a macro definition are left until they've been copied out
+    fn find_macro_call(
+        &self,
+        macros: &HashMap<SymbolScopeId, MacroData>,
+        in_macros: &HashSet<AstNodeId>,
+    ) -> Option<(AstNodeId, SymbolScopeId)> {
+        let app = AstNodeKind::ToProcess(ToProcessKind::Application);
+
+        self.get_node_values_with_scope(self.ast.get_root_id(), self.syms.get_root_scope_id())
+            .into_iter()
+            .filter(|(id, v, _)| !in_macros.contains(id) && v.kind == app)
+            .find_map(|(id, _, scope)| {
+                let head = self.ast.get_nth_kid_id(id, 0)?;
+
+                // Heads copied from a macro body were resolved where the macro was written
+                let sym_id = match self.ast.tree.get(head)?.value().kind {
+                    AstNodeKind::Symbol(sym_id) => sym_id,
+                    _ => self.resolve_ref(head, scope)?,
+                };
+
+                macros.contains_key(&sym_id).then_some((id, sym_id))
+            })
+    }
+
+    /// Turn a call to a macro into a do holding a copy of the macro's body
+    fn expand_macro(&mut self, id: AstNodeId, mac: &MacroData) -> Result<(), FrontEndError> {
+        let kids = self.ast.get_kids_ids(id);
+        let args = &kids[1..];
+
+        if args.len() != mac.params.len() {
+            let err = SyntaxErrorKind::MacroArgs {
+                name: self.macro_name(mac),
+                expected: mac.params.len(),
+                found: args.len(),
+            };
+            return Err(self.node_error(id, err));
+        }
+
+        // Each arg comes with the scope markers around it, if it has any
+        let subs: HashMap<_, _> = mac
+            .params
+            .iter()
+            .zip(args)
+            .map(|(param, arg)| {
+                let node = self.ast.tree.get(*arg).unwrap();
+                let ids: Vec<_> = [node.prev_sibling(), Some(node), node.next_sibling()]
+                    .into_iter()
+                    .flatten()
+                    .filter(|n| n.id() == *arg || n.value().kind.is_set_scope())
+                    .map(|n| n.id())
+                    .collect();
+                (*param, ids)
+            })
+            .collect();
+
+        let node_scopes: HashMap<_, _> = self
+            .get_node_values_with_scope(self.ast.get_root_id(), self.syms.get_root_scope_id())
+            .into_iter()
+            .map(|(n, _, scope)| (n, scope))
+            .collect();
+
+        let call_scope = node_scopes[&id];
+
+        let mut exp = Expansion {
+            call: self.ast.tree.get(id).unwrap().value().clone(),
+            call_scope,
+            subs,
+            node_scopes,
+            scope: mac.scope,
+            scopes: HashMap::from([(mac.scope, call_scope)]),
+            symbols: HashMap::new(),
+        };
+
+        // The body is everything in the lambda body after the params
+        let body: Vec<_> = self.ast.tree.get(mac.body).unwrap().children().skip(1).map(|n| n.id()).collect();
+        let old: Vec<_> = self.ast.tree.get(id).unwrap().children().map(|n| n.id()).collect();
+
+        for kid in old {
+            self.detach_node(kid)
+        }
+
+        let mut scope = mac.scope;
+
+        for b in body {
+            scope = self.copy_expansion(id, b, scope, &mut exp)?;
+        }
+
+        self.change_node_kind(id, ToProcessKind::Do.into());
+        Ok(())
+    }
+
+    /// Copy a node from a macro body, in scope in the body, to parent
+    /// Params are replaced with copies of the args and a template is unwrapped
+    /// Returns the scope in the body of whatever comes after the node
+    fn copy_expansion(
+        &mut self,
+        parent: AstNodeId,
+        id: AstNodeId,
+        scope: ScopeId,
+        exp: &mut Expansion,
+    ) -> Result<ScopeId, FrontEndError> {
+        let node = self.ast.tree.get(id).unwrap().value().clone();
+        let kids: Vec<_> = self.ast.tree.get(id).unwrap().children().map(|n| n.id()).collect();
+
+        let kind = match node.kind {
+            AstNodeKind::SetScope(s) => {
+                let marker = node.change_kind(AstNodeKind::SetScope(self.copy_scope(s, exp)));
+                self.ast.tree.get_mut(parent).unwrap().append(marker);
+                return Ok(s);
+            }
+
+            AstNodeKind::QuasiQuote => {
+                let mut scope = scope;
+                for kid in kids {
+                    scope = self.copy_expansion(parent, kid, scope, exp)?;
+                }
+                return Ok(scope);
+            }
+
+            AstNodeKind::Unquote => {
+                let param = self.unquoted_param(id, exp.scope).expect("Checked with the defmacro");
+                let dest = self.copy_scope(scope, exp);
+                for a in exp.subs[&param].clone() {
+                    self.copy_arg(parent, a, dest, exp)
+                }
+                return Ok(scope);
+            }
+
+            AstNodeKind::UnquoteSplicing => {
+                let param = self.unquoted_param(id, exp.scope).expect("Checked with the defmacro");
+                let dest = self.copy_scope(scope, exp);
+                let is_arg = |a: &&AstNodeId| !self.ast.tree.get(**a).unwrap().value().kind.is_set_scope();
+                let arg = *exp.subs[&param].iter().find(is_arg).unwrap();
+
+                match self.ast.tree.get(arg).unwrap().value().kind {
+                    AstNodeKind::ToProcess(ToProcessKind::Application)
+                    | AstNodeKind::Array
+                    | AstNodeKind::List
+                    | AstNodeKind::Null => {
+                        let items: Vec<_> =
+                            self.ast.tree.get(arg).unwrap().children().map(|n| n.id()).collect();
+
+                        for item in items {
+                            self.copy_arg(parent, item, dest, exp)
+                        }
+                    }
+                    _ => {
+                        let text = self.ast.get_source_text(arg).to_owned();
+                        return Err(self.node_error(arg, SyntaxErrorKind::BadSplice(text)));
+                    }
+                }
+                return Ok(scope);
+            }
+
+            // Names bound in the body get this copy's symbols, free names stay where the macro is
+            AstNodeKind::ToProcess(ToProcessKind::Symbol) => match self.resolve_ref(id, scope) {
+                Some(sym_id) if exp.subs.contains_key(&sym_id) => {
+                    let dest = self.copy_scope(scope, exp);
+                    for a in exp.subs[&sym_id].clone() {
+                        self.copy_arg(parent, a, dest, exp)
+                    }
+                    return Ok(scope);
+                }
+                Some(sym_id) => AstNodeKind::Symbol(self.copy_symbol(sym_id, exp)),
+                // Left for intern_refs to report
+                None => node.kind.clone(),
+            },
+
+            AstNodeKind::Symbol(sym_id) => AstNodeKind::Symbol(self.copy_symbol(sym_id, exp)),
+
+            ref kind => kind.clone(),
+        };
+
+        let text = self.ast.get_source_text(id).to_owned();
+
+        // Errors in the copy point at the call
+        let new_node = AstNode {
+            kind: kind.clone(),
+            type_annotation: node.type_annotation.clone(),
+            ..exp.call.clone()
+        };
+
+        let new_id = self.ast.tree.get_mut(parent).unwrap().append(new_node).id();
+        self.ast.expanded_text.insert(new_id, text);
+
+        match (&node.kind, kind) {
+            (_, AstNodeKind::ToProcess(ToProcessKind::Symbol)) => {
+                self.ast.resolve_in.insert(new_id, scope);
+            }
+            (AstNodeKind::Symbol(old), AstNodeKind::Symbol(copy)) if *old != copy => {
+                self.definitions.entry(copy).or_insert(new_id);
+            }
+            _ => (),
+        }
+
+        let mut kid_scope = scope;
+        for kid in kids {
+            kid_scope = self.copy_expansion(new_id, kid, kid_scope, exp)?;
+        }
+
+        Ok(scope)
+    }
+
+    /// The scope in this copy of a macro body for a scope in the body
+    fn copy_scope(&mut self, scope: ScopeId, exp: &mut Expansion) -> ScopeId {
+        if let Some(copy) = exp.scopes.get(&scope) {
+            return *copy;
+        }
+
+        let parent = self.syms.get_parent_scope_id(scope).expect("Macro body scope with no parent");
+        let parent = self.copy_scope(parent, exp);
+
+        let name = format!("scope_{}", self.syms.get_next_scope_id());
+        let copy = self.syms.create_or_get_scope_for_parent(&name, parent);
+        exp.scopes.insert(scope, copy);
+        copy
+    }
+
+    /// Symbols bound in a macro's body get new ones in each copy, so they can't capture the args'
+    fn copy_symbol(&mut self, sym_id: SymbolScopeId, exp: &mut Expansion) -> SymbolScopeId {
+        if !self.is_inside_scope(sym_id.scope_id, exp.scope) {
+            return sym_id;
+        }
+
+        if let Some(copy) = exp.symbols.get(&sym_id) {
+            return *copy;
+        }
+
+        let name = self.syms.get_symbol_info_from_id(sym_id).unwrap().name().to_owned();
+        let scope = self.copy_scope(sym_id.scope_id, exp);
+
+        // A def in the body defines into the caller's scope, where it may already be
+        let copy = match self.syms.create_symbol_in_scope(scope, &name) {
+            Ok(copy) => copy,
+            Err(_) => self.syms.get_symbol_info(&name, scope).unwrap().symbol_id,
+        };
+
+        exp.symbols.insert(sym_id, copy);
+        copy
+    }
+
+    /// Copy an arg into a macro's expansion, dest is the scope it's copied into
+    /// Args keep their own text and resolve where they were written
+    fn copy_arg(&mut self, parent: AstNodeId, id: AstNodeId, dest: ScopeId, exp: &Expansion) {
+        let mut node = self.ast.tree.get(id).unwrap().value().clone();
+
+        if node.kind == AstNodeKind::SetScope(exp.call_scope) {
+            node.kind = AstNodeKind::SetScope(dest);
+        }
+
+        let is_ref = node.kind == ToProcessKind::Symbol.into();
+        let new_id = self.ast.tree.get_mut(parent).unwrap().append(node).id();
+
+        if let Some(text) = self.ast.expanded_text.get(&id).cloned() {
+            self.ast.expanded_text.insert(new_id, text);
+        }
+
+        let scope = self.ast.resolve_in.get(&id).or(exp.node_scopes.get(&id)).cloned();
+
+        if let Some(scope) = scope.filter(|_| is_ref) {
+            self.ast.resolve_in.insert(new_id, scope);
+        }
+
+        let kids: Vec<_> = self.ast.tree.get(id).unwrap().children().map(|n| n.id()).collect();
+
+        for kid in kids {
+            self.copy_arg(new_id, kid, dest, exp)
+        }
+    }
+
     /// Go over the special forms and wrap up the data nicely fro codegen
-    fn process_special_forms(&mut self) -> Result<(), FrontEndError> {
+    fn process_special_forms(&mut self) -> Result<(), Vec<FrontEndError>> {
         let nodes = self
             .get_node_values_with_scope(self.ast.tree.root().id(), self.syms.get_root_scope_id());
+        let mut errors = vec![];
 
         for (id, value, _enclosing_scope) in nodes.into_iter() {
             if let AstNodeKind::ToProcess(kind) = &value.kind {
@@ -256,24 +864,180 @@ impl<'a> AstLowerer<'a> {
                     }
 
                     ToProcessKind::Application => {
+                        let head = self.ast.get_nth_kid_id(id, 0).expect("Application with no head");
+
+                        if !can_be_called(&self.ast.tree.get(head).unwrap().value().kind) {
+                            errors.push(self.node_error(head, SyntaxErrorKind::IllegalApplication));
+                            continue;
+                        }
+
                         let app_data = Box::new(ApplicationData::new(self, id));
                         self.change_node_kind(id, AstNodeKind::Application(app_data))
                     }
 
+                    ToProcessKind::Lambda => match LambdaData::new(self, id) {
+                        Ok(lambda_data) => self.change_node_kind(id, AstNodeKind::Lambda(Box::new(lambda_data))),
+                        Err(e) => errors.push(self.node_error(id, e)),
+                    },
+
                     ToProcessKind::Let => {
                         let let_data = Box::new(LetData::new(self, id));
                         self.change_node_kind(id, AstNodeKind::Let(let_data));
                     }
 
+                    ToProcessKind::Cond => match CondData::new(self, id) {
+                        Ok(cond_data) => self.change_node_kind(id, AstNodeKind::Cond(Box::new(cond_data))),
+                        Err(e) => errors.push(self.node_error(id, e)),
+                    },
+
+                    ToProcessKind::Do => {
+                        let do_data = Box::new(DoData::new(self, id));
+                        self.change_node_kind(id, AstNodeKind::Do(do_data));
+                    }
+
+                    ToProcessKind::Macro => match MacroData::new(self, id) {
+                        Ok(macro_data) => self.change_node_kind(id, AstNodeKind::Macro(Box::new(macro_data))),
+                        Err(e) => errors.push(self.node_error(id, e)),
+                    },
+
                     _ => (),
                 };
+            } else if value.kind == AstNodeKind::DefMacro {
+                match MacroData::new(self, id) {
+                    Ok(macro_data) => self.change_node_kind(id, AstNodeKind::Macro(Box::new(macro_data))),
+                    Err(e) => errors.push(self.node_error(id, e)),
+                }
+            }
+        }
+
+        all_ok(errors)
+    }
+
+    /// Check every recur is in tail position of a fn body and record the body it jumps to
+    fn process_recurs(&mut self) -> Result<(), FrontEndError> {
+        self.check_tail(self.ast.get_root_id(), false, None)
+    }
+
+    /// tail is true if the value of this node is what the enclosing fn body returns
+    fn check_tail(
+        &mut self,
+        id: AstNodeId,
+        tail: bool,
+        body: Option<&LambdaBodyData>,
+    ) -> Result<(), FrontEndError> {
+        use SyntaxErrorKind::*;
+
+        let kind = self.ast.tree.get(id).unwrap().value().kind.clone();
+        let kids = self.ast.get_kids_ids(id);
+
+        match kind {
+            AstNodeKind::ToProcess(ToProcessKind::Recur) => {
+                let body = body.ok_or_else(|| self.node_error(id, RecurOutsideFn))?;
+
+                if !tail {
+                    let note = "recur jumps back to the start of the fn, so nothing can be left to do after it";
+                    return Err(self.node_error(id, RecurNotInTail).with_note(note));
+                }
+
+                let recur_data = RecurData::new(self, id, body).map_err(|e| self.node_error(id, e))?;
+
+                for arg in kids {
+                    self.check_tail(arg, false, Some(body))?;
+                }
+
+                self.change_node_kind(id, AstNodeKind::Recur(Box::new(recur_data)));
+            }
+
+            AstNodeKind::Lambda(lambda_data) => {
+                for body in lambda_data.bodies.iter() {
+                    let forms = &self.ast.get_kids_ids(body.id)[1..];
+                    self.check_tail_forms(forms, true, Some(body))?;
+                }
+            }
+
+            AstNodeKind::If(if_data) => {
+                self.check_tail(if_data.predicate, false, body)?;
+                self.check_tail(if_data.if_true, tail, body)?;
+
+                if let Some(if_false) = if_data.if_false {
+                    self.check_tail(if_false, tail, body)?;
+                }
+            }
+
+            AstNodeKind::Let(_) => {
+                let (args, forms) = kids.split_first().expect("Let with no args");
+                self.check_tail(*args, false, body)?;
+                self.check_tail_forms(forms, tail, body)?;
+            }
+
+            AstNodeKind::Do(do_data) => self.check_tail_forms(&do_data.forms, tail, body)?,
+
+            AstNodeKind::Cond(cond_data) => {
+                for (test, value) in cond_data.clauses.iter() {
+                    self.check_tail(*test, false, body)?;
+                    self.check_tail(*value, tail, body)?;
+                }
+
+                if let Some(default) = cond_data.default {
+                    self.check_tail(default, tail, body)?;
+                }
+            }
+
+            // Macro bodies are checked where they're expanded
+            AstNodeKind::Macro(_) => (),
+
+            _ => {
+                for kid in kids {
+                    self.check_tail(kid, false, body)?;
+                }
             }
         }
 
         Ok(())
     }
 
+    /// Only the last of a series of forms can be in tail position
+    fn check_tail_forms(
+        &mut self,
+        forms: &[AstNodeId],
+        tail: bool,
+        body: Option<&LambdaBodyData>,
+    ) -> Result<(), FrontEndError> {
+        for (i, form) in forms.iter().enumerate() {
+            self.check_tail(*form, tail && i + 1 == forms.len(), body)?
+        }
+
+        Ok(())
+    }
+
+    /// Turn literal nodes into something codegen can use directly
     fn create_values(&mut self) -> Result<(), FrontEndError> {
+        let ids = self.ast.get_rec_ids(self.ast.tree.root().id());
+
+        for id in ids {
+            let v = self.ast.tree.get(id).unwrap().value().clone();
+
+            match v.kind {
+                AstNodeKind::Bool => {
+                    let kind = match self.ast.get_source_text(id) {
+                        "true" => AstNodeKind::True,
+                        _ => AstNodeKind::False,
+                    };
+                    self.change_node_kind(id, kind)
+                }
+
+                AstNodeKind::Number => {
+                    let text = self.ast.get_source_text(id);
+                    if parse_number(text).is_none() {
+                        let err = SyntaxErrorKind::InvalidNumber(text.to_owned());
+                        return Err(self.node_error(id, err));
+                    }
+                }
+
+                _ => (),
+            }
+        }
+
         Ok(())
     }
 
@@ -3,7 +3,6 @@ use logos::{Logos, Lexer};
     // #[regex(r"([a-zA-Z-_]+[a-zA-Z0-9-_]*)(::[a-zA-Z-_]+[a-zA-Z0-9-_]*)+")]
 #[derive(Logos, Copy, Clone, Debug, PartialEq, Eq)]
 #[logos(skip r"[ \t\f\n]+")]
-
 #[logos(subpattern id_al = r"[!+\-*!a-zA-Z-_]")]
 #[logos(subpattern id_alnum = r"(?&id_al)|[0-9]")]
 #[logos(subpattern id = r"(?&id_al)+(?&id_alnum)*")]
@@ -53,9 +52,12 @@ pub enum TokenKind {
     BackSlash,
 
 
-    #[regex(";.*\n")]
+    #[regex(";[^\n]*")]
     Comment,
 
+    /// Never lexed, fills the gaps between tokens in a stream that keeps trivia
+    Whitespace,
+
     #[token("&")]
     Ampersand,
 
@@ -80,6 +82,9 @@ pub enum TokenKind {
     #[token(",")]
     Comma,
 
+    #[token(",@")]
+    CommaAt,
+
     #[token(">")]
     GreaterThan,
 
@@ -121,6 +126,58 @@ impl TokenKind {
     pub fn is_comment(&self) -> bool {
         self == &TokenKind::Comment
     }
+
+    /// Tokens that don't change what the code means
+    pub fn is_trivia(&self) -> bool {
+        matches!(self, TokenKind::Comment | TokenKind::Whitespace)
+    }
+}
+
+/// Convert the text of a DecNumber, HexNumber or BinNumber to a value
+/// None if it doesn't fit in a u64
+pub fn parse_number(text: &str) -> Option<u64> {
+    let text = text.replace('_', "");
+
+    let (digits, radix) = if let Some(hex) = text
+        .strip_prefix("0x")
+        .or_else(|| text.strip_prefix("0X"))
+        .or_else(|| text.strip_prefix('$'))
+    {
+        (hex, 16)
+    } else if let Some(bin) = text
+        .strip_prefix("0b")
+        .or_else(|| text.strip_prefix("0B"))
+        .or_else(|| text.strip_prefix('%'))
+    {
+        (bin, 2)
+    } else {
+        (text.as_str(), 10)
+    };
+
+    u64::from_str_radix(digits, radix).ok()
+}
+
+/// Convert the text of a QuotedString to the string it represents
+/// Strips the quotes and processes escape sequences
+pub fn parse_quoted_string(text: &str) -> String {
+    let text = &text[1..text.len() - 1];
+    let mut ret = String::with_capacity(text.len());
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('n') => ret.push('\n'),
+                Some('t') => ret.push('\t'),
+                Some(c) => ret.push(c),
+                None => (),
+            }
+        } else {
+            ret.push(c)
+        }
+    }
+
+    ret
 }
 
 impl From<std::ops::Range<usize>> for TextSpan {
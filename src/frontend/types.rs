@@ -1,3 +1,5 @@
+use std::collections::{HashMap, HashSet};
+
 #[derive(Default, PartialEq, Clone, Debug   )]
 pub enum Type {
     #[default]
@@ -7,11 +9,280 @@ pub enum Type {
     F64,
     Integer,
     String,
+    KeyWord,
+    Map,
+    Array,
     User(String),
     Char,
     Struct,
+    /// A function we don't know the signature of, like a multi arity lambda
     Lambda,
     Void,
+    /// A type variable, filled in by inference
+    Var(usize),
+    /// Parameter and return types of a function
+    Fn(Vec<Type>, Box<Type>),
+    /// A function with a & rest param, it takes any number of args after these
+    Variadic(Vec<Type>, Box<Type>),
+}
+
+
+
+impl std::fmt::Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use Type::*;
+        let name = match self {
+            ToInfer => "unknown",
+            Bool => "bool",
+            F32 => "f32",
+            F64 => "f64",
+            Integer => "int",
+            String => "string",
+            KeyWord => "keyword",
+            Map => "map",
+            Array => "array",
+            User(name) => name,
+            Char => "char",
+            Struct => "struct",
+            Lambda => "fn",
+            Void => "null",
+            Var(n) => return write!(f, "'t{n}"),
+            Fn(params, ret) => {
+                let params: Vec<_> = params.iter().map(|p| p.to_string()).collect();
+                return write!(f, "(fn [{}] {ret})", params.join(" "));
+            }
+            Variadic(params, ret) => {
+                let mut params: Vec<_> = params.iter().map(|p| p.to_string()).collect();
+                params.push("& array".to_owned());
+                return write!(f, "(fn [{}] {ret})", params.join(" "));
+            }
+        };
+        f.write_str(name)
+    }
+}
+
+impl Type {
+    pub fn is_known(&self) -> bool {
+        *self != Type::ToInfer
+    }
+
+    /// Known, with no type variables left in it
+    pub fn is_concrete(&self) -> bool {
+        self.is_known() && self.free_vars().is_empty()
+    }
+
+    /// Every type variable in this type
+    pub fn free_vars(&self) -> HashSet<usize> {
+        let mut ret = HashSet::new();
+        self.add_free_vars(&mut ret);
+        ret
+    }
+
+    fn add_free_vars(&self, vars: &mut HashSet<usize>) {
+        match self {
+            Type::Var(n) => {
+                vars.insert(*n);
+            }
+            Type::Fn(params, ret) | Type::Variadic(params, ret) => {
+                for p in params {
+                    p.add_free_vars(vars)
+                }
+                ret.add_free_vars(vars)
+            }
+            _ => (),
+        }
+    }
+
+    fn replace_vars(&self, with: &HashMap<usize, Type>) -> Type {
+        match self {
+            Type::Var(n) => with.get(n).cloned().unwrap_or_else(|| self.clone()),
+            Type::Fn(params, ret) => Type::Fn(
+                params.iter().map(|p| p.replace_vars(with)).collect(),
+                Box::new(ret.replace_vars(with)),
+            ),
+            Type::Variadic(params, ret) => Type::Variadic(
+                params.iter().map(|p| p.replace_vars(with)).collect(),
+                Box::new(ret.replace_vars(with)),
+            ),
+            _ => self.clone(),
+        }
+    }
+}
+
+/// A type generalised over some of its variables
+/// (fn [x] x) has the scheme forall 'a. (fn ['a] 'a)
+#[derive(PartialEq, Clone, Debug)]
+pub struct Scheme {
+    pub vars: Vec<usize>,
+    pub ty: Type,
+}
+
+impl Scheme {
+    /// A scheme that can't be instantiated to anything other than its type
+    pub fn mono(ty: Type) -> Self {
+        Self { vars: vec![], ty }
+    }
+}
+
+#[derive(PartialEq, Clone, Debug)]
+pub enum UnifyError {
+    Mismatch,
+    InfiniteType,
+}
+
+/// What each type variable has been unified with so far
+#[derive(Default, Clone, Debug)]
+pub struct Substitution {
+    bindings: HashMap<usize, Type>,
+    next_var: usize,
+}
+
+impl Substitution {
+    pub fn fresh(&mut self) -> Type {
+        self.next_var += 1;
+        Type::Var(self.next_var - 1)
+    }
+
+    /// Replace every bound variable in this type, as far as we know so far
+    pub fn resolve(&self, t: &Type) -> Type {
+        match t {
+            Type::Var(n) => match self.bindings.get(n) {
+                Some(t) => self.resolve(t),
+                None => t.clone(),
+            },
+            Type::Fn(params, ret) => Type::Fn(
+                params.iter().map(|p| self.resolve(p)).collect(),
+                Box::new(self.resolve(ret)),
+            ),
+            Type::Variadic(params, ret) => Type::Variadic(
+                params.iter().map(|p| self.resolve(p)).collect(),
+                Box::new(self.resolve(ret)),
+            ),
+            _ => t.clone(),
+        }
+    }
+
+    pub fn unify(&mut self, a: &Type, b: &Type) -> Result<(), UnifyError> {
+        use Type::*;
+
+        match (self.resolve(a), self.resolve(b)) {
+            (Var(x), Var(y)) if x == y => Ok(()),
+
+            (Var(x), t) | (t, Var(x)) => {
+                if t.free_vars().contains(&x) {
+                    Err(UnifyError::InfiniteType)
+                } else {
+                    self.bindings.insert(x, t);
+                    Ok(())
+                }
+            }
+
+            (Fn(p1, r1), Fn(p2, r2)) | (Variadic(p1, r1), Variadic(p2, r2)) => {
+                if p1.len() != p2.len() {
+                    return Err(UnifyError::Mismatch);
+                }
+
+                for (a, b) in p1.iter().zip(&p2) {
+                    self.unify(a, b)?
+                }
+
+                self.unify(&r1, &r2)
+            }
+
+            // A variadic fn can stand in for a fn taking at least as many args
+            (Variadic(p1, r1), Fn(p2, r2)) | (Fn(p2, r2), Variadic(p1, r1)) => {
+                if p2.len() < p1.len() {
+                    return Err(UnifyError::Mismatch);
+                }
+
+                for (a, b) in p1.iter().zip(&p2) {
+                    self.unify(a, b)?
+                }
+
+                self.unify(&r1, &r2)
+            }
+
+            // We don't know the signature so anything goes
+            (Lambda, Fn(..) | Variadic(..)) | (Fn(..) | Variadic(..), Lambda) => Ok(()),
+
+            (a, b) if a == b => Ok(()),
+
+            _ => Err(UnifyError::Mismatch),
+        }
+    }
+
+    /// Generalise every variable in this type that isn't also used by the environment
+    pub fn generalize(&self, t: &Type, env_vars: &HashSet<usize>) -> Scheme {
+        let ty = self.resolve(t);
+        let mut vars: Vec<_> = ty.free_vars().difference(env_vars).cloned().collect();
+        vars.sort();
+        Scheme { vars, ty }
+    }
+
+    /// A copy of the scheme's type with fresh variables for the generalised ones
+    pub fn instantiate(&mut self, scheme: &Scheme) -> Type {
+        let fresh = scheme.vars.iter().map(|v| (*v, self.fresh())).collect();
+        scheme.ty.replace_vars(&fresh)
+    }
 }
 
+#[allow(unused_imports)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn func(params: Vec<Type>, ret: Type) -> Type {
+        Type::Fn(params, Box::new(ret))
+    }
+
+    #[test]
+    fn test_unify() {
+        use Type::*;
 
+        let mut subst = Substitution::default();
+        let (a, b) = (subst.fresh(), subst.fresh());
+
+        let f = func(vec![a.clone()], b.clone());
+        subst.unify(&f, &func(vec![Integer], a.clone())).unwrap();
+        assert_eq!(subst.resolve(&b), Integer);
+
+        assert_eq!(subst.unify(&a, &String), Err(UnifyError::Mismatch));
+        assert_eq!(subst.unify(&f, &func(vec![], Integer)), Err(UnifyError::Mismatch));
+
+        let c = subst.fresh();
+        assert_eq!(subst.unify(&c, &func(vec![c.clone()], Integer)), Err(UnifyError::InfiniteType));
+    }
+
+    #[test]
+    fn test_unify_variadic() {
+        use Type::*;
+
+        let mut subst = Substitution::default();
+        let variadic = Variadic(vec![Integer], Box::new(Array));
+
+        assert!(subst.unify(&variadic, &func(vec![Integer, String], Array)).is_ok());
+        assert_eq!(subst.unify(&variadic, &func(vec![], Array)), Err(UnifyError::Mismatch));
+        assert!(subst.unify(&Lambda, &variadic).is_ok());
+    }
+
+    #[test]
+    fn test_generalize() {
+        use Type::*;
+
+        let mut subst = Substitution::default();
+        let (a, b) = (subst.fresh(), subst.fresh());
+        let t = func(vec![a.clone(), b.clone()], a.clone());
+
+        // b is used elsewhere so it stays the same in every instance
+        let scheme = subst.generalize(&t, &b.free_vars());
+        assert_eq!(scheme.vars, vec![0]);
+
+        let instance = subst.instantiate(&scheme);
+        assert_eq!(instance, func(vec![Var(2), b.clone()], Var(2)));
+        assert_eq!(instance.to_string(), "(fn ['t2 't1] 't2)");
+
+        assert_eq!(subst.instantiate(&Scheme::mono(t.clone())), t);
+        assert!(!t.is_concrete());
+        assert!(func(vec![Integer], Void).is_concrete());
+    }
+}
@@ -0,0 +1,434 @@
+/// Tree walking interpreter for the lowered AST
+/// Slow but simple, used as a reference to check the VM against
+use crate::builtins::{get_builtin, BuiltIn};
+use crate::frontend::{parse_number, parse_quoted_string, AstNodeId, AstNodeKind, Module};
+use crate::symbols::{SymbolScopeId, SymbolTree};
+use crate::value::{Arities, OperationErrorKind, Value};
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use thiserror::Error;
+
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum InterpreterError {
+    #[error("Operation error: {0:?}")]
+    Operation(OperationErrorKind),
+    #[error("Division by zero")]
+    DivisionByZero,
+    #[error("{0} is not something you can call")]
+    NotCallable(String),
+    #[error("Wrong number of arguments to {0}: got {1}")]
+    WrongNumberOfArgs(String, usize),
+    #[error("No body of this fn takes {found} args, it takes {arities}")]
+    NoMatchingArity { found: usize, arities: Arities },
+    #[error("{0} isn't a list")]
+    NotAList(String),
+    #[error("Symbol {0} has no value")]
+    UnboundSymbol(String),
+    #[error("{0} is captured from an enclosing function")]
+    FreeVariable(String),
+    #[error("Can't evaluate {0}")]
+    Unsupported(String),
+    #[error("Call stack overflow")]
+    StackOverflow,
+}
+
+impl From<OperationErrorKind> for InterpreterError {
+    fn from(value: OperationErrorKind) -> Self {
+        InterpreterError::Operation(value)
+    }
+}
+
+pub type IResult<T> = Result<T, InterpreterError>;
+
+const MAX_CALL_DEPTH: usize = 1000;
+/// Rust stack to allow for each ploy call, eval recurses a few times for every call
+/// A debug build needs about 26k for a simple recursive fn, the rest is room for deeper expressions
+const STACK_PER_CALL: usize = 64 * 1024;
+
+pub struct Interpreter<'a> {
+    module: &'a Module,
+    globals: HashMap<SymbolScopeId, Value>,
+    /// Local bindings, one frame per active call
+    frames: Vec<HashMap<SymbolScopeId, Value>>,
+    /// New values for the params, set by a recur for the running call to pick up
+    recur: Option<Vec<(SymbolScopeId, Value)>>,
+}
+
+impl<'a> Interpreter<'a> {
+    pub fn new(module: &'a Module) -> Self {
+        Self {
+            module,
+            globals: Default::default(),
+            frames: vec![Default::default()],
+            recur: None,
+        }
+    }
+
+    fn syms(&self) -> &SymbolTree {
+        &self.module.syms
+    }
+
+    fn kind(&self, id: AstNodeId) -> &AstNodeKind {
+        &self.module.ast.tree.get(id).unwrap().value().kind
+    }
+
+    fn kids(&self, id: AstNodeId) -> thin_vec::ThinVec<AstNodeId> {
+        self.module.ast.get_kids_ids(id)
+    }
+
+    fn sym_name(&self, id: SymbolScopeId) -> String {
+        self.syms()
+            .get_symbol_info_from_id(id)
+            .map(|si| si.name().to_owned())
+            .unwrap_or_else(|_| format!("{id:?}"))
+    }
+
+    fn is_global(&self, id: SymbolScopeId) -> bool {
+        self.module.is_global(id)
+    }
+
+    fn set_sym(&mut self, id: SymbolScopeId, val: Value) {
+        if self.is_global(id) {
+            self.globals.insert(id, val);
+        } else {
+            self.frames.last_mut().unwrap().insert(id, val);
+        }
+    }
+
+    fn get_sym(&self, id: SymbolScopeId) -> IResult<Value> {
+        if let Some(b) = get_builtin(self.syms(), id) {
+            return Ok(Value::BuiltIn(b));
+        }
+
+        let found = if self.is_global(id) {
+            self.globals.get(&id)
+        } else {
+            self.frames.last().unwrap().get(&id)
+        };
+
+        match found {
+            Some(v) => Ok(v.clone()),
+            None if self.frames.iter().any(|f| f.contains_key(&id)) => {
+                Err(InterpreterError::FreeVariable(self.sym_name(id)))
+            }
+            None => Err(InterpreterError::UnboundSymbol(self.sym_name(id))),
+        }
+    }
+
+    /// Evaluate the whole module, returning the value of the last top level form
+    /// Runs on its own thread, with a stack big enough to hit MAX_CALL_DEPTH before it overflows
+    pub fn run(&mut self) -> IResult<Value> {
+        let root = self.module.ast.get_root_id();
+
+        std::thread::scope(|s| {
+            std::thread::Builder::new()
+                .name("interpreter".to_owned())
+                .stack_size(MAX_CALL_DEPTH * STACK_PER_CALL)
+                .spawn_scoped(s, || self.eval(root))
+                .expect("Starting the interpreter thread")
+                .join()
+                .unwrap_or_else(|e| std::panic::resume_unwind(e))
+        })
+    }
+
+    fn eval_forms(&mut self, ids: &[AstNodeId]) -> IResult<Value> {
+        let mut ret = Value::Null;
+
+        for id in ids {
+            ret = self.eval(*id)?;
+        }
+
+        Ok(ret)
+    }
+
+    fn eval_all(&mut self, ids: &[AstNodeId]) -> IResult<Vec<Value>> {
+        ids.iter().map(|id| self.eval(*id)).collect()
+    }
+
+    pub fn eval(&mut self, id: AstNodeId) -> IResult<Value> {
+        use AstNodeKind::*;
+
+        let kind = self.kind(id).clone();
+
+        match kind {
+            Program | Module(_) => self.eval_forms(&self.kids(id)),
+
+            Import => Ok(Value::Null),
+
+            AssignSymbol(sym_id) => {
+                let val = self.module.ast.get_nth_kid_id(id, 0).unwrap();
+                let val = self.eval(val)?;
+                self.set_sym(sym_id, val.clone());
+                Ok(val)
+            }
+
+            Symbol(sym_id) => self.get_sym(sym_id),
+
+            // Only lambdas that capture something need to carry their values around
+            Lambda(lambda_data) if lambda_data.captures.is_empty() => Ok(Value::Lambda(id)),
+
+            Lambda(lambda_data) => {
+                let captured = lambda_data
+                    .captures
+                    .iter()
+                    .map(|sym_id| Ok((*sym_id, self.get_sym(*sym_id)?)))
+                    .collect::<IResult<Vec<_>>>()?;
+                Ok(Value::Closure(id, Arc::new(captured)))
+            }
+
+            True => Ok(Value::Bool(true)),
+            False => Ok(Value::Bool(false)),
+            Null => Ok(Value::Null),
+
+            Number => {
+                let text = self.module.ast.get_source_text(id);
+                let val = parse_number(text).expect("Number not validated by the front end");
+                Ok(Value::Signed(val as i64))
+            }
+
+            QuotedString => {
+                let text = parse_quoted_string(self.module.ast.get_source_text(id));
+                Ok(Value::Text(text.into()))
+            }
+
+            KeyWord => {
+                let text = self.module.ast.get_source_text(id);
+                Ok(Value::KeyWord(text.into()))
+            }
+
+            Quoted => {
+                let kid = self.module.ast.get_nth_kid_id(id, 0).unwrap();
+                if let Symbol(..) = self.kind(kid) {
+                    let text = format!("'{}", self.module.ast.get_source_text(kid));
+                    Ok(Value::KeyWord(text.into()))
+                } else {
+                    self.eval(kid)
+                }
+            }
+
+            Map => {
+                let mut pairs = vec![];
+
+                for pair in self.kids(id) {
+                    let kv = self.eval_all(&self.kids(pair))?;
+                    let (k, v) = (kv[0].clone(), kv[1].clone());
+
+                    match pairs.iter_mut().find(|(pk, _)| *pk == k) {
+                        Some(p) => *p = (k, v),
+                        None => pairs.push((k, v)),
+                    }
+                }
+
+                Ok(Value::Map(Arc::new(pairs)))
+            }
+
+            Array | List => {
+                let vals = self.eval_all(&self.kids(id))?;
+                Ok(Value::Array(Arc::new(vals)))
+            }
+
+            If(if_data) => {
+                if self.eval(if_data.predicate)?.is_truthy() {
+                    self.eval(if_data.if_true)
+                } else if let Some(if_false) = if_data.if_false {
+                    self.eval(if_false)
+                } else {
+                    Ok(Value::Null)
+                }
+            }
+
+            And => {
+                let mut ret = Value::Bool(true);
+                for id in self.kids(id) {
+                    ret = self.eval(id)?;
+                    if !ret.is_truthy() {
+                        break;
+                    }
+                }
+                Ok(ret)
+            }
+
+            Or => {
+                let mut ret = Value::Bool(false);
+                for id in self.kids(id) {
+                    ret = self.eval(id)?;
+                    if ret.is_truthy() {
+                        break;
+                    }
+                }
+                Ok(ret)
+            }
+
+            Do(do_data) => self.eval_forms(&do_data.forms),
+
+            // Always in tail position so the call it's in returns straight after
+            Recur(recur_data) => {
+                let args = self.eval_all(&recur_data.args)?;
+                self.recur = Some(recur_data.params.iter().cloned().zip(args).collect());
+                Ok(Value::Null)
+            }
+
+            Cond(cond_data) => {
+                for (test, value) in cond_data.clauses.iter() {
+                    if self.eval(*test)?.is_truthy() {
+                        return self.eval(*value);
+                    }
+                }
+
+                match cond_data.default {
+                    Some(default) => self.eval(default),
+                    None => Ok(Value::Null),
+                }
+            }
+
+            // Calls have already been expanded by the front end
+            Macro(_) => Ok(Value::Null),
+
+            Let(_) => {
+                let kids = self.kids(id);
+                let (args, forms) = kids.split_first().expect("Let with no args");
+
+                for arg in self.kids(*args) {
+                    self.eval(arg)?;
+                }
+
+                self.eval_forms(forms)
+            }
+
+            LetArg => {
+                let sym = self.module.ast.get_nth_kid_id(id, 0).unwrap();
+                let val = self.module.ast.get_nth_kid_id(id, 1).unwrap();
+
+                if let Symbol(sym_id) = *self.kind(sym) {
+                    let val = self.eval(val)?;
+                    self.set_sym(sym_id, val.clone());
+                    Ok(val)
+                } else {
+                    Err(InterpreterError::Unsupported("let binding".to_owned()))
+                }
+            }
+
+            Application(app_data) => {
+                let func = app_data.func;
+
+                // (:key map) looks the key up in the map
+                if let KeyWord = self.kind(func) {
+                    let name = self.module.ast.get_source_text(func).to_owned();
+
+                    if app_data.args.len() != 1 {
+                        return Err(InterpreterError::WrongNumberOfArgs(name, app_data.args.len()));
+                    }
+
+                    let key = self.eval(func)?;
+                    let map = self.eval(app_data.args[0])?;
+                    return Ok(map.get(&key));
+                }
+
+                let func = self.eval(func)?;
+                let args = self.eval_all(&app_data.args)?;
+                self.apply(func, args)
+            }
+
+            kind => Err(InterpreterError::Unsupported(format!("{kind:?}"))),
+        }
+    }
+
+    pub fn apply(&mut self, func: Value, args: Vec<Value>) -> IResult<Value> {
+        match func {
+            Value::BuiltIn(b) => apply_builtin(b, args),
+            Value::Lambda(id) => self.call_lambda(id, &[], args),
+            Value::Closure(id, captured) => self.call_lambda(id, &captured, args),
+            func => Err(InterpreterError::NotCallable(func.to_string())),
+        }
+    }
+
+    fn call_lambda(
+        &mut self,
+        id: AstNodeId,
+        captured: &[(SymbolScopeId, Value)],
+        args: Vec<Value>,
+    ) -> IResult<Value> {
+        let AstNodeKind::Lambda(lambda_data) = self.kind(id) else {
+            panic!("Calling something that isn't a lambda")
+        };
+
+        let body = lambda_data
+            .body_for(args.len())
+            .ok_or_else(|| InterpreterError::NoMatchingArity {
+                found: args.len(),
+                arities: lambda_data.arities(),
+            })?;
+
+        let (params, rest) = (body.params.clone(), body.rest);
+        let kids = self.kids(body.id);
+        let forms = &kids[1..];
+
+        if self.frames.len() >= MAX_CALL_DEPTH {
+            return Err(InterpreterError::StackOverflow);
+        }
+
+        let mut frame: HashMap<_, _> = captured.iter().cloned().collect();
+
+        let mut args = args.into_iter();
+        frame.extend(params.into_iter().zip(args.by_ref()));
+
+        // Anything left over goes to the rest param as a list
+        if let Some(rest) = rest {
+            frame.insert(rest, Value::Array(Arc::new(args.collect())));
+        }
+
+        self.frames.push(frame);
+
+        let ret = loop {
+            let ret = self.eval_forms(forms);
+
+            match self.recur.take() {
+                Some(params) if ret.is_ok() => self.frames.last_mut().unwrap().extend(params),
+                _ => break ret,
+            }
+        };
+
+        self.frames.pop();
+        ret
+    }
+}
+
+fn apply_builtin(b: BuiltIn, args: Vec<Value>) -> IResult<Value> {
+    if !b.accepts(args.len()) {
+        return Err(InterpreterError::WrongNumberOfArgs(b.name().to_owned(), args.len()));
+    }
+
+    let mut args = args.into_iter();
+    let first = args.next().unwrap();
+
+    match b {
+        BuiltIn::Eq => Ok(Value::Bool(first == args.next().unwrap())),
+        BuiltIn::Not => Ok(Value::Bool(!first.is_truthy())),
+
+        BuiltIn::Nth | BuiltIn::NthRest => {
+            let (Value::Array(vals), Value::Signed(n)) = (&first, args.next().unwrap()) else {
+                return Err(InterpreterError::NotAList(first.to_string()));
+            };
+            let n = n.max(0) as usize;
+
+            match b {
+                BuiltIn::Nth => Ok(vals.get(n).cloned().unwrap_or(Value::Null)),
+                _ => Ok(Value::Array(Arc::new(vals.iter().skip(n).cloned().collect()))),
+            }
+        }
+
+        _ => args.try_fold(first, |acc, v| match b {
+            BuiltIn::Add => Ok((acc + v)?),
+            BuiltIn::Sub => Ok((acc - v)?),
+            BuiltIn::Mul => Ok((acc * v)?),
+            _ => {
+                if v.is_zero() {
+                    Err(InterpreterError::DivisionByZero)
+                } else {
+                    Ok((acc / v)?)
+                }
+            }
+        }),
+    }
+}
@@ -0,0 +1,461 @@
+/// Control flow graph of the code from CodeGen
+/// Code is split into basic blocks, runs of instructions only entered at the top
+/// and only left at the bottom
+///
+/// Instructions stay as they are, except addresses in them are the ids of the blocks
+/// they point at. Blocks are kept in the order they'll be laid out, a block that runs
+/// off the end to anything other than the next one gets a branch added when it's turned
+/// back into code
+use super::error::{IrError, IrErrorKind, IrResult};
+use super::instructions::{Instruction, Reg};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+pub type BlockId = usize;
+
+/// Phi(dest, args), copies the arg for the block control came from into dest
+/// Only in blocks of code in SSA form
+#[derive(Clone, Debug, PartialEq)]
+pub struct Phi {
+    pub dest: Reg,
+    pub args: Vec<(BlockId, Reg)>,
+}
+
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct Block {
+    pub phis: Vec<Phi>,
+    pub code: Vec<Instruction>,
+    /// Where control goes if it runs off the end
+    pub next: Option<BlockId>,
+    pub preds: Vec<BlockId>,
+}
+
+impl Block {
+    /// Every block control can go to from this one
+    pub fn succs(&self) -> Vec<BlockId> {
+        let branch = self.code.last().and_then(|i| i.branch_target());
+        let mut ret: Vec<_> = branch.into_iter().chain(self.next).collect();
+        ret.dedup();
+        ret
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cfg {
+    pub blocks: Vec<Block>,
+    /// Blocks code starts running from, the top level and every fn's entry point
+    pub entries: Vec<BlockId>,
+}
+
+impl Cfg {
+    /// Split code into blocks
+    /// Code that can't be reached is dropped
+    pub fn new(code: &[Instruction]) -> IrResult<Self> {
+        use Instruction::*;
+
+        if let Some(pc) = code.iter().position(|i| matches!(i, Jmp(_) | CmpBr(..))) {
+            let msg = "indirect jumps in a control flow graph".to_owned();
+            return Err(IrError::new(IrErrorKind::Unsupported(msg), pc));
+        }
+
+        if code.is_empty() {
+            return Ok(Self { blocks: vec![], entries: vec![] });
+        }
+
+        // Blocks start at anything an address points at and after every branch
+        let mut leaders = BTreeSet::from([0]);
+        let mut entries = BTreeSet::from([0]);
+
+        for (pc, i) in code.iter().enumerate() {
+            if let LoadAddr(_, t) = i {
+                entries.insert(*t);
+            }
+
+            leaders.extend(i.target());
+
+            if i.branch_target().is_some() || !i.falls_through() {
+                leaders.insert(pc + 1);
+            }
+        }
+
+        leaders.retain(|pc| *pc < code.len());
+        entries.retain(|pc| *pc < code.len());
+
+        let mut starts: Vec<usize> = leaders.iter().copied().collect();
+        starts.push(code.len());
+
+        let block_at = |pc: usize| starts.binary_search(&pc).unwrap();
+
+        let mut blocks: Vec<Block> = starts
+            .windows(2)
+            .map(|w| {
+                let code: Vec<_> = code[w[0]..w[1]]
+                    .iter()
+                    .map(|i| match i.target() {
+                        Some(t) if t < code.len() => i.with_target(block_at(t)).unwrap(),
+                        _ => *i,
+                    })
+                    .collect();
+
+                let runs_on = code.last().is_some_and(|i| i.falls_through()) && w[1] < starts[starts.len() - 1];
+
+                Block {
+                    next: runs_on.then(|| block_at(w[1])),
+                    code,
+                    ..Default::default()
+                }
+            })
+            .collect();
+
+        let entries: Vec<BlockId> = entries.into_iter().map(block_at).collect();
+
+        // Drop what can't be reached
+        let mut seen = BTreeSet::new();
+        let mut todo = entries.clone();
+
+        while let Some(b) = todo.pop() {
+            if seen.insert(b) {
+                todo.extend(blocks[b].succs())
+            }
+        }
+
+        let new_ids: BTreeMap<BlockId, BlockId> = seen.iter().enumerate().map(|(new, old)| (*old, new)).collect();
+
+        blocks = blocks
+            .into_iter()
+            .enumerate()
+            .filter(|(id, _)| seen.contains(id))
+            .map(|(_, b)| Block {
+                code: b
+                    .code
+                    .iter()
+                    .map(|i| match i.target() {
+                        Some(t) => i.with_target(new_ids[&t]).unwrap(),
+                        None => *i,
+                    })
+                    .collect(),
+                next: b.next.map(|n| new_ids[&n]),
+                ..Default::default()
+            })
+            .collect();
+
+        let mut ret = Self {
+            blocks,
+            entries: entries.iter().map(|e| new_ids[e]).collect(),
+        };
+
+        ret.set_preds();
+        Ok(ret)
+    }
+
+    /// Work out every block's preds from the other blocks' succs
+    pub fn set_preds(&mut self) {
+        for b in &mut self.blocks {
+            b.preds.clear()
+        }
+
+        for id in 0..self.blocks.len() {
+            for s in self.blocks[id].succs() {
+                self.blocks[s].preds.push(id)
+            }
+        }
+    }
+
+    /// Add an empty block that runs on to next, returning its id
+    pub fn add_block(&mut self, next: BlockId) -> BlockId {
+        self.blocks.push(Block {
+            next: Some(next),
+            ..Default::default()
+        });
+        self.blocks.len() - 1
+    }
+
+    /// Lay the blocks back out as code
+    /// Panics if there are phis left, take the code out of SSA first
+    pub fn to_code(&self) -> Vec<Instruction> {
+        let needs_branch = |id: BlockId| self.blocks[id].next.is_some_and(|n| n != id + 1);
+
+        let mut addrs = vec![0];
+        for (id, b) in self.blocks.iter().enumerate() {
+            assert!(b.phis.is_empty(), "Laying out code with phis in it");
+            addrs.push(addrs[id] + b.code.len() + needs_branch(id) as usize);
+        }
+
+        let mut code = vec![];
+
+        for (id, b) in self.blocks.iter().enumerate() {
+            code.extend(b.code.iter().map(|i| match i.target() {
+                Some(t) => i.with_target(addrs[t]).unwrap(),
+                None => *i,
+            }));
+
+            if needs_branch(id) {
+                code.push(Instruction::Br(addrs[b.next.unwrap()]))
+            }
+        }
+
+        code
+    }
+
+    /// Block ids in reverse post order from the entries
+    /// Every block comes before its succs, apart from along a loop's back edge
+    pub fn reverse_post_order(&self) -> Vec<BlockId> {
+        let mut seen = vec![false; self.blocks.len()];
+        let mut order = vec![];
+
+        for e in &self.entries {
+            // Each block is pushed with the index of the next succ to visit
+            let mut stack = vec![(*e, 0)];
+            seen[*e] = true;
+
+            while let Some((b, i)) = stack.pop() {
+                match self.blocks[b].succs().get(i) {
+                    Some(s) => {
+                        stack.push((b, i + 1));
+                        if !seen[*s] {
+                            seen[*s] = true;
+                            stack.push((*s, 0));
+                        }
+                    }
+                    None => order.push(b),
+                }
+            }
+        }
+
+        order.reverse();
+        order
+    }
+
+    pub fn dominators(&self) -> Dominators {
+        Dominators::new(self)
+    }
+}
+
+impl fmt::Display for Cfg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (id, b) in self.blocks.iter().enumerate() {
+            let preds: Vec<_> = b.preds.iter().map(|p| format!("b{p}")).collect();
+            let entry = if self.entries.contains(&id) { " entry" } else { "" };
+            writeln!(f, "b{id}:{entry} preds [{}]", preds.join(" "))?;
+
+            for phi in &b.phis {
+                let args: Vec<_> = phi.args.iter().map(|(b, r)| format!("b{b}: {r:?}")).collect();
+                writeln!(f, "    Phi({:?}, [{}])", phi.dest, args.join(", "))?;
+            }
+
+            for i in &b.code {
+                writeln!(f, "    {i:?}")?;
+            }
+
+            if let Some(n) = b.next {
+                writeln!(f, "    -> b{n}")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// The dominator tree of a Cfg
+/// Block a dominates b if every path from an entry to b goes through a
+/// Worked out with Cooper, Harvey and Kennedy's iterative algorithm
+#[derive(Clone, Debug, PartialEq)]
+pub struct Dominators {
+    /// Closest block dominating each block other than itself, None for entries
+    idoms: Vec<Option<BlockId>>,
+    children: Vec<Vec<BlockId>>,
+}
+
+impl Dominators {
+    fn new(cfg: &Cfg) -> Self {
+        let n = cfg.blocks.len();
+        // Entries hang off a made up root so every block has one
+        let root = n;
+        let rpo = cfg.reverse_post_order();
+
+        let mut order = vec![usize::MAX; n + 1];
+        order[root] = 0;
+        for (i, b) in rpo.iter().enumerate() {
+            order[*b] = i + 1;
+        }
+
+        let mut idoms: Vec<Option<usize>> = vec![None; n + 1];
+        idoms[root] = Some(root);
+        for e in &cfg.entries {
+            idoms[*e] = Some(root);
+        }
+
+        let intersect = |idoms: &[Option<usize>], mut a: usize, mut b: usize| {
+            while a != b {
+                while order[a] > order[b] {
+                    a = idoms[a].unwrap();
+                }
+                while order[b] > order[a] {
+                    b = idoms[b].unwrap();
+                }
+            }
+            a
+        };
+
+        let mut changed = true;
+
+        while changed {
+            changed = false;
+
+            for b in rpo.iter().filter(|b| !cfg.entries.contains(b)) {
+                let mut preds = cfg.blocks[*b].preds.iter().filter(|p| idoms[**p].is_some());
+
+                let Some(first) = preds.next() else {
+                    continue;
+                };
+
+                let idom = preds.fold(*first, |a, p| intersect(&idoms, a, *p));
+
+                if idoms[*b] != Some(idom) {
+                    idoms[*b] = Some(idom);
+                    changed = true;
+                }
+            }
+        }
+
+        let idoms: Vec<_> = idoms[..n].iter().map(|d| d.filter(|d| *d != root)).collect();
+
+        let mut children = vec![vec![]; n];
+        for b in &rpo {
+            if let Some(d) = idoms[*b] {
+                children[d].push(*b)
+            }
+        }
+
+        Self { idoms, children }
+    }
+
+    /// Closest block that dominates b, None if b is an entry
+    pub fn idom(&self, b: BlockId) -> Option<BlockId> {
+        self.idoms[b]
+    }
+
+    /// Blocks b is the idom of
+    pub fn children(&self, b: BlockId) -> &[BlockId] {
+        &self.children[b]
+    }
+
+    /// Does a dominate b, every block dominates itself
+    pub fn dominates(&self, a: BlockId, mut b: BlockId) -> bool {
+        loop {
+            if a == b {
+                return true;
+            }
+
+            match self.idoms[b] {
+                Some(d) => b = d,
+                None => return false,
+            }
+        }
+    }
+
+    /// Each block's dominance frontier, the blocks where its domination stops
+    /// b is in the frontier of a if a dominates a pred of b but doesn't strictly dominate b
+    pub fn frontiers(&self, cfg: &Cfg) -> Vec<BTreeSet<BlockId>> {
+        let mut ret = vec![BTreeSet::new(); cfg.blocks.len()];
+
+        for (b, block) in cfg.blocks.iter().enumerate() {
+            if block.preds.len() < 2 {
+                continue;
+            }
+
+            for p in &block.preds {
+                let mut runner = Some(*p);
+
+                while let Some(r) = runner.filter(|r| Some(*r) != self.idoms[b]) {
+                    ret[r].insert(b);
+                    runner = self.idoms[r];
+                }
+            }
+        }
+
+        ret
+    }
+}
+
+#[allow(unused_imports)]
+mod test {
+    use super::*;
+    use crate::ir::instructions::exec;
+    use crate::ir::vm::Registers;
+    use pretty_assertions::assert_eq;
+
+    /// Sum 1 to 4 in a loop, then call a fn that doubles its arg
+    fn looping() -> Vec<Instruction> {
+        use {Instruction::*, Reg::{Arg, Gp}};
+
+        vec![
+            Load(Gp(0), 4),
+            Load(Gp(1), 0),
+            Load(Gp(2), 1),
+            BrEq(Gp(0), Reg::Zero, 7),
+            Add(Gp(1), Gp(1), Gp(0)),
+            Sub(Gp(0), Gp(0), Gp(2)),
+            Br(3),
+            Br(13),
+            // Unreachable
+            Load(Gp(5), 5),
+            // The fn
+            Enter(1),
+            Mov(Gp(0), Arg(0)),
+            Add(Reg::Ret(0), Gp(0), Gp(0)),
+            Ret,
+            LoadAddr(Gp(3), 9),
+            NewClosure(Gp(4), Gp(3)),
+            Mov(Arg(0), Gp(1)),
+            Call(Gp(4), 1),
+            Ret,
+        ]
+    }
+
+    #[test]
+    fn test_blocks() {
+        use Instruction::*;
+
+        let code = looping();
+        let cfg = Cfg::new(&code).unwrap();
+
+        // Entry, loop test, loop body, exit branch, fn, call
+        assert_eq!(cfg.blocks.len(), 6);
+        assert_eq!(cfg.entries, vec![0, 4]);
+        assert_eq!(cfg.blocks[1].code, vec![BrEq(Reg::Gp(0), Reg::Zero, 3)]);
+        assert_eq!(cfg.blocks[1].succs(), vec![3, 2]);
+        assert_eq!(cfg.blocks[1].preds, vec![0, 2]);
+        assert_eq!(cfg.blocks[5].code[0], LoadAddr(Reg::Gp(3), 4));
+
+        // Laying it out again drops the unreachable load
+        let laid_out = cfg.to_code();
+        assert_eq!(laid_out.len(), code.len() - 1);
+        assert!(!laid_out.contains(&Load(Reg::Gp(5), 5)));
+
+        for code in [code, laid_out] {
+            let mut regs = Registers::default();
+            assert_eq!(exec(&code, &mut regs), Ok(20));
+        }
+    }
+
+    #[test]
+    fn test_dominators() {
+        let cfg = Cfg::new(&looping()).unwrap();
+        let doms = cfg.dominators();
+
+        let idoms: Vec<_> = (0..cfg.blocks.len()).map(|b| doms.idom(b)).collect();
+        assert_eq!(idoms, vec![None, Some(0), Some(1), Some(1), None, Some(3)]);
+        assert_eq!(doms.children(1), &[2, 3]);
+        assert!(doms.dominates(0, 5));
+        assert!(!doms.dominates(2, 3));
+        assert!(!doms.dominates(0, 4));
+
+        // The loop body's domination stops at the loop test
+        let frontiers = doms.frontiers(&cfg);
+        assert_eq!(frontiers[2], BTreeSet::from([1]));
+        assert_eq!(frontiers[1], BTreeSet::from([1]));
+        assert!(frontiers[0].is_empty());
+    }
+}
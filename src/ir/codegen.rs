@@ -1,17 +1,40 @@
 use crate::{
-    frontend::{AstNodeId, AstNodeKind, AstNodeRef, AstTree, Module, Type},
-    symbols::{ScopeId, SymbolTree},
-    value::TypeInfo,
+    builtins::{get_builtin, BuiltIn},
+    frontend::{parse_number, parse_quoted_string, AstNodeId, AstNodeKind, AstNodeRef, AstTree, LambdaBodyData, Module, Type},
+    symbols::{ScopeId, SymbolScopeId, SymbolTree},
+    opts::CodeGenOpts,
+    opts::Pass,
+    value::{TypeInfo, Value},
 };
 
-use super::instructions::{Instruction, Reg};
+use super::{
+    error::{IrError, IrErrorKind, IrResult},
+    instructions::{Instruction, Reg},
+    labels::Labels,
+    vm::{constant_ref, FALSE, NULL, TRUE},
+};
+
+use std::collections::HashMap;
 
 pub struct CodeGen<'a> {
     module: &'a Module,
     code: Vec<Instruction>,
+    next_reg: usize,
+    globals: HashMap<SymbolScopeId, RegWithType>,
+    locals: HashMap<SymbolScopeId, RegWithType>,
+    constants: Vec<Value>,
+    labels: Labels,
+    /// Set when the next node generated is what the enclosing fn body returns
+    tail: bool,
+    /// Where a recur in the fn body being generated jumps to, just after its params are bound
+    recur_start: Option<usize>,
+    /// Work out arithmetic on literals at compile time
+    fold: bool,
+    /// Every call worked out at compile time, with its value
+    folded: Vec<(AstNodeId, Value)>,
 }
 
-use std::{process::exit, sync::Arc};
+use std::process::exit;
 
 struct Param {
     param_id: u64,
@@ -24,38 +47,32 @@ pub struct ProcInfo {
     params: ThinVec<()>,
 }
 
-pub struct DynamicScope {
-    node_id: AstNodeId,
-    lexical_scope: ScopeId,
-    caller: Option<Arc<DynamicScope>>,
-    inputs: (),
-}
-
+#[derive(Clone, Debug, PartialEq)]
 pub struct RegWithType {
-    register: usize,
-    kind: Type,
-}
-
-impl DynamicScope {
-    pub fn new(module: &Module, node_id: AstNodeId, caller: Option<Arc<DynamicScope>>) -> Self {
-        let lexical_scope = module.get_scope_for_node(node_id).unwrap();
-
-        Self {
-            node_id,
-            caller,
-            lexical_scope,
-            inputs: (),
-        }
-    }
+    pub register: Reg,
+    pub kind: Type,
 }
 
 use thin_vec::ThinVec;
 
 impl<'a> CodeGen<'a> {
     pub fn new(module: &'a Module) -> Self {
+        Self::with_opts(module, &Default::default())
+    }
+
+    pub fn with_opts(module: &'a Module, opts: &CodeGenOpts) -> Self {
         Self {
             module,
             code: Default::default(),
+            next_reg: 0,
+            globals: Default::default(),
+            locals: Default::default(),
+            constants: Default::default(),
+            labels: Default::default(),
+            tail: false,
+            recur_start: None,
+            fold: opts.enabled(Pass::Fold),
+            folded: vec![],
         }
     }
 
@@ -67,10 +84,28 @@ impl<'a> CodeGen<'a> {
         &self.module.syms
     }
 
-    pub fn node(&self, id: AstNodeId) -> AstNodeRef {
+    pub fn node(&self, id: AstNodeId) -> AstNodeRef<'_> {
         self.tree().get(id).unwrap()
     }
 
+    pub fn code(&self) -> &[Instruction] {
+        &self.code
+    }
+
+    /// Calls that were folded to a constant
+    pub fn folded(&self) -> &[(AstNodeId, Value)] {
+        &self.folded
+    }
+
+    /// Strings and keywords the code refers to, see vm::constant_ref
+    pub fn constants(&self) -> &[Value] {
+        &self.constants
+    }
+
+    fn err<T>(&self, kind: IrErrorKind) -> IrResult<T> {
+        Err(IrError::new(kind, self.get_pc()))
+    }
+
     pub fn emit(&mut self, _i: Instruction) -> usize {
         let addr = self.code.len();
         self.code.push(_i);
@@ -80,44 +115,58 @@ impl<'a> CodeGen<'a> {
         self.code[_addr] = _i;
     }
 
-    pub fn assign(&mut self, _reg: usize, _node_id: AstNodeId) -> Type {
-        panic!()
+    /// Generate code for a node and put the result in a register
+    pub fn assign(&mut self, reg: Reg, node_id: AstNodeId) -> IrResult<Type> {
+        let kind = self.code_gen(node_id)?;
+        self.emit(Instruction::Mov(reg, Reg::Ret(0)));
+        Ok(kind)
     }
 
     pub fn get_child_ids(node: AstNodeRef) -> ThinVec<AstNodeId> {
-        node.children().map(|n| n.id()).collect()
+        node.children()
+            .filter(|n| !n.value().kind.is_set_scope())
+            .map(|n| n.id())
+            .collect()
     }
 
-    pub fn eval_children(&mut self, id: AstNodeId) -> ThinVec<RegWithType> {
-        use super::instructions::Instruction::*;
+    /// Evaluate each child into it's own fresh register
+    pub fn eval_children(&mut self, id: AstNodeId) -> IrResult<ThinVec<RegWithType>> {
+        let n = self.module.ast.get_kids_ids(id).len();
+        self.eval_n_children(id, n)
+    }
 
-        let children: ThinVec<_> = self
-            .node(id)
-            .children()
-            .map(|n| n.id())
-            .enumerate()
-            .collect();
-
-        children
-            .into_iter()
-            .map(|(register, id)| {
-                let kind = self.assign(register, id);
-                RegWithType { kind, register }
-            })
-            .collect()
+    pub fn eval_n_children(&mut self, id: AstNodeId, n: usize) -> IrResult<ThinVec<RegWithType>> {
+        let ids = self.module.ast.get_kids_ids(id);
+        self.eval_ids(&ids[..n])
     }
 
-    pub fn eval_n_children(&mut self, _id: AstNodeId, _n: usize) -> ThinVec<RegWithType> {
-        panic!()
+    fn eval_ids(&mut self, ids: &[AstNodeId]) -> IrResult<ThinVec<RegWithType>> {
+        let mut ret = ThinVec::new();
+
+        for id in ids {
+            let register = self.get_reg();
+            let kind = self.assign(register, *id)?;
+            ret.push(RegWithType { register, kind })
+        }
+
+        Ok(ret)
     }
 
     pub fn get_pc(&self) -> usize {
         self.code.len()
     }
 
+    /// Change the address an instruction refers to
+    pub fn fixup(&mut self, addr: usize, new_val: usize) {
+        let i = self.code[addr]
+            .with_target(new_val)
+            .expect("Fixing up an instruction with no address");
+        self.code[addr] = i
+    }
+
     pub fn fixup_load(&mut self, addr: usize, new_val: usize) {
-        if let Instruction::Load(reg, _) = self.code[addr] {
-            self.code[addr] = Instruction::Load(reg, new_val);
+        if let Instruction::LoadAddr(..) = self.code[addr] {
+            self.fixup(addr, new_val)
         } else {
             panic!()
         }
@@ -127,131 +176,678 @@ impl<'a> CodeGen<'a> {
         self.fixup_load(addr, self.get_pc())
     }
 
+    /// Get a fresh virtual register
     pub fn get_reg(&mut self) -> Reg {
-        panic!()
+        let r = Reg::Gp(self.next_reg);
+        self.next_reg += 1;
+        r
     }
 
+    /// Start a new namespace for labels
     pub fn new_syms(&mut self) {
-        panic!()
+        self.labels.new_scope()
     }
-    pub fn fixup_syms(&mut self) {}
+
+    /// Close the current label namespace and patch all references to it's labels
+    pub fn fixup_syms(&mut self) -> IrResult<()> {
+        for f in self.labels.close_scope()? {
+            self.fixup(f.addr, f.dest)
+        }
+        Ok(())
+    }
+
     pub fn declare_sym(&mut self, _name: &str) {}
 
-    pub fn set_sym(&mut self, _name: &str, _val: usize) {
-        panic!()
+    pub fn set_sym(&mut self, name: &str, val: usize) -> IrResult<()> {
+        self.labels.define(name, val)
+    }
+
+    /// Emit an instruction that refers to a label, its address is fixed up later
+    fn emit_with_label(&mut self, label: &str, i: Instruction) -> usize {
+        let addr = self.emit(i);
+        self.labels.reference(label, addr);
+        addr
+    }
+
+    /// Load the address of a label into a register
+    pub fn load_label(&mut self, reg: Reg, label: &str) {
+        self.emit_with_label(label, Instruction::LoadAddr(reg, 0));
+    }
+
+    pub fn branch_equal(&mut self, label: &str, a: Reg, b: Reg) {
+        self.emit_with_label(label, Instruction::BrEq(a, b, 0));
     }
 
-    pub fn branch_equal(&mut self, _label: &str, _a: Reg, _b: Reg) {
-        panic!()
+    /// Branch if the value in a is 0, false or null
+    pub fn branch_false(&mut self, label: &str, a: Reg) {
+        let flag = self.get_reg();
+        self.emit(Instruction::Test(flag, a));
+        self.branch_equal(label, flag, Reg::Zero);
+    }
+
+    pub fn jump(&mut self, label: &str) {
+        self.emit_with_label(label, Instruction::Br(0));
+    }
+
+    pub fn label(&mut self, label: &str) -> IrResult<()> {
+        self.set_sym(label, self.get_pc())
+    }
+
+    /// Intern a string or keyword, returning the value that refers to it
+    fn constant(&mut self, val: Value) -> usize {
+        let idx = match self.constants.iter().position(|c| *c == val) {
+            Some(idx) => idx,
+            None => {
+                self.constants.push(val);
+                self.constants.len() - 1
+            }
+        };
+
+        constant_ref(idx)
+    }
+
+    fn is_global(&self, id: SymbolScopeId) -> bool {
+        self.module.is_global(id)
+    }
+
+    fn sym_name(&self, id: SymbolScopeId) -> String {
+        self.syms()
+            .get_symbol_info_from_id(id)
+            .map(|si| si.name().to_owned())
+            .unwrap_or_else(|_| format!("{id:?}"))
+    }
+
+    /// Get the storage for a symbol that is about to be assigned to
+    fn get_sym_reg_for_write(&mut self, id: SymbolScopeId) -> Reg {
+        if let Some(r) = self.globals.get(&id).or_else(|| self.locals.get(&id)) {
+            return r.register;
+        }
+
+        let register = if self.is_global(id) {
+            Reg::Global(self.globals.len())
+        } else {
+            self.get_reg()
+        };
+
+        let r = RegWithType {
+            register,
+            kind: Type::ToInfer,
+        };
+
+        if self.is_global(id) {
+            self.globals.insert(id, r);
+        } else {
+            self.locals.insert(id, r);
+        }
+
+        register
+    }
+
+    /// Find where a symbol being read lives
+    fn get_sym_reg(&mut self, id: SymbolScopeId) -> IrResult<RegWithType> {
+        if self.is_global(id) {
+            self.get_sym_reg_for_write(id);
+            return Ok(self.globals[&id].clone());
+        }
+
+        match self.locals.get(&id) {
+            Some(r) => Ok(r.clone()),
+            None => self.err(IrErrorKind::FreeVariable(self.sym_name(id))),
+        }
+    }
+
+    fn set_sym_type(&mut self, id: SymbolScopeId, kind: Type) {
+        if let Some(r) = self.globals.get_mut(&id).or_else(|| self.locals.get_mut(&id)) {
+            r.kind = kind
+        }
+    }
+
+    /// Generate a series of forms, result is the last one
+    /// If the series is in tail position so is the last form
+    fn gen_forms(&mut self, ids: &[AstNodeId]) -> IrResult<Type> {
+        let tail = std::mem::take(&mut self.tail);
+        let mut kind = Type::Void;
+
+        if ids.is_empty() {
+            self.emit(Instruction::Load(Reg::Ret(0), NULL));
+        }
+
+        for (i, id) in ids.iter().enumerate() {
+            self.tail = tail && i + 1 == ids.len();
+            kind = self.code_gen(*id)?;
+        }
+
+        Ok(kind)
+    }
+
+    /// Generate code for the whole module
+    /// Code starts at address 0, the result of the last top level form is in Ret(0)
+    pub fn code_gen_module(&mut self) -> IrResult<Type> {
+        let root = self.module.ast.get_root_id();
+        self.new_syms();
+        let kind = self.code_gen(root)?;
+        self.emit(Instruction::Ret);
+        self.fixup_syms()?;
+        Ok(kind)
+    }
+
+    /// Generate a function for a lambda body
+    /// Each function gets its own frame, its registers are numbered from zero
+    /// Params arrive in Arg regs and captured values are copied out of the closure
+    fn gen_lambda_body(&mut self, body: &LambdaBodyData, captures: &[SymbolScopeId]) -> IrResult<Type> {
+        use Instruction::*;
+
+        let kids = self.module.ast.get_kids_ids(body.id);
+        let forms = &kids[1..];
+
+        let saved_locals = std::mem::take(&mut self.locals);
+        let saved_next_reg = std::mem::replace(&mut self.next_reg, 0);
+        let enter = self.emit(Enter(0));
+
+        for (i, sym_id) in body.params.iter().enumerate() {
+            let r = self.get_sym_reg_for_write(*sym_id);
+            self.emit(Mov(r, Reg::Arg(i)));
+        }
+
+        if let Some(rest) = body.rest {
+            let r = self.get_sym_reg_for_write(rest);
+            self.emit(Rest(r, body.arity()));
+        }
+
+        for (i, sym_id) in captures.iter().enumerate() {
+            let r = self.get_sym_reg_for_write(*sym_id);
+            self.emit(Env(r, i));
+        }
+
+        let saved_recur_start = self.recur_start.replace(self.get_pc());
+        self.tail = true;
+        let ret = self.gen_forms(forms);
+        self.emit(Ret);
+        self.emit_at(enter, Enter(self.next_reg));
+
+        self.locals = saved_locals;
+        self.next_reg = saved_next_reg;
+        self.recur_start = saved_recur_start;
+        ret
+    }
+
+    fn gen_builtin(&mut self, b: BuiltIn, args: &[AstNodeId]) -> IrResult<Type> {
+        use Instruction::*;
+
+        if !b.accepts(args.len()) {
+            return self.err(IrErrorKind::WrongNumberOfArgs(b.name().to_owned(), args.len()));
+        }
+
+        let regs = self.eval_ids(args)?;
+        let r0 = Reg::Ret(0);
+        let first = regs[0].register;
+
+        let kind = match b {
+            BuiltIn::Eq => {
+                self.emit(Cmp(r0, first, regs[1].register));
+                Type::Bool
+            }
+
+            BuiltIn::Not => {
+                let flag = self.get_reg();
+                self.emit(Test(flag, first));
+                self.emit(Cmp(r0, flag, Reg::Zero));
+                Type::Bool
+            }
+
+            BuiltIn::Nth => {
+                self.emit(Get(r0, first, regs[1].register));
+                Type::ToInfer
+            }
+
+            BuiltIn::NthRest => {
+                self.emit(Slice(r0, first, regs[1].register));
+                Type::Array
+            }
+
+            BuiltIn::Add | BuiltIn::Sub | BuiltIn::Mul | BuiltIn::Div => {
+                self.emit(Mov(r0, first));
+
+                for r in &regs[1..] {
+                    let r = r.register;
+                    let i = match b {
+                        BuiltIn::Add => Add(r0, r0, r),
+                        BuiltIn::Sub => Sub(r0, r0, r),
+                        BuiltIn::Mul => Mul(r0, r0, r),
+                        _ => Div(r0, r0, r),
+                    };
+                    self.emit(i);
+                }
+                Type::Integer
+            }
+        };
+
+        Ok(kind)
     }
 
-    pub fn jump(&mut self, _label: &str) {
-        panic!()
+    /// The value of a literal number, or of an arithmetic builtin called on them
+    /// Uses the same operations as the interpreter, division by zero is left for run time
+    fn fold(&self, id: AstNodeId) -> Option<Value> {
+        match &self.node(id).value().kind {
+            AstNodeKind::Number => {
+                let val = parse_number(self.module.ast.get_source_text(id))?;
+                Some(Value::Signed(val as i64))
+            }
+
+            AstNodeKind::Application(app_data) => {
+                let AstNodeKind::Symbol(sym_id) = self.node(app_data.func).value().kind else {
+                    return None;
+                };
+
+                // Only arithmetic folds, anything else would fold to its first arg
+                let b = get_builtin(self.syms(), sym_id)?;
+                let is_arith = matches!(b, BuiltIn::Add | BuiltIn::Sub | BuiltIn::Mul | BuiltIn::Div);
+
+                if !is_arith || !b.accepts(app_data.args.len()) {
+                    return None;
+                }
+
+                let mut args = app_data.args.iter().map(|a| self.fold(*a));
+                let first = args.next()??;
+
+                args.try_fold(first, |acc, v| {
+                    let v = v?;
+                    let res = match b {
+                        BuiltIn::Add => acc + v,
+                        BuiltIn::Sub => acc - v,
+                        BuiltIn::Mul => acc * v,
+                        BuiltIn::Div if !v.is_zero() => acc / v,
+                        _ => return None,
+                    };
+                    res.ok()
+                })
+            }
+
+            _ => None,
+        }
     }
-    pub fn label(&mut self, _label: &str) {
-        panic!()
+
+    /// A call in tail position replaces the running function rather than returning to it
+    fn gen_call(&mut self, func: AstNodeId, args: &[AstNodeId], tail: bool) -> IrResult<Type> {
+        use Instruction::*;
+
+        let f = self.get_reg();
+        self.assign(f, func)?;
+
+        let regs = self.eval_ids(args)?;
+
+        for (i, r) in regs.iter().enumerate() {
+            self.emit(Mov(Reg::Arg(i), r.register));
+        }
+
+        if tail {
+            self.emit(TailCall(f, regs.len()));
+        } else {
+            self.emit(Call(f, regs.len()));
+        }
+
+        Ok(Type::ToInfer)
     }
 
-    pub fn code_gen(&mut self, node_id: AstNodeId) -> Type {
+    pub fn code_gen(&mut self, node_id: AstNodeId) -> IrResult<Type> {
         use super::instructions::Instruction::*;
 
+        let tail = std::mem::take(&mut self.tail);
+        let r0 = Reg::Ret(0);
+
+        // Arithmetic on literals is worked out now
+        if self.fold && matches!(self.node(node_id).value().kind, AstNodeKind::Application(_)) {
+            if let Some(val @ Value::Signed(n)) = self.fold(node_id) {
+                self.emit(Load(r0, n as usize));
+                self.folded.push((node_id, val));
+                return Ok(Type::Integer);
+            }
+        }
+
         let node = self.node(node_id);
-        let _enclosing_scope = self.module.id_to_scope.get(&node_id).unwrap();
 
         match &node.value().kind {
-            AstNodeKind::AssignSymbol(_symbol_id) => {
+            AstNodeKind::Program | AstNodeKind::Module(_) => {
+                let forms = self.module.ast.get_kids_ids(node_id);
+                self.gen_forms(&forms)
+            }
+
+            // Imported modules are grafted in ahead of the forms that import them
+            AstNodeKind::Import => {
+                self.emit(Load(r0, NULL));
+                Ok(Type::Void)
+            }
+
+            AstNodeKind::AssignSymbol(symbol_id) => {
                 // get define result into a register
                 // move register to variable
-                panic!()
+                let symbol_id = *symbol_id;
+                let dest = self.get_sym_reg_for_write(symbol_id);
+                let val = self.module.ast.get_nth_kid_id(node_id, 0).unwrap();
+                let kind = self.assign(dest, val)?;
+                self.set_sym_type(symbol_id, kind.clone());
+                Ok(kind)
             }
 
-            AstNodeKind::Lambda => {
-                panic!()
+            AstNodeKind::Symbol(symbol_id) => {
+                let symbol_id = *symbol_id;
+
+                if let Some(b) = get_builtin(self.syms(), symbol_id) {
+                    return self.err(IrErrorKind::Unsupported(format!("builtin {b} as a value")));
+                }
+
+                let r = self.get_sym_reg(symbol_id)?;
+                self.emit(Mov(r0, r.register));
+                Ok(r.kind)
             }
 
-            AstNodeKind::LambdaBody => {
-                panic!()
+            AstNodeKind::Lambda(lambda_data) => {
+                let lambda_data = lambda_data.clone();
+                let arities = lambda_data.arities();
+
+                if arities.fixed.iter().any(|n| *n >= usize::BITS as usize) {
+                    return self.err(IrErrorKind::Unsupported("fn with this many params".to_owned()));
+                }
+
+                self.new_syms();
+                self.jump("skip");
+                self.label("entry")?;
+
+                // The entry point picks a body by the number of args the call has
+                for (i, body) in lambda_data.bodies.iter().enumerate() {
+                    let label = format!("body_{i}");
+                    let n = body.arity();
+
+                    match body.rest {
+                        Some(_) => self.emit_with_label(&label, BrArgcMin(n, 0)),
+                        None => self.emit_with_label(&label, BrArgc(n, 0)),
+                    };
+                }
+
+                let mask = arities.fixed.iter().fold(0, |mask, n| mask | (1 << n));
+                self.emit(NoArity(mask, arities.rest));
+
+                for (i, body) in lambda_data.bodies.iter().enumerate() {
+                    self.label(&format!("body_{i}"))?;
+                    self.gen_lambda_body(body, &lambda_data.captures)?;
+                }
+
+                self.label("skip")?;
+
+                let entry = self.get_reg();
+                self.load_label(entry, "entry");
+                self.emit(NewClosure(r0, entry));
+
+                for sym_id in lambda_data.captures.iter() {
+                    let r = self.get_sym_reg(*sym_id)?;
+                    self.emit(Capture(r0, r.register));
+                }
+
+                self.fixup_syms()?;
+                Ok(Type::Lambda)
             }
 
             AstNodeKind::And => {
                 // this is really repeated ifs
-                panic!()
+                let forms = self.module.ast.get_kids_ids(node_id);
+                self.new_syms();
+                self.emit(Load(r0, TRUE));
+
+                for id in forms {
+                    self.code_gen(id)?;
+                    self.branch_false("exit", r0);
+                }
+
+                self.label("exit")?;
+                self.fixup_syms()?;
+                Ok(Type::ToInfer)
             }
 
             AstNodeKind::Or => {
-                panic!()
-            }
+                let forms = self.module.ast.get_kids_ids(node_id);
+                let (is_true, one) = (self.get_reg(), self.get_reg());
+                self.new_syms();
+                self.emit(Load(one, 1));
+                self.emit(Load(r0, FALSE));
+
+                for id in forms {
+                    self.code_gen(id)?;
+                    self.emit(Test(is_true, r0));
+                    self.branch_equal("exit", is_true, one);
+                }
 
-            AstNodeKind::Let(..) => {
-                panic!()
+                self.label("exit")?;
+                self.fixup_syms()?;
+                Ok(Type::ToInfer)
             }
 
-            AstNodeKind::If(..) => {
-                let ids: ThinVec<_> = node.children().map(|n| n.id()).collect();
+            AstNodeKind::Let(let_data) => {
+                let bindings = let_data.bindings.clone();
+
+                // Make space for all the bindings up front,
+                // a value can only refer to the bindings before it
+                for b in &bindings {
+                    self.get_sym_reg_for_write(*b);
+                }
 
-                use super::instructions::Reg::*;
+                let kids = self.module.ast.get_kids_ids(node_id);
+                let (args, forms) = kids.split_first().expect("Let with no args");
 
-                let p = ids[0];
-                let when_true = ids[1];
-                let when_false = ids[2];
+                for arg in self.module.ast.get_kids_ids(*args) {
+                    self.code_gen(arg)?;
+                }
 
-                let pred_type = self.code_gen(p);
+                self.tail = tail;
+                self.gen_forms(forms)
+            }
 
-                if pred_type != Type::Bool {
-                    self.code_gen(when_false)
+            AstNodeKind::LetArg => {
+                let sym = self.module.ast.get_nth_kid_id(node_id, 0).unwrap();
+                let val = self.module.ast.get_nth_kid_id(node_id, 1).unwrap();
+
+                if let AstNodeKind::Symbol(symbol_id) = self.node(sym).value().kind {
+                    let dest = self.get_sym_reg_for_write(symbol_id);
+                    let kind = self.assign(dest, val)?;
+                    self.set_sym_type(symbol_id, kind.clone());
+                    Ok(kind)
                 } else {
-                    self.new_syms();
-                    self.branch_equal("false_clause", Ret(0), Zero);
-                    let true_type = self.code_gen(when_true);
-                    self.jump("exit");
-                    self.label("false_clause");
-                    let false_type = self.code_gen(when_false);
-                    self.label("exit");
-                    self.fixup_syms();
+                    self.err(IrErrorKind::Unsupported("let binding".to_owned()))
+                }
+            }
+
+            AstNodeKind::If(if_data) => {
+                let if_data = if_data.clone();
+
+                self.code_gen(if_data.predicate)?;
+
+                self.new_syms();
+                self.branch_false("false_clause", r0);
+                self.tail = tail;
+                let true_type = self.code_gen(if_data.if_true)?;
+                self.jump("exit");
+                self.label("false_clause")?;
 
-                    if false_type != true_type {
-                        panic!("Clauses return different types")
+                let false_type = match if_data.if_false {
+                    Some(when_false) => {
+                        self.tail = tail;
+                        self.code_gen(when_false)?
                     }
+                    None => {
+                        self.emit(Load(r0, NULL));
+                        Type::Void
+                    }
+                };
+
+                self.label("exit")?;
+                self.fixup_syms()?;
 
-                    true_type
+                if false_type != true_type {
+                    Ok(Type::ToInfer)
+                } else {
+                    Ok(true_type)
                 }
             }
 
             AstNodeKind::True => {
-                self.emit(Mov(Reg::Ret(0), Reg::Zero));
-                Type::Bool
+                self.emit(Load(r0, TRUE));
+                Ok(Type::Bool)
             }
 
             AstNodeKind::False => {
-                self.emit(Load(Reg::Ret(0), 1));
-                Type::Bool
+                self.emit(Load(r0, FALSE));
+                Ok(Type::Bool)
             }
 
-            AstNodeKind::Application(..) => {
-                let n = node.first_child().unwrap();
-                match &n.value().kind {
-                    AstNodeKind::Application(..) => {
-                        panic!()
-                    }
+            AstNodeKind::Null => {
+                self.emit(Load(r0, NULL));
+                Ok(Type::Void)
+            }
+
+            AstNodeKind::Number => {
+                let text = self.module.ast.get_source_text(node_id);
+                let val = parse_number(text).expect("Number not validated by the front end");
+                self.emit(Load(r0, val as usize));
+                Ok(Type::Integer)
+            }
+
+            AstNodeKind::QuotedString => {
+                let text = parse_quoted_string(self.module.ast.get_source_text(node_id));
+                let val = self.constant(Value::Text(text.into()));
+                self.emit(Load(r0, val));
+                Ok(Type::String)
+            }
+
+            AstNodeKind::KeyWord => {
+                let text = self.module.ast.get_source_text(node_id);
+                let val = self.constant(Value::KeyWord(text.into()));
+                self.emit(Load(r0, val));
+                Ok(Type::KeyWord)
+            }
+
+            AstNodeKind::Quoted => {
+                let kid = self.module.ast.get_nth_kid_id(node_id, 0).unwrap();
+
+                if let AstNodeKind::Symbol(..) = self.node(kid).value().kind {
+                    let text = format!("'{}", self.module.ast.get_source_text(kid));
+                    let val = self.constant(Value::KeyWord(text.into()));
+                    self.emit(Load(r0, val));
+                    Ok(Type::KeyWord)
+                } else {
+                    self.code_gen(kid)
+                }
+            }
+
+            AstNodeKind::Map => {
+                let map = self.get_reg();
+                self.emit(NewMap(map));
+
+                for pair in self.module.ast.get_kids_ids(node_id) {
+                    let kv = self.eval_children(pair)?;
+                    self.emit(Insert(map, kv[0].register, kv[1].register));
+                }
+
+                self.emit(Mov(r0, map));
+                Ok(Type::Map)
+            }
+
+            AstNodeKind::Array | AstNodeKind::List => {
+                let array = self.get_reg();
+                self.emit(NewArray(array));
+
+                for id in self.module.ast.get_kids_ids(node_id) {
+                    self.code_gen(id)?;
+                    self.emit(Push(array, r0));
+                }
+
+                self.emit(Mov(r0, array));
+                Ok(Type::Array)
+            }
 
-                    AstNodeKind::Symbol(_id) => {
-                        // assign a value to each of the params
-                        //  Each param is code genned
-                        //  compare the types to to type sig of the function
+            AstNodeKind::Application(app_data) => {
+                let app_data = app_data.clone();
+                let func = self.node(app_data.func);
 
-                        // So..
-                        // I need a vec of params
-                        // each param has an id and a type
-                        panic!()
+                match &func.value().kind {
+                    AstNodeKind::Symbol(id) if get_builtin(self.syms(), *id).is_some() => {
+                        let b = get_builtin(self.syms(), *id).unwrap();
+                        self.gen_builtin(b, &app_data.args)
                     }
 
+                    AstNodeKind::KeyWord => {
+                        // (:key map) looks the key up in the map
+                        if app_data.args.len() != 1 {
+                            let name = self.module.ast.get_source_text(app_data.func).to_owned();
+                            return self.err(IrErrorKind::WrongNumberOfArgs(name, app_data.args.len()));
+                        }
+
+                        let key = self.get_reg();
+                        self.assign(key, app_data.func)?;
+                        self.code_gen(app_data.args[0])?;
+                        self.emit(Get(r0, r0, key));
+                        Ok(Type::ToInfer)
+                    }
 
-                    _ => panic!(),
+                    _ => self.gen_call(app_data.func, &app_data.args, tail),
                 }
             }
 
+            AstNodeKind::Do(do_data) => {
+                let forms = do_data.forms.clone();
+                self.tail = tail;
+                self.gen_forms(&forms)
+            }
+
+            // Rebind the params and jump back to the start of the body
+            AstNodeKind::Recur(recur_data) => {
+                let recur_data = recur_data.clone();
+                let start = self.recur_start.expect("recur outside of a fn");
+                let regs = self.eval_ids(&recur_data.args)?;
+
+                for (param, r) in recur_data.params.iter().zip(regs) {
+                    let dest = self.get_sym_reg(*param)?.register;
+                    self.emit(Mov(dest, r.register));
+                }
+
+                self.emit(Br(start));
+                Ok(Type::ToInfer)
+            }
+
+            AstNodeKind::Cond(cond_data) => {
+                let cond_data = cond_data.clone();
+                self.new_syms();
+
+                for (i, (test, value)) in cond_data.clauses.iter().enumerate() {
+                    let next = format!("next_{i}");
+                    self.code_gen(*test)?;
+                    self.branch_false(&next, r0);
+                    self.tail = tail;
+                    self.code_gen(*value)?;
+                    self.jump("exit");
+                    self.label(&next)?;
+                }
+
+                match cond_data.default {
+                    Some(default) => {
+                        self.tail = tail;
+                        self.code_gen(default)?;
+                    }
+                    None => {
+                        self.emit(Load(r0, NULL));
+                    }
+                }
+
+                self.label("exit")?;
+                self.fixup_syms()?;
+                Ok(Type::ToInfer)
+            }
+
+            // Calls have already been expanded, nothing to do at run time
+            AstNodeKind::Macro(_) => {
+                self.emit(Load(r0, NULL));
+                Ok(Type::Void)
+            }
 
-            _ => panic!("Unandled node!"),
+            kind => self.err(IrErrorKind::Unsupported(format!("{kind:?}"))),
         }
     }
 }
@@ -1,9 +1,56 @@
+use super::instructions::Reg;
+use crate::value::Arities;
 use thiserror::Error;
 
-#[derive(Debug, Error, Clone)]
-pub enum IrErrorKind  {
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum IrErrorKind {
+    #[error("Division by zero")]
+    DivisionByZero,
+    #[error("Jump to illegal address {0:#06x}")]
+    BadJumpTarget(usize),
+    #[error("Register {0:?} out of range")]
+    RegisterOutOfRange(Reg),
+    #[error("Ran off the end of the code")]
+    RanOffEnd,
+    #[error("Call stack overflow")]
+    StackOverflow,
+    #[error("Illegal heap reference {0:#x}")]
+    IllegalHeapRef(usize),
+    #[error("Value is not a map")]
+    NotAMap,
+    #[error("Value is not an array")]
+    NotAnArray,
+    #[error("Value {0:#x} is not a function")]
+    NotAFunction(usize),
+    #[error("Closure has no captured value {0}")]
+    BadCapture(usize),
+    #[error("No body of this fn takes {found} args, it takes {arities}")]
+    NoMatchingArity { found: usize, arities: Arities },
+    #[error("Can't generate code for {0}")]
+    Unsupported(String),
+    #[error("{0} is captured from an enclosing function")]
+    FreeVariable(String),
+    #[error("Label {0} is used but never defined")]
+    UndefinedLabel(String),
+    #[error("Label {0} is defined more than once")]
+    DuplicateLabel(String),
+    #[error("Wrong number of arguments to {0}: got {1}")]
+    WrongNumberOfArgs(String, usize),
+    #[error("Can't allocate registers with only {0} of them")]
+    TooFewRegisters(usize),
 }
 
+#[derive(Debug, Error, Clone, PartialEq)]
+#[error("{kind} (pc: {pc:#06x})")]
 pub struct IrError {
-    kind: IrErrorKind,
+    pub kind: IrErrorKind,
+    pub pc: usize,
 }
+
+impl IrError {
+    pub fn new(kind: IrErrorKind, pc: usize) -> Self {
+        Self { kind, pc }
+    }
+}
+
+pub type IrResult<T> = Result<T, IrError>;
@@ -1,15 +1,21 @@
+use super::error::IrResult;
+use super::vm::{Machine, Registers};
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
 pub enum Reg {
     Zero,
     Arg(usize),
     Gp(usize),
-    Ret(usize)
+    Ret(usize),
+    /// Top level definitions, shared by every call frame
+    Global(usize),
 }
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum Instruction {
     Load(Reg,usize),
+    /// Load the address of some code, moved along with the code it points at
+    LoadAddr(Reg, usize),
     Add(Reg, Reg, Reg),
     Sub(Reg, Reg, Reg),
     Mul(Reg, Reg, Reg),
@@ -18,36 +24,161 @@ pub enum Instruction {
     Cmp(Reg, Reg, Reg),
     And(Reg, Reg, Reg),
     Or(Reg, Reg, Reg),
+    /// Test(dest, val), 1 if val is true and 0 if it's 0, false or null, for branching on
+    Test(Reg, Reg),
+    /// Branch to the address held in the 3rd reg if the first two are equal
     CmpBr(Reg,Reg,Reg),
+    /// Jump to the address held in reg
     Jmp(Reg),
+    /// Branch to an address
+    Br(usize),
+    /// Branch to an address if the two regs are equal
+    BrEq(Reg, Reg, usize),
+    /// Call(closure, n), call the closure held in reg with n args passed in Arg regs
+    /// The caller's registers are saved in a new activation record
+    Call(Reg, usize),
+    /// TailCall(closure, n), call the closure in place of the running function
+    /// The callee reuses the caller's activation record and returns straight to its caller
+    TailCall(Reg, usize),
+    /// BrArgc(n, addr), branch to an address if the running call was given n args
+    BrArgc(usize, usize),
+    /// BrArgcMin(n, addr), branch to an address if the running call was given n or more args
+    BrArgcMin(usize, usize),
+    /// Raise an error as no body takes the number of args given
+    /// Bit n of the mask is set if there's a body taking n args
+    /// The second field is the fewest args the body with a rest param takes
+    NoArity(usize, Option<usize>),
+    /// Rest(dest, n), allocate an array of the call's args from the nth on
+    Rest(Reg, usize),
+    /// Return from a call, or stop if not in a call
     Ret,
+    /// First instruction of a function, gives it a frame of this many Gp regs
+    Enter(usize),
+    /// Env(dest, n), copy the closure's nth captured value
+    Env(Reg, usize),
+    /// NewClosure(dest, entry), allocate a closure for the code at the address in entry
+    NewClosure(Reg, Reg),
+    /// Capture(closure, val), add a captured value to a closure
+    Capture(Reg, Reg),
+    /// Allocate an empty map on the heap
+    NewMap(Reg),
+    /// Allocate an empty array on the heap
+    NewArray(Reg),
+    /// Insert(map, key, val)
+    Insert(Reg, Reg, Reg),
+    /// Push(array, val)
+    Push(Reg, Reg),
+    /// Get(dest, map or array, key or index), null if missing
+    Get(Reg, Reg, Reg),
+    /// Slice(dest, array, n), allocate an array of the elements from the nth on
+    Slice(Reg, Reg, Reg),
+    /// Spill(slot, src), store a register in one of the running call's stack slots
+    Spill(usize, Reg),
+    /// Reload(dest, slot), load a register from one of the running call's stack slots
+    Reload(Reg, usize),
 }
 
-pub fn exec(_ins: &[Instruction], _regs: &mut [usize]) -> usize {
-    panic!()
-    // use Instruction::*;
-
-    // let ret_reg = 0;
-    // let mut pc = 0;
-
-    // loop {
-    //     let i = &ins[pc];
-
-    //     match *i {
-    //         Ret => break,
-    //         Add(d, a, b) => regs[d] = regs[a] + regs[b],
-    //         Sub(d, a, b) => regs[d] = regs[a] - regs[b],
-    //         Mul(d, a, b) => regs[d] = regs[a] * regs[b],
-    //         Div(d, a, b) => regs[d] = regs[a] / regs[b],
-    //         Mov(d, a) => regs[d] = regs[a],
-    //         Cmp(d, a, b) => regs[d] = if regs[a] == regs[b] { 1 } else { 0 },
-    //         And(d, a, b) => regs[d] = if regs[a] != 0  && regs[b] != 0 {1} else {0},
-    //         Or(d, a, b) => regs[d] = if regs[a] != 0  || regs[b] != 0 {1} else {0},
-    //         _ => panic!(),
-    //     }
-    //     pc += 1;
-    // }
-
-    // regs[ret_reg]
+impl Instruction {
+    /// Change the address an instruction refers to
+    /// None if this instruction doesn't hold an address
+    pub fn with_target(self, dest: usize) -> Option<Self> {
+        use Instruction::*;
+        match self {
+            LoadAddr(r, _) => Some(LoadAddr(r, dest)),
+            Br(_) => Some(Br(dest)),
+            BrEq(a, b, _) => Some(BrEq(a, b, dest)),
+            BrArgc(n, _) => Some(BrArgc(n, dest)),
+            BrArgcMin(n, _) => Some(BrArgcMin(n, dest)),
+            _ => None,
+        }
+    }
+
+    /// The address an instruction refers to, if it has one
+    pub fn target(&self) -> Option<usize> {
+        use Instruction::*;
+        match *self {
+            LoadAddr(_, t) | Br(t) | BrEq(_, _, t) | BrArgc(_, t) | BrArgcMin(_, t) => Some(t),
+            _ => None,
+        }
+    }
+
+    /// The address control can go to, if this is a branch
+    pub fn branch_target(&self) -> Option<usize> {
+        match self {
+            Instruction::LoadAddr(..) => None,
+            _ => self.target(),
+        }
+    }
+
+    /// False if control never goes on to the next instruction
+    pub fn falls_through(&self) -> bool {
+        use Instruction::*;
+        !matches!(self, Ret | TailCall(..) | NoArity(..) | Br(_) | Jmp(_))
+    }
+
+    /// The registers this instruction writes and the ones it reads
+    pub fn defs_uses(&self) -> (Vec<Reg>, Vec<Reg>) {
+        use Instruction::*;
+        match *self {
+            Load(d, _) | LoadAddr(d, _) | Reload(d, _) => (vec![d], vec![]),
+            Env(d, _) | Rest(d, _) | NewMap(d) | NewArray(d) => (vec![d], vec![]),
+            Mov(d, a) | Test(d, a) | NewClosure(d, a) => (vec![d], vec![a]),
+            Add(d, a, b) | Sub(d, a, b) | Mul(d, a, b) | Div(d, a, b) => (vec![d], vec![a, b]),
+            Cmp(d, a, b) | And(d, a, b) | Or(d, a, b) => (vec![d], vec![a, b]),
+            Get(d, a, b) | Slice(d, a, b) => (vec![d], vec![a, b]),
+            CmpBr(a, b, c) | Insert(a, b, c) => (vec![], vec![a, b, c]),
+            BrEq(a, b, _) | Capture(a, b) | Push(a, b) => (vec![], vec![a, b]),
+            Jmp(a) | Call(a, _) | TailCall(a, _) | Spill(_, a) => (vec![], vec![a]),
+            Br(_) | BrArgc(..) | BrArgcMin(..) | NoArity(..) | Ret | Enter(_) => (vec![], vec![]),
+        }
+    }
+
+    /// Rename every register this instruction reads or writes
+    pub fn map_regs(self, f: impl Fn(Reg) -> Reg) -> Self {
+        self.rename(&f, &f)
+    }
+
+    /// Rename the registers this instruction writes with def and the ones it reads with uses
+    pub fn rename(self, def: impl Fn(Reg) -> Reg, uses: impl Fn(Reg) -> Reg) -> Self {
+        use Instruction::*;
+        let (d, u) = (def, uses);
+        match self {
+            Load(r, v) => Load(d(r), v),
+            LoadAddr(r, t) => LoadAddr(d(r), t),
+            Add(r, a, b) => Add(d(r), u(a), u(b)),
+            Sub(r, a, b) => Sub(d(r), u(a), u(b)),
+            Mul(r, a, b) => Mul(d(r), u(a), u(b)),
+            Div(r, a, b) => Div(d(r), u(a), u(b)),
+            Mov(r, a) => Mov(d(r), u(a)),
+            Test(r, a) => Test(d(r), u(a)),
+            Cmp(r, a, b) => Cmp(d(r), u(a), u(b)),
+            And(r, a, b) => And(d(r), u(a), u(b)),
+            Or(r, a, b) => Or(d(r), u(a), u(b)),
+            CmpBr(a, b, t) => CmpBr(u(a), u(b), u(t)),
+            Jmp(t) => Jmp(u(t)),
+            BrEq(a, b, t) => BrEq(u(a), u(b), t),
+            Call(t, n) => Call(u(t), n),
+            TailCall(t, n) => TailCall(u(t), n),
+            Rest(r, n) => Rest(d(r), n),
+            Env(r, n) => Env(d(r), n),
+            NewClosure(r, e) => NewClosure(d(r), u(e)),
+            Capture(c, v) => Capture(u(c), u(v)),
+            NewMap(r) => NewMap(d(r)),
+            NewArray(r) => NewArray(d(r)),
+            Insert(m, k, v) => Insert(u(m), u(k), u(v)),
+            Push(a, v) => Push(u(a), u(v)),
+            Get(r, m, k) => Get(d(r), u(m), u(k)),
+            Slice(r, a, n) => Slice(d(r), u(a), u(n)),
+            Spill(n, r) => Spill(n, u(r)),
+            Reload(r, n) => Reload(d(r), n),
+            Br(_) | BrArgc(..) | BrArgcMin(..) | NoArity(..) | Ret | Enter(_) => self,
+        }
+    }
 }
 
+/// Run some code from address zero until it returns
+/// Returns the contents of Ret(0)
+pub fn exec(ins: &[Instruction], regs: &mut Registers) -> IrResult<usize> {
+    let mut machine = Machine::new(ins, regs);
+    machine.run()
+}
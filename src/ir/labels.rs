@@ -0,0 +1,133 @@
+/// Symbolic labels for the IR emitter
+/// Labels live in nested namespaces, opened with new_scope and closed with close_scope
+/// A reference to a label not defined in its own namespace is passed out to the
+/// enclosing one when the namespace closes, so forward references to outer labels work
+use super::error::{IrError, IrErrorKind, IrResult};
+use std::collections::HashMap;
+
+#[derive(Clone, Debug, PartialEq)]
+struct LabelRef {
+    addr: usize,
+    label: String,
+}
+
+#[derive(Clone, Debug, Default)]
+struct LabelScope {
+    labels: HashMap<String, usize>,
+    refs: Vec<LabelRef>,
+}
+
+/// An instruction at addr needs its target changing to dest
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Fixup {
+    pub addr: usize,
+    pub dest: usize,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Labels {
+    scopes: Vec<LabelScope>,
+}
+
+impl Labels {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn depth(&self) -> usize {
+        self.scopes.len()
+    }
+
+    pub fn new_scope(&mut self) {
+        self.scopes.push(Default::default())
+    }
+
+    fn current(&mut self) -> &mut LabelScope {
+        self.scopes.last_mut().expect("No label namespace open")
+    }
+
+    /// Define a label at addr in the current namespace
+    pub fn define(&mut self, label: &str, addr: usize) -> IrResult<()> {
+        let scope = self.current();
+
+        if scope.labels.contains_key(label) {
+            Err(IrError::new(IrErrorKind::DuplicateLabel(label.to_owned()), addr))
+        } else {
+            scope.labels.insert(label.to_owned(), addr);
+            Ok(())
+        }
+    }
+
+    /// Record that the instruction at addr refers to label
+    pub fn reference(&mut self, label: &str, addr: usize) {
+        self.current().refs.push(LabelRef {
+            addr,
+            label: label.to_owned(),
+        })
+    }
+
+    /// Close the current namespace
+    /// Returns the fixups for every reference resolved in this namespace
+    /// Unresolved references move to the enclosing namespace, or are an error if there isn't one
+    pub fn close_scope(&mut self) -> IrResult<Vec<Fixup>> {
+        let scope = self.scopes.pop().expect("No label namespace open");
+        let mut fixups = vec![];
+
+        for r in scope.refs {
+            if let Some(dest) = scope.labels.get(&r.label) {
+                fixups.push(Fixup {
+                    addr: r.addr,
+                    dest: *dest,
+                })
+            } else if let Some(parent) = self.scopes.last_mut() {
+                parent.refs.push(r)
+            } else {
+                return Err(IrError::new(IrErrorKind::UndefinedLabel(r.label), r.addr));
+            }
+        }
+
+        Ok(fixups)
+    }
+}
+
+#[allow(unused_imports)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_nested() {
+        let mut labels = Labels::new();
+
+        labels.new_scope();
+        labels.reference("exit", 0);
+        labels.new_scope();
+        labels.reference("exit", 1);
+        labels.reference("outer", 2);
+        labels.define("exit", 3).unwrap();
+        assert_eq!(labels.close_scope(), Ok(vec![Fixup { addr: 1, dest: 3 }]));
+        labels.define("exit", 4).unwrap();
+        labels.define("outer", 5).unwrap();
+
+        let mut fixups = labels.close_scope().unwrap();
+        fixups.sort_by_key(|f| f.addr);
+        assert_eq!(
+            fixups,
+            vec![Fixup { addr: 0, dest: 4 }, Fixup { addr: 2, dest: 5 }]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut labels = Labels::new();
+        labels.new_scope();
+        labels.define("a", 0).unwrap();
+
+        let err = labels.define("a", 1).unwrap_err();
+        assert_eq!(err.kind, IrErrorKind::DuplicateLabel("a".to_owned()));
+
+        labels.reference("nowhere", 2);
+        let err = labels.close_scope().unwrap_err();
+        assert_eq!(err, IrError::new(IrErrorKind::UndefinedLabel("nowhere".to_owned()), 2));
+    }
+}
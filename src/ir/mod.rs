@@ -1,4 +1,10 @@
+
 pub mod instructions;
 pub mod codegen;
+pub mod cfg;
 pub mod error;
-
+pub mod labels;
+pub mod opt;
+pub mod regalloc;
+pub mod ssa;
+pub mod vm;
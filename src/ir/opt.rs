@@ -0,0 +1,197 @@
+/// Optimisation passes over a Cfg in SSA form
+/// Each register is written once so a copy can be replaced by what it copies
+/// everywhere, and a write no one reads can go
+use super::cfg::Cfg;
+use super::instructions::{Instruction, Reg};
+use super::ssa::defs_uses;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Instructions that do nothing but write their dest
+/// Div can fail and allocations are seen by whatever they're stored in, so they stay
+fn is_pure(i: &Instruction) -> bool {
+    use Instruction::*;
+    matches!(
+        i,
+        Load(..)
+            | LoadAddr(..)
+            | Mov(..)
+            | Add(..)
+            | Sub(..)
+            | Mul(..)
+            | Cmp(..)
+            | And(..)
+            | Or(..)
+            | Test(..)
+            | Env(..)
+    )
+}
+
+/// Follow a chain of copies to the register it starts from
+fn resolve(copies: &BTreeMap<Reg, Reg>, mut r: Reg) -> Reg {
+    // A cycle of copies can only come from code that never runs, give up after going round once
+    for _ in 0..=copies.len() {
+        match copies.get(&r) {
+            Some(s) => r = *s,
+            None => break,
+        }
+    }
+    r
+}
+
+impl Cfg {
+    /// Replace reads of copies with what they copy, returns the number of copies
+    /// * Mov from a Gp reg or Zero
+    /// * A phi that, apart from itself, only ever picks the one register
+    ///
+    /// Ret and Arg regs are written by calls so aren't copied from
+    /// The copies are left in place for dead code elimination
+    pub fn propagate_copies(&mut self) -> usize {
+        let mut copies = BTreeMap::new();
+
+        for i in self.blocks.iter().flat_map(|b| b.code.iter()) {
+            if let Instruction::Mov(d @ Reg::Gp(_), s @ (Reg::Gp(_) | Reg::Zero)) = *i {
+                copies.insert(d, s);
+            }
+        }
+
+        // Removing one phi can make another pick only one register
+        let mut changed = true;
+        while changed {
+            changed = false;
+
+            for p in self.blocks.iter().flat_map(|b| b.phis.iter()) {
+                if copies.contains_key(&p.dest) {
+                    continue;
+                }
+
+                let args: BTreeSet<_> = p
+                    .args
+                    .iter()
+                    .map(|a| resolve(&copies, a.1))
+                    .filter(|a| *a != p.dest)
+                    .collect();
+
+                if let [r] = args.into_iter().collect::<Vec<_>>()[..] {
+                    copies.insert(p.dest, r);
+                    changed = true;
+                }
+            }
+        }
+
+        for block in self.blocks.iter_mut() {
+            for p in block.phis.iter_mut() {
+                for a in p.args.iter_mut() {
+                    a.1 = resolve(&copies, a.1);
+                }
+            }
+
+            for i in block.code.iter_mut() {
+                *i = i.rename(|d| d, |u| resolve(&copies, u));
+            }
+        }
+
+        copies.len()
+    }
+
+    /// Remove pure instructions and phis whose results are never read
+    /// returns how many were removed
+    ///
+    /// Anything with an effect, or that writes a register other than a Gp, is live and so
+    /// is anything writing a register a live instruction reads
+    pub fn eliminate_dead_code(&mut self) -> usize {
+        let is_critical = |i: &Instruction| !is_pure(i) || i.defs_uses().0.iter().any(|d| !matches!(d, Reg::Gp(_)));
+
+        // What reads each register written, either a phi or an instruction
+        let mut sources: BTreeMap<Reg, Vec<Reg>> = BTreeMap::new();
+        let mut work = vec![];
+
+        for block in &self.blocks {
+            for p in &block.phis {
+                sources.insert(p.dest, p.args.iter().map(|a| a.1).collect());
+            }
+
+            for i in &block.code {
+                let (defs, uses) = defs_uses(i);
+
+                if is_critical(i) {
+                    work.extend(uses);
+                } else {
+                    for d in defs {
+                        sources.insert(d, uses.clone());
+                    }
+                }
+            }
+        }
+
+        let mut live = BTreeSet::new();
+        while let Some(r) = work.pop() {
+            if live.insert(r) {
+                work.extend(sources.get(&r).into_iter().flatten());
+            }
+        }
+
+        let mut removed = 0;
+        for block in self.blocks.iter_mut() {
+            let before = block.phis.len() + block.code.len();
+            block.phis.retain(|p| live.contains(&p.dest));
+            block
+                .code
+                .retain(|i| is_critical(i) || defs_uses(i).0.iter().any(|d| live.contains(d)));
+            removed += before - block.phis.len() - block.code.len();
+        }
+
+        removed
+    }
+}
+
+#[allow(unused_imports)]
+mod test {
+    use super::*;
+    use crate::ir::instructions::exec;
+    use crate::ir::vm::Registers;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_passes() {
+        use {Instruction::*, Reg::Gp};
+
+        // Sum 1..=n through a pointless copy, working out a value that's never used
+        let code = vec![
+            Load(Gp(0), 0),
+            Load(Gp(1), 4),
+            Load(Gp(2), 1),
+            BrEq(Gp(1), Reg::Zero, 9),
+            Mov(Gp(3), Gp(1)),
+            Add(Gp(0), Gp(0), Gp(3)),
+            Mul(Gp(4), Gp(3), Gp(3)),
+            Sub(Gp(1), Gp(1), Gp(2)),
+            Br(3),
+            Mov(Reg::Ret(0), Gp(0)),
+            Ret,
+        ];
+
+        let mut regs = Registers::default();
+        assert_eq!(exec(&code, &mut regs), Ok(10));
+
+        let mut cfg = Cfg::new(&code).unwrap();
+        cfg.to_ssa();
+
+        // SSA turns the move into Ret(0) into a copy as well
+        assert_eq!(cfg.propagate_copies(), 2, "{cfg}");
+        let movs = |cfg: &Cfg| {
+            let code = cfg.blocks.iter().flat_map(|b| b.code.iter());
+            code.filter(|i| matches!(i, Mov(Gp(_), Gp(_)))).count()
+        };
+        assert_eq!(movs(&cfg), 2, "{cfg}");
+
+        // The copies and the square go, nothing else does
+        assert_eq!(cfg.eliminate_dead_code(), 3, "{cfg}");
+        assert_eq!(movs(&cfg), 0, "{cfg}");
+        assert!(!cfg.blocks.iter().flat_map(|b| b.code.iter()).any(|i| matches!(i, Mul(..))));
+        assert_eq!(cfg.eliminate_dead_code(), 0, "{cfg}");
+
+        cfg.from_ssa();
+        let mut regs = Registers::default();
+        assert_eq!(exec(&cfg.to_code(), &mut regs), Ok(10), "{cfg}");
+    }
+}
@@ -0,0 +1,507 @@
+/// Graph colouring register allocator
+/// CodeGen hands out a fresh Gp register for every value, this maps them onto a fixed number
+///
+/// Every call gets a fresh frame so each function is allocated on its own
+/// * Liveness is worked out over the function's control flow graph
+/// * Registers live at the same time interfere and can't share a colour
+/// * Registers with fewer neighbours than there are colours are taken out of the graph
+///   until it's empty, then put back in reverse order taking a colour no neighbour has
+/// * If every register left has too many neighbours the one cheapest to spill comes out anyway,
+///   cost is uses and defs weighted by loop depth, over the number of neighbours
+/// * Anything still without a colour lives in a stack slot, reloaded into a short lived temp
+///   around each use and def, and the function is allocated again
+use super::error::{IrError, IrErrorKind, IrResult};
+use super::instructions::{Instruction, Reg};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+/// The most registers a single instruction needs at once
+pub const MIN_REGISTERS: usize = 3;
+
+type Graph = BTreeMap<usize, BTreeSet<usize>>;
+
+pub struct RegAlloc {
+    num_regs: usize,
+    /// Temps made for spill code, spilling them again wouldn't help
+    temps: HashSet<usize>,
+    next_temp: usize,
+}
+
+enum Colouring {
+    Done(HashMap<usize, usize>),
+    Spill(Vec<usize>),
+}
+
+/// Gp registers written and read by an instruction
+fn defs_uses(i: &Instruction) -> (Vec<usize>, Vec<usize>) {
+    let gp = |regs: Vec<Reg>| {
+        regs.into_iter()
+            .filter_map(|r| match r {
+                Reg::Gp(n) => Some(n),
+                _ => None,
+            })
+            .collect()
+    };
+
+    let (defs, uses) = i.defs_uses();
+    (gp(defs), gp(uses))
+}
+
+/// Where control can go after the instruction at pc
+fn successors(code: &[Instruction], pc: usize) -> Vec<usize> {
+    let i = &code[pc];
+    let next = i.falls_through().then_some(pc + 1);
+
+    next.into_iter()
+        .chain(i.branch_target())
+        .filter(|a| *a < code.len())
+        .collect()
+}
+
+/// Every address reachable from start
+fn reachable(code: &[Instruction], start: usize, seen: &mut BTreeSet<usize>) {
+    let mut todo = vec![start];
+
+    while let Some(pc) = todo.pop() {
+        if pc < code.len() && seen.insert(pc) {
+            todo.extend(successors(code, pc))
+        }
+    }
+}
+
+/// The addresses of the top level code and each function
+/// Code no frame can reach is left out, it's never run
+fn frames(code: &[Instruction]) -> IrResult<Vec<Vec<usize>>> {
+    let mut ret = vec![];
+
+    for (pc, i) in code.iter().enumerate() {
+        match i {
+            Instruction::Jmp(_) | Instruction::CmpBr(..) => {
+                let msg = "indirect jumps in register allocation".to_owned();
+                return Err(IrError::new(IrErrorKind::Unsupported(msg), pc));
+            }
+            Instruction::Enter(_) if pc != 0 => {
+                let mut seen = BTreeSet::new();
+                reachable(code, pc, &mut seen);
+                ret.push(seen.into_iter().collect())
+            }
+            _ => (),
+        }
+    }
+
+    if !code.is_empty() {
+        let mut seen = BTreeSet::new();
+        reachable(code, 0, &mut seen);
+        ret.push(seen.into_iter().collect())
+    }
+
+    Ok(ret)
+}
+
+/// Registers live after each instruction of a frame
+fn liveness(code: &[Instruction], pcs: &[usize]) -> HashMap<usize, BTreeSet<usize>> {
+    let mut live_in: HashMap<usize, BTreeSet<usize>> = HashMap::new();
+    let mut live_out: HashMap<usize, BTreeSet<usize>> = HashMap::new();
+    let mut changed = true;
+
+    while changed {
+        changed = false;
+
+        for pc in pcs.iter().rev() {
+            let out: BTreeSet<usize> = successors(code, *pc)
+                .iter()
+                .filter_map(|s| live_in.get(s))
+                .flatten()
+                .copied()
+                .collect();
+
+            let (defs, uses) = defs_uses(&code[*pc]);
+            let mut inn: BTreeSet<usize> = out.iter().filter(|r| !defs.contains(r)).copied().collect();
+            inn.extend(uses);
+
+            if live_in.get(pc) != Some(&inn) {
+                live_in.insert(*pc, inn);
+                changed = true;
+            }
+
+            live_out.insert(*pc, out);
+        }
+    }
+
+    live_out
+}
+
+/// How many loops each instruction of a frame is in
+/// A branch backwards closes a loop from its target to itself
+fn loop_depths(code: &[Instruction], pcs: &[usize]) -> HashMap<usize, u32> {
+    let mut depths: HashMap<usize, u32> = pcs.iter().map(|pc| (*pc, 0)).collect();
+
+    for pc in pcs {
+        for s in successors(code, *pc).into_iter().filter(|s| s <= pc) {
+            for d in pcs.iter().filter(|a| (s..=*pc).contains(a)) {
+                *depths.get_mut(d).unwrap() += 1
+            }
+        }
+    }
+
+    depths
+}
+
+/// Rebuild code with each instruction replaced by a run of them
+/// Addresses are moved to the start of the run that replaced what they pointed at
+fn rebuild(code: &[Instruction], mut f: impl FnMut(usize, Instruction) -> Vec<Instruction>) -> Vec<Instruction> {
+    let runs: Vec<_> = code.iter().enumerate().map(|(pc, i)| f(pc, *i)).collect();
+
+    let mut addrs = vec![0];
+    for run in &runs {
+        addrs.push(addrs.last().unwrap() + run.len())
+    }
+
+    runs.into_iter()
+        .flatten()
+        .map(|i| match i.target().and_then(|t| addrs.get(t)) {
+            Some(t) => i.with_target(*t).unwrap(),
+            None => i,
+        })
+        .collect()
+}
+
+impl RegAlloc {
+    pub fn new(num_regs: usize) -> Self {
+        Self {
+            num_regs,
+            temps: Default::default(),
+            next_temp: 0,
+        }
+    }
+
+    fn err<T>(&self) -> IrResult<T> {
+        Err(IrError::new(IrErrorKind::TooFewRegisters(self.num_regs), 0))
+    }
+
+    /// Allocate registers for code from CodeGen
+    /// Gp registers in the result are all below the number of registers asked for
+    pub fn alloc(&mut self, code: &[Instruction]) -> IrResult<Vec<Instruction>> {
+        if self.num_regs < MIN_REGISTERS {
+            return self.err();
+        }
+
+        let mut code = code.to_vec();
+        let max_reg = code.iter().flat_map(|i| {
+            let (defs, uses) = defs_uses(i);
+            defs.into_iter().chain(uses)
+        });
+        self.next_temp = max_reg.max().map_or(0, |r| r + 1);
+
+        loop {
+            let mut coloured = vec![];
+            let mut spills = vec![];
+
+            for pcs in frames(&code)? {
+                match self.colour(&code, &pcs)? {
+                    Colouring::Done(colours) => coloured.push((pcs, colours)),
+                    Colouring::Spill(regs) => spills.push((pcs, regs)),
+                }
+            }
+
+            if spills.is_empty() {
+                return Ok(Self::assign(&code, coloured));
+            }
+
+            code = self.spill(&code, spills);
+        }
+    }
+
+    /// Colour the registers of a frame, or say which need spilling
+    fn colour(&self, code: &[Instruction], pcs: &[usize]) -> IrResult<Colouring> {
+        let live_out = liveness(code, pcs);
+        let depths = loop_depths(code, pcs);
+
+        let mut graph = Graph::new();
+        let mut costs: HashMap<usize, usize> = HashMap::new();
+        // Registers moved between each other, best given the same colour
+        let mut moves: HashMap<usize, Vec<usize>> = HashMap::new();
+
+        for pc in pcs {
+            let (defs, uses) = defs_uses(&code[*pc]);
+            let weight = 10usize.saturating_pow(depths[pc]);
+
+            for r in defs.iter().chain(&uses) {
+                graph.entry(*r).or_default();
+                *costs.entry(*r).or_default() += weight;
+            }
+
+            // A copy's source and dest hold the same value so don't interfere
+            let copied = match code[*pc] {
+                Instruction::Mov(Reg::Gp(d), Reg::Gp(s)) => {
+                    moves.entry(d).or_default().push(s);
+                    moves.entry(s).or_default().push(d);
+                    Some(s)
+                }
+                _ => None,
+            };
+
+            for d in &defs {
+                for l in live_out[pc].iter().filter(|l| *l != d && Some(**l) != copied) {
+                    graph.entry(*d).or_default().insert(*l);
+                    graph.entry(*l).or_default().insert(*d);
+                }
+            }
+        }
+
+        // Simplify
+        let mut degrees: HashMap<usize, usize> = graph.iter().map(|(r, n)| (*r, n.len())).collect();
+        let mut remaining: BTreeSet<usize> = graph.keys().copied().collect();
+        let mut stack = vec![];
+
+        let spill_cost = |r: &usize, degrees: &HashMap<usize, usize>| match self.temps.contains(r) {
+            true => f64::INFINITY,
+            false => costs[r] as f64 / degrees[r] as f64,
+        };
+
+        while !remaining.is_empty() {
+            let next = remaining
+                .iter()
+                .find(|r| degrees[r] < self.num_regs)
+                .or_else(|| {
+                    remaining
+                        .iter()
+                        .min_by(|a, b| spill_cost(a, &degrees).total_cmp(&spill_cost(b, &degrees)))
+                })
+                .copied()
+                .unwrap();
+
+            remaining.remove(&next);
+            stack.push(next);
+
+            for n in graph[&next].iter().filter(|n| remaining.contains(n)) {
+                *degrees.get_mut(n).unwrap() -= 1;
+            }
+        }
+
+        // Select
+        let mut colours = HashMap::new();
+        let mut spills = vec![];
+
+        while let Some(r) = stack.pop() {
+            let taken: HashSet<usize> = graph[&r].iter().filter_map(|n| colours.get(n)).copied().collect();
+            let free = |c: &usize| !taken.contains(c);
+
+            let partner = moves.get(&r).into_iter().flatten().filter_map(|m| colours.get(m));
+
+            match partner.copied().find(free).or_else(|| (0..self.num_regs).find(free)) {
+                Some(c) => {
+                    colours.insert(r, c);
+                }
+                None if self.temps.contains(&r) => return self.err(),
+                None => spills.push(r),
+            }
+        }
+
+        if spills.is_empty() {
+            Ok(Colouring::Done(colours))
+        } else {
+            Ok(Colouring::Spill(spills))
+        }
+    }
+
+    /// Move registers out to stack slots
+    /// Each use is reloaded into a new temp just before and each def is stored just after
+    fn spill(&mut self, code: &[Instruction], spills: Vec<(Vec<usize>, Vec<usize>)>) -> Vec<Instruction> {
+        use Instruction::*;
+
+        let mut slots = HashMap::new();
+
+        for (pcs, regs) in spills {
+            // Slots already in use from earlier rounds
+            let used = pcs.iter().filter_map(|pc| match code[*pc] {
+                Spill(n, _) | Reload(_, n) => Some(n + 1),
+                _ => None,
+            });
+            let base = used.max().unwrap_or(0);
+
+            for pc in pcs {
+                for (i, r) in regs.iter().enumerate() {
+                    slots.insert((pc, *r), base + i);
+                }
+            }
+        }
+
+        rebuild(code, |pc, i| {
+            let (defs, uses) = defs_uses(&i);
+            let regs: Vec<_> = defs.iter().chain(&uses).copied().collect();
+
+            let mut before = vec![];
+            let mut after = vec![];
+            let mut renames = HashMap::new();
+
+            for r in regs {
+                let Some(slot) = slots.get(&(pc, r)).copied() else {
+                    continue;
+                };
+
+                if renames.contains_key(&r) {
+                    continue;
+                }
+
+                let temp = self.next_temp;
+                self.next_temp += 1;
+                self.temps.insert(temp);
+                renames.insert(r, temp);
+
+                if uses.contains(&r) {
+                    before.push(Reload(Reg::Gp(temp), slot))
+                }
+
+                if defs.contains(&r) {
+                    after.push(Spill(slot, Reg::Gp(temp)))
+                }
+            }
+
+            let i = i.map_regs(|r| match r {
+                Reg::Gp(n) => Reg::Gp(renames.get(&n).copied().unwrap_or(n)),
+                r => r,
+            });
+
+            before.push(i);
+            before.extend(after);
+            before
+        })
+    }
+
+    /// Rewrite the code with its registers coloured
+    /// Each function's frame is cut down to the registers it uses
+    /// and moves between registers given the same colour are dropped
+    fn assign(code: &[Instruction], coloured: Vec<(Vec<usize>, HashMap<usize, usize>)>) -> Vec<Instruction> {
+        let mut colours = HashMap::new();
+        let mut frame_sizes = HashMap::new();
+
+        for (pcs, frame) in coloured {
+            let size = frame.values().max().map_or(0, |c| c + 1);
+
+            for pc in pcs {
+                colours.insert(pc, frame.clone());
+                frame_sizes.insert(pc, size);
+            }
+        }
+
+        rebuild(code, |pc, i| {
+            let Some(frame) = colours.get(&pc) else {
+                // In no frame, so either dispatch code with no Gp regs or never run
+                return match defs_uses(&i) {
+                    (d, u) if d.is_empty() && u.is_empty() => vec![i],
+                    _ => vec![],
+                };
+            };
+
+            let i = i.map_regs(|r| match r {
+                Reg::Gp(n) => Reg::Gp(frame[&n]),
+                r => r,
+            });
+
+            match i {
+                Instruction::Enter(_) => vec![Instruction::Enter(frame_sizes[&pc])],
+                Instruction::Mov(d, s) if d == s => vec![],
+                i => vec![i],
+            }
+        })
+    }
+}
+
+#[allow(unused_imports)]
+mod test {
+    use super::*;
+    use crate::ir::instructions::exec;
+    use crate::ir::vm::{regs_used, Registers};
+    use pretty_assertions::assert_eq;
+
+    fn max_gp(code: &[Instruction]) -> Option<usize> {
+        code.iter()
+            .flat_map(regs_used)
+            .filter_map(|r| match r {
+                Reg::Gp(n) => Some(n),
+                _ => None,
+            })
+            .max()
+    }
+
+    #[test]
+    fn test_alloc() {
+        use {Instruction::*, Reg::Gp};
+
+        // Eight values all live at once, summed at the end
+        let mut code: Vec<_> = (0..8).map(|i| Load(Gp(i), i + 1)).collect();
+        code.push(Mov(Gp(8), Gp(0)));
+        code.extend((1..8).map(|i| Add(Gp(8), Gp(8), Gp(i))));
+        code.push(Mov(Reg::Ret(0), Gp(8)));
+        code.push(Ret);
+
+        let mut regs = Registers::default();
+        assert_eq!(exec(&code, &mut regs), Ok(36));
+
+        for n in [3, 4, 8, 9] {
+            let allocated = RegAlloc::new(n).alloc(&code).unwrap();
+            assert!(max_gp(&allocated) < Some(n), "{n} regs: {allocated:?}");
+
+            let spills = allocated.iter().filter(|i| matches!(i, Spill(..))).count();
+            assert_eq!(spills == 0, n >= 8, "{n} regs: {allocated:?}");
+
+            let mut regs = Registers::default();
+            assert_eq!(exec(&allocated, &mut regs), Ok(36), "{n} regs");
+        }
+
+        let err = RegAlloc::new(2).alloc(&code).unwrap_err();
+        assert_eq!(err.kind, IrErrorKind::TooFewRegisters(2));
+    }
+
+    #[test]
+    fn test_alloc_branches() {
+        use {Instruction::*, Reg::Gp};
+
+        // Count Gp(0) down to zero in a loop, adding Gp(2..6) to Gp(1) each time round
+        // then call a function that adds its arg to a captured value
+        let code = vec![
+            Load(Gp(0), 3),
+            Load(Gp(1), 0),
+            Load(Gp(2), 1),
+            Load(Gp(3), 2),
+            Load(Gp(4), 3),
+            Load(Gp(5), 4),
+            BrEq(Gp(0), Reg::Zero, 13),
+            Add(Gp(1), Gp(1), Gp(2)),
+            Add(Gp(1), Gp(1), Gp(3)),
+            Add(Gp(1), Gp(1), Gp(4)),
+            Add(Gp(1), Gp(1), Gp(5)),
+            Sub(Gp(0), Gp(0), Gp(2)),
+            Br(6),
+            Br(21),
+            // Unreachable, dropped
+            Mov(Gp(50), Gp(51)),
+            // The function
+            Enter(20),
+            Env(Gp(10), 0),
+            Mov(Gp(11), Reg::Arg(0)),
+            Add(Gp(12), Gp(10), Gp(11)),
+            Mov(Reg::Ret(0), Gp(12)),
+            Ret,
+            LoadAddr(Gp(6), 15),
+            NewClosure(Gp(7), Gp(6)),
+            Capture(Gp(7), Gp(1)),
+            Load(Reg::Arg(0), 100),
+            Call(Gp(7), 1),
+            Ret,
+        ];
+
+        let mut regs = Registers::default();
+        assert_eq!(exec(&code, &mut regs), Ok(130));
+
+        for n in [3, 4, 5, 8] {
+            let allocated = RegAlloc::new(n).alloc(&code).unwrap();
+            assert!(max_gp(&allocated) < Some(n), "{n} regs: {allocated:?}");
+            assert!(!allocated.contains(&Mov(Gp(50), Gp(51))));
+            assert!(allocated.iter().any(|i| matches!(i, Enter(s) if *s <= n)));
+
+            let mut regs = Registers::default();
+            assert_eq!(exec(&allocated, &mut regs), Ok(130), "{n} regs: {allocated:?}");
+        }
+    }
+}
@@ -0,0 +1,412 @@
+/// Static single assignment form for a Cfg
+/// Every register is written once, where control flow joins phis pick the value
+/// from the block control came from
+///
+/// Gp and Ret regs are renamed onto fresh Gp regs. Arg and Global regs are shared
+/// with other calls so stay as they are, as does what a call leaves in Ret(0)
+/// * A call writes Ret(0) itself, uses of that value read Ret(0)
+/// * Before a Ret the value being returned is moved back into Ret(0)
+use super::cfg::{BlockId, Cfg, Phi};
+use super::instructions::{Instruction, Reg};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+fn is_var(r: &Reg) -> bool {
+    matches!(r, Reg::Gp(_) | Reg::Ret(_))
+}
+
+/// Registers renamed by SSA that an instruction writes and reads
+/// Includes Ret(0) written by a call and read by a return
+pub(super) fn defs_uses(i: &Instruction) -> (Vec<Reg>, Vec<Reg>) {
+    let (mut defs, mut uses) = i.defs_uses();
+
+    match i {
+        Instruction::Call(..) => defs.push(Reg::Ret(0)),
+        Instruction::Ret => uses.push(Reg::Ret(0)),
+        _ => (),
+    }
+
+    defs.retain(is_var);
+    uses.retain(is_var);
+    (defs, uses)
+}
+
+/// Order copies that happen all at once so none overwrites a value another still needs
+/// A cycle, like a swap, is broken by saving one value in a temp
+fn sequence(mut copies: Vec<(Reg, Reg)>, next_reg: &mut usize) -> Vec<Instruction> {
+    copies.retain(|(d, s)| d != s);
+    let mut ret = vec![];
+
+    while !copies.is_empty() {
+        match copies.iter().position(|(d, _)| !copies.iter().any(|(_, s)| s == d)) {
+            Some(i) => {
+                let (d, s) = copies.remove(i);
+                ret.push(Instruction::Mov(d, s))
+            }
+            None => {
+                let d = copies[0].0;
+                let temp = Reg::Gp(*next_reg);
+                *next_reg += 1;
+                ret.push(Instruction::Mov(temp, d));
+
+                for c in copies.iter_mut().filter(|c| c.1 == d) {
+                    c.1 = temp
+                }
+            }
+        }
+    }
+
+    ret
+}
+
+enum Visit {
+    Enter(BlockId),
+    /// Done with a block's subtree, pop the names it pushed
+    Leave(Vec<Reg>),
+}
+
+impl Cfg {
+    /// Number of the first Gp reg not used anywhere
+    fn next_gp(&self) -> usize {
+        let regs = self.blocks.iter().flat_map(|b| {
+            let phis = b.phis.iter().flat_map(|p| p.args.iter().map(|a| a.1).chain([p.dest]));
+            let code = b.code.iter().flat_map(|i| {
+                let (defs, uses) = i.defs_uses();
+                defs.into_iter().chain(uses)
+            });
+            phis.chain(code).collect::<Vec<_>>()
+        });
+
+        regs.filter_map(|r| match r {
+            Reg::Gp(n) => Some(n + 1),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0)
+    }
+
+    /// Registers live on entry to each block, ignoring phis
+    fn live_in(&self) -> Vec<BTreeSet<Reg>> {
+        let mut live_in = vec![BTreeSet::new(); self.blocks.len()];
+        let order = self.reverse_post_order();
+        let mut changed = true;
+
+        while changed {
+            changed = false;
+
+            for b in order.iter().rev() {
+                let block = &self.blocks[*b];
+                let mut live: BTreeSet<Reg> = block.succs().iter().flat_map(|s| live_in[*s].clone()).collect();
+
+                for i in block.code.iter().rev() {
+                    let (defs, uses) = defs_uses(i);
+                    for d in defs {
+                        live.remove(&d);
+                    }
+                    live.extend(uses);
+                }
+
+                if live != live_in[*b] {
+                    live_in[*b] = live;
+                    changed = true;
+                }
+            }
+        }
+
+        live_in
+    }
+
+    /// Convert to SSA form
+    /// Phis only go where the register they're for is live, so none are dead
+    pub fn to_ssa(&mut self) {
+        let doms = self.dominators();
+        let frontiers = doms.frontiers(self);
+        let live_in = self.live_in();
+
+        // Blocks each register is written in
+        let mut sites: BTreeMap<Reg, BTreeSet<BlockId>> = BTreeMap::new();
+        for (b, block) in self.blocks.iter().enumerate() {
+            for i in &block.code {
+                for d in defs_uses(i).0 {
+                    sites.entry(d).or_default().insert(b);
+                }
+            }
+        }
+
+        // A block needs a phi where a def's domination ends, and the phi is a def too
+        let mut phi_vars: Vec<Vec<Reg>> = vec![vec![]; self.blocks.len()];
+
+        for (var, blocks) in sites {
+            let mut todo: Vec<_> = blocks.into_iter().collect();
+            let mut placed = BTreeSet::new();
+
+            while let Some(b) = todo.pop() {
+                for f in &frontiers[b] {
+                    if live_in[*f].contains(&var) && placed.insert(*f) {
+                        phi_vars[*f].push(var);
+                        self.blocks[*f].phis.push(Phi { dest: var, args: vec![] });
+                        todo.push(*f);
+                    }
+                }
+            }
+        }
+
+        // Rename down the dominator tree, each register's current name on top of its stack
+        let mut next_reg = self.next_gp();
+        let mut names: HashMap<Reg, Vec<Reg>> = HashMap::new();
+        let mut todo: Vec<_> = self.entries.iter().rev().map(|e| Visit::Enter(*e)).collect();
+
+        let current = |names: &HashMap<Reg, Vec<Reg>>, r: Reg| match is_var(&r) {
+            true => names.get(&r).and_then(|s| s.last()).copied().unwrap_or(r),
+            false => r,
+        };
+
+        while let Some(visit) = todo.pop() {
+            let b = match visit {
+                Visit::Enter(b) => b,
+                Visit::Leave(pushed) => {
+                    for r in pushed {
+                        names.get_mut(&r).unwrap().pop();
+                    }
+                    continue;
+                }
+            };
+
+            let mut pushed = vec![];
+
+            for (phi, var) in self.blocks[b].phis.iter_mut().zip(&phi_vars[b]) {
+                phi.dest = Reg::Gp(next_reg);
+                next_reg += 1;
+                names.entry(*var).or_default().push(phi.dest);
+                pushed.push(*var);
+            }
+
+            let mut code = vec![];
+
+            for i in std::mem::take(&mut self.blocks[b].code) {
+                let ret_val = current(&names, Reg::Ret(0));
+
+                if i == Instruction::Ret && ret_val != Reg::Ret(0) {
+                    code.push(Instruction::Mov(Reg::Ret(0), ret_val))
+                }
+
+                let new_names: HashMap<Reg, Reg> = i
+                    .defs_uses()
+                    .0
+                    .into_iter()
+                    .filter(is_var)
+                    .map(|d| {
+                        next_reg += 1;
+                        (d, Reg::Gp(next_reg - 1))
+                    })
+                    .collect();
+
+                code.push(i.rename(|d| new_names.get(&d).copied().unwrap_or(d), |u| current(&names, u)));
+
+                for (var, name) in new_names {
+                    names.entry(var).or_default().push(name);
+                    pushed.push(var);
+                }
+
+                if let Instruction::Call(..) = i {
+                    names.entry(Reg::Ret(0)).or_default().push(Reg::Ret(0));
+                    pushed.push(Reg::Ret(0));
+                }
+            }
+
+            self.blocks[b].code = code;
+
+            for s in self.blocks[b].succs() {
+                for (phi, var) in self.blocks[s].phis.iter_mut().zip(&phi_vars[s]) {
+                    phi.args.push((b, current(&names, *var)))
+                }
+            }
+
+            todo.push(Visit::Leave(pushed));
+            todo.extend(doms.children(b).iter().rev().map(|c| Visit::Enter(*c)));
+        }
+    }
+
+    /// Take the code out of SSA form, replacing phis with copies in their preds
+    pub fn from_ssa(&mut self) {
+        // Copies can't go ahead of a conditional branch as they'd happen whichever way it went
+        // so those edges get a block of their own
+        for b in 0..self.blocks.len() {
+            if self.blocks[b].phis.is_empty() {
+                continue;
+            }
+
+            for p in self.blocks[b].preds.clone() {
+                let last = self.blocks[p].code.last().copied();
+
+                if !last.is_some_and(|i| i.branch_target().is_some() && i.falls_through()) {
+                    continue;
+                }
+
+                let edge = self.add_block(b);
+                let pred = &mut self.blocks[p];
+
+                if let Some(i) = pred.code.last_mut().filter(|i| i.branch_target() == Some(b)) {
+                    *i = i.with_target(edge).unwrap()
+                }
+
+                if pred.next == Some(b) {
+                    pred.next = Some(edge)
+                }
+
+                for phi in &mut self.blocks[b].phis {
+                    for arg in phi.args.iter_mut().filter(|a| a.0 == p) {
+                        arg.0 = edge
+                    }
+                }
+            }
+        }
+
+        self.set_preds();
+        let mut next_reg = self.next_gp();
+
+        for b in 0..self.blocks.len() {
+            let phis = std::mem::take(&mut self.blocks[b].phis);
+
+            if phis.is_empty() {
+                continue;
+            }
+
+            for p in self.blocks[b].preds.clone() {
+                let copies = phis
+                    .iter()
+                    .filter_map(|phi| phi.args.iter().find(|a| a.0 == p).map(|a| (phi.dest, a.1)))
+                    .collect();
+
+                let code = &mut self.blocks[p].code;
+                let at = match code.last() {
+                    Some(i) if i.branch_target().is_some() => code.len() - 1,
+                    _ => code.len(),
+                };
+
+                code.splice(at..at, sequence(copies, &mut next_reg));
+            }
+        }
+
+        // Renaming made new Gp regs, make sure every fn's frame is big enough for them
+        for b in 0..self.blocks.len() {
+            let Some(Instruction::Enter(n)) = self.blocks[b].code.first().copied() else {
+                continue;
+            };
+
+            let mut seen = BTreeSet::new();
+            let mut todo = vec![b];
+
+            while let Some(b) = todo.pop() {
+                if seen.insert(b) {
+                    todo.extend(self.blocks[b].succs())
+                }
+            }
+
+            let size = seen
+                .iter()
+                .flat_map(|b| self.blocks[*b].code.iter())
+                .flat_map(|i| {
+                    let (defs, uses) = i.defs_uses();
+                    defs.into_iter().chain(uses)
+                })
+                .filter_map(|r| match r {
+                    Reg::Gp(n) => Some(n + 1),
+                    _ => None,
+                })
+                .fold(n, usize::max);
+
+            self.blocks[b].code[0] = Instruction::Enter(size);
+        }
+    }
+}
+
+#[allow(unused_imports)]
+mod test {
+    use super::*;
+    use crate::ir::instructions::exec;
+    use crate::ir::vm::Registers;
+    use pretty_assertions::assert_eq;
+
+    /// Every register written in the cfg, phi dests included
+    fn all_defs(cfg: &Cfg) -> Vec<Reg> {
+        cfg.blocks
+            .iter()
+            .flat_map(|b| {
+                let phis = b.phis.iter().map(|p| p.dest);
+                phis.chain(b.code.iter().flat_map(|i| defs_uses(i).0)).collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_to_ssa() {
+        use {Instruction::*, Reg::Gp};
+
+        // Swap a and b n times in a loop, then return a * 10 + b
+        let code = vec![
+            Load(Gp(0), 1),
+            Load(Gp(1), 2),
+            Load(Gp(2), 3),
+            Load(Gp(3), 1),
+            BrEq(Gp(2), Reg::Zero, 10),
+            Mov(Gp(4), Gp(0)),
+            Mov(Gp(0), Gp(1)),
+            Mov(Gp(1), Gp(4)),
+            Sub(Gp(2), Gp(2), Gp(3)),
+            Br(4),
+            Load(Gp(5), 10),
+            Mul(Gp(5), Gp(0), Gp(5)),
+            Add(Reg::Ret(0), Gp(5), Gp(1)),
+            Ret,
+        ];
+
+        let mut regs = Registers::default();
+        assert_eq!(exec(&code, &mut regs), Ok(21));
+
+        let mut cfg = Cfg::new(&code).unwrap();
+        cfg.to_ssa();
+
+        let defs = all_defs(&cfg);
+        let unique: BTreeSet<_> = defs.iter().collect();
+        assert_eq!(defs.len(), unique.len(), "{cfg}");
+
+        // The loop test joins a, b and n from before the loop and from the body
+        // the temp isn't live there so doesn't get one
+        let phis = &cfg.blocks[1].phis;
+        assert_eq!(phis.len(), 3, "{cfg}");
+        assert!(phis.iter().all(|p| p.args.iter().map(|a| a.0).eq([0, 2])), "{cfg}");
+
+        // What's returned is moved back into Ret(0)
+        let exit = &cfg.blocks[3].code;
+        assert!(matches!(exit[exit.len() - 2], Mov(Reg::Ret(0), Gp(_))), "{cfg}");
+
+        let mut round_trip = cfg.clone();
+        round_trip.from_ssa();
+        assert!(round_trip.blocks.iter().all(|b| b.phis.is_empty()));
+        let mut regs = Registers::default();
+        assert_eq!(exec(&round_trip.to_code(), &mut regs), Ok(21), "{round_trip}");
+
+        // Copy propagating the swap through the phis leaves them swapping each other
+        let (a, b) = (cfg.blocks[1].phis[0].dest, cfg.blocks[1].phis[1].dest);
+        cfg.blocks[1].phis[0].args[1].1 = b;
+        cfg.blocks[1].phis[1].args[1].1 = a;
+        cfg.from_ssa();
+        let mut regs = Registers::default();
+        assert_eq!(exec(&cfg.to_code(), &mut regs), Ok(21), "{cfg}");
+    }
+
+    #[test]
+    fn test_sequence() {
+        use {Instruction::*, Reg::Gp};
+
+        let mut next_reg = 10;
+
+        let moves = sequence(vec![(Gp(1), Gp(2)), (Gp(3), Gp(1)), (Gp(4), Gp(4))], &mut next_reg);
+        assert_eq!(moves, vec![Mov(Gp(3), Gp(1)), Mov(Gp(1), Gp(2))]);
+
+        // A swap needs a temp
+        let moves = sequence(vec![(Gp(1), Gp(2)), (Gp(2), Gp(1))], &mut next_reg);
+        assert_eq!(moves, vec![Mov(Gp(10), Gp(1)), Mov(Gp(1), Gp(2)), Mov(Gp(2), Gp(10))]);
+        assert_eq!(next_reg, 11);
+    }
+}
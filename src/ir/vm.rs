@@ -0,0 +1,616 @@
+/// Register VM that runs the IR emitted by CodeGen
+use super::error::{IrError, IrErrorKind, IrResult};
+use super::instructions::{Instruction, Reg};
+use crate::value::Arities;
+
+////////////////////////////////////////////////////////////////////////////////
+/// Register file for the VM
+/// Reg::Zero always reads as zero, writes to it are thrown away
+#[derive(Clone, Debug, PartialEq)]
+pub struct Registers {
+    pub args: Vec<usize>,
+    pub gp: Vec<usize>,
+    pub ret: Vec<usize>,
+    pub globals: Vec<usize>,
+}
+
+impl Default for Registers {
+    fn default() -> Self {
+        Self::new(8, 256, 4, 64)
+    }
+}
+
+impl Registers {
+    pub fn new(num_args: usize, num_gp: usize, num_ret: usize, num_globals: usize) -> Self {
+        Self {
+            args: vec![0; num_args],
+            gp: vec![0; num_gp],
+            ret: vec![0; num_ret],
+            globals: vec![0; num_globals],
+        }
+    }
+
+    /// Make a register file big enough for every register this code uses
+    pub fn for_code(code: &[Instruction]) -> Self {
+        let mut ret = Self::default();
+
+        for r in code.iter().flat_map(regs_used) {
+            let (bank, n) = match r {
+                Reg::Zero => continue,
+                Reg::Arg(n) => (&mut ret.args, n),
+                Reg::Gp(n) => (&mut ret.gp, n),
+                Reg::Ret(n) => (&mut ret.ret, n),
+                Reg::Global(n) => (&mut ret.globals, n),
+            };
+
+            if n >= bank.len() {
+                bank.resize(n + 1, 0)
+            }
+        }
+
+        ret
+    }
+
+    pub fn get(&self, r: Reg) -> Result<usize, IrErrorKind> {
+        let v = match r {
+            Reg::Zero => Some(&0),
+            Reg::Arg(n) => self.args.get(n),
+            Reg::Gp(n) => self.gp.get(n),
+            Reg::Ret(n) => self.ret.get(n),
+            Reg::Global(n) => self.globals.get(n),
+        };
+
+        v.copied().ok_or(IrErrorKind::RegisterOutOfRange(r))
+    }
+
+    pub fn set(&mut self, r: Reg, val: usize) -> Result<(), IrErrorKind> {
+        let v = match r {
+            Reg::Zero => return Ok(()),
+            Reg::Arg(n) => self.args.get_mut(n),
+            Reg::Gp(n) => self.gp.get_mut(n),
+            Reg::Ret(n) => self.ret.get_mut(n),
+            Reg::Global(n) => self.globals.get_mut(n),
+        };
+
+        let v = v.ok_or(IrErrorKind::RegisterOutOfRange(r))?;
+        *v = val;
+        Ok(())
+    }
+}
+
+/// All of the registers an instruction reads or writes
+pub fn regs_used(i: &Instruction) -> Vec<Reg> {
+    use Instruction::*;
+    match *i {
+        Load(d, _) | LoadAddr(d, _) | Reload(d, _) => vec![d],
+        Spill(_, r) => vec![r],
+        Add(d, a, b) | Sub(d, a, b) | Mul(d, a, b) | Div(d, a, b) => vec![d, a, b],
+        Cmp(d, a, b) | And(d, a, b) | Or(d, a, b) => vec![d, a, b],
+        CmpBr(a, b, t) => vec![a, b, t],
+        Mov(d, a) | Test(d, a) => vec![d, a],
+        Jmp(t) | Call(t, _) | TailCall(t, _) => vec![t],
+        Br(_) | BrArgc(..) | BrArgcMin(..) | NoArity(..) => vec![],
+        BrEq(a, b, _) => vec![a, b],
+        Ret | Enter(_) => vec![],
+        Env(d, _) | Rest(d, _) => vec![d],
+        NewClosure(d, e) => vec![d, e],
+        Capture(c, v) => vec![c, v],
+        NewMap(d) | NewArray(d) => vec![d],
+        Insert(m, k, v) => vec![m, k, v],
+        Push(a, v) => vec![a, v],
+        Get(d, m, k) | Slice(d, m, k) => vec![d, m, k],
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// A value that refers to something, rather than being a number, has a tag in its top bits
+/// so a result can be turned back into a Value without knowing its type
+/// Numbers with these bits set can't be told apart from references
+const TAG_MASK: usize = 0xf << 60;
+const HEAP_TAG: usize = 0x4 << 60;
+const CONSTANT_TAG: usize = 0x5 << 60;
+const NULL_TAG: usize = 0x6 << 60;
+const BOOL_TAG: usize = 0x7 << 60;
+
+/// Null and the bools are tagged too so they can't be mistaken for 0 and 1
+pub const NULL: usize = NULL_TAG;
+pub const FALSE: usize = BOOL_TAG;
+pub const TRUE: usize = BOOL_TAG | 1;
+
+pub fn from_bool(b: bool) -> usize {
+    if b {
+        TRUE
+    } else {
+        FALSE
+    }
+}
+
+/// 0, false and null are false, anything else is true
+pub fn is_truthy(v: usize) -> bool {
+    !matches!(v, 0 | FALSE | NULL)
+}
+
+fn untag(v: usize, tag: usize) -> Option<usize> {
+    (v & TAG_MASK == tag).then_some(v & !TAG_MASK)
+}
+
+/// The value that refers to the nth heap object
+pub fn heap_ref(n: usize) -> usize {
+    HEAP_TAG | n
+}
+
+/// The index of the heap object this value refers to, if it refers to one
+pub fn heap_index(v: usize) -> Option<usize> {
+    untag(v, HEAP_TAG)
+}
+
+/// The value that refers to the nth of the program's constants
+pub fn constant_ref(n: usize) -> usize {
+    CONSTANT_TAG | n
+}
+
+/// The index of the constant this value refers to, if it refers to one
+pub fn constant_index(v: usize) -> Option<usize> {
+    untag(v, CONSTANT_TAG)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// Things that live on the VM heap
+/// Registers refer to them with a handle from heap_ref
+#[derive(Clone, Debug, PartialEq)]
+pub enum HeapObject {
+    Map(Vec<(usize, usize)>),
+    Array(Vec<usize>),
+    /// A function's entry point with the values it captured when it was made
+    Closure { entry: usize, captures: Vec<usize> },
+}
+
+/// Activation record for a call
+/// Holds the caller's state while the callee runs in a fresh frame
+/// The record below it on the stack is the caller's own
+#[derive(Clone, Debug)]
+struct ActivationRecord {
+    return_pc: usize,
+    args: Vec<usize>,
+    gp: Vec<usize>,
+    stack: Vec<usize>,
+    /// The caller's captured values
+    env: Vec<usize>,
+    argc: usize,
+}
+
+const MAX_CALL_DEPTH: usize = 10_000;
+
+////////////////////////////////////////////////////////////////////////////////
+pub struct Machine<'a> {
+    code: &'a [Instruction],
+    regs: &'a mut Registers,
+    pc: usize,
+    frames: Vec<ActivationRecord>,
+    heap: Vec<HeapObject>,
+    /// Spill slots of the running call
+    stack: Vec<usize>,
+    /// Values captured by the closure that's running
+    env: Vec<usize>,
+    /// Number of args the running call was given
+    argc: usize,
+}
+
+impl<'a> Machine<'a> {
+    pub fn new(code: &'a [Instruction], regs: &'a mut Registers) -> Self {
+        Self {
+            code,
+            regs,
+            pc: 0,
+            frames: vec![],
+            heap: vec![],
+            stack: vec![],
+            env: vec![],
+            argc: 0,
+        }
+    }
+
+    pub fn get_pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn get_heap_object(&self, handle: usize) -> Option<&HeapObject> {
+        heap_index(handle).and_then(|i| self.heap.get(i))
+    }
+
+    fn alloc(&mut self, d: Reg, obj: HeapObject) -> IrResult<()> {
+        self.heap.push(obj);
+        self.set(d, heap_ref(self.heap.len() - 1))
+    }
+
+    fn heap_object_mut(&mut self, r: Reg) -> IrResult<&mut HeapObject> {
+        let handle = self.get(r)?;
+        let err = self.err(IrErrorKind::IllegalHeapRef(handle));
+        heap_index(handle).and_then(|i| self.heap.get_mut(i)).ok_or(err)
+    }
+
+    fn err(&self, kind: IrErrorKind) -> IrError {
+        IrError::new(kind, self.pc)
+    }
+
+    fn get(&self, r: Reg) -> IrResult<usize> {
+        self.regs.get(r).map_err(|k| self.err(k))
+    }
+
+    fn set(&mut self, r: Reg, val: usize) -> IrResult<()> {
+        self.regs.set(r, val).map_err(|k| self.err(k))
+    }
+
+    fn binop(&mut self, d: Reg, a: Reg, b: Reg, f: impl Fn(usize, usize) -> usize) -> IrResult<()> {
+        let v = f(self.get(a)?, self.get(b)?);
+        self.set(d, v)
+    }
+
+    /// Heap objects are equal if what's in them is, like the interpreter's values
+    fn equal(&self, a: usize, b: usize) -> bool {
+        if a == b {
+            return true;
+        }
+
+        let all_equal = |a: &[usize], b: &[usize]| {
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| self.equal(*a, *b))
+        };
+
+        match (self.get_heap_object(a), self.get_heap_object(b)) {
+            (Some(HeapObject::Array(a)), Some(HeapObject::Array(b))) => all_equal(a, b),
+            (Some(HeapObject::Map(a)), Some(HeapObject::Map(b))) => {
+                let (ka, va): (Vec<_>, Vec<_>) = a.iter().copied().unzip();
+                let (kb, vb): (Vec<_>, Vec<_>) = b.iter().copied().unzip();
+                all_equal(&ka, &kb) && all_equal(&va, &vb)
+            }
+            (
+                Some(HeapObject::Closure { entry, captures }),
+                Some(HeapObject::Closure { entry: entry_b, captures: captures_b }),
+            ) => entry == entry_b && all_equal(captures, captures_b),
+            _ => false,
+        }
+    }
+
+    /// The entry point and captured values of the closure in register r
+    fn get_closure(&self, r: Reg) -> IrResult<(usize, Vec<usize>)> {
+        let handle = self.get(r)?;
+
+        match self.get_heap_object(handle) {
+            Some(HeapObject::Closure { entry, captures }) => {
+                Ok((self.check_target(*entry)?, captures.clone()))
+            }
+            _ => Err(self.err(IrErrorKind::NotAFunction(handle))),
+        }
+    }
+
+    /// Work out where a jump through register r lands
+    fn jump_target(&self, r: Reg) -> IrResult<usize> {
+        let target = self.get(r)?;
+        self.check_target(target)
+    }
+
+    fn check_target(&self, target: usize) -> IrResult<usize> {
+        if target < self.code.len() {
+            Ok(target)
+        } else {
+            Err(self.err(IrErrorKind::BadJumpTarget(target)))
+        }
+    }
+
+    /// Execute a single instruction
+    /// Returns true if the machine has hit a Ret
+    pub fn step(&mut self) -> IrResult<bool> {
+        use Instruction::*;
+
+        let i = *self
+            .code
+            .get(self.pc)
+            .ok_or_else(|| self.err(IrErrorKind::RanOffEnd))?;
+
+        let mut next_pc = self.pc + 1;
+
+        match i {
+            Ret => match self.frames.pop() {
+                Some(record) => {
+                    self.regs.args = record.args;
+                    self.regs.gp = record.gp;
+                    self.stack = record.stack;
+                    self.env = record.env;
+                    self.argc = record.argc;
+                    next_pc = record.return_pc;
+                }
+                None => return Ok(true),
+            },
+            Call(t, argc) => {
+                if self.frames.len() >= MAX_CALL_DEPTH {
+                    return Err(self.err(IrErrorKind::StackOverflow));
+                }
+
+                let (target, captures) = self.get_closure(t)?;
+                self.frames.push(ActivationRecord {
+                    return_pc: next_pc,
+                    args: self.regs.args.clone(),
+                    gp: std::mem::take(&mut self.regs.gp),
+                    stack: std::mem::take(&mut self.stack),
+                    env: std::mem::replace(&mut self.env, captures),
+                    argc: std::mem::replace(&mut self.argc, argc),
+                });
+                next_pc = target
+            }
+            TailCall(t, argc) => {
+                let (target, captures) = self.get_closure(t)?;
+                self.env = captures;
+                self.argc = argc;
+                next_pc = target
+            }
+            BrArgc(n, t) => {
+                if self.argc == n {
+                    next_pc = self.check_target(t)?
+                }
+            }
+            BrArgcMin(n, t) => {
+                if self.argc >= n {
+                    next_pc = self.check_target(t)?
+                }
+            }
+            NoArity(mask, rest) => {
+                let fixed = (0..usize::BITS as usize).filter(|n| mask & (1 << n) != 0).collect();
+                let arities = Arities { fixed, rest };
+                let found = self.argc;
+                return Err(self.err(IrErrorKind::NoMatchingArity { found, arities }));
+            }
+            Rest(d, n) => {
+                let vals = (n..self.argc)
+                    .map(|i| self.get(Reg::Arg(i)))
+                    .collect::<IrResult<_>>()?;
+                self.alloc(d, HeapObject::Array(vals))?
+            }
+            Enter(n) => {
+                self.regs.gp = vec![0; n];
+                self.stack.clear()
+            }
+            Spill(n, r) => {
+                let v = self.get(r)?;
+                if n >= self.stack.len() {
+                    self.stack.resize(n + 1, 0)
+                }
+                self.stack[n] = v
+            }
+            Reload(d, n) => self.set(d, self.stack.get(n).copied().unwrap_or(0))?,
+            Env(d, n) => {
+                let v = *self.env.get(n).ok_or_else(|| self.err(IrErrorKind::BadCapture(n)))?;
+                self.set(d, v)?
+            }
+            NewClosure(d, e) => {
+                let entry = self.get(e)?;
+                let captures = vec![];
+                self.alloc(d, HeapObject::Closure { entry, captures })?
+            }
+            Capture(c, v) => {
+                let v = self.get(v)?;
+                let handle = self.get(c)?;
+                match self.heap_object_mut(c)? {
+                    HeapObject::Closure { captures, .. } => captures.push(v),
+                    _ => return Err(self.err(IrErrorKind::NotAFunction(handle))),
+                }
+            }
+            NewMap(d) => self.alloc(d, HeapObject::Map(vec![]))?,
+            NewArray(d) => self.alloc(d, HeapObject::Array(vec![]))?,
+            Insert(m, k, v) => {
+                let (k, v) = (self.get(k)?, self.get(v)?);
+                match self.heap_object_mut(m)? {
+                    HeapObject::Map(pairs) => match pairs.iter_mut().find(|(pk, _)| *pk == k) {
+                        Some(pair) => pair.1 = v,
+                        None => pairs.push((k, v)),
+                    },
+                    _ => return Err(self.err(IrErrorKind::NotAMap)),
+                }
+            }
+            Push(a, v) => {
+                let v = self.get(v)?;
+                match self.heap_object_mut(a)? {
+                    HeapObject::Array(vals) => vals.push(v),
+                    _ => return Err(self.err(IrErrorKind::NotAnArray)),
+                }
+            }
+            Get(d, m, k) => {
+                let k = self.get(k)?;
+                // Like the interpreter, looking up a key in anything that isn't a map gives null
+                let v = match heap_index(self.get(m)?) {
+                    None => None,
+                    Some(_) => match self.heap_object_mut(m)? {
+                        HeapObject::Map(pairs) => pairs.iter().find(|(pk, _)| *pk == k).map(|p| p.1),
+                        HeapObject::Array(vals) => vals.get(k).copied(),
+                        HeapObject::Closure { .. } => None,
+                    },
+                };
+                self.set(d, v.unwrap_or(NULL))?
+            }
+            Slice(d, a, n) => {
+                let n = self.get(n)?;
+                let vals = match self.heap_object_mut(a)? {
+                    HeapObject::Array(vals) => vals.iter().skip(n).copied().collect(),
+                    _ => return Err(self.err(IrErrorKind::NotAnArray)),
+                };
+                self.alloc(d, HeapObject::Array(vals))?
+            }
+            Load(d, v) | LoadAddr(d, v) => self.set(d, v)?,
+            Add(d, a, b) => self.binop(d, a, b, usize::wrapping_add)?,
+            Sub(d, a, b) => self.binop(d, a, b, usize::wrapping_sub)?,
+            Mul(d, a, b) => self.binop(d, a, b, usize::wrapping_mul)?,
+            Div(d, a, b) => {
+                let (a, b) = (self.get(a)? as i64, self.get(b)? as i64);
+                if b == 0 {
+                    return Err(self.err(IrErrorKind::DivisionByZero));
+                }
+                self.set(d, a.wrapping_div(b) as usize)?
+            }
+            Mov(d, a) => {
+                let v = self.get(a)?;
+                self.set(d, v)?
+            }
+            Cmp(d, a, b) => {
+                let eq = self.equal(self.get(a)?, self.get(b)?);
+                self.set(d, from_bool(eq))?
+            }
+            And(d, a, b) => self.binop(d, a, b, |a, b| from_bool(is_truthy(a) && is_truthy(b)))?,
+            Or(d, a, b) => self.binop(d, a, b, |a, b| from_bool(is_truthy(a) || is_truthy(b)))?,
+            Test(d, a) => {
+                let v = self.get(a)?;
+                self.set(d, is_truthy(v) as usize)?
+            }
+            CmpBr(a, b, t) => {
+                if self.get(a)? == self.get(b)? {
+                    next_pc = self.jump_target(t)?
+                }
+            }
+            Jmp(t) => next_pc = self.jump_target(t)?,
+            Br(t) => next_pc = self.check_target(t)?,
+            BrEq(a, b, t) => {
+                if self.get(a)? == self.get(b)? {
+                    next_pc = self.check_target(t)?
+                }
+            }
+        }
+
+        self.pc = next_pc;
+        Ok(false)
+    }
+
+    /// Run until a Ret
+    /// Returns the contents of Ret(0)
+    pub fn run(&mut self) -> IrResult<usize> {
+        while !self.step()? {}
+        self.get(Reg::Ret(0))
+    }
+}
+
+#[allow(unused_imports)]
+mod test {
+    use super::*;
+    use crate::ir::instructions::exec;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_arith() {
+        use {Instruction::*, Reg::{Gp, Zero}};
+
+        let code = [
+            Load(Gp(0), 10),
+            Load(Gp(1), 3),
+            Mul(Gp(2), Gp(0), Gp(1)),
+            Sub(Gp(2), Gp(2), Gp(1)),
+            Div(Reg::Ret(0), Gp(2), Gp(1)),
+            Ret,
+        ];
+
+        let mut regs = Registers::default();
+        assert_eq!(exec(&code, &mut regs), Ok(9));
+    }
+
+    #[test]
+    fn test_branch() {
+        use {Instruction::*, Reg::{Gp, Zero}};
+
+        // Count Gp(0) down to zero, adding 2 to Ret(0) each time
+        let code = [
+            Load(Gp(0), 5),
+            Load(Gp(1), 1),
+            Load(Gp(2), 2),
+            Load(Gp(3), 9),
+            Load(Gp(4), 5),
+            CmpBr(Gp(0), Zero, Gp(3)),
+            Add(Reg::Ret(0), Reg::Ret(0), Gp(2)),
+            Sub(Gp(0), Gp(0), Gp(1)),
+            Jmp(Gp(4)),
+            Ret,
+        ];
+
+        let mut regs = Registers::default();
+        assert_eq!(exec(&code, &mut regs), Ok(10));
+    }
+
+    #[test]
+    fn test_closure() {
+        use {Instruction::*, Reg::{Arg, Gp}};
+
+        // A closure that adds its captured value to its arg
+        let code = [
+            Load(Gp(0), 7),
+            NewClosure(Gp(1), Gp(0)),
+            Load(Gp(2), 40),
+            Capture(Gp(1), Gp(2)),
+            Load(Arg(0), 2),
+            Call(Gp(1), 1),
+            Ret,
+            Enter(1),
+            Env(Gp(0), 0),
+            Add(Reg::Ret(0), Gp(0), Arg(0)),
+            Ret,
+        ];
+
+        let mut regs = Registers::default();
+        assert_eq!(exec(&code, &mut regs), Ok(42));
+
+        // Calling something that isn't a closure
+        let code = [Load(Gp(0), 3), Call(Gp(0), 0)];
+        let err = exec(&code, &mut regs).unwrap_err();
+        assert_eq!(err.kind, IrErrorKind::NotAFunction(3));
+    }
+
+    #[test]
+    fn test_rest_args() {
+        use {Instruction::*, Reg::{Arg, Gp}};
+
+        // A closure taking one or more args that returns the second of its rest args
+        let code = |argc| {
+            [
+                Load(Gp(0), 7),
+                NewClosure(Gp(1), Gp(0)),
+                Load(Arg(0), 1),
+                Load(Arg(1), 20),
+                Load(Arg(2), 22),
+                Call(Gp(1), argc),
+                Ret,
+                BrArgcMin(1, 9),
+                NoArity(0, Some(1)),
+                Enter(2),
+                Rest(Gp(0), 1),
+                Load(Gp(1), 1),
+                Get(Reg::Ret(0), Gp(0), Gp(1)),
+                Ret,
+            ]
+        };
+
+        let mut regs = Registers::default();
+        assert_eq!(exec(&code(3), &mut regs), Ok(22));
+
+        let err = exec(&code(0), &mut regs).unwrap_err();
+        let arities = Arities { fixed: vec![], rest: Some(1) };
+        assert_eq!(err.kind, IrErrorKind::NoMatchingArity { found: 0, arities });
+        assert_eq!(err.kind.to_string(), "No body of this fn takes 0 args, it takes 1 or more");
+    }
+
+    #[test]
+    fn test_errors() {
+        use {Instruction::*, Reg::{Gp, Zero}};
+
+        let mut regs = Registers::new(1, 1, 1, 0);
+
+        let code = [Load(Gp(0), 1), Div(Reg::Ret(0), Gp(0), Zero), Ret];
+        let err = exec(&code, &mut regs).unwrap_err();
+        assert_eq!(err, IrError::new(IrErrorKind::DivisionByZero, 1));
+
+        let code = [Load(Gp(0), 100), Jmp(Gp(0))];
+        let err = exec(&code, &mut regs).unwrap_err();
+        assert_eq!(err, IrError::new(IrErrorKind::BadJumpTarget(100), 1));
+
+        let code = [Load(Gp(1), 1), Ret];
+        let err = exec(&code, &mut regs).unwrap_err();
+        assert_eq!(err.kind, IrErrorKind::RegisterOutOfRange(Gp(1)));
+
+        let code = [Mov(Zero, Gp(0))];
+        let err = exec(&code, &mut regs).unwrap_err();
+        assert_eq!(err.kind, IrErrorKind::RanOffEnd);
+    }
+}
@@ -1,9 +1,14 @@
 #![allow(dead_code)]
 #![allow(unused_imports)]
 
+pub mod builtins;
 pub mod cli;
+pub mod diagnostics;
 pub mod error;
+pub mod fmt;
 pub mod frontend;
+pub mod interpreter;
+pub mod lsp;
 pub mod opts;
 pub mod symbols;
 pub mod value;
@@ -0,0 +1,166 @@
+/// An open document and the result of compiling it
+use super::protocol::{Location, Position, Range};
+use crate::builtins::get_builtin;
+use crate::frontend::{FrontEndError, Module, ModuleJob};
+use crate::opts::Opts;
+use crate::sources::{SourceFile, SourceOrigin};
+use serde_json::{json, Value};
+
+pub struct Document {
+    pub uri: String,
+    source: SourceFile,
+    module: Result<Module, Vec<FrontEndError>>,
+}
+
+impl Document {
+    pub fn new(opts: &Opts, uri: &str, text: &str) -> Self {
+        let source = SourceFile::new(text.to_owned(), SourceOrigin::Text);
+        let module = Module::new(ModuleJob::new(opts, &source));
+
+        Self {
+            uri: uri.to_owned(),
+            source,
+            module,
+        }
+    }
+
+    fn text(&self) -> &str {
+        self.source.text()
+    }
+
+    /// Convert a byte offset to an LSP position
+    pub fn to_position(&self, offset: usize) -> Position {
+        let offset = offset.min(self.text().len());
+        let before = &self.text()[..offset];
+        let line_start = before.rfind('\n').map(|n| n + 1).unwrap_or(0);
+
+        Position {
+            line: before.matches('\n').count(),
+            character: before[line_start..].encode_utf16().count(),
+        }
+    }
+
+    /// Convert an LSP position to a byte offset
+    pub fn to_offset(&self, pos: Position) -> Option<usize> {
+        let line_start = self.source.get_offset(pos.line, 0)?;
+        let line = self.source.get_line(pos.line)?;
+        let mut utf16 = 0;
+
+        for (i, c) in line.char_indices() {
+            if utf16 >= pos.character || c == '\n' {
+                return Some(line_start + i);
+            }
+            utf16 += c.len_utf16();
+        }
+
+        Some(line_start + line.len())
+    }
+
+    pub fn to_range(&self, r: &std::ops::Range<usize>) -> Range {
+        Range {
+            start: self.to_position(r.start),
+            end: self.to_position(r.end),
+        }
+    }
+
+    pub fn diagnostics(&self) -> Vec<Value> {
+        let Err(errors) = &self.module else {
+            return vec![];
+        };
+
+        errors.iter().map(|e| self.diagnostic(e)).collect()
+    }
+
+    fn diagnostic(&self, e: &FrontEndError) -> Value {
+        let d = e.to_diagnostic(&self.source);
+        let notes = d.notes.iter().map(|n| format!("note: {n}"));
+        let help = d.help.iter().map(|h| format!("help: {h}"));
+
+        let (range, message) = match &e.file {
+            // The error is in an imported file, so there's nowhere in this one to point at
+            Some(file) => (0..0, format!("{}: {}", file.origin, d.message)),
+            None => (e.pos.clone(), d.message.clone()),
+        };
+        let message: Vec<_> = [message].into_iter().chain(notes).chain(help).collect();
+
+        json!({
+            "range": self.to_range(&range),
+            "severity": d.level.lsp_severity(),
+            "source": "ploy",
+            "code": d.code,
+            "message": message.join("\n"),
+        })
+    }
+
+    pub fn definition(&self, pos: Position) -> Option<Location> {
+        let module = self.module.as_ref().ok()?;
+        let (_, sym_id) = module.get_symbol_at(self.to_offset(pos)?)?;
+        let def = module.get_definition(sym_id)?;
+        let node = module.ast.tree.get(def)?.value();
+
+        match &node.text_span.origin {
+            // Defined in an imported module
+            SourceOrigin::File(_, path) if node.text_span.origin != self.source.origin => {
+                let file = module.ast.get_source_file_for_node(def);
+                let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.clone());
+
+                Some(Location {
+                    uri: format!("file://{}", path.to_string_lossy()),
+                    range: range_in_file(file, &node.text_range)?,
+                })
+            }
+
+            _ => Some(Location {
+                uri: self.uri.clone(),
+                range: self.to_range(&node.text_range),
+            }),
+        }
+    }
+
+    pub fn hover(&self, pos: Position) -> Option<Value> {
+        let module = self.module.as_ref().ok()?;
+        let (id, sym_id) = module.get_symbol_at(self.to_offset(pos)?)?;
+        let info = module.syms.get_symbol_info_from_id(sym_id).ok()?;
+        let name = info.name();
+
+        let text = if let Some(b) = get_builtin(&module.syms, sym_id) {
+            let (min, max) = b.arity();
+            let args = match max {
+                Some(max) if max == min => format!("{min}"),
+                Some(max) => format!("{min} to {max}"),
+                None => format!("{min} or more"),
+            };
+            format!("`{name}` builtin, takes {args} args")
+        } else {
+            let def = module.get_definition(sym_id)?;
+            let r = &module.ast.tree.get(def)?.value().text_range;
+            let file = module.ast.get_source_file_for_node(def);
+            let line = file.get_line(file.get_location(r.start)?.line)?;
+            let scope = module.syms.get_fqn_from_id(sym_id.scope_id);
+            let ty = module.get_type(def);
+            format!("`{name}: {ty}` in `{scope}`\n```ploy\n{}\n```", line.trim_end())
+        };
+
+        let r = &module.ast.tree.get(id)?.value().text_range;
+
+        Some(json!({
+            "contents": { "kind": "markdown", "value": text },
+            "range": self.to_range(r),
+        }))
+    }
+}
+
+/// The LSP range of a span in a file that isn't open, spans that get here are symbols so they're on one line
+fn range_in_file(file: &SourceFile, r: &std::ops::Range<usize>) -> Option<Range> {
+    let span = file.get_file_span_from_range(r.clone())?.span;
+    let line = file.get_line(span.location.line)?;
+    let position = |col: usize| Position {
+        line: span.location.line,
+        character: line[..col.min(line.len())].encode_utf16().count(),
+    };
+
+    Some(Range {
+        start: position(span.location.col),
+        end: position(span.location.col + span.len),
+    })
+}
@@ -0,0 +1,10 @@
+/// Language server
+/// Speaks JSON-RPC over stdio and reuses the front end to
+/// publish diagnostics and answer go to definition and hover requests
+mod document;
+mod protocol;
+mod server;
+
+pub use document::Document;
+pub use protocol::{read_message, write_message, LspError, Location, Message, Position, Range};
+pub use server::Server;
@@ -0,0 +1,87 @@
+/// Message framing and the bits of the LSP data model we use
+/// Every message is a JSON body preceded by a Content-Length header
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::io::{BufRead, Write};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum LspError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("Bad header: {0}")]
+    BadHeader(String),
+    #[error("Message has no Content-Length header")]
+    NoContentLength,
+}
+
+/// Zero based line and UTF-16 column
+#[derive(Clone, Copy, Debug, PartialEq, Default, Serialize, Deserialize)]
+pub struct Position {
+    pub line: usize,
+    pub character: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Default, Serialize, Deserialize)]
+pub struct Range {
+    pub start: Position,
+    pub end: Position,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Location {
+    pub uri: String,
+    pub range: Range,
+}
+
+/// A request, response or notification
+#[derive(Clone, Debug, Deserialize)]
+pub struct Message {
+    pub id: Option<Value>,
+    pub method: Option<String>,
+    #[serde(default)]
+    pub params: Value,
+}
+
+/// Read the next message, None if the input is finished
+pub fn read_message<R: BufRead>(input: &mut R) -> Result<Option<Value>, LspError> {
+    let mut len = None;
+
+    loop {
+        let mut line = String::new();
+
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+
+        let line = line.trim_end();
+
+        if line.is_empty() {
+            break;
+        }
+
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| LspError::BadHeader(line.to_owned()))?;
+
+        if name.eq_ignore_ascii_case("Content-Length") {
+            let value = value.trim().parse::<usize>();
+            len = Some(value.map_err(|_| LspError::BadHeader(line.to_owned()))?);
+        }
+    }
+
+    let len = len.ok_or(LspError::NoContentLength)?;
+    let mut body = vec![0; len];
+    input.read_exact(&mut body)?;
+
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+pub fn write_message<W: Write>(output: &mut W, msg: &Value) -> Result<(), LspError> {
+    let body = serde_json::to_string(msg)?;
+    write!(output, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+    output.flush()?;
+    Ok(())
+}
@@ -0,0 +1,246 @@
+/// Dispatches client messages to the open documents
+use super::document::Document;
+use super::protocol::{read_message, write_message, LspError, Message, Position};
+use crate::opts::Opts;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, Write};
+
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INVALID_REQUEST: i64 = -32600;
+
+#[derive(Deserialize)]
+struct TextDocumentId {
+    uri: String,
+}
+
+#[derive(Deserialize)]
+struct TextDocumentItem {
+    uri: String,
+    text: String,
+}
+
+#[derive(Deserialize)]
+struct ContentChange {
+    text: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Open {
+    text_document: TextDocumentItem,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Change {
+    text_document: TextDocumentId,
+    content_changes: Vec<ContentChange>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Close {
+    text_document: TextDocumentId,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PositionParams {
+    text_document: TextDocumentId,
+    position: Position,
+}
+
+pub struct Server {
+    opts: Opts,
+    documents: HashMap<String, Document>,
+    shutting_down: bool,
+    /// Set from the [lsp] log-file key, stdout is taken by the protocol
+    log: Option<File>,
+}
+
+impl Server {
+    pub fn new(opts: &Opts) -> Self {
+        let log = opts.lsp.log_file.as_ref().and_then(|p| {
+            OpenOptions::new().create(true).append(true).open(p).ok()
+        });
+
+        Self {
+            opts: opts.clone(),
+            documents: Default::default(),
+            shutting_down: false,
+            log,
+        }
+    }
+
+    fn log(&mut self, text: &str) {
+        if let Some(log) = &mut self.log {
+            let _ = writeln!(log, "{text}");
+        }
+    }
+
+    /// Serve messages until the client sends exit or closes the input
+    /// A message that isn't valid JSON or isn't a message gets an error back, the rest still get served
+    pub fn run<R: BufRead, W: Write>(&mut self, mut input: R, mut output: W) -> Result<(), LspError> {
+        loop {
+            let msg = match read_message(&mut input) {
+                Ok(Some(msg)) => msg,
+                Ok(None) => break,
+                // The whole body was read, so the next message can still be found
+                Err(LspError::Json(e)) => {
+                    self.send(&mut output, &error(Value::Null, PARSE_ERROR, &e.to_string()))?;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            let id = msg.get("id").cloned().unwrap_or_default();
+            let msg: Message = match serde_json::from_value(msg) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    self.send(&mut output, &error(id, INVALID_REQUEST, &e.to_string()))?;
+                    continue;
+                }
+            };
+
+            self.log(&format!("<- {}", msg.method.as_deref().unwrap_or("response")));
+
+            if msg.method.as_deref() == Some("exit") {
+                break;
+            }
+
+            for reply in self.handle(msg) {
+                self.send(&mut output, &reply)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn send<W: Write>(&mut self, output: &mut W, reply: &Value) -> Result<(), LspError> {
+        if let Some(err) = reply.get("error") {
+            self.log(&format!("-> error {err}"));
+        }
+        write_message(output, reply)
+    }
+
+    /// Handle one message, returning the messages to send back
+    pub fn handle(&mut self, msg: Message) -> Vec<Value> {
+        let Some(method) = msg.method.as_deref() else {
+            // A response to something we sent, we never send requests
+            return vec![];
+        };
+
+        match (msg.id, method) {
+            (Some(id), _) if self.shutting_down => {
+                vec![error(id, INVALID_REQUEST, "Server is shutting down")]
+            }
+
+            (Some(id), method) => match self.request(method, msg.params) {
+                Ok(result) => vec![json!({ "jsonrpc": "2.0", "id": id, "result": result })],
+                Err((code, text)) => vec![error(id, code, &text)],
+            },
+
+            (None, method) => self.notification(method, msg.params),
+        }
+    }
+
+    fn request(&mut self, method: &str, params: Value) -> Result<Value, (i64, String)> {
+        match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "definitionProvider": true,
+                    "hoverProvider": true,
+                },
+                "serverInfo": { "name": "ploy", "version": env!("CARGO_PKG_VERSION") },
+            })),
+
+            "shutdown" => {
+                self.shutting_down = true;
+                Ok(Value::Null)
+            }
+
+            "textDocument/definition" => {
+                let p: PositionParams = parse_params(params)?;
+                let loc = self
+                    .documents
+                    .get(&p.text_document.uri)
+                    .and_then(|doc| doc.definition(p.position));
+                Ok(json!(loc))
+            }
+
+            "textDocument/hover" => {
+                let p: PositionParams = parse_params(params)?;
+                let hover = self
+                    .documents
+                    .get(&p.text_document.uri)
+                    .and_then(|doc| doc.hover(p.position));
+                Ok(json!(hover))
+            }
+
+            _ => Err((METHOD_NOT_FOUND, format!("Unknown method {method}"))),
+        }
+    }
+
+    fn notification(&mut self, method: &str, params: Value) -> Vec<Value> {
+        // Bad notifications can't be answered, so they're dropped
+        match method {
+            "textDocument/didOpen" => match parse_params::<Open>(params) {
+                Ok(p) => self.update(&p.text_document.uri, &p.text_document.text),
+                Err(_) => vec![],
+            },
+
+            "textDocument/didChange" => match parse_params::<Change>(params) {
+                // We ask for full syncs so the last change is the whole text
+                Ok(p) => match p.content_changes.last() {
+                    Some(change) => self.update(&p.text_document.uri, &change.text),
+                    None => vec![],
+                },
+                Err(_) => vec![],
+            },
+
+            "textDocument/didClose" => match parse_params::<Close>(params) {
+                Ok(p) => {
+                    self.documents.remove(&p.text_document.uri);
+                    vec![publish_diagnostics(&p.text_document.uri, vec![])]
+                }
+                Err(_) => vec![],
+            },
+
+            _ => vec![],
+        }
+    }
+
+    /// Recompile a document and publish its diagnostics
+    fn update(&mut self, uri: &str, text: &str) -> Vec<Value> {
+        let doc = Document::new(&self.opts, uri, text);
+        let diags = doc.diagnostics();
+        self.documents.insert(uri.to_owned(), doc);
+        vec![publish_diagnostics(uri, diags)]
+    }
+}
+
+fn parse_params<T: for<'de> Deserialize<'de>>(params: Value) -> Result<T, (i64, String)> {
+    serde_json::from_value(params).map_err(|e| (INVALID_PARAMS, e.to_string()))
+}
+
+fn error(id: Value, code: i64, message: &str) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message },
+    })
+}
+
+fn publish_diagnostics(uri: &str, diagnostics: Vec<Value>) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": { "uri": uri, "diagnostics": diagnostics },
+    })
+}
@@ -3,7 +3,7 @@
 
 use anyhow::Context;
 use ploy::{
-    error::{to_full_error, PloyErrorKind},
+    error::{to_full_error, to_full_errors, PloyErrorKind},
     frontend::{Module, ModuleJob},
     *,
 };
@@ -14,19 +14,120 @@ use thiserror::Error;
 use frontend::FrontEndCtx;
 use opts::Opts;
 
-fn main() -> anyhow::Result<()> {
+fn main() {
+    let opts = match cli::parse_opts(opts::DEFAULT_PROJECT_FILE) {
+        Ok(opts) => opts,
+        Err(e) => exit_with(&e.into(), opts::MessageFormat::Human),
+    };
 
-    let opts = cli::parse_opts(opts::DEFAULT_PROJECT_FILE)?;
+    if let Err(e) = run(&opts) {
+        exit_with(&e, opts.message_format)
+    }
+}
+
+/// Show the error in the format asked for and quit
+/// JSON errors go to stdout, one object a line, so tools can read them
+fn exit_with(e: &PloyErrorKind, format: opts::MessageFormat) -> ! {
+    match format {
+        opts::MessageFormat::Json => {
+            for j in e.to_json() {
+                println!("{j}");
+            }
+        }
+
+        opts::MessageFormat::Human => {
+            let colour = diagnostics::use_colour();
+            eprint!("{}", diagnostics::render_all(&e.diagnostics(), colour));
+        }
+    }
+
+    std::process::exit(1);
+}
+
+fn run(opts: &Opts) -> Result<(), PloyErrorKind> {
+    if let opts::Action::Lsp = opts.action {
+        let mut server = lsp::Server::new(opts);
+        server.run(std::io::stdin().lock(), std::io::stdout().lock())?;
+        return Ok(());
+    }
 
     let mut loader = sources::SourceLoader::new();
 
+    for p in opts.search_paths() {
+        loader.add_search_path(p)?;
+    }
+
+    if let opts::Action::Fmt = opts.action {
+        return fmt(opts, &mut loader);
+    }
+
     let id = loader.load_file(&opts.project_file)?;
     let sf = loader.get_source_file(id).expect("source file");
 
-    let job = ModuleJob::new(&opts, sf);
-    let _module: Module = job.try_into()?;
+    let job = ModuleJob::new(opts, sf);
+    let module: Module = job.try_into()?;
+
+    match opts.action {
+        opts::Action::Run => {
+            let program = compile::Program::new(&module, &opts.codegen)?;
+            let value = program.run()?;
+            println!("{value}");
+        }
+
+        // Nothing to say, a tool reading JSON only wants errors
+        _ if opts.message_format == opts::MessageFormat::Json => (),
+        _ => println!("Compiled fine"),
+    }
+
+    Ok(())
+}
+
+/// Format the project file and every source file under the source roots
+/// Checking only says which files would change, it's an error if any would
+fn fmt(opts: &Opts, loader: &mut sources::SourceLoader) -> Result<(), PloyErrorKind> {
+    let mut paths = vec![];
+
+    // With no entry point configured this is Ploy.toml itself, which isn't source
+    if ploy::fmt::is_source(&opts.project_file) {
+        paths.push(opts.project_file.clone());
+    }
+
+    for root in &opts.project.source_roots {
+        let found = ploy::fmt::find_sources(root)
+            .with_context(|| format!("Can't search source root {}", root.display()))?;
+        paths.extend(found);
+    }
+
+    let mut ids = vec![];
+    for p in paths {
+        ids.push(loader.load_file(p)?);
+    }
+    ids.sort();
+    ids.dedup();
+
+    let mut unformatted = 0;
+
+    for id in ids {
+        let sf = loader.get_source_file(id)?;
+        let sources::SourceOrigin::File(_, path) = &sf.origin else {
+            continue;
+        };
+
+        let text = ploy::fmt::format_source(sf).map_err(|e| to_full_errors(e, sf))?;
+
+        if text != sf.text() {
+            if opts.check_format {
+                println!("Would reformat {}", path.display());
+                unformatted += 1;
+            } else {
+                std::fs::write(path, text).with_context(|| format!("Can't write {}", path.display()))?;
+            }
+        }
+    }
 
-    println!("Compiled fine");
+    if unformatted > 0 {
+        return Err(anyhow::anyhow!("{unformatted} file(s) need formatting").into());
+    }
 
     Ok(())
 }
@@ -5,7 +5,9 @@ use std::path::PathBuf;
 pub enum Action {
     Build,
     Check,
+    Run,
     Lsp,
+    Fmt,
 }
 
 #[derive(Default,Debug, Clone, Deserialize, Copy)]
@@ -17,6 +19,118 @@ pub enum Verbosity {
     Debug,
 }
 
+/// The [project] table of a project file
+#[derive(Debug, Clone, Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct Project {
+    pub name: String,
+    #[serde(default)]
+    pub version: Option<String>,
+    /// Directories entry points are searched for in
+    #[serde(default)]
+    pub source_roots: Vec<PathBuf>,
+    #[serde(default)]
+    pub entry_points: Vec<PathBuf>,
+    /// Extra directories to search for source files
+    #[serde(default)]
+    pub search_paths: Vec<PathBuf>,
+}
+
+/// The [lsp] table of a project file
+#[derive(Debug, Clone, Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
+#[serde(default)]
+pub struct LspOpts {
+    pub log_file: Option<PathBuf>,
+}
+
+/// Optimisation passes, each can be turned off or have its effect dumped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Pass {
+    /// Work out arithmetic on literals at compile time
+    Fold,
+    /// Use the source of a copy in place of the copy
+    CopyProp,
+    /// Remove code whose result is never used
+    Dce,
+}
+
+impl Pass {
+    pub const ALL: [Pass; 3] = [Pass::Fold, Pass::CopyProp, Pass::Dce];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Pass::Fold => "fold",
+            Pass::CopyProp => "copy-prop",
+            Pass::Dce => "dce",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|p| p.name() == name)
+    }
+}
+
+/// The [codegen] table of a project file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+#[serde(default)]
+pub struct CodeGenOpts {
+    /// Number of Gp registers values are allocated to
+    pub registers: usize,
+    /// Passes that aren't run
+    pub disable: Vec<Pass>,
+    /// Passes that print the code before and after they run
+    pub dump: Vec<Pass>,
+}
+
+impl Default for CodeGenOpts {
+    fn default() -> Self {
+        Self {
+            registers: 16,
+            disable: vec![],
+            dump: vec![],
+        }
+    }
+}
+
+impl CodeGenOpts {
+    pub fn enabled(&self, pass: Pass) -> bool {
+        !self.disable.contains(&pass)
+    }
+
+    pub fn dumps(&self, pass: Pass) -> bool {
+        self.enabled(pass) && self.dump.contains(&pass)
+    }
+}
+
+/// How errors are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MessageFormat {
+    /// Rendered with the source they're in, for people to read
+    #[default]
+    Human,
+    /// One JSON object a line on stdout, for tools to read
+    Json,
+}
+
+impl MessageFormat {
+    pub const ALL: [MessageFormat; 2] = [MessageFormat::Human, MessageFormat::Json];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            MessageFormat::Human => "human",
+            MessageFormat::Json => "json",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|f| f.name() == name)
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 #[serde(default)]
@@ -24,16 +138,42 @@ pub struct Opts {
     pub project_file: PathBuf,
     pub action: Action,
     pub verbosity: Verbosity,
+    pub message_format: MessageFormat,
+    /// Fmt only reports files that would change, rather than changing them
+    #[serde(skip)]
+    pub check_format: bool,
+    /// Filled in from the other tables of the project file
+    #[serde(skip)]
+    pub project: Project,
+    #[serde(skip)]
+    pub lsp: LspOpts,
+    #[serde(skip)]
+    pub codegen: CodeGenOpts,
 }
 
 pub const DEFAULT_PROJECT_FILE : &str = "Ploy.toml";
 
+impl Opts {
+    /// Every directory source files can be found in
+    pub fn search_paths(&self) -> impl Iterator<Item = &PathBuf> {
+        self.project
+            .source_roots
+            .iter()
+            .chain(self.project.search_paths.iter())
+    }
+}
+
 impl Default for Opts {
     fn default() -> Self {
         Self {
             project_file: DEFAULT_PROJECT_FILE.to_owned().into(),
             action: Action::Check,
             verbosity: Default::default(),
+            message_format: Default::default(),
+            check_format: false,
+            project: Default::default(),
+            lsp: Default::default(),
+            codegen: Default::default(),
         }
     }
 }
@@ -34,6 +34,11 @@ impl SourceLoader {
         Default::default()
     }
 
+    pub fn add_search_path<P: AsRef<Path>>(&mut self, p: P) -> Result<(), SourcesError> {
+        self.searcher.add_path(p)?;
+        Ok(())
+    }
+
     pub fn resolve_file_path<P: AsRef<Path>>(&self, p: P) -> Result<PathBuf, SourcesError> {
         let ret  = self.searcher.search(&p)?;
         Ok(ret)
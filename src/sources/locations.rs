@@ -8,6 +8,15 @@ pub enum SourceOrigin {
     File(FileId, PathBuf),
 }
 
+impl std::fmt::Display for SourceOrigin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SourceOrigin::Text => write!(f, "<text>"),
+            SourceOrigin::File(_, path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
 /// Describes a point in some text
 #[derive(Clone, Debug, Copy, PartialEq,Default)]
 pub struct Location {
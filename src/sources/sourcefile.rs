@@ -25,6 +25,13 @@ impl SourceFile {
         self.lines.get_location_from_offset(_offset)
     }
 
+    /// Get the offset into the text of a line and column
+    pub fn get_offset(&self, line: usize, col: usize) -> Option<usize> {
+        let r = self.lines.get_line_range(line)?;
+        let offset = r.start + col;
+        (offset <= r.end).then_some(offset)
+    }
+
     pub fn get_line(&self, line: usize) -> Option<&str> {
         self.lines.get_line_range(line).map(|r| &self.text[r])
     }
@@ -18,8 +18,8 @@ fn compare_loc(offset: usize, x: &std::ops::Range<usize>) -> std::cmp::Ordering
 
 impl Lines {
     pub fn new(text: &str) -> Self {
-        let is_cr = |v| (v == b'\n');
-        let filter = |(i, v)| is_cr(v).then_some(i);
+        let is_cr = |v| v == b'\n';
+        let filter = |(i, v)| is_cr(v).then_some(i + 1);
 
         let offsets: Vec<_> = text.bytes().enumerate().filter_map(filter).collect();
         let x = vec![vec![0], offsets, vec![text.len()]]
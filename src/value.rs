@@ -1,4 +1,6 @@
+use super::builtins::BuiltIn;
 use super::frontend::AstNodeId;
+use super::symbols::SymbolScopeId;
 
 use std::sync::Arc;
 
@@ -6,6 +8,7 @@ use std::sync::Arc;
 pub enum Value {
     Unbound,
     Null,
+    Bool(bool),
     Macro,
     Float(f64),
     Signed(i64),
@@ -13,7 +16,14 @@ pub enum Value {
     Text(Arc<str>),
     Type(Box<TypeInfo>),
     Lambda(AstNodeId),
-    KeyWord,
+    /// A lambda with the values it captured when it was made
+    Closure(AstNodeId, Arc<Vec<(SymbolScopeId, Value)>>),
+    BuiltIn(BuiltIn),
+    /// A function compiled for the VM, with the address of its code
+    Code(usize),
+    KeyWord(Arc<str>),
+    Map(Arc<Vec<(Value, Value)>>),
+    Array(Arc<Vec<Value>>),
 }
 
 #[derive(Clone,Debug, PartialEq)]
@@ -44,7 +54,7 @@ pub struct TypeInfo {
 
 pub type OperationError<V> = Result<V, OperationErrorKind>;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum OperationErrorKind {
     IncompatibleOperands,
     IllegalNegation,
@@ -59,9 +69,21 @@ impl std::fmt::Display for Value {
             Unsigned(a) => format!("{a}u64"),
             Float(a) => format!("{a}f64"),
             Text(a) => format!("\"{a}\""),
+            Bool(a) => format!("{a}"),
+            KeyWord(a) => a.to_string(),
+            Lambda(_) | Closure(..) | Code(_) => "fn".to_string(),
             Macro => "macro".to_string(),
             Null => "null".to_string(),
             Unbound => "Unbound symbol".to_string(),
+            BuiltIn(b) => format!("builtin {b}"),
+            Map(pairs) => {
+                let pairs: Vec<_> = pairs.iter().map(|(k, v)| format!("{k} {v}")).collect();
+                format!("{{{}}}", pairs.join(" "))
+            }
+            Array(vals) => {
+                let vals: Vec<_> = vals.iter().map(|v| v.to_string()).collect();
+                format!("[{}]", vals.join(" "))
+            }
             _ => panic!(),
         };
 
@@ -69,6 +91,32 @@ impl std::fmt::Display for Value {
     }
 }
 
+/// Numbers of args a fn's bodies take, used when a call matches none of them
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Arities {
+    pub fixed: Vec<usize>,
+    /// Fewest args the body with a & rest param takes
+    pub rest: Option<usize>,
+}
+
+impl Arities {
+    pub fn accepts(&self, n: usize) -> bool {
+        self.fixed.contains(&n) || self.rest.map(|min| n >= min).unwrap_or(false)
+    }
+}
+
+impl std::fmt::Display for Arities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut all: Vec<_> = self.fixed.iter().map(|n| n.to_string()).collect();
+
+        if let Some(min) = self.rest {
+            all.push(format!("{min} or more"))
+        }
+
+        f.write_str(&all.join(" or "))
+    }
+}
+
 impl Value {
     pub fn is_number(&self) -> bool {
         matches!(
@@ -81,6 +129,27 @@ impl Value {
         matches!(self,Value::Unbound)
     }
 
+    pub fn is_zero(&self) -> bool {
+        matches!(self, Value::Signed(0) | Value::Unsigned(0)) || *self == Value::Float(0.0)
+    }
+
+    /// Null, false and zero are false, everything else is true
+    pub fn is_truthy(&self) -> bool {
+        !(self.is_zero() || matches!(self, Value::Null | Value::Bool(false)))
+    }
+
+    /// Look up a key in a map or an index in an array
+    /// Null if it isn't there
+    pub fn get(&self, key: &Value) -> Value {
+        let found = match (self, key) {
+            (Value::Map(pairs), _) => pairs.iter().find(|(k, _)| k == key).map(|p| p.1.clone()),
+            (Value::Array(vals), Value::Signed(i)) => vals.get(*i as usize).cloned(),
+            (Value::Array(vals), Value::Unsigned(i)) => vals.get(*i as usize).cloned(),
+            _ => None,
+        };
+        found.unwrap_or(Value::Null)
+    }
+
     pub fn into_double(self) -> Self {
         use Value::*;
         match self {
@@ -121,11 +190,11 @@ impl std::ops::Add for Value {
         use OperationErrorKind::*;
         use Value::*;
         match (self, &rhs) {
-            (Signed(a), Signed(b)) => Ok(Signed(a + b)),
-            (Signed(a), Unsigned(b)) => Ok(Signed(a + *b as i64)),
+            (Signed(a), Signed(b)) => Ok(Signed(a.wrapping_add(*b))),
+            (Signed(a), Unsigned(b)) => Ok(Signed(a.wrapping_add(*b as i64))),
             (Signed(a), Float(b)) => Ok(Float(a as f64 + b)),
-            (Unsigned(a), Signed(b)) => Ok(Signed(a as i64 + b)),
-            (Unsigned(a), Unsigned(b)) => Ok(Unsigned(a + b)),
+            (Unsigned(a), Signed(b)) => Ok(Signed((a as i64).wrapping_add(*b))),
+            (Unsigned(a), Unsigned(b)) => Ok(Unsigned(a.wrapping_add(*b))),
             (Unsigned(a), Float(b)) => Ok(Float(a as f64 + b)),
             (Float(a), Signed(b)) => Ok(Float(a + *b as f64)),
             (Float(a), Unsigned(b)) => Ok(Float(a + *b as f64)),
@@ -150,11 +219,11 @@ impl std::ops::Sub for Value {
         use OperationErrorKind::*;
         use Value::*;
         match (self, &rhs) {
-            (Signed(a), Signed(b)) => Ok(Signed(a - b)),
-            (Signed(a), Unsigned(b)) => Ok(Signed(a - *b as i64)),
+            (Signed(a), Signed(b)) => Ok(Signed(a.wrapping_sub(*b))),
+            (Signed(a), Unsigned(b)) => Ok(Signed(a.wrapping_sub(*b as i64))),
             (Signed(a), Float(b)) => Ok(Float(a as f64 - b)),
-            (Unsigned(a), Signed(b)) => Ok(Signed(a as i64 - b)),
-            (Unsigned(a), Unsigned(b)) => Ok(Unsigned(a - b)),
+            (Unsigned(a), Signed(b)) => Ok(Signed((a as i64).wrapping_sub(*b))),
+            (Unsigned(a), Unsigned(b)) => Ok(Unsigned(a.wrapping_sub(*b))),
             (Unsigned(a), Float(b)) => Ok(Float(a as f64 - b)),
             (Float(a), Signed(b)) => Ok(Float(a - *b as f64)),
             (Float(a), Unsigned(b)) => Ok(Float(a - *b as f64)),
@@ -162,7 +231,7 @@ impl std::ops::Sub for Value {
             (Null, _) | (_, Null) | (Macro, _) | (_, Macro) | (Text(_), _) | (_, Text(_)) => {
                 Err(IncompatibleOperands)
             }
-            _ => panic!(),
+            _ => Err(IncompatibleOperands),
         }
     }
 }
@@ -174,11 +243,11 @@ impl std::ops::Mul for Value {
         use Value::*;
 
         match (self, &rhs) {
-            (Signed(a), Signed(b)) => Ok(Signed(a * b)),
-            (Signed(a), Unsigned(b)) => Ok(Signed(a * *b as i64)),
+            (Signed(a), Signed(b)) => Ok(Signed(a.wrapping_mul(*b))),
+            (Signed(a), Unsigned(b)) => Ok(Signed(a.wrapping_mul(*b as i64))),
             (Signed(a), Float(b)) => Ok(Float(a as f64 * b)),
-            (Unsigned(a), Signed(b)) => Ok(Signed(a as i64 * b)),
-            (Unsigned(a), Unsigned(b)) => Ok(Unsigned(a * b)),
+            (Unsigned(a), Signed(b)) => Ok(Signed((a as i64).wrapping_mul(*b))),
+            (Unsigned(a), Unsigned(b)) => Ok(Unsigned(a.wrapping_mul(*b))),
             (Unsigned(a), Float(b)) => Ok(Float(a as f64 * b)),
             (Float(a), Signed(b)) => Ok(Float(a * *b as f64)),
             (Float(a), Unsigned(b)) => Ok(Float(a * *b as f64)),
@@ -186,7 +255,7 @@ impl std::ops::Mul for Value {
             (Null, _) | (_, Null) | (Macro, _) | (_, Macro) | (Text(_), _) | (_, Text(_)) => {
                 Err(IncompatibleOperands)
             }
-            _ => panic!(),
+            _ => Err(IncompatibleOperands),
         }
     }
 }
@@ -199,11 +268,11 @@ impl std::ops::Div for Value {
         use Value::*;
 
         match (self, &rhs) {
-            (Signed(a), Signed(b)) => Ok(Signed(a / b)),
-            (Signed(a), Unsigned(b)) => Ok(Signed(a / *b as i64)),
+            (Signed(a), Signed(b)) => Ok(Signed(a.wrapping_div(*b))),
+            (Signed(a), Unsigned(b)) => Ok(Signed(a.wrapping_div(*b as i64))),
             (Signed(a), Float(b)) => Ok(Float(a as f64 / b)),
-            (Unsigned(a), Signed(b)) => Ok(Signed(a as i64 / b)),
-            (Unsigned(a), Unsigned(b)) => Ok(Unsigned(a / b)),
+            (Unsigned(a), Signed(b)) => Ok(Signed((a as i64).wrapping_div(*b))),
+            (Unsigned(a), Unsigned(b)) => Ok(Unsigned(a.wrapping_div(*b))),
             (Unsigned(a), Float(b)) => Ok(Float(a as f64 / b)),
             (Float(a), Signed(b)) => Ok(Float(a / *b as f64)),
             (Float(a), Unsigned(b)) => Ok(Float(a / *b as f64)),
@@ -211,7 +280,7 @@ impl std::ops::Div for Value {
             (Null, _) | (_, Null) | (Macro, _) | (_, Macro) | (Text(_), _) | (_, Text(_)) => {
                 Err(IncompatibleOperands)
             }
-            _ => panic!(),
+            _ => Err(IncompatibleOperands),
         }
     }
 }
@@ -236,7 +305,7 @@ impl std::ops::Rem for Value {
             (Null, _) | (_, Null) | (Macro, _) | (_, Macro) | (Text(_), _) | (_, Text(_)) => {
                 Err(IncompatibleOperands)
             }
-            _ => panic!(),
+            _ => Err(IncompatibleOperands),
         }
     }
 }
@@ -260,7 +329,7 @@ impl std::ops::BitXor for Value {
             | (_, Macro)
             | (Text(_), _)
             | (_, Text(_)) => Err(IncompatibleOperands),
-            _ => panic!(),
+            _ => Err(IncompatibleOperands),
         }
     }
 }
@@ -283,7 +352,7 @@ impl std::ops::BitAnd for Value {
             | (_, Macro)
             | (Text(_), _)
             | (_, Text(_)) => Err(IncompatibleOperands),
-            _ => panic!(),
+            _ => Err(IncompatibleOperands),
         }
     }
 }
@@ -306,7 +375,7 @@ impl std::ops::BitOr for Value {
             | (_, Macro)
             | (Text(_), _)
             | (_, Text(_)) => Err(IncompatibleOperands),
-            _ => panic!(),
+            _ => Err(IncompatibleOperands),
         }
     }
 }
@@ -329,7 +398,7 @@ impl std::ops::Shr for Value {
             | (_, Macro)
             | (Text(_), _)
             | (_, Text(_)) => Err(IncompatibleOperands),
-            _ => panic!(),
+            _ => Err(IncompatibleOperands),
         }
     }
 }
@@ -353,7 +422,7 @@ impl std::ops::Shl for Value {
             | (_, Macro)
             | (Text(_), _)
             | (_, Text(_)) => Err(IncompatibleOperands),
-            _ => panic!(),
+            _ => Err(IncompatibleOperands),
         }
     }
 }
@@ -402,5 +471,9 @@ mod test {
         let a = Float(10.0);
         assert_eq!(-a, Ok(Float(-10.0)));
 
+        // Integers wrap like they do in the VM
+        assert_eq!(Signed(i64::MAX) + Signed(1), Ok(Signed(i64::MIN)));
+        assert_eq!(Unsigned(0) - Unsigned(1), Ok(Unsigned(u64::MAX)));
+
     }
 }
@@ -11,8 +11,10 @@ use unraveler::Parser;
 use ploy::error::PloyErrorKind;
 
 pub fn compile_module<P: AsRef<Path>>(p : P) -> Result<Module,PloyErrorKind>{
-    let mut opts = Opts::default();
-    opts.project_file = p.as_ref().into();
+    let opts = Opts {
+        project_file: p.as_ref().into(),
+        ..Default::default()
+    };
     let mut loader = sources::SourceLoader::new();
     let id = loader.load_file(&opts.project_file).context("Can't load source file")?;
     let sf = loader.get_source_file(id).context("Can't get source file")?;
@@ -21,6 +23,14 @@ pub fn compile_module<P: AsRef<Path>>(p : P) -> Result<Module,PloyErrorKind>{
     Ok(module)
 }
 
+pub fn module_from_text(text: &str) -> Result<Module, PloyErrorKind> {
+    let opts = Opts::default();
+    let sf = SourceFile::new(text.to_owned(), sources::SourceOrigin::Text);
+    let job = ModuleJob::new(&opts, &sf);
+    let module = Module::try_from(job)?;
+    Ok(module)
+}
+
 pub fn as_ast<P>(text: &str, mut p: P) -> Result<Ast, PloyErrorKind>
 where
     P: for<'a> Parser<Span<'a>, ParseNode, FrontEndError>,
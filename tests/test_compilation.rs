@@ -3,7 +3,1020 @@
 mod common;
 use common::*;
 use ploy::{sources::SourceFile, *, error::PloyErrorKind};
-
 use frontend::*;
 use parsers::*;
 use unraveler::Parser;
+use ir::{codegen::CodeGen, instructions::exec, regalloc::RegAlloc, vm::{Registers, NULL, TRUE}};
+
+fn run_text(text: &str) -> usize {
+    let module = module_from_text(text).expect("Compiling module");
+    let mut cg = CodeGen::new(&module);
+    cg.code_gen_module().expect("Generating code");
+    let mut regs = Registers::for_code(cg.code());
+    exec(cg.code(), &mut regs).expect("Running code")
+}
+
+#[test]
+fn test_codegen_test_file() -> Result<(), PloyErrorKind> {
+    let module = compile_module("testsrc/test.ploy")?;
+    let mut cg = CodeGen::new(&module);
+    cg.code_gen_module().expect("Generating code");
+    assert!(!cg.code().is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_run() {
+    let test = [
+        ("(+ 1 2 3)", 6),
+        ("(def x 10) (* x 0b11)", 30),
+        ("(if (eq 1 2) 10 20)", 20),
+        ("(if true 10)", 10),
+        ("(let [a 2 b 3] (- b a))", 1),
+        // A binding's value sees what the name meant before the let
+        ("(def z 5) (let [x z z 3] (+ x z))", 8),
+        ("(def f (fn [n] (+ n 1))) (let [f (f 2)] f)", 3),
+        ("(and 1 2 3)", 3),
+        ("(or false 0 4)", 4),
+        ("(:b {:a 1 :b 2})", 2),
+        // Anything that isn't a map has no keys
+        ("(:a 5)", NULL),
+        ("(:a (fn [x] x))", NULL),
+        ("(def add (fn [a b] (+ a b))) (add 0xff 1)", 256),
+        (
+            "(def fact (fn [n] (if (eq n 0) 1 (* n (fact (- n 1)))))) (fact 5)",
+            120,
+        ),
+        ("(do 1 2 3)", 3),
+        ("(cond (eq 1 2) 10 (eq 2 2) 20 :else 30)", 20),
+        ("(cond false 10 :else 30)", 30),
+        ("(cond false 10)", NULL),
+        ("(macro sq [x] (* x x)) (sq (+ 1 2))", 9),
+        ("(macro twice [f x] (f (f x))) (def inc (fn [n] (+ n 1))) (twice inc 1)", 3),
+        ("(macro sq [x] (* x x)) (macro quad [x] (sq (sq x))) (quad 2)", 16),
+        ("(macro with-y [x] (let [y 10] (+ x y))) (let [a 1] (with-y a))", 11),
+    ];
+
+    for (text, expected) in test {
+        assert_eq!(run_text(text), expected, "{text}");
+    }
+}
+
+#[test]
+fn test_special_form_errors() {
+    let errs = [
+        ("(cond true 1 false)", "Unxpected syntax: Cond needs a value for every test", 0..19),
+        ("(cond :else 1 true 2)", "Unxpected syntax: Nothing can come after an :else clause", 0..21),
+        ("(cond true 1 :else \"s\")", "Type error: cond clauses disagree, int and string", 19..22),
+        ("(macro sq [x] (* x x)) (sq 1 2)", "Unxpected syntax: Macro sq takes 1 args, got 2", 23..31),
+        // Copies of a macro's body sit on the call they were expanded for
+        ("(macro m [x] (m x)) (m 1)", "Unxpected syntax: Macro m keeps expanding into itself", 20..25),
+        ("(macro sq [x] (* x x)) (sq \"s\")", "Type error: expected int, found string", 27..30),
+        ("(def x 1)\n(def x 2)", "Unxpected syntax: x is already defined", 15..16),
+        ("(fn [a a] a)", "Unxpected syntax: a is already defined", 7..8),
+    ];
+
+    for (text, msg, pos) in errs {
+        let err = check_text(text).err().unwrap_or_else(|| panic!("{text} should fail"));
+        assert_eq!(err.to_string(), msg, "{text}");
+        assert_eq!(err.pos, pos, "{text}");
+    }
+}
+
+#[test]
+fn test_defmacro() {
+    let test = [
+        ("(defmacro sq [x] `(* ,x ,x)) (sq (+ 1 2))", 9),
+        ("(defmacro sum [xs] `(+ ,@xs)) (sum [1 2 3])", 6),
+        // Args aren't evaluated so a list is spliced like any other form
+        ("(defmacro sum [xs] `(+ 1 ,@xs)) (sum (2 3))", 6),
+        ("(defmacro app [xs] `(,@xs)) (app ((fn [x] (+ x 1)) 2))", 3),
+        ("(defmacro unless [c a b] `(if ,c ,b ,a)) (unless false 1 2)", 1),
+        ("(defmacro sq [x] `(* ,x ,x)) (defmacro quad [x] `(sq (sq ,x))) (quad 2)", 16),
+        // The template's y can't capture the caller's y
+        ("(defmacro add-y [x] `(let [y 10] (+ ,x y))) (let [y 1] (add-y y))", 11),
+        // k is the k the macro can see, not the caller's
+        ("(def k 5) (defmacro add-k [x] `(+ ,x k)) (let [k 100] (add-k 1))", 6),
+        ("(defmacro dbl [x] `(let [t ,x] (+ t t))) (+ (dbl 1) (dbl 2))", 6),
+        ("(defmacro dbl [x] `(let [t ,x] (+ t t))) (dbl (dbl 1))", 4),
+        // A local with the macro's name shadows it
+        ("(defmacro sq [x] `(* ,x ,x)) (let [sq (fn [x] (+ x 1))] (sq 2))", 3),
+        ("(defmacro sq [x] `(* ,x ,x)) (def f (fn [sq] (sq 2))) (f (fn [x] (+ x 1)))", 3),
+    ];
+
+    for (text, expected) in test {
+        assert_eq!(run_text(text), expected, "{text}");
+    }
+
+    let errs = [
+        ("(defmacro sq [x] `(* ,x ,x)) (sq 1 2)", "Unxpected syntax: Macro sq takes 1 args, got 2", 29..37),
+        ("(defmacro m [x] (+ ,x 1))", "Unxpected syntax: The body of macro m must be a single quasiquoted template", 0..25),
+        ("(defmacro m [x] `(+ ,y 1))", "Unxpected syntax: Only the macro's parameters can be unquoted", 20..22),
+        ("(defmacro sum [xs] `(+ ,@xs)) (sum 1)", "Unxpected syntax: Can't splice 1, it isn't a list", 35..36),
+        ("(+ 1 ,x)", "Unxpected syntax: Quasiquote and unquote can only be used in a defmacro", 5..7),
+        ("(do (defmacro m [] `1))", "Unxpected syntax: defmacro can only be used at the top level", 4..22),
+        ("(defmacro m [x] `(m ,x)) (m 1)", "Unxpected syntax: Macro m keeps expanding into itself", 25..30),
+        // Errors in the template's code point at the call
+        ("(defmacro bad [x] `(+ \"s\" ,x)) (bad 1)", "Type error: expected int, found string", 31..38),
+    ];
+
+    for (text, msg, pos) in errs {
+        let err = check_text(text).err().unwrap_or_else(|| panic!("{text} should fail"));
+        assert_eq!(err.to_string(), msg, "{text}");
+        assert_eq!(err.pos, pos, "{text}");
+    }
+}
+
+#[test]
+fn test_closures() {
+    use value::Value;
+
+    let test = [
+        ("(def adder (fn [n] (fn [x] (+ x n)))) ((adder 10) 5)", 15),
+        ("(def mk (fn [a] (let [b 2] (fn [x] (+ a b x))))) ((mk 1) 10)", 13),
+        ("(def k (fn [a] (fn [b] (fn [c] (+ a b c))))) (((k 1) 2) 3)", 6),
+        ("(let [y 7 f (fn [x] (+ x y))] (f 1))", 8),
+        ("(def adder (fn [n] (fn [x] (+ x n)))) (let [a (adder 1) b (adder 100)] (+ (a 1) (b 1)))", 103),
+    ];
+
+    for (text, expected) in test {
+        assert_eq!(run_text(text), expected, "{text}");
+
+        let module = module_from_text(text).expect("Compiling module");
+        let val = interpreter::Interpreter::new(&module).run();
+        assert_eq!(val, Ok(Value::Signed(expected as i64)), "{text}");
+    }
+
+    // A let binding isn't in scope in its own value
+    let text = "(def f (fn [n] (let [k (fn [m] (if (eq m 0) n (k (- m 1))))] (k 3)))) (f 7)";
+    let err = check_text(text).err().expect("Self capture");
+    assert_eq!(err.to_string(), "Unxpected syntax: Undefined symbol k");
+    assert_eq!(err.pos, 47..48);
+}
+
+#[test]
+fn test_multi_arity() {
+    use value::Value;
+
+    let test = [
+        ("(def f (fn ([x] x) ([x y] (+ x y)))) (+ (f 1) (f 2 3))", 6),
+        ("(def f (fn ([] 1) ([a b c] (* a b c)))) (+ (f) (f 2 3 4))", 25),
+        ("(def mk (fn [n] (fn ([] n) ([x] (+ x n))))) (let [g (mk 10)] (+ (g) (g 1)))", 21),
+        // Calling itself at another arity
+        ("(def f (fn ([a] (f a 1)) ([a b] (+ a b)))) (f 5)", 6),
+    ];
+
+    for (text, expected) in test {
+        assert_eq!(run_text(text), expected, "{text}");
+
+        let module = module_from_text(text).expect("Compiling module");
+        let val = interpreter::Interpreter::new(&module).run();
+        assert_eq!(val, Ok(Value::Signed(expected as i64)), "{text}");
+    }
+
+    let err = check_text("(fn ([x] x) ([y] y))").err().expect("Duplicate arity");
+    assert_eq!(err.to_string(), "Unxpected syntax: More than one body of this fn takes 1 args");
+    assert_eq!(err.pos, 0..20);
+
+    // Multi arity lambdas aren't typed so bad calls are caught when they're run
+    let text = "(def f (fn ([x] x) ([x y] y))) (f 1 2 3)";
+    let msg = "No body of this fn takes 3 args, it takes 1 or 2";
+
+    let module = module_from_text(text).expect("Compiling module");
+    let mut cg = CodeGen::new(&module);
+    cg.code_gen_module().expect("Generating code");
+    let mut regs = Registers::for_code(cg.code());
+    let err = exec(cg.code(), &mut regs).unwrap_err();
+    assert_eq!(err.kind.to_string(), msg);
+
+    let err = interpreter::Interpreter::new(&module).run().unwrap_err();
+    assert_eq!(err.to_string(), msg);
+}
+
+#[test]
+fn test_rest_args() {
+    use value::Value;
+
+    let test = [
+        ("(def f (fn [a & rest] a)) (+ (f 1) (f 2 3 4))", 3),
+        ("(def f (fn [& xs] 10)) (+ (f) (f 1 2))", 20),
+        ("(def f (fn ([] 1) ([a] a) ([a b & more] (+ a b)))) (+ (f) (f 10) (f 100 200 300))", 311),
+    ];
+
+    for (text, expected) in test {
+        assert_eq!(run_text(text), expected, "{text}");
+
+        let module = module_from_text(text).expect("Compiling module");
+        let val = interpreter::Interpreter::new(&module).run();
+        assert_eq!(val, Ok(Value::Signed(expected as i64)), "{text}");
+    }
+
+    // The left over args are collected into a list
+    let module = module_from_text("(def f (fn [a & rest] rest)) (f 1 2 3)").expect("Compiling module");
+    let val = interpreter::Interpreter::new(&module).run();
+    let expected = Value::Array(vec![Value::Signed(2), Value::Signed(3)].into());
+    assert_eq!(val, Ok(expected));
+
+    let errs = [
+        ("(def f (fn [a b & rest] a)) (f 1)", "Type error: expected at least 2 args, found 1", 28..33),
+        ("(fn [a & b c] a)", "Unxpected syntax: Nothing can come after a & rest param", 0..16),
+        (
+            "(fn ([a & b] a) ([& c] 1))",
+            "Unxpected syntax: Only one body of a fn can have a & rest param",
+            0..26,
+        ),
+        (
+            "(fn ([a & b] a) ([x y] x))",
+            "Unxpected syntax: More than one body of this fn takes 2 args",
+            0..26,
+        ),
+        ("(macro m [& xs] 1)", "Unxpected syntax: & rest params can only be used in a fn", 0..18),
+        ("(defmacro m [& xs] `(+ 1 2))", "Unxpected syntax: & rest params can only be used in a fn", 0..28),
+    ];
+
+    for (text, msg, pos) in errs {
+        let err = check_text(text).err().expect(text);
+        assert_eq!(err.to_string(), msg, "{text}");
+        assert_eq!(err.pos, pos, "{text}");
+    }
+
+    let text = "(def f (fn ([x] x) ([x y z & more] y))) (f 1 2)";
+    let msg = "No body of this fn takes 2 args, it takes 1 or 3 or more";
+
+    let module = module_from_text(text).expect("Compiling module");
+    let mut cg = CodeGen::new(&module);
+    cg.code_gen_module().expect("Generating code");
+    let mut regs = Registers::for_code(cg.code());
+    let err = exec(cg.code(), &mut regs).unwrap_err();
+    assert_eq!(err.kind.to_string(), msg);
+
+    let err = interpreter::Interpreter::new(&module).run().unwrap_err();
+    assert_eq!(err.to_string(), msg);
+}
+
+#[test]
+fn test_destructuring() {
+    use value::Value;
+
+    let test = [
+        ("(let [[a b] [1 2]] (+ a b))", 3),
+        ("(let [[a [b c]] [1 [2 3]]] (+ a b c))", 6),
+        ("(let [{:keys [x y]} {:x 10 :y 20}] (- y x))", 10),
+        ("(let [[a & more] [1 2 3] [b c] more] (+ a b c))", 6),
+        ("(def f (fn [[a b] {:keys [k]}] (* (+ a b) k))) (f [1 2] {:k 10})", 30),
+        ("(def f (fn [a & [b c]] (+ a b c))) (f 1 2 3)", 6),
+        ("(def f (fn ([[a]] a) ([[a] [b]] (+ a b)))) (+ (f [1]) (f [2] [3]))", 6),
+        // nth can't be shadowed by the program
+        ("(let [nth 5 [a b] [1 2]] (+ nth a b))", 8),
+    ];
+
+    for (text, expected) in test {
+        assert_eq!(run_text(text), expected, "{text}");
+
+        let module = module_from_text(text).expect("Compiling module");
+        let val = interpreter::Interpreter::new(&module).run();
+        assert_eq!(val, Ok(Value::Signed(expected as i64)), "{text}");
+    }
+
+    let errs = [
+        ("(let [[a & b c] [1]] a)", "Unxpected syntax: Nothing can come after a & rest param", 13..14),
+        ("(let [[a b] 1] a)", "Type error: expected array, found int", 6..11),
+        ("(macro m [[a]] a)", "Unxpected syntax: Destructuring can only be used in a let or a fn's args", 10..13),
+        ("(defmacro m [[a]] `1)", "Unxpected syntax: Destructuring can only be used in a let or a fn's args", 13..16),
+        ("(let [{:keys [x x]} {:x 1}] x)", "Unxpected syntax: x is bound more than once in this pattern", 16..17),
+        ("(def f (fn [[a [b a]]] a))", "Unxpected syntax: a is bound more than once in this pattern", 18..19),
+    ];
+
+    for (text, msg, pos) in errs {
+        let err = check_text(text).err().expect(text);
+        assert_eq!(err.to_string(), msg, "{text}");
+        assert_eq!(err.pos, pos, "{text}");
+    }
+}
+
+#[test]
+fn test_tail_calls() {
+    use value::Value;
+
+    // Far deeper than the VM's call stack, only runs if tail calls reuse the frame
+    let test = [
+        ("(def count (fn [n acc] (if (eq n 0) acc (count (- n 1) (+ acc 1))))) (count 50000 0)", 50000),
+        (
+            "(def ev (fn [n] (if (eq n 0) 1 (od (- n 1))))) (def od (fn [n] (if (eq n 0) 0 (ev (- n 1))))) (ev 50001)",
+            0,
+        ),
+        ("(def f (fn [n] (cond (eq n 0) 7 :else (let [m (- n 1)] (do (f m)))))) (f 50000)", 7),
+    ];
+
+    for (text, expected) in test {
+        assert_eq!(run_text(text), expected, "{text}");
+    }
+
+    // recur loops in both back ends
+    let test = [
+        ("(def sum (fn [n acc] (if (eq n 0) acc (recur (- n 1) (+ acc n))))) (sum 50000 0)", 1250025000),
+        ("(def f (fn ([] 0) ([n] (if (eq n 0) 10 (recur (- n 1)))))) (f 50000)", 10),
+        ("(def f (fn [n & xs] (if (eq n 0) (nth xs 1) (recur (- n 1) xs)))) (f 50000 5 6)", 6),
+        ("(def swap (fn [a b n] (if (eq n 0) (- a b) (recur b a (- n 1))))) (swap 1 10 3)", 9),
+        ("(def mk (fn [k] (fn [n] (if (eq n 0) k (recur (- n 1)))))) ((mk 3) 50000)", 3),
+    ];
+
+    for (text, expected) in test {
+        assert_eq!(run_text(text), expected, "{text}");
+
+        let module = module_from_text(text).expect("Compiling module");
+        let val = interpreter::Interpreter::new(&module).run();
+        assert_eq!(val, Ok(Value::Signed(expected as i64)), "{text}");
+    }
+
+    let errs = [
+        ("(recur 1)", "Unxpected syntax: recur can only be used in a fn", 0..9),
+        ("(fn [n] (+ 1 (recur n)))", "Unxpected syntax: recur can only be used in tail position", 13..22),
+        ("(fn [n] (if (recur n) 1 2))", "Unxpected syntax: recur can only be used in tail position", 12..21),
+        ("(fn [n] (recur n) 1)", "Unxpected syntax: recur can only be used in tail position", 8..17),
+        ("(fn [a b] (recur 1))", "Unxpected syntax: recur takes 2 args here, got 1", 10..19),
+        ("(fn [n] (+ n 1) (recur \"s\"))", "Type error: expected int, found string", 23..26),
+    ];
+
+    for (text, msg, pos) in errs {
+        let err = check_text(text).err().expect(text);
+        assert_eq!(err.to_string(), msg, "{text}");
+        assert_eq!(err.pos, pos, "{text}");
+    }
+}
+
+#[test]
+fn test_captures() {
+    let module = module_from_text("(def g 1) (def k (fn [a] (fn [b] (fn [c] (+ a b c g)))))")
+        .expect("Compiling module");
+
+    let captures: Vec<Vec<String>> = module
+        .ast
+        .get_rec_ids(module.ast.get_root_id())
+        .into_iter()
+        .filter_map(|id| match &module.ast.tree.get(id).unwrap().value().kind {
+            AstNodeKind::Lambda(data) => Some(data.captures.clone()),
+            _ => None,
+        })
+        .map(|caps| {
+            caps.iter()
+                .map(|id| module.syms.get_symbol_info_from_id(*id).unwrap().name().to_owned())
+                .collect()
+        })
+        .collect();
+
+    // g is global so never needs capturing
+    assert_eq!(captures, vec![vec![], vec!["a".to_owned()], vec!["a".to_owned(), "b".to_owned()]]);
+}
+
+#[test]
+fn test_register_allocation() {
+    let test = [
+        ("(+ 1 2 3)", 6),
+        ("(let [a 1 b 2 c 3 d 4 e 5] (+ (* a b) (* c d) (- e a) (+ a b c d e)))", 33),
+        ("(def f (fn [a b c d] (+ (- a b) (- c d) (* a b c d)))) (f 5 4 3 2)", 122),
+        ("(def fact (fn [n] (if (eq n 0) 1 (* n (fact (- n 1)))))) (fact 5)", 120),
+        ("(cond (eq 1 2) 10 (eq 2 2) 20 :else 30)", 20),
+        ("(:b {:a 1 :b (+ 1 1)})", 2),
+        ("(def mk (fn [a b] (fn [c] (+ a b c)))) ((mk 1 2) 3)", 6),
+        ("(def f (fn ([a] a) ([a b & xs] (+ a b (nth xs 0))))) (+ (f 1) (f 1 2 3))", 7),
+        ("(let [[a b & more] [1 2 3 4] {:keys [x y]} {:x 5 :y 6}] (+ a b (nth more 1) x y))", 18),
+        ("(def sum (fn [n acc] (if (eq n 0) acc (recur (- n 1) (+ acc n))))) (sum 100 0)", 5050),
+        ("(def ev (fn [n] (if (eq n 0) 1 (od (- n 1))))) (def od (fn [n] (if (eq n 0) 0 (ev (- n 1))))) (ev 101)", 0),
+    ];
+
+    for (text, expected) in test {
+        let module = module_from_text(text).expect("Compiling module");
+        let mut cg = CodeGen::new(&module);
+        cg.code_gen_module().expect("Generating code");
+
+        for n in [3, 4, 16] {
+            let code = RegAlloc::new(n).alloc(cg.code()).expect("Allocating registers");
+            let max_gp = code.iter().flat_map(ir::vm::regs_used).filter_map(|r| match r {
+                ir::instructions::Reg::Gp(i) => Some(i),
+                _ => None,
+            });
+            assert!(max_gp.max() < Some(n), "{text} with {n} regs");
+
+            let mut regs = Registers::for_code(&code);
+            assert_eq!(exec(&code, &mut regs), Ok(expected), "{text} with {n} regs");
+        }
+    }
+}
+
+#[test]
+fn test_ssa() {
+    use ir::{cfg::Cfg, instructions::Reg};
+    use std::collections::HashSet;
+
+    let test = [
+        ("(let [a 1 b 2] (if (eq a 1) (+ a b) (- a b)))", 3),
+        ("(cond (eq 1 2) 10 (eq 2 2) 20 :else 30)", 20),
+        ("(and 1 (or false 0 4) 3)", 3),
+        ("(def fact (fn [n] (if (eq n 0) 1 (* n (fact (- n 1)))))) (fact 5)", 120),
+        ("(def mk (fn [a b] (fn [c] (+ a b c)))) ((mk 1 2) 3)", 6),
+        ("(def f (fn ([a] a) ([a b & xs] (+ a b (nth xs 0))))) (+ (f 1) (f 1 2 3))", 7),
+        ("(def sum (fn [n acc] (if (eq n 0) acc (recur (- n 1) (+ acc n))))) (sum 100 0)", 5050),
+        ("(def swap (fn [a b n] (if (eq n 0) (- a b) (recur b a (- n 1))))) (swap 1 10 3)", 9),
+        ("(not 0)", TRUE),
+        ("(if (not 5) 1 2)", 2),
+    ];
+
+    for (text, expected) in test {
+        let module = module_from_text(text).expect("Compiling module");
+        let mut cg = CodeGen::new(&module);
+        cg.code_gen_module().expect("Generating code");
+
+        let mut cfg = Cfg::new(cg.code()).expect("Making cfg");
+        cfg.to_ssa();
+
+        // Every Gp reg is written once
+        let mut defs = HashSet::new();
+        for b in &cfg.blocks {
+            let phis = b.phis.iter().map(|p| p.dest);
+            for r in phis.chain(b.code.iter().flat_map(|i| i.defs_uses().0)) {
+                assert!(!matches!(r, Reg::Gp(_)) || defs.insert(r), "{text}\n{cfg}");
+            }
+        }
+
+        cfg.from_ssa();
+        let code = cfg.to_code();
+        let mut regs = Registers::for_code(&code);
+        assert_eq!(exec(&code, &mut regs), Ok(expected), "{text}\n{cfg}");
+
+        let code = RegAlloc::new(3).alloc(&code).expect("Allocating registers");
+        let mut regs = Registers::for_code(&code);
+        assert_eq!(exec(&code, &mut regs), Ok(expected), "{text} allocated");
+    }
+}
+
+#[test]
+fn test_program_values() {
+    use value::Value;
+
+    let test = [
+        ("(+ 1 2 3)", Value::Signed(6)),
+        ("(eq 1 1)", Value::Bool(true)),
+        ("\"hello\"", Value::Text("hello".into())),
+        (":key", Value::KeyWord(":key".into())),
+        ("()", Value::Null),
+        ("(def f (fn [x] x)) (f \"a\")", Value::Text("a".into())),
+        ("(def add (fn [a b] (+ a b))) (add 1 2)", Value::Signed(3)),
+        ("(def t (fn [] true)) (t)", Value::Bool(true)),
+        // Null isn't 0
+        ("(if false 1)", Value::Null),
+        ("(cond false 1)", Value::Null),
+        ("(nth [1] 3)", Value::Null),
+        ("(+ 0 0)", Value::Signed(0)),
+        ("[true false]", Value::Array(vec![Value::Bool(true), Value::Bool(false)].into())),
+        (
+            "{:a 1 :b \"x\"}",
+            Value::Map(vec![(Value::KeyWord(":a".into()), Value::Signed(1)), (Value::KeyWord(":b".into()), Value::Text("x".into()))].into()),
+        ),
+        (
+            "(def l (fn [& xs] xs)) (l 1 :k)",
+            Value::Array(vec![Value::Signed(1), Value::KeyWord(":k".into())].into()),
+        ),
+    ];
+
+    for (text, expected) in test {
+        let module = module_from_text(text).expect("Compiling module");
+        let program = compile::Program::new(&module, &Default::default()).expect("Generating code");
+        assert_eq!(program.run(), Ok(expected), "{text}");
+    }
+
+    // What ploy run prints
+    let printed = |text: &str| {
+        let module = module_from_text(text).expect("Compiling module");
+        let program = compile::Program::new(&module, &Default::default()).expect("Generating code");
+        program.run().expect("Running").to_string()
+    };
+
+    assert_eq!(printed("(def f (fn [x] x)) (f \"a\")"), "\"a\"");
+    assert_eq!(printed("{:a 1 :b {:c \"d\"}}"), "{:a 1i64 :b {:c \"d\"}}");
+    assert_eq!(printed("(fn [x] x)"), "fn");
+    assert_eq!(printed("[true false (if false 1)]"), "[true false null]");
+    assert_eq!(printed("(def add (fn [a b] (+ a b))) (add 1 2)"), "3i64");
+}
+
+#[test]
+fn test_interpreter_matches_vm() {
+    let test = [
+        "(+ 1 2 3)",
+        "(def x 10) (* x 0b11)",
+        "(if (eq 1 2) 10 20)",
+        "(let [a 2 b 3] (- b a))",
+        "(def z 5) (let [x z z 3] (+ x z))",
+        "(and 1 2 3)",
+        "(or false 0 4)",
+        "(:b {:a 1 :b 2})",
+        "(:a 5)",
+        "(def add (fn [a b] (+ a b))) (add 0xff 1)",
+        "(def fact (fn [n] (if (eq n 0) 1 (* n (fact (- n 1)))))) (fact 5)",
+        "(cond (eq 1 2) 10 (eq 2 2) 20 :else 30)",
+        "(do 1 2 3)",
+        "(macro sq [x] (* x x)) (sq (+ 1 2))",
+        "(defmacro add-y [x] `(let [y 10] (+ ,x y))) (let [y 1] (add-y y))",
+        "(defmacro sq [x] `(* ,x ,x)) (let [sq (fn [x] (+ x 1))] (sq 2))",
+        "(if false 1)",
+        "(nth [1] 3)",
+        "[true false {:a :b}]",
+        "(eq [1 [2]] [1 [2]])",
+        "(eq {:a 1} {:a 2})",
+        "(let [f (fn [x] x)] (eq f f))",
+    ];
+
+    // Functions don't match, the VM only knows where their code is
+    for text in test {
+        let module = module_from_text(text).expect("Compiling module");
+        let val = interpreter::Interpreter::new(&module)
+            .run()
+            .expect("Interpreting");
+
+        let program = compile::Program::new(&module, &Default::default()).expect("Generating code");
+        assert_eq!(program.run(), Ok(val), "{text}");
+    }
+}
+
+#[test]
+fn test_interpreter_stack_overflow() {
+    use interpreter::InterpreterError;
+
+    // Deep recursion that isn't too deep still works
+    let text = "(def f (fn [n] (if (eq n 0) 0 (+ 1 (f (- n 1)))))) (f 990)";
+    let module = module_from_text(text).expect("Compiling module");
+    let val = interpreter::Interpreter::new(&module).run();
+    assert_eq!(val, Ok(value::Value::Signed(990)));
+
+    // Never returns, so it hits the call depth limit before the Rust stack runs out
+    let text = "(def f (fn [n] (+ 1 (f (+ n 1))))) (f 0)";
+    let module = module_from_text(text).expect("Compiling module");
+    let val = interpreter::Interpreter::new(&module).run();
+    assert_eq!(val, Err(InterpreterError::StackOverflow));
+}
+
+/// Write some files to a fresh temporary directory and compile the first one
+/// Only the first error is kept
+fn module_from_files(dir: &str, files: &[(&str, &str)]) -> Result<Module, FrontEndError> {
+    use sources::SourceOrigin;
+
+    let dir = std::env::temp_dir().join(dir);
+    let _ = std::fs::remove_dir_all(&dir);
+
+    for (name, text) in files {
+        let path = dir.join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, text).unwrap();
+    }
+
+    let (name, text) = files[0];
+    let source = SourceFile::new(text.to_owned(), SourceOrigin::File(0, dir.join(name)));
+    Module::new(ModuleJob::new(&opts::Opts::default(), &source)).map_err(|errs| errs[0].clone())
+}
+
+#[test]
+fn test_imports() {
+    let module = module_from_files(
+        "ploy_test_imports",
+        &[
+            ("main.ploy", "(import foo::bar)\n(import baz)\n(+ foo::bar::x baz::y)"),
+            ("foo/bar.ploy", "(import baz)\n(def x (+ baz::y 1))"),
+            ("baz.ploy", "(def y 10)"),
+        ],
+    )
+    .expect("Compiling module");
+
+    let mut cg = CodeGen::new(&module);
+    cg.code_gen_module().expect("Generating code");
+    let mut regs = Registers::for_code(cg.code());
+    assert_eq!(exec(cg.code(), &mut regs), Ok(21));
+
+    let val = interpreter::Interpreter::new(&module).run();
+    assert_eq!(val, Ok(value::Value::Signed(21)));
+}
+
+#[test]
+fn test_import_macros() {
+    // The template's y is the module's, not the caller's
+    let module = module_from_files(
+        "ploy_test_import_macros",
+        &[
+            ("main.ploy", "(def y 100)\n(import baz)\n(baz::add-y 1)"),
+            ("baz.ploy", "(def y 10)\n(defmacro add-y [x] `(+ ,x y))"),
+        ],
+    )
+    .expect("Compiling module");
+
+    let mut cg = CodeGen::new(&module);
+    cg.code_gen_module().expect("Generating code");
+    let mut regs = Registers::for_code(cg.code());
+    assert_eq!(exec(cg.code(), &mut regs), Ok(11));
+
+    let val = interpreter::Interpreter::new(&module).run();
+    assert_eq!(val, Ok(value::Value::Signed(11)));
+
+    // A module's macros need qualifying like the rest of it
+    let err = module_from_files(
+        "ploy_test_import_macros_unqualified",
+        &[
+            ("main.ploy", "(import baz)\n(add-y 1)"),
+            ("baz.ploy", "(def y 10)\n(defmacro add-y [x] `(+ ,x y))"),
+        ],
+    )
+    .err()
+    .expect("Failing to find add-y");
+
+    assert_eq!(err.kind.to_string(), "Unxpected syntax: Undefined symbol add-y");
+    assert_eq!(err.pos, 14..19);
+}
+
+#[test]
+fn test_import_errors() {
+    let err = module_from_files(
+        "ploy_test_import_cycle",
+        &[
+            ("main.ploy", "(import a)"),
+            ("a.ploy", "(import b)"),
+            ("b.ploy", "(import a)"),
+        ],
+    )
+    .err()
+    .expect("Finding cycle");
+
+    assert_eq!(err.kind.to_string(), "Import cycle: a -> b -> a");
+    assert!(err.file.is_some());
+
+    let err = module_from_files("ploy_test_import_missing", &[("main.ploy", "\n(import nope)")])
+        .err()
+        .expect("Failing to find module");
+
+    assert_eq!(err.kind.to_string(), "Can't find module nope");
+    assert_eq!(err.pos, 1..14);
+    assert!(err.file.is_none());
+
+    // A module can't see the definitions of the file importing it
+    let err = module_from_files(
+        "ploy_test_import_scope",
+        &[
+            ("main.ploy", "(def x 1)\n(import baz)\n(+ x baz::y)"),
+            ("baz.ploy", "(def y (+ x 1))"),
+        ],
+    )
+    .err()
+    .expect("Failing to find x");
+
+    assert_eq!(err.kind.to_string(), "Unxpected syntax: Undefined symbol x");
+    assert_eq!(err.pos, 10..11);
+    assert!(err.file.is_some());
+
+    // Or anything from another module it didn't qualify
+    let err = module_from_files(
+        "ploy_test_import_unqualified",
+        &[
+            ("main.ploy", "(import foo)\n(import baz)\nfoo::z"),
+            ("baz.ploy", "(def y 1)"),
+            ("foo.ploy", "(import baz)\n(def z y)"),
+        ],
+    )
+    .err()
+    .expect("Failing to find y");
+
+    assert_eq!(err.kind.to_string(), "Unxpected syntax: Undefined symbol y");
+}
+
+/// Compile some text, keeping the first error
+fn check_text(text: &str) -> Result<Module, FrontEndError> {
+    let sf = SourceFile::new(text.to_owned(), sources::SourceOrigin::Text);
+    Module::new(ModuleJob::new(&opts::Opts::default(), &sf)).map_err(|errs| errs[0].clone())
+}
+
+#[test]
+fn test_error_recovery() {
+    let text = "(if)\n(def x 1)\n(+ x (if))\n(let [a] 1)\n(+ x y)";
+    let sf = SourceFile::new(text.to_owned(), sources::SourceOrigin::Text);
+    let errs = Module::new(ModuleJob::new(&opts::Opts::default(), &sf)).err().expect("Failing to parse");
+
+    // Every syntax error, but nothing from later on as the undefined y isn't reported
+    let lines: Vec<_> = errs.iter().map(|e| sf.get_location(e.pos.start).unwrap().line).collect();
+    assert_eq!(lines, vec![0, 2, 3]);
+
+    let report = format!("{:?}", module_from_text(text).err().expect("Failing to parse"));
+    assert_eq!(report.matches("Parsing: No match").count(), 2, "{report}");
+    assert_eq!(report.matches('^').count(), 3, "{report}");
+
+    // Heads that can't be called parse, but each one is an error when lowering
+    let text = "(1 2)\n(def x 1)\n(+ x (3))";
+    let report = format!("{:?}", module_from_text(text).err().expect("Failing to lower"));
+    assert_eq!(report.matches("This isn't something you can call").count(), 2, "{report}");
+
+    // Without syntax errors every top level form is still looked at
+    let errors_on_lines = |text: &str| {
+        let sf = SourceFile::new(text.to_owned(), sources::SourceOrigin::Text);
+        let errs = Module::new(ModuleJob::new(&opts::Opts::default(), &sf)).err().expect(text);
+        errs.iter().map(|e| sf.get_location(e.pos.start).unwrap().line).collect::<Vec<_>>()
+    };
+
+    assert_eq!(errors_on_lines("(foo 1)\n(bar 2)"), vec![0, 1]);
+    assert_eq!(errors_on_lines("(cond 1)\n(def x 1)\n(cond 2)"), vec![0, 2]);
+    assert_eq!(errors_on_lines("(def x :int \"s\")\n(+ x 1)\n(- :a 1)\n(+ 1 (+ :b :c))"), vec![0, 2, 3]);
+}
+
+#[test]
+fn test_diagnostics() {
+    let report = |text: &str| module_from_text(text).err().expect("Failing to compile").to_string();
+
+    let expected = [
+        "error[E0003]: Type error: if branches disagree, int and string",
+        " --> <text>:2:12",
+        "  |",
+        "2 | (if true 1 \"no\")",
+        "  |            ^^^^",
+        "  |          - this is int",
+        "",
+    ];
+    assert_eq!(report("(def x 1)\n(if true 1 \"no\")"), expected.join("\n"));
+
+    let expected = [
+        "error[E0001]: Unxpected syntax: Unexpected input",
+        " --> <text>:1:10",
+        "  |",
+        "1 | (def x 1))",
+        "  |          ^",
+        "  |",
+        "  = help: there's nothing open for this to close",
+        "",
+    ];
+    assert_eq!(report("(def x 1))"), expected.join("\n"));
+
+    let text = "(def f (fn [n] (+ 1 (recur n))))";
+    assert!(report(text).ends_with("= note: recur jumps back to the start of the fn, so nothing can be left to do after it\n"));
+}
+
+#[test]
+fn test_json_errors() {
+    use serde_json::json;
+    let errors = |text: &str| module_from_text(text).err().expect("Failing to compile").to_json();
+
+    let e = errors("(def x 1)\n(if true 1 \"no\")");
+    assert_eq!(
+        e,
+        vec![json!({
+            "file": "<text>",
+            "range": { "start": 21, "end": 25 },
+            "line": 2,
+            "col": 12,
+            "severity": "error",
+            "kind": "type",
+            "code": "E0003",
+            "message": "Type error: if branches disagree, int and string",
+        })]
+    );
+
+    // One object for each syntax error
+    let e = errors("(def x 1))\n(def y 2))");
+    let at: Vec<_> = e.iter().map(|j| (j["line"].clone(), j["col"].clone(), j["kind"].clone())).collect();
+    assert_eq!(at, vec![(json!(1), json!(10), json!("syntax")), (json!(2), json!(10), json!("syntax"))]);
+}
+
+#[test]
+fn test_type_checking() {
+    let ok = [
+        "(def x :int 10) (+ x 1)",
+        "(def add (fn [a :int b :int] (+ a b)) :int) (add 1 2)",
+        "(if (eq 1 2) \"yes\" \"no\")",
+        "(let [s \"hi\" n 1] (+ n 2))",
+        "(def fact (fn [n] (if (eq n 0) 1 (* n (fact (- n 1)))))) (fact 5)",
+        "(cond (eq 1 2) 10 (eq 2 2) 20 :else 30)",
+        "(do 1 2 3)",
+        "(macro sq [x] (* x x)) (sq (+ 1 2))",
+    ];
+
+    for text in ok {
+        check_text(text).unwrap_or_else(|e| panic!("{text}: {e}"));
+    }
+
+    let errs = [
+        ("(def x :int \"hello\")", "Type error: expected int, found string", 12..19),
+        ("(if true 1 \"no\")", "Type error: if branches disagree, int and string", 11..15),
+        ("(+ 1 :key)", "Type error: expected int, found keyword", 5..9),
+        ("(def f (fn [a :bool] a)) (f 1)", "Type error: expected bool, found int", 28..29),
+        ("(def f (fn [a] a)) (f 1 2)", "Type error: expected 1 args, found 2", 19..26),
+        ("(def x 1) (x 2)", "Type error: a value of type int can't be called", 11..12),
+        ("(fn [a] \"a\") :int", "Type error: returns string but is annotated as int", 8..11),
+        ("(def x : thing 1)", "Type error: unknown type thing", 5..6),
+        ("(- 5)", "Type error: expected at least 2 args, found 1", 0..5),
+        ("(eq 1)", "Type error: expected 2 args, found 1", 0..6),
+        ("(eq 1 \"a\")", "Type error: expected int, found string", 6..9),
+    ];
+
+    for (text, msg, pos) in errs {
+        let err = check_text(text).err().unwrap_or_else(|| panic!("{text} should fail"));
+        assert_eq!(err.to_string(), msg, "{text}");
+        assert_eq!(err.pos, pos, "{text}");
+    }
+}
+
+#[test]
+fn test_inferred_types() {
+    use frontend::Type;
+
+    let module = check_text("(def x (let [a 1] (+ a a))) (def s \"s\")").unwrap();
+    let types: Vec<_> = module
+        .definitions
+        .values()
+        .map(|id| module.get_type(*id))
+        .collect();
+
+    assert_eq!(types.len(), 3);
+    assert!(types.contains(&Type::String));
+    assert_eq!(types.iter().filter(|t| **t == Type::Integer).count(), 2);
+}
+
+/// The inferred type of a top level definition
+fn type_of(module: &Module, name: &str) -> frontend::Type {
+    let root = module.syms.get_root_scope_id();
+    let sym = module.syms.get_symbol_info(name, root).expect("Finding symbol");
+    let def = module.get_definition(sym.symbol_id).expect("Finding definition");
+    module.get_type(def)
+}
+
+#[test]
+fn test_type_inference() {
+    use frontend::Type::*;
+
+    let module = check_text("(def id (fn [x] x)) (def n (id 1)) (def s (id \"s\"))").unwrap();
+    assert_eq!(type_of(&module, "n"), Integer);
+    assert_eq!(type_of(&module, "s"), String);
+
+    match type_of(&module, "id") {
+        Fn(params, ret) => assert_eq!(params, vec![*ret]),
+        t => panic!("id has type {t}"),
+    }
+
+    let module = check_text("(def twice (fn [f x] (f (f x)))) (def inc (fn [n] (+ n 1)))").unwrap();
+    assert_eq!(type_of(&module, "inc"), Fn(vec![Integer], Box::new(Integer)));
+
+    // (fn [(fn ['a] 'a) 'a] 'a)
+    match type_of(&module, "twice") {
+        Fn(params, ret) => {
+            let a = *ret;
+            assert!(matches!(a, Var(_)));
+            assert_eq!(params, vec![Fn(vec![a.clone()], Box::new(a.clone())), a]);
+        }
+        t => panic!("twice has type {t}"),
+    }
+
+    // let bound lambdas are generalised too
+    check_text("(let [f (fn [x] x)] (+ (f 1) 1) (f \"a\"))").unwrap();
+
+    // lambda params aren't
+    let err = check_text("(fn [f] (+ (f 1) 1) (f \"a\"))").err().unwrap();
+    assert_eq!(err.to_string(), "Type error: expected int, found string");
+
+    let err = check_text("(fn [f] (f f))").err().unwrap();
+    assert!(err.to_string().contains("would have to contain itself"), "{err}");
+
+    // An if with no else is null when the predicate is false
+    let module = check_text("(def f (fn [g] (if true (g 1)))) (def b (if true 1))").unwrap();
+    let g = Fn(vec![Integer], Box::new(Void));
+    assert_eq!(type_of(&module, "f"), Fn(vec![g], Box::new(Void)));
+    assert!(matches!(type_of(&module, "b"), Var(_)));
+}
+
+#[test]
+fn test_infer_test_file() {
+    use frontend::Type::*;
+
+    let module = compile_module("testsrc/infer.ploy").expect("Compiling inference file");
+
+    let known = [
+        ("n", Integer),
+        ("s", String),
+        ("three", Integer),
+        ("k", KeyWord),
+        ("both", Integer),
+    ];
+
+    for (name, t) in known {
+        assert_eq!(type_of(&module, name), t, "{name}");
+    }
+
+    match type_of(&module, "first") {
+        Fn(params, ret) => {
+            assert_eq!(params[0], *ret);
+            assert_ne!(params[0], params[1]);
+            assert!(matches!(*ret, Var(_)));
+        }
+        t => panic!("first has type {t}"),
+    }
+
+    // Looking a key up says nothing about what it's looked up in
+    match type_of(&module, "lookup") {
+        Fn(params, ret) => assert!(matches!((&params[0], *ret), (Var(a), Var(b)) if *a != b)),
+        t => panic!("lookup has type {t}"),
+    }
+
+    let g = Fn(vec![Integer], Box::new(Void));
+    assert_eq!(type_of(&module, "when-one"), Fn(vec![g], Box::new(Void)));
+
+    // Neither of add's params is used in a way that pins its type
+    let module = compile_module("testsrc/test.ploy").expect("Compiling test file");
+
+    match type_of(&module, "add") {
+        Fn(params, ret) => {
+            assert!(matches!(params.as_slice(), [Var(a), Var(b)] if a != b));
+            assert_eq!(*ret, Array);
+        }
+        t => panic!("add has type {t}"),
+    }
+}
+
+#[test]
+fn test_constant_folding() {
+    use ir::instructions::{Instruction::{Load, Ret}, Reg};
+    use opts::{CodeGenOpts, Pass};
+
+    let code_for = |text: &str, opts: &CodeGenOpts| {
+        let module = module_from_text(text).expect("Compiling module");
+        let mut cg = CodeGen::with_opts(&module, opts);
+        cg.code_gen_module().expect("Generating code");
+        (cg.code().to_vec(), cg.folded().len())
+    };
+
+    let (code, folded) = code_for("(+ 1 (* 2 3))", &Default::default());
+    assert_eq!(code, vec![Load(Reg::Ret(0), 7), Ret]);
+    assert_eq!(folded, 1);
+
+    // Only the outermost literal expression is recorded
+    let (_, folded) = code_for("(let [a 2] (+ a (- 10 (div 9 3))))", &Default::default());
+    assert_eq!(folded, 1);
+
+    // Dividing by zero is left for the VM to report
+    let (code, folded) = code_for("(div 1 0)", &Default::default());
+    assert_eq!(folded, 0);
+    assert!(code.len() > 1);
+
+    let no_fold = CodeGenOpts {
+        disable: vec![Pass::Fold],
+        ..Default::default()
+    };
+    let (code, folded) = code_for("(+ 1 (* 2 3))", &no_fold);
+    assert_eq!(folded, 0);
+    assert!(code.len() > 1);
+}
+
+#[test]
+fn test_optimisation_passes() {
+    use compile::Program;
+    use opts::{CodeGenOpts, Pass};
+
+    let test = [
+        ("(+ 1 (* 2 3))", 7),
+        ("(let [a 1 b a c b] (+ a b c))", 3),
+        ("(let [a 1 b 2] (if (eq a 1) (+ a b) (- a b)))", 3),
+        ("(def fact (fn [n] (if (eq n 0) 1 (* n (fact (- n 1)))))) (fact 5)", 120),
+        ("(def mk (fn [a b] (fn [c] (+ a b c)))) ((mk 1 2) 3)", 6),
+        ("(def f (fn ([a] a) ([a b & xs] (+ a b (nth xs 0))))) (+ (f 1) (f 1 2 3))", 7),
+        ("(let [[a b & more] [1 2 3 4] {:keys [x y]} {:x 5 :y 6}] (+ a b (nth more 1) x y))", 18),
+        ("(def swap (fn [a b n] (if (eq n 0) (- a b) (recur b a (- n 1))))) (swap 1 10 3)", 9),
+        ("(not 0)", TRUE),
+        ("(if (not 5) 1 2)", 2),
+    ];
+
+    // Every combination of the passes being on or off
+    let combos: Vec<Vec<Pass>> = (0..1 << Pass::ALL.len())
+        .map(|bits: usize| {
+            let on = |i: usize| bits & (1 << i) != 0;
+            Pass::ALL.iter().enumerate().filter(|(i, _)| !on(*i)).map(|(_, p)| *p).collect()
+        })
+        .collect();
+
+    for (text, expected) in test {
+        let module = module_from_text(text).expect("Compiling module");
+        let mut sizes = vec![];
+
+        for disable in &combos {
+            let opts = CodeGenOpts {
+                disable: disable.clone(),
+                ..Default::default()
+            };
+
+            let program = Program::new(&module, &opts).expect("Generating code");
+            let mut regs = Registers::for_code(&program.code);
+            assert_eq!(exec(&program.code, &mut regs), Ok(expected), "{text} without {disable:?}");
+            sizes.push(program.code.len());
+        }
+
+        // All on is never worse than all off
+        let (all_on, all_off) = (sizes[combos.len() - 1], sizes[0]);
+        assert!(all_on <= all_off, "{text} {sizes:?}");
+    }
+
+    // Copies of copies leave less to run
+    let size = |disable: Vec<Pass>| {
+        let module = module_from_text("(let [a 1 b a c b] (+ a b c))").unwrap();
+        let opts = CodeGenOpts { disable, ..Default::default() };
+        Program::new(&module, &opts).unwrap().code.len()
+    };
+    assert!(size(vec![]) < size(vec![Pass::CopyProp, Pass::Dce]));
+}
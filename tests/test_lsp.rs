@@ -0,0 +1,186 @@
+use ploy::{lsp::*, opts::Opts};
+use serde_json::{json, Value};
+use std::io::Cursor;
+
+const URI: &str = "file:///test.ploy";
+
+fn frame(msgs: &[Value]) -> Vec<u8> {
+    let mut ret = vec![];
+    for m in msgs {
+        write_message(&mut ret, m).unwrap();
+    }
+    ret
+}
+
+/// Run a scripted session through the server, returning everything it sent back
+fn session(msgs: &[Value]) -> Vec<Value> {
+    session_from_bytes(&Opts::default(), frame(msgs))
+}
+
+fn session_from_bytes(opts: &Opts, input: Vec<u8>) -> Vec<Value> {
+    let mut output = vec![];
+    Server::new(opts)
+        .run(Cursor::new(input), &mut output)
+        .expect("Running server");
+
+    let mut output = Cursor::new(output);
+    let mut ret = vec![];
+    while let Some(msg) = read_message(&mut output).unwrap() {
+        ret.push(msg)
+    }
+    ret
+}
+
+fn open(text: &str) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/didOpen",
+        "params": { "textDocument": { "uri": URI, "languageId": "ploy", "version": 1, "text": text } }
+    })
+}
+
+fn at(id: usize, method: &str, line: usize, character: usize) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "method": method,
+        "params": {
+            "textDocument": { "uri": URI },
+            "position": { "line": line, "character": character }
+        }
+    })
+}
+
+#[test]
+fn test_lsp_lifecycle() {
+    let replies = session(&[
+        json!({"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {}}),
+        json!({"jsonrpc": "2.0", "method": "initialized", "params": {}}),
+        json!({"jsonrpc": "2.0", "id": 2, "method": "nonsense"}),
+        json!({"jsonrpc": "2.0", "id": 3, "method": "shutdown"}),
+        json!({"jsonrpc": "2.0", "id": 4, "method": "initialize", "params": {}}),
+        json!({"jsonrpc": "2.0", "method": "exit"}),
+        json!({"jsonrpc": "2.0", "id": 5, "method": "shutdown"}),
+    ]);
+
+    assert_eq!(replies.len(), 4);
+    assert_eq!(replies[0]["result"]["capabilities"]["hoverProvider"], true);
+    assert_eq!(replies[1]["error"]["code"], -32601);
+    assert_eq!(replies[2], json!({"jsonrpc": "2.0", "id": 3, "result": null}));
+    assert_eq!(replies[3]["error"]["code"], -32600);
+}
+
+#[test]
+fn test_lsp_diagnostics() {
+    let replies = session(&[open("(def x 10)\n(+ x y)")]);
+
+    assert_eq!(replies.len(), 1);
+    assert_eq!(replies[0]["method"], "textDocument/publishDiagnostics");
+
+    let diags = &replies[0]["params"]["diagnostics"];
+    assert_eq!(diags.as_array().unwrap().len(), 1);
+    assert_eq!(diags[0]["message"], "Unxpected syntax: Undefined symbol y");
+    assert_eq!(
+        diags[0]["range"],
+        json!({"start": {"line": 1, "character": 5}, "end": {"line": 1, "character": 6}})
+    );
+
+    let replies = session(&[open("(def x 10)\n(+ x 1)")]);
+    assert_eq!(replies[0]["params"]["diagnostics"], json!([]));
+
+    // Errors past the first are reported too
+    let replies = session(&[open("(foo 1)\n(bar 2)")]);
+    assert_eq!(replies[0]["params"]["diagnostics"].as_array().unwrap().len(), 2);
+
+    // Defining a name twice is a diagnostic, not a crash
+    let replies = session(&[open("(def x 1)\n(def x 2)"), open("(fn [a a] a)")]);
+    assert_eq!(replies.len(), 2);
+    assert_eq!(replies[0]["params"]["diagnostics"][0]["message"], "Unxpected syntax: x is already defined");
+    assert_eq!(replies[1]["params"]["diagnostics"][0]["message"], "Unxpected syntax: a is already defined");
+
+    // Every syntax error is reported
+    let replies = session(&[open("(if)\n(def x 10)\n(x))")]);
+    let diags = replies[0]["params"]["diagnostics"].as_array().unwrap().clone();
+    let lines: Vec<_> = diags.iter().map(|d| d["range"]["start"]["line"].clone()).collect();
+    assert_eq!(lines, vec![json!(0), json!(2)]);
+    assert_eq!(
+        diags[1]["message"],
+        "Unxpected syntax: Unexpected input\nhelp: there's nothing open for this to close"
+    );
+    assert_eq!(diags[1]["code"], "E0001");
+}
+
+#[test]
+fn test_lsp_definition_and_hover() {
+    let text = "(def add (fn [a b]\n  (+ a b)))\n(add 1 2)";
+
+    let replies = session(&[
+        open(text),
+        at(1, "textDocument/definition", 2, 2),
+        at(2, "textDocument/definition", 1, 7),
+        at(3, "textDocument/definition", 1, 3),
+        at(4, "textDocument/hover", 2, 1),
+        at(5, "textDocument/hover", 1, 3),
+    ]);
+
+    let range = |l0, c0, l1, c1| {
+        json!({"start": {"line": l0, "character": c0}, "end": {"line": l1, "character": c1}})
+    };
+
+    assert_eq!(replies[1]["result"], json!({"uri": URI, "range": range(0, 5, 0, 8)}));
+    assert_eq!(replies[2]["result"], json!({"uri": URI, "range": range(0, 16, 0, 17)}));
+    // + is a builtin, it has no source
+    assert_eq!(replies[3]["result"], Value::Null);
+
+    let hover = &replies[4]["result"];
+    assert_eq!(hover["range"], range(2, 1, 2, 4));
+    let text = hover["contents"]["value"].as_str().unwrap();
+    assert!(text.contains("(def add (fn [a b]"));
+    assert!(text.starts_with("`add: (fn [int int] int)`"), "{text}");
+
+    let hover = &replies[5]["result"];
+    assert_eq!(hover["contents"]["value"], "`+` builtin, takes 2 or more args");
+}
+
+#[test]
+fn test_lsp_bad_messages() {
+    let mut input = b"Content-Length: 9\r\n\r\n{\"bad\": }".to_vec();
+    input.extend(frame(&[
+        json!({"jsonrpc": "2.0", "id": 1, "method": 2}),
+        json!([1, 2]),
+        json!({"jsonrpc": "2.0", "id": 2, "method": "shutdown"}),
+    ]));
+
+    // Each bad message is answered and the server carries on
+    let replies = session_from_bytes(&Opts::default(), input);
+    assert_eq!(replies.len(), 4);
+    assert_eq!(replies[0]["error"]["code"], -32700);
+    assert_eq!(replies[0]["id"], Value::Null);
+    assert_eq!(replies[1]["error"]["code"], -32600);
+    assert_eq!(replies[1]["id"], 1);
+    assert_eq!(replies[2]["error"]["code"], -32600);
+    assert_eq!(replies[3], json!({"jsonrpc": "2.0", "id": 2, "result": null}));
+}
+
+#[test]
+fn test_lsp_definition_in_import() {
+    let dir = std::env::temp_dir().join("ploy_test_lsp_import");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("baz.ploy"), "; ü\n(def  y 10)").unwrap();
+
+    let mut opts = Opts::default();
+    opts.project.search_paths.push(dir.clone());
+
+    let input = frame(&[
+        open("(import baz)\n(+ baz::y 1)"),
+        at(1, "textDocument/definition", 1, 8),
+    ]);
+    let replies = session_from_bytes(&opts, input);
+
+    let path = std::fs::canonicalize(dir.join("baz.ploy")).unwrap();
+    let range = json!({"start": {"line": 1, "character": 6}, "end": {"line": 1, "character": 7}});
+    assert_eq!(replies[0]["params"]["diagnostics"], json!([]));
+    let uri = format!("file://{}", path.display());
+    assert_eq!(replies[1]["result"], json!({"uri": uri, "range": range}));
+}
@@ -12,7 +12,7 @@ fn test_lamda_body()-> Result<(), PloyErrorKind> {
     use AstNodeKind::*;
 
     let test = vec![
-        ("([a b] a b)", vec![Args, Symbol, Symbol]),
+        ("([a b] a b)", vec![Args, ToProcessKind::Symbol.into(), ToProcessKind::Symbol.into()]),
         ("([])", vec![Args]),
         ("([a b c] \"xxxxx\")", vec![Args, QuotedString]),
 
@@ -36,8 +36,8 @@ fn test_define() -> Result<(), PloyErrorKind> {
     use AstNodeKind::*;
 
     let test = vec![
-        ("(def a b)", vec![Arg, Symbol]),
-        ("(define x (fn[a] 12))", vec![Arg, Lambda]),
+        ("(def a b)", vec![Arg, ToProcessKind::Symbol.into()]),
+        ("(define x (fn[a] 12))", vec![Arg, ToProcessKind::Lambda.into()]),
         ("(define y ())", vec![Arg, Null]),
         (
             "(define  ^{:test b :spam \"hello\"} y  ())",
@@ -51,7 +51,7 @@ fn test_define() -> Result<(), PloyErrorKind> {
 #[test]
 fn test_pair() -> Result<(), PloyErrorKind> {
     use AstNodeKind::*;
-    let test = vec![(":keword a", vec![KeyWord, Symbol])];
+    let test = vec![(":keword a", vec![KeyWord, ToProcessKind::Symbol.into()])];
     test_parsers(parse_pair, Pair, &test)
 }
 #[test]
@@ -68,7 +68,7 @@ fn test_keyword_pair() -> Result<(), PloyErrorKind> {
     use AstNodeKind::*;
 
     let test = vec![
-        (":keword a", vec![KeyWord, Symbol]),
+        (":keword a", vec![KeyWord, ToProcessKind::Symbol.into()]),
         (":whoops \"Hello there!\"", vec![KeyWord, QuotedString]),
     ];
 
@@ -89,14 +89,14 @@ fn test_if() -> Result<(), PloyErrorKind> {
     use AstNodeKind::*;
 
     let test = vec![
-        ("(if a b)", vec![Symbol, Symbol]),
-        ("(if :a b :a)", vec![KeyWord, Symbol, KeyWord]),
-        ("(if a b 12)", vec![Symbol, Symbol, Number]),
-        ("(if a b (x a))", vec![Symbol, Symbol, ToProcess::Application.into()]),
-        ("(if a b ())", vec![Symbol, Symbol, Null]),
+        ("(if a b)", vec![ToProcessKind::Symbol.into(), ToProcessKind::Symbol.into()]),
+        ("(if :a b :a)", vec![KeyWord, ToProcessKind::Symbol.into(), KeyWord]),
+        ("(if a b 12)", vec![ToProcessKind::Symbol.into(), ToProcessKind::Symbol.into(), Number]),
+        ("(if a b (x a))", vec![ToProcessKind::Symbol.into(), ToProcessKind::Symbol.into(), ToProcessKind::Application.into()]),
+        ("(if a b ())", vec![ToProcessKind::Symbol.into(), ToProcessKind::Symbol.into(), Null]),
     ];
 
-    test_parsers(parse_if, ToProcess::If, &test)
+    test_parsers(parse_if, ToProcessKind::If, &test)
 }
 
 #[test]
@@ -104,10 +104,19 @@ fn test_let() -> Result<(), PloyErrorKind> {
     use AstNodeKind::*;
 
     let test = vec![
-        ("(let [a 10] (println a) true)", vec![LetArgs, ToProcess::Application.into(), Bool]),
+        ("(let [a 10] (println a) true)", vec![LetArgs, ToProcessKind::Application.into(), Bool]),
     ];
 
-    test_parsers(parse_let, Let, &test)
+    test_parsers(parse_let, ToProcessKind::Let, &test)?;
+
+    let test = vec![
+        ("[a & more]", vec![Arg, RestArg]),
+        ("[[a] {:keys [b]}]", vec![VecPattern, MapPattern]),
+    ];
+    test_parsers(parse_binding, VecPattern, &test)?;
+
+    let test = vec![("{:keys [x y]}", vec![Arg, Arg])];
+    test_parsers(parse_binding, MapPattern, &test)
 }
 
 #[test]
@@ -123,7 +132,7 @@ fn test_lambda() -> Result<(), PloyErrorKind> {
         ),
     ];
 
-    test_parsers(parse_lambda, Lambda, &test)
+    test_parsers(parse_lambda, ToProcessKind::Lambda, &test)
 }
 
 #[test]
@@ -135,7 +144,151 @@ fn test_args() -> Result<(), PloyErrorKind> {
         ("[^:meta a b c]", vec![Arg, Arg, Arg]),
         ("[a : string b c]", vec![Arg, Arg, Arg]),
         ("[z]", vec![Arg]),
+        ("[a b & rest]", vec![Arg, Arg, RestArg]),
+        ("[& rest]", vec![RestArg]),
+        ("[[a b] {:keys [x y]} & [c]]", vec![VecPattern, MapPattern, RestArg]),
     ];
 
     test_parsers(parse_args, Args, &test)
 }
+
+#[test]
+fn test_cond_do_macro() -> Result<(), PloyErrorKind> {
+    use AstNodeKind::*;
+
+    let test = vec![
+        ("(cond)", vec![]),
+        ("(cond a 1 :else 2)", vec![ToProcessKind::Symbol.into(), Number, KeyWord, Number]),
+    ];
+    test_parsers(parse_cond, ToProcessKind::Cond, &test)?;
+
+    let test = vec![("(do)", vec![]), ("(do 1 (a))", vec![Number, ToProcessKind::Application.into()])];
+    test_parsers(parse_do, ToProcessKind::Do, &test)?;
+
+    let test = vec![("(recur)", vec![]), ("(recur 1 a)", vec![Number, ToProcessKind::Symbol.into()])];
+    test_parsers(parse_recur, ToProcessKind::Recur, &test)?;
+
+    let test = vec![("(macro sq [x] (* x x))", vec![Arg, ToProcessKind::Lambda.into()])];
+    test_parsers(parse_macro, ToProcessKind::Macro, &test)?;
+
+    let ast = as_ast("(macro sq [x] (* x x) x)", parse_macro)?;
+    let lambda = ast.get_nth_kid_id(ast.get_root_id(), 1).unwrap();
+    let body = ast.tree.get(lambda).unwrap().first_child().unwrap();
+    assert_eq!(
+        kids_kinds(body),
+        vec![Args, ToProcessKind::Application.into(), ToProcessKind::Symbol.into()]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_defmacro() -> Result<(), PloyErrorKind> {
+    use AstNodeKind::*;
+
+    let test = vec![("(defmacro sq [x] `(* ,x ,x))", vec![Arg, ToProcessKind::Lambda.into()])];
+    test_parsers(parse_defmacro, DefMacro, &test)?;
+
+    let ast = as_ast("(defmacro sum [xs] `(+ 1 ,@xs))", parse_defmacro)?;
+    let lambda = ast.get_nth_kid_id(ast.get_root_id(), 1).unwrap();
+    let body = ast.tree.get(lambda).unwrap().first_child().unwrap();
+    assert_eq!(kids_kinds(body), vec![Args, QuasiQuote]);
+
+    let quasi = body.last_child().unwrap();
+    let template = quasi.first_child().unwrap();
+    assert_eq!(
+        kids_kinds(template),
+        vec![ToProcessKind::Symbol.into(), Number, UnquoteSplicing]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_error_recovery() {
+    use AstNodeKind::*;
+
+    let text = "(def a 1)\n(let [a] 1)\n(if)\n(+ a [1\n(def b 2)\n)\n3";
+    let source_file = SourceFile::new(text.to_owned(), sources::SourceOrigin::Text);
+    let tokes = tokenize(&source_file);
+    let (ast, errors) = parse_ast(&tokes, source_file.clone());
+
+    let kids: Vec<_> = ast.tree.root().children().map(|n| n.value().kind.clone()).collect();
+    assert_eq!(kids, vec![Define, Error, Error, Error, Define, Error, Number]);
+
+    // The unclosed form stops at the next form starting a line
+    let broken: Vec<_> = ast
+        .tree
+        .root()
+        .children()
+        .filter(|n| n.value().kind == Error)
+        .map(|n| ast.get_source_text(n.id()))
+        .collect();
+    assert_eq!(broken, vec!["(let [a] 1)", "(if)", "(+ a [1", ")"]);
+
+    let starts: Vec<_> = errors.iter().map(|e| text[e.pos.start..].lines().next().unwrap()).collect();
+    assert_eq!(starts, vec!["a] 1)", ")", ")", ")"]);
+    assert_eq!(errors[3].kind.to_string(), "Unxpected syntax: Unexpected input");
+}
+
+#[test]
+fn test_cst_round_trip() {
+    let file = std::fs::read_to_string("testsrc/test.ploy").unwrap();
+
+    let texts = [
+        file.as_str(),
+        "",
+        "  \n\t",
+        "; just a comment",
+        "(def a 1) ; no newline after this",
+        "(def f (fn [x] ; the arg\n  (* x x)))\n\n\n(f 2)\n",
+        "'(1 2 `(3 ,@xs)) ^{:doc \"hi\"} x",
+        "(+ a [1\n(def b 2)\n)\n)) ]",
+        "(if\r\n  \"ünï\" 'c')\n% @ ' ",
+    ];
+
+    for text in texts {
+        let source_file = SourceFile::new(text.to_owned(), sources::SourceOrigin::Text);
+        let tokes = tokenize_with_trivia(&source_file);
+        let cst = parse_cst(&tokes);
+
+        assert_eq!(cst.to_text(text), text);
+        assert_eq!(cst.range, 0..text.len());
+
+        // Without trivia it's the same tokens the parser sees
+        let kinds: Vec<_> = cst.tokens().filter(|t| !t.is_trivia()).map(|t| t.token_kind()).collect();
+        let expected: Vec<_> = tokenize(&source_file).iter().map(|t| Some(t.kind)).collect();
+        assert_eq!(kinds, expected, "{text}");
+    }
+}
+
+#[test]
+fn test_cst() {
+    use TokenKind::*;
+
+    let text = "; sq\n(def sq (fn [x] (* x x))) ' y\n(1 [2";
+    let source_file = SourceFile::new(text.to_owned(), sources::SourceOrigin::Text);
+    let cst = parse_cst(&tokenize_with_trivia(&source_file));
+
+    let forms: Vec<_> = cst.forms().map(|f| (f.kind, f.text(text))).collect();
+    assert_eq!(
+        forms,
+        vec![
+            (CstKind::List, "(def sq (fn [x] (* x x)))"),
+            (CstKind::Prefixed, "' y"),
+            (CstKind::List, "(1 [2"),
+        ]
+    );
+
+    assert_eq!(cst.children[0].token_kind(), Some(Comment));
+
+    let def = cst.forms().next().unwrap();
+    assert!(def.is_closed());
+    let kids: Vec<_> = def.forms().map(|f| f.text(text)).collect();
+    assert_eq!(kids, vec!["(", "def", "sq", "(fn [x] (* x x))", ")"]);
+
+    // The file ends before either is closed
+    let open = cst.forms().nth(2).unwrap();
+    assert!(!open.is_closed());
+    assert!(!open.forms().last().unwrap().is_closed());
+}